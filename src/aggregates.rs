@@ -0,0 +1,283 @@
+//! Running transaction totals per merchant and period.
+//!
+//! Every transaction counts towards a day, a month and a year item under its outlet and under its
+//! settlement merchant, e.g. `AGG#DAY#2025-01-05#EUR`, `AGG#MONTH#2025-01#EUR` and
+//! `AGG#YEAR#2025#EUR`, dated by the transaction and split by currency. The writes that record a
+//! transaction or change its status adjust the items with `UpdateItem ADD` in the same DynamoDB
+//! transaction, so a summary is a single query however many transactions the merchant has.
+
+use crate::models::{Transaction, TransactionStatus, TransactionType};
+use anyhow::{Error, bail};
+use async_graphql::{Enum, SimpleObject};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use strum_macros::Display;
+
+pub const AGGREGATE_PREFIX: &str = "AGG";
+
+#[derive(Enum, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash, Display)]
+pub enum AggregatePeriod {
+    Day,
+    Month,
+    Year,
+}
+
+impl AggregatePeriod {
+    pub const ALL: [AggregatePeriod; 3] = [
+        AggregatePeriod::Day,
+        AggregatePeriod::Month,
+        AggregatePeriod::Year,
+    ];
+
+    fn key_name(self) -> &'static str {
+        match self {
+            AggregatePeriod::Day => "DAY",
+            AggregatePeriod::Month => "MONTH",
+            AggregatePeriod::Year => "YEAR",
+        }
+    }
+
+    /// The period an RFC 3339 timestamp falls in, e.g. `2025-01` for a month.
+    pub fn date_of(self, timestamp: &str) -> &str {
+        let len = match self {
+            AggregatePeriod::Day => 10,
+            AggregatePeriod::Month => 7,
+            AggregatePeriod::Year => 4,
+        };
+        timestamp.get(..len).unwrap_or(timestamp)
+    }
+
+    /// Accepts `2025-01-05` for days, `2025-01` for months and `2025` for years.
+    pub fn validate(self, date: &str) -> Result<(), Error> {
+        let valid = match self {
+            AggregatePeriod::Day => NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok(),
+            AggregatePeriod::Month => {
+                date.len() == 7
+                    && NaiveDate::parse_from_str(&format!("{date}-01"), "%Y-%m-%d").is_ok()
+            }
+            AggregatePeriod::Year => date.len() == 4 && date.chars().all(|c| c.is_ascii_digit()),
+        };
+        if !valid {
+            bail!("Invalid {} {date}", self.to_string().to_lowercase());
+        }
+        Ok(())
+    }
+
+    /// Sort key prefix of every currency's item for the period.
+    pub fn sort_key_prefix(self, date: &str) -> String {
+        format!("{AGGREGATE_PREFIX}#{}#{date}#", self.key_name())
+    }
+}
+
+/// Totals of one merchant's transactions in one currency over one period.
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct TransactionAggregate {
+    pub merchant_id: String,
+    #[graphql(skip)]
+    pub id: String,
+    pub period: AggregatePeriod,
+    pub date: String,
+    pub currency: String,
+    pub transactions: i64,
+    /// Purchases less refunds, leaving out chargebacks, as in payouts.
+    pub amount: f64,
+    pub purchases: i64,
+    pub refunds: i64,
+    pub processed: i64,
+    pub cleared: i64,
+    pub chargebacked: i64,
+    pub paid: i64,
+    pub chargebacked_amount: f64,
+}
+
+/// Identifies one aggregate item.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AggregateKey {
+    pub merchant_id: String,
+    pub period: AggregatePeriod,
+    pub date: String,
+    pub currency: String,
+}
+
+impl AggregateKey {
+    pub fn sort_key(&self) -> String {
+        format!(
+            "{}{}",
+            self.period.sort_key_prefix(&self.date),
+            self.currency
+        )
+    }
+
+    /// The items a transaction counts towards: each period under its outlet and, when another
+    /// merchant settles it, under that merchant too.
+    pub fn of(transaction: &Transaction) -> Vec<AggregateKey> {
+        let mut merchant_ids = vec![transaction.merchant_id.as_str()];
+        if transaction.settlement_merchant_id != transaction.merchant_id {
+            merchant_ids.push(transaction.settlement_merchant_id.as_str());
+        }
+        merchant_ids
+            .into_iter()
+            .flat_map(|merchant_id| {
+                AggregatePeriod::ALL.map(|period| AggregateKey {
+                    merchant_id: merchant_id.to_string(),
+                    period,
+                    date: period.date_of(&transaction.date_transaction).to_string(),
+                    currency: transaction.currency.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Change to the counters of an aggregate item.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AggregateDelta {
+    pub transactions: i64,
+    pub amount: f64,
+    pub purchases: i64,
+    pub refunds: i64,
+    pub processed: i64,
+    pub cleared: i64,
+    pub chargebacked: i64,
+    pub paid: i64,
+    pub chargebacked_amount: f64,
+}
+
+impl AggregateDelta {
+    /// What one transaction adds to its aggregates.
+    pub fn of(transaction: &Transaction) -> Self {
+        let mut delta = AggregateDelta {
+            transactions: 1,
+            ..AggregateDelta::default()
+        };
+        match transaction.transaction_type {
+            TransactionType::Purchase => delta.purchases = 1,
+            TransactionType::Refund => delta.refunds = 1,
+        }
+        match transaction.status {
+            TransactionStatus::Processed => delta.processed = 1,
+            TransactionStatus::Cleared => delta.cleared = 1,
+            TransactionStatus::Chargebacked => delta.chargebacked = 1,
+            TransactionStatus::Paid => delta.paid = 1,
+        }
+        delta.amount = match (transaction.transaction_type, transaction.status) {
+            (_, TransactionStatus::Chargebacked) => 0.0,
+            (TransactionType::Purchase, _) => transaction.amount,
+            (TransactionType::Refund, _) => -transaction.amount,
+        };
+        if transaction.status == TransactionStatus::Chargebacked {
+            delta.chargebacked_amount = transaction.amount;
+        }
+        delta
+    }
+
+    /// What replacing `before` with `after` changes; zero when nothing counted changed.
+    pub fn between(before: &Transaction, after: &Transaction) -> Self {
+        let mut delta = AggregateDelta::of(after);
+        delta.subtract(&AggregateDelta::of(before));
+        delta
+    }
+
+    pub fn add(&mut self, other: &AggregateDelta) {
+        self.transactions += other.transactions;
+        self.amount += other.amount;
+        self.purchases += other.purchases;
+        self.refunds += other.refunds;
+        self.processed += other.processed;
+        self.cleared += other.cleared;
+        self.chargebacked += other.chargebacked;
+        self.paid += other.paid;
+        self.chargebacked_amount += other.chargebacked_amount;
+    }
+
+    fn subtract(&mut self, other: &AggregateDelta) {
+        self.transactions -= other.transactions;
+        self.amount -= other.amount;
+        self.purchases -= other.purchases;
+        self.refunds -= other.refunds;
+        self.processed -= other.processed;
+        self.cleared -= other.cleared;
+        self.chargebacked -= other.chargebacked;
+        self.paid -= other.paid;
+        self.chargebacked_amount -= other.chargebacked_amount;
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == AggregateDelta::default()
+    }
+
+    /// Attribute names and DynamoDB numbers to `ADD`. Amounts are rounded to cents so float
+    /// sums do not leave fractions of a cent in the stored decimals.
+    pub fn counters(&self) -> [(&'static str, String); 9] {
+        [
+            ("transactions", self.transactions.to_string()),
+            ("amount", round_cents(self.amount).to_string()),
+            ("purchases", self.purchases.to_string()),
+            ("refunds", self.refunds.to_string()),
+            ("processed", self.processed.to_string()),
+            ("cleared", self.cleared.to_string()),
+            ("chargebacked", self.chargebacked.to_string()),
+            ("paid", self.paid.to_string()),
+            (
+                "chargebacked_amount",
+                round_cents(self.chargebacked_amount).to_string(),
+            ),
+        ]
+    }
+}
+
+/// Sums what `transactions` add to each of their aggregates.
+pub fn fold<'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+) -> HashMap<AggregateKey, AggregateDelta> {
+    let mut deltas: HashMap<AggregateKey, AggregateDelta> = HashMap::new();
+    for transaction in transactions {
+        let delta = AggregateDelta::of(transaction);
+        for key in AggregateKey::of(transaction) {
+            deltas.entry(key).or_default().add(&delta);
+        }
+    }
+    deltas
+}
+
+impl TransactionAggregate {
+    /// The item holding exactly `delta`, as rebuilt from scratch.
+    pub fn new(key: &AggregateKey, delta: &AggregateDelta) -> Self {
+        TransactionAggregate {
+            merchant_id: key.merchant_id.clone(),
+            id: key.sort_key(),
+            period: key.period,
+            date: key.date.clone(),
+            currency: key.currency.clone(),
+            transactions: delta.transactions,
+            amount: round_cents(delta.amount),
+            purchases: delta.purchases,
+            refunds: delta.refunds,
+            processed: delta.processed,
+            cleared: delta.cleared,
+            chargebacked: delta.chargebacked,
+            paid: delta.paid,
+            chargebacked_amount: round_cents(delta.chargebacked_amount),
+        }
+    }
+
+    /// Applies `delta` the way `UpdateItem ADD` does, amounts rounded to cents.
+    pub fn add(&mut self, delta: &AggregateDelta) {
+        self.transactions += delta.transactions;
+        self.amount = round_cents(self.amount + round_cents(delta.amount));
+        self.purchases += delta.purchases;
+        self.refunds += delta.refunds;
+        self.processed += delta.processed;
+        self.cleared += delta.cleared;
+        self.chargebacked += delta.chargebacked;
+        self.paid += delta.paid;
+        self.chargebacked_amount =
+            round_cents(self.chargebacked_amount + round_cents(delta.chargebacked_amount));
+    }
+}
+
+fn round_cents(amount: f64) -> f64 {
+    // Adding zero turns a negative zero into "0" rather than "-0".
+    (amount * 100.0).round() / 100.0 + 0.0
+}
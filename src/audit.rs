@@ -0,0 +1,75 @@
+use crate::models::Role;
+use async_graphql::SimpleObject;
+use chrono::{SecondsFormat, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+pub const AUDIT_PREFIX: &str = "AUDIT";
+pub const ANONYMOUS_ACTOR: &str = "anonymous";
+
+/// Identity of the caller as passed in the `X-Actor-Id` header.
+#[derive(Clone, Debug)]
+pub struct ActorId(pub String);
+
+#[derive(Clone, Debug)]
+pub struct Actor {
+    pub id: String,
+    pub role: Option<Role>,
+    pub request_id: Option<String>,
+}
+
+/// One audited operation. Entries are partitioned by day (`AUDIT#2025-01-05`) and sorted by
+/// `id`, which starts with the timestamp.
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct AuditEntry {
+    pub id: String,
+    pub actor_id: String,
+    pub role: Option<String>,
+    pub operation: String,
+    pub target_ids: Vec<String>,
+    /// JSON image of the target before the operation.
+    pub before: Option<String>,
+    /// JSON image of the target after the operation.
+    pub after: Option<String>,
+    pub timestamp: String,
+    /// `X-Request-Id` of the request that performed the operation.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: &Actor, operation: &str, target_ids: Vec<String>) -> Self {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        let suffix: u32 = rand::thread_rng().r#gen();
+        Self {
+            id: format!("{timestamp}#{suffix:08x}"),
+            actor_id: actor.id.clone(),
+            role: actor.role.map(|role| role.to_string()),
+            operation: operation.to_string(),
+            target_ids,
+            before: None,
+            after: None,
+            timestamp,
+            request_id: actor.request_id.clone(),
+        }
+    }
+
+    pub fn with_before<T: Serialize>(mut self, image: &T) -> Self {
+        self.before = serde_json::to_string(image).ok();
+        self
+    }
+
+    pub fn with_after<T: Serialize>(mut self, image: &T) -> Self {
+        self.after = serde_json::to_string(image).ok();
+        self
+    }
+
+    pub fn partition_key(&self) -> String {
+        audit_partition_key(&self.timestamp[..10])
+    }
+}
+
+/// Partition holding the audit entries of a `YYYY-MM-DD` day.
+pub fn audit_partition_key(date: &str) -> String {
+    format!("{AUDIT_PREFIX}#{date}")
+}
@@ -0,0 +1,657 @@
+//! In-process cache of merchant reads.
+//!
+//! Merchants change rarely but are read by nearly every request, to resolve `merchant`, to find
+//! the outlets below a group and to find who settles an outlet's transactions. [`CachingStore`]
+//! keeps those answers for `--merchant-cache-ttl-secs`, up to `--merchant-cache-capacity` entries
+//! each, in front of another store.
+//!
+//! Merchant writes made through the cache invalidate it. Writes made elsewhere, by another
+//! replica or the CLI, are seen once the TTL runs out, or right away when the server follows the
+//! table stream with `--merchant-cache-stream-consumer`. Since a change to one merchant can move
+//! every outlet below it, any invalidation drops all hierarchy lookups. A read that overlapped an
+//! invalidation is returned but not cached, as it may predate the change.
+
+use crate::aggregates::{AggregatePeriod, TransactionAggregate};
+use crate::audit::{Actor, AuditEntry};
+use crate::events::TransactionStatusChange;
+use crate::metrics::metrics;
+use crate::models::{
+    CardBrand, HierarchyChange, Merchant, PageRequest, Payout, Transaction, TransactionCursor,
+    TransactionStatus,
+};
+use crate::store::{AuditStore, MerchantStore, SharedStore, Store, TransactionStore};
+use crate::streams::{ChangeHandler, TableChange};
+use crate::webhooks::{DeliveryAttempt, DeliveryOutcome, Webhook, WebhookDelivery};
+use anyhow::Error;
+use async_trait::async_trait;
+use clap::Args;
+use futures::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// `max-age` of the `Cache-Control` hint on merchant reads, matching the default TTL.
+pub const MERCHANT_MAX_AGE_SECS: u64 = 60;
+
+/// Caching of merchant reads.
+#[derive(Args, Clone, Debug)]
+pub struct CacheArgs {
+    /// How long merchant reads are cached, in seconds; 0 turns the cache off.
+    #[arg(long, global = true, env = "MERCHANT_CACHE_TTL_SECS", default_value_t = MERCHANT_MAX_AGE_SECS)]
+    pub merchant_cache_ttl_secs: u64,
+
+    /// Most merchants, and most hierarchy lookups of each kind, held at once.
+    #[arg(
+        long,
+        global = true,
+        env = "MERCHANT_CACHE_CAPACITY",
+        default_value_t = 10_000
+    )]
+    pub merchant_cache_capacity: usize,
+
+    /// Follow the table stream under this consumer name, invalidating merchants changed
+    /// elsewhere. Each replica needs its own name.
+    #[arg(long, global = true, env = "MERCHANT_CACHE_STREAM_CONSUMER")]
+    pub merchant_cache_stream_consumer: Option<String>,
+}
+
+/// Values kept for a fixed time, up to a number of entries.
+pub struct TtlCache<V> {
+    name: &'static str,
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, (V, Instant)>>,
+}
+
+impl<V: Clone> TtlCache<V> {
+    fn new(name: &'static str, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            name,
+            ttl,
+            capacity: capacity.max(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = match entries.get(key) {
+            Some((value, expires_at)) if Instant::now() < *expires_at => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        metrics().observe_cache_lookup(self.name, value.is_some());
+        value
+    }
+
+    /// Stores a value if `fresh`, checked under the lock that removals also take, first dropping
+    /// expired entries, or every entry when none expired, when the cache is full.
+    pub fn insert_if(&self, key: String, value: V, fresh: impl FnOnce() -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        if !fresh() {
+            return;
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, (_, expires_at)| now < *expires_at);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (value, Instant::now() + self.ttl));
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Cached merchants and hierarchy lookups, shared by the store and the stream handler.
+pub struct MerchantCache {
+    merchants: TtlCache<Merchant>,
+    settlement_merchants: TtlCache<String>,
+    descendant_outlets: TtlCache<Vec<String>>,
+    /// Bumped by every invalidation, so reads that overlapped one can tell.
+    generation: AtomicU64,
+}
+
+impl MerchantCache {
+    pub fn new(args: &CacheArgs) -> Self {
+        let ttl = Duration::from_secs(args.merchant_cache_ttl_secs);
+        let capacity = args.merchant_cache_capacity;
+        Self {
+            merchants: TtlCache::new("merchant", ttl, capacity),
+            settlement_merchants: TtlCache::new("settlement_merchant", ttl, capacity),
+            descendant_outlets: TtlCache::new("descendant_outlets", ttl, capacity),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Forgets a changed merchant and every hierarchy lookup.
+    pub fn invalidate(&self, merchant_id: &str) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.merchants.remove(merchant_id);
+        self.settlement_merchants.clear();
+        self.descendant_outlets.clear();
+    }
+
+    /// The generation to pass to [`Self::insert_read`] for a read starting now.
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches a value read since `generation`, unless an invalidation happened in the meantime.
+    fn insert_read<V: Clone>(&self, cache: &TtlCache<V>, generation: u64, key: String, value: V) {
+        cache.insert_if(key, value, || self.generation() == generation);
+    }
+}
+
+/// A store answering merchant reads from a [`MerchantCache`] when it can.
+pub struct CachingStore {
+    inner: SharedStore,
+    cache: Arc<MerchantCache>,
+}
+
+impl CachingStore {
+    pub fn new(inner: SharedStore, cache: Arc<MerchantCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+/// Invalidates merchants changed by other writers, as seen on the table stream.
+pub struct CacheInvalidation(pub Arc<MerchantCache>);
+
+impl ChangeHandler for CacheInvalidation {
+    fn name(&self) -> &'static str {
+        "merchant-cache"
+    }
+
+    fn handle<'a>(&'a self, change: &'a TableChange) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            if let TableChange::Merchant(change) = change
+                && let Some(merchant) = change.latest()
+            {
+                self.0.invalidate(&merchant.id);
+            }
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl MerchantStore for CachingStore {
+    async fn add_merchant(&self, merchant: &Merchant) -> Result<(), Error> {
+        let result = self.inner.add_merchant(merchant).await;
+        self.cache.invalidate(&merchant.id);
+        result
+    }
+
+    async fn get_merchant(&self, merchant_id: String) -> Result<Merchant, Error> {
+        if let Some(merchant) = self.cache.merchants.get(&merchant_id) {
+            return Ok(merchant);
+        }
+        let generation = self.cache.generation();
+        let merchant = self.inner.get_merchant(merchant_id.clone()).await?;
+        self.cache.insert_read(
+            &self.cache.merchants,
+            generation,
+            merchant_id,
+            merchant.clone(),
+        );
+        Ok(merchant)
+    }
+
+    async fn get_settlement_merchant_id(&self, outlet_id: &str) -> Result<String, Error> {
+        if let Some(settlement_merchant_id) = self.cache.settlement_merchants.get(outlet_id) {
+            return Ok(settlement_merchant_id);
+        }
+        let generation = self.cache.generation();
+        let settlement_merchant_id = self.inner.get_settlement_merchant_id(outlet_id).await?;
+        self.cache.insert_read(
+            &self.cache.settlement_merchants,
+            generation,
+            outlet_id.to_string(),
+            settlement_merchant_id.clone(),
+        );
+        Ok(settlement_merchant_id)
+    }
+
+    async fn get_descendant_outlets(&self, merchant_id: String) -> Result<Vec<String>, Error> {
+        if let Some(outlets) = self.cache.descendant_outlets.get(&merchant_id) {
+            return Ok(outlets);
+        }
+        let generation = self.cache.generation();
+        let outlets = self
+            .inner
+            .get_descendant_outlets(merchant_id.clone())
+            .await?;
+        self.cache.insert_read(
+            &self.cache.descendant_outlets,
+            generation,
+            merchant_id,
+            outlets.clone(),
+        );
+        Ok(outlets)
+    }
+
+    async fn attach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        let result = self
+            .inner
+            .attach_sub_merchant(parent_id, child_id, actor)
+            .await;
+        self.cache.invalidate(parent_id);
+        self.cache.invalidate(child_id);
+        result
+    }
+
+    async fn detach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        let result = self
+            .inner
+            .detach_sub_merchant(parent_id, child_id, actor)
+            .await;
+        self.cache.invalidate(parent_id);
+        self.cache.invalidate(child_id);
+        result
+    }
+
+    async fn move_merchant(
+        &self,
+        merchant_id: &str,
+        new_parent_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        // The old parent loses a sub-merchant, so it is looked up before it is unknown.
+        let old_parent_id = self
+            .inner
+            .get_merchant(merchant_id.to_string())
+            .await
+            .ok()
+            .and_then(|merchant| merchant.parent_merchant_id);
+        let result = self
+            .inner
+            .move_merchant(merchant_id, new_parent_id, actor)
+            .await;
+        self.cache.invalidate(merchant_id);
+        self.cache.invalidate(new_parent_id);
+        if let Some(old_parent_id) = old_parent_id {
+            self.cache.invalidate(&old_parent_id);
+        }
+        result
+    }
+
+    async fn get_hierarchy_history(
+        &self,
+        merchant_id: String,
+    ) -> Result<Vec<HierarchyChange>, Error> {
+        self.inner.get_hierarchy_history(merchant_id).await
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        self.inner.add_webhook(webhook, actor).await
+    }
+
+    async fn get_webhooks(&self, merchant_id: &str) -> Result<Vec<Webhook>, Error> {
+        self.inner.get_webhooks(merchant_id).await
+    }
+
+    async fn get_webhook(
+        &self,
+        merchant_id: &str,
+        webhook_id: &str,
+    ) -> Result<Option<Webhook>, Error> {
+        self.inner.get_webhook(merchant_id, webhook_id).await
+    }
+
+    async fn delete_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        self.inner.delete_webhook(webhook, actor).await
+    }
+
+    async fn get_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<WebhookDelivery>, bool), Error> {
+        self.inner.get_webhook_deliveries(webhook_id, page).await
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: &str,
+        limit: i32,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        self.inner.get_due_webhook_deliveries(now, limit).await
+    }
+
+    async fn claim_webhook_delivery(
+        &self,
+        delivery: &WebhookDelivery,
+        lease_until: &str,
+    ) -> Result<bool, Error> {
+        self.inner
+            .claim_webhook_delivery(delivery, lease_until)
+            .await
+    }
+
+    async fn record_webhook_delivery_attempt(
+        &self,
+        delivery: &WebhookDelivery,
+        attempt: &DeliveryAttempt,
+        outcome: &DeliveryOutcome,
+    ) -> Result<(), Error> {
+        self.inner
+            .record_webhook_delivery_attempt(delivery, attempt, outcome)
+            .await
+    }
+}
+
+#[async_trait]
+impl TransactionStore for CachingStore {
+    async fn add_transaction(&self, transaction: Transaction) -> Result<bool, Error> {
+        self.inner.add_transaction(transaction).await
+    }
+
+    async fn record_transaction(
+        &self,
+        transaction: Transaction,
+        actor: &Actor,
+    ) -> Result<bool, Error> {
+        self.inner.record_transaction(transaction, actor).await
+    }
+
+    async fn update_transaction_status(
+        &self,
+        merchant_id: &str,
+        transaction_id: &str,
+        status: TransactionStatus,
+        actor: &Actor,
+    ) -> Result<TransactionStatusChange, Error> {
+        self.inner
+            .update_transaction_status(merchant_id, transaction_id, status, actor)
+            .await
+    }
+
+    async fn get_transactions(
+        &self,
+        merchant_id: String,
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        self.inner
+            .get_transactions(merchant_id, year, month, day, card_brand, page)
+            .await
+    }
+
+    async fn get_transactions_for_merchants(
+        &self,
+        merchant_ids: &[String],
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        self.inner
+            .get_transactions_for_merchants(merchant_ids, year, month, day, card_brand, page)
+            .await
+    }
+
+    async fn get_transactions_for_merchants_between(
+        &self,
+        merchant_ids: &[String],
+        earlier_transaction: &str,
+        later_transaction: &str,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        self.inner
+            .get_transactions_for_merchants_between(
+                merchant_ids,
+                earlier_transaction,
+                later_transaction,
+                card_brand,
+                page,
+            )
+            .await
+    }
+
+    async fn get_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        self.inner
+            .get_transactions_for_settlement_merchant(settlement_merchant_id, page)
+            .await
+    }
+
+    async fn get_all_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_transaction: &str,
+        later_transaction: &str,
+    ) -> Result<Vec<Transaction>, Error> {
+        self.inner
+            .get_all_transactions_for_settlement_merchant(
+                settlement_merchant_id,
+                earlier_transaction,
+                later_transaction,
+            )
+            .await
+    }
+
+    async fn get_transaction_aggregates(
+        &self,
+        merchant_id: &str,
+        period: AggregatePeriod,
+        date: &str,
+    ) -> Result<Vec<TransactionAggregate>, Error> {
+        self.inner
+            .get_transaction_aggregates(merchant_id, period, date)
+            .await
+    }
+
+    async fn add_payout(&self, payout: &Payout, actor: &Actor) -> Result<(), Error> {
+        self.inner.add_payout(payout, actor).await
+    }
+
+    async fn get_payouts(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_payout: &str,
+        later_payout: &str,
+    ) -> Result<Vec<Payout>, Error> {
+        self.inner
+            .get_payouts(settlement_merchant_id, earlier_payout, later_payout)
+            .await
+    }
+}
+
+#[async_trait]
+impl AuditStore for CachingStore {
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), Error> {
+        self.inner.add_audit_entry(audit_entry).await
+    }
+
+    async fn get_audit_log(
+        &self,
+        date: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<AuditEntry>, bool), Error> {
+        self.inner.get_audit_log(date, page).await
+    }
+}
+
+#[async_trait]
+impl Store for CachingStore {
+    async fn check_ready(&self) -> Result<(), Error> {
+        self.inner.check_ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheArgs, CacheInvalidation, CachingStore, MerchantCache, TtlCache};
+    use crate::build_schema;
+    use crate::limits::QueryLimits;
+    use crate::memory::MemoryStore;
+    use crate::models::{Merchant, MerchantLevel, Role};
+    use crate::persisted_queries::PersistedQueries;
+    use crate::store::{MerchantStore, SharedStore};
+    use crate::streams::{Change, ChangeHandler, ChangeKind, TableChange};
+    use async_graphql::Request;
+    use std::{sync::Arc, thread, time::Duration};
+
+    fn merchant(name: &str) -> Merchant {
+        Merchant {
+            id: "MERCHANT#cached".to_string(),
+            name: name.to_string(),
+            founded_date: "1735689600000".to_string(),
+            industry: "Retail".to_string(),
+            vat_number: "VAT123".to_string(),
+            created_at: 1735689600000,
+            merchant_level: MerchantLevel::Outlet,
+            sub_merchants: vec![],
+            parent_merchant_id: None,
+            has_settlement_permissions: true,
+            has_billing_permissions: true,
+        }
+    }
+
+    fn cache() -> Arc<MerchantCache> {
+        Arc::new(MerchantCache::new(&CacheArgs {
+            merchant_cache_ttl_secs: 60,
+            merchant_cache_capacity: 100,
+            merchant_cache_stream_consumer: None,
+        }))
+    }
+
+    #[actix_web::test]
+    async fn merchants_are_cached_until_changed_through_the_cache_or_the_stream() {
+        let inner: SharedStore = Arc::new(MemoryStore::default());
+        inner.add_merchant(&merchant("Before")).await.unwrap();
+        let cache = cache();
+        let store = CachingStore::new(inner.clone(), cache.clone());
+        let name = || async {
+            store
+                .get_merchant("MERCHANT#cached".to_string())
+                .await
+                .unwrap()
+                .name
+        };
+        assert_eq!(name().await, "Before");
+
+        inner.add_merchant(&merchant("Elsewhere")).await.unwrap();
+        assert_eq!(name().await, "Before", "read from the cache");
+        let change = TableChange::Merchant(Change {
+            kind: ChangeKind::Modify,
+            old: Some(merchant("Before")),
+            new: Some(merchant("Elsewhere")),
+        });
+        CacheInvalidation(cache).handle(&change).await.unwrap();
+        assert_eq!(name().await, "Elsewhere");
+
+        store.add_merchant(&merchant("Through")).await.unwrap();
+        assert_eq!(name().await, "Through");
+    }
+
+    #[test]
+    fn reads_overlapping_an_invalidation_are_not_cached() {
+        let cache = cache();
+        let generation = cache.generation();
+        cache.invalidate("MERCHANT#cached");
+        cache.insert_read(
+            &cache.merchants,
+            generation,
+            "MERCHANT#cached".to_string(),
+            merchant("Stale"),
+        );
+        assert!(cache.merchants.get("MERCHANT#cached").is_none());
+
+        let generation = cache.generation();
+        cache.insert_read(
+            &cache.merchants,
+            generation,
+            "MERCHANT#cached".to_string(),
+            merchant("Fresh"),
+        );
+        assert_eq!(
+            cache
+                .merchants
+                .get("MERCHANT#cached")
+                .map(|merchant| merchant.name),
+            Some("Fresh".to_string())
+        );
+    }
+
+    #[test]
+    fn entries_expire_and_a_full_cache_makes_room() {
+        let cache = TtlCache::new("test", Duration::from_millis(20), 2);
+        cache.insert_if("a".to_string(), 1, || true);
+        cache.insert_if("b".to_string(), 2, || true);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert_if("c".to_string(), 3, || true);
+        assert_eq!(
+            cache.get("a"),
+            None,
+            "nothing had expired, so everything went"
+        );
+        assert_eq!(cache.get("c"), Some(3));
+        thread::sleep(Duration::from_millis(25));
+        assert_eq!(cache.get("c"), None);
+    }
+
+    #[actix_web::test]
+    async fn only_responses_of_merchant_reads_may_be_cached() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        store.add_merchant(&merchant("Cached")).await.unwrap();
+        let schema = build_schema(store, QueryLimits::default(), PersistedQueries::default());
+        let cache_control = |query: &'static str| {
+            let schema = schema.clone();
+            async move {
+                let response = schema.execute(Request::new(query).data(Role::Reader)).await;
+                assert!(response.errors.is_empty(), "{:?}", response.errors);
+                response.cache_control.value()
+            }
+        };
+
+        assert_eq!(
+            cache_control(r#"{ merchant(merchantId: "MERCHANT#cached") { id name } }"#).await,
+            Some("max-age=60, private".to_string())
+        );
+        assert_eq!(
+            cache_control(
+                r#"{
+                    merchant(merchantId: "MERCHANT#cached") { id }
+                    transactionSummary(merchantId: "MERCHANT#cached", period: DAY, date: "2025-01-01") { transactions }
+                }"#
+            )
+            .await,
+            Some("no-cache, private".to_string())
+        );
+    }
+}
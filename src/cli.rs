@@ -0,0 +1,236 @@
+use crate::aggregates::AggregatePeriod;
+use crate::cache::CacheArgs;
+use crate::export::ExportFormat;
+use crate::generate::GenerateArgs;
+use crate::limits::QueryLimits;
+use crate::models::CardBrand;
+use crate::output::OutputFormat;
+use crate::persisted_queries::PersistedQueryArgs;
+use crate::rate_limit::RateLimitArgs;
+use crate::resilience::ResilienceArgs;
+use crate::store::StoreKind;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about = "Merchant and transaction GraphQL API")]
+pub struct Cli {
+    /// DynamoDB endpoint, DynamoDB Local by default.
+    #[arg(
+        long,
+        global = true,
+        env = "DYNAMODB_ENDPOINT",
+        default_value = "http://db:8000"
+    )]
+    pub dynamodb_endpoint: String,
+
+    /// DynamoDB table holding every item.
+    #[arg(long, global = true, env = "TABLE_NAME", default_value = crate::dynamo::TABLE_NAME)]
+    pub table: String,
+
+    /// Where `serve` keeps its data.
+    #[arg(long, global = true, env = "STORE", value_enum, default_value_t)]
+    pub store: StoreKind,
+
+    #[command(flatten)]
+    pub resilience: ResilienceArgs,
+
+    #[command(flatten)]
+    pub limits: QueryLimits,
+
+    #[command(flatten)]
+    pub rate_limits: RateLimitArgs,
+
+    #[command(flatten)]
+    pub persisted_queries: PersistedQueryArgs,
+
+    #[command(flatten)]
+    pub cache: CacheArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the GraphQL server (the default).
+    Serve,
+    /// Apply pending schema migrations.
+    Migrate {
+        /// Log what each pending migration would do without changing the table.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Load merchants and transactions from a fixture file.
+    Seed {
+        /// JSON or YAML fixture; the bundled `fixtures/default.yaml` when omitted.
+        #[arg(long)]
+        fixture: Option<PathBuf>,
+        /// RNG seed for generated transactions, overriding the fixture's.
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Write large volumes of synthetic transactions for benchmarking.
+    Generate(GenerateArgs),
+    /// Import transactions from a CSV file; see `src/import.rs` for the columns.
+    Import {
+        /// CSV file with a header line.
+        file: PathBuf,
+        /// Where to copy rejected rows; `<file>.rejects.csv` when omitted.
+        #[arg(long)]
+        rejects: Option<PathBuf>,
+        /// Progress file that lets an interrupted import resume; `<file>.checkpoint` when omitted.
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Follow the table stream, logging every merchant, transaction and payout change.
+    Stream {
+        /// Name the consumer's shard checkpoints are kept under.
+        #[arg(long, default_value = "log")]
+        consumer: String,
+    },
+    /// Print the GraphQL schema as SDL.
+    Schema {
+        /// Instead compare against this SDL file, failing on changes that break its clients.
+        #[arg(long)]
+        check: Option<PathBuf>,
+    },
+    /// Look up merchants.
+    Merchant {
+        #[command(subcommand)]
+        command: MerchantCommand,
+    },
+    /// Look up transactions.
+    Transactions {
+        #[command(subcommand)]
+        command: TransactionsCommand,
+    },
+    /// Look up payouts.
+    Payouts {
+        #[command(subcommand)]
+        command: PayoutsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MerchantCommand {
+    /// Print one merchant.
+    Get {
+        /// Merchant id, with or without the `MERCHANT#` prefix.
+        merchant_id: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TransactionsCommand {
+    /// Print a merchant's transactions, newest first, with card numbers masked.
+    List(ListTransactionsArgs),
+    /// Stream every matching transaction as CSV or NDJSON, with card numbers masked.
+    Export(ExportTransactionsArgs),
+    /// Print a merchant's totals for one day, month or year, one row per currency.
+    Summary(TransactionSummaryArgs),
+}
+
+#[derive(Args)]
+pub struct ListTransactionsArgs {
+    /// Merchant id, with or without the `MERCHANT#` prefix.
+    #[arg(long)]
+    pub merchant: String,
+    /// First day to include, e.g. 2025-01-01.
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last day to include.
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_parser = parse_card_brand)]
+    pub brand: Option<CardBrand>,
+    /// Include the transactions of every outlet below the merchant.
+    #[arg(long)]
+    pub include_descendants: bool,
+    #[arg(long, default_value_t = 100)]
+    pub limit: usize,
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct ExportTransactionsArgs {
+    /// Merchant id, with or without the `MERCHANT#` prefix.
+    #[arg(long)]
+    pub merchant: String,
+    /// First day to include, e.g. 2025-01-01.
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last day to include.
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_parser = parse_card_brand)]
+    pub brand: Option<CardBrand>,
+    /// Include the transactions of every outlet below the merchant.
+    #[arg(long)]
+    pub include_descendants: bool,
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+    /// File to write instead of stdout.
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct TransactionSummaryArgs {
+    /// Merchant id, with or without the `MERCHANT#` prefix.
+    #[arg(long)]
+    pub merchant: String,
+    #[arg(long, value_parser = parse_period)]
+    pub period: AggregatePeriod,
+    /// 2025-01-05 for a day, 2025-01 for a month or 2025 for a year.
+    #[arg(long)]
+    pub date: String,
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(Subcommand)]
+pub enum PayoutsCommand {
+    /// Print the payouts of a settlement merchant, summed from its transactions.
+    List(ListPayoutsArgs),
+}
+
+#[derive(Args)]
+pub struct ListPayoutsArgs {
+    /// Settlement merchant id, with or without the `MERCHANT#` prefix.
+    #[arg(long)]
+    pub merchant: String,
+    /// First settlement day to include.
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last settlement day to include.
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+fn parse_card_brand(value: &str) -> Result<CardBrand, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "visa" => Ok(CardBrand::Visa),
+        "mastercard" => Ok(CardBrand::Mastercard),
+        _ => Err(format!(
+            "unknown card brand {value}, expected visa or mastercard"
+        )),
+    }
+}
+
+fn parse_period(value: &str) -> Result<AggregatePeriod, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "day" => Ok(AggregatePeriod::Day),
+        "month" => Ok(AggregatePeriod::Month),
+        "year" => Ok(AggregatePeriod::Year),
+        _ => Err(format!(
+            "unknown period {value}, expected day, month or year"
+        )),
+    }
+}
@@ -0,0 +1,134 @@
+use crate::cli::{
+    ExportTransactionsArgs, ListPayoutsArgs, ListTransactionsArgs, MerchantCommand, PayoutsCommand,
+    TransactionSummaryArgs, TransactionsCommand,
+};
+use crate::dynamo::{
+    MERCHANT_PREFIX, Table, get_descendant_outlets, get_merchant, get_transaction_aggregates,
+    get_transactions_for_merchants_between, payout_date_range, transaction_date_range,
+};
+use crate::export::{ExportQuery, transactions_export};
+use crate::models::PageRequest;
+use crate::output::print;
+use crate::store::{DynamoStore, TransactionStore};
+use crate::telemetry::mask_pan;
+use anyhow::Error;
+use futures::TryStreamExt;
+use std::{
+    fs::File,
+    io::{self, Write},
+    sync::Arc,
+};
+
+pub async fn merchant(table: &Table, command: MerchantCommand) -> Result<(), Error> {
+    match command {
+        MerchantCommand::Get {
+            merchant_id,
+            format,
+        } => {
+            let merchant = get_merchant(table, merchant_key(&merchant_id)).await?;
+            print(&[merchant], format)
+        }
+    }
+}
+
+pub async fn transactions(table: &Table, command: TransactionsCommand) -> Result<(), Error> {
+    match command {
+        TransactionsCommand::List(args) => list_transactions(table, args).await,
+        TransactionsCommand::Export(args) => export_transactions(table, args).await,
+        TransactionsCommand::Summary(args) => transaction_summary(table, args).await,
+    }
+}
+
+pub async fn payouts(table: &Table, command: PayoutsCommand) -> Result<(), Error> {
+    match command {
+        PayoutsCommand::List(args) => list_payouts(table, args).await,
+    }
+}
+
+async fn list_transactions(table: &Table, args: ListTransactionsArgs) -> Result<(), Error> {
+    let merchant_id = merchant_key(&args.merchant);
+    let merchant_ids = if args.include_descendants {
+        get_descendant_outlets(table, merchant_id).await?
+    } else {
+        vec![merchant_id]
+    };
+    let (earlier_transaction, later_transaction) = transaction_date_range(args.from, args.to);
+    let (mut transactions, has_more) = get_transactions_for_merchants_between(
+        table,
+        &merchant_ids,
+        &earlier_transaction,
+        &later_transaction,
+        args.brand,
+        &PageRequest::forward(None, args.limit),
+    )
+    .await?;
+    for transaction in &mut transactions {
+        transaction.pan = mask_pan(&transaction.pan);
+    }
+    print(&transactions, args.format)?;
+    if has_more {
+        tracing::info!(
+            limit = args.limit,
+            "More transactions match; raise --limit to see them"
+        );
+    }
+    Ok(())
+}
+
+async fn export_transactions(table: &Table, args: ExportTransactionsArgs) -> Result<(), Error> {
+    let query = ExportQuery {
+        merchant_id: merchant_key(&args.merchant),
+        include_descendants: args.include_descendants,
+        from: args.from,
+        to: args.to,
+        card_brand: args.brand,
+        format: args.format,
+    };
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut chunks = Box::pin(
+        transactions_export(Arc::new(DynamoStore::new(table.clone())), &query, true).await?,
+    );
+    while let Some(chunk) = chunks.try_next().await? {
+        match output.write_all(&chunk) {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+async fn transaction_summary(table: &Table, args: TransactionSummaryArgs) -> Result<(), Error> {
+    let aggregates = get_transaction_aggregates(
+        table,
+        &merchant_key(&args.merchant),
+        args.period,
+        &args.date,
+    )
+    .await?;
+    print(&aggregates, args.format)
+}
+
+async fn list_payouts(table: &Table, args: ListPayoutsArgs) -> Result<(), Error> {
+    let (earlier_payout, later_payout) = payout_date_range(args.from, args.to);
+    let payouts = DynamoStore::new(table.clone())
+        .get_payouts(
+            &merchant_key(&args.merchant),
+            &earlier_payout,
+            &later_payout,
+        )
+        .await?;
+    print(&payouts, args.format)
+}
+
+/// Accepts merchant ids with or without the `MERCHANT#` prefix.
+fn merchant_key(merchant_id: &str) -> String {
+    if merchant_id.starts_with(&format!("{MERCHANT_PREFIX}#")) {
+        merchant_id.to_string()
+    } else {
+        format!("{MERCHANT_PREFIX}#{merchant_id}")
+    }
+}
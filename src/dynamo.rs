@@ -1,419 +1,116 @@
+use crate::aggregates::{
+    AggregateDelta, AggregateKey, AggregatePeriod, TransactionAggregate, fold,
+};
+use crate::audit::{Actor, AuditEntry, audit_partition_key};
+use crate::errors::{AppError, SdkContext, THROTTLING_ERROR_CODES};
+use crate::events::{TransactionEvent, TransactionStatusChange, publish};
+use crate::metrics::{CapacityKind, metrics};
 use crate::models::{
-    CardBrand, Merchant, MerchantLevel, Transaction, TransactionStatus, TransactionType,
+    CardBrand, HierarchyAction, HierarchyChange, Merchant, MerchantLevel, PageRequest, Payout,
+    Role, Transaction, TransactionCursor, TransactionStatus,
+};
+use crate::resilience::{CallPolicy, OperationTimedOut};
+use crate::streams::StreamCheckpoint;
+use crate::telemetry::mask_pan;
+use crate::webhooks::{
+    DELIVERY_PREFIX, DeliveryAttempt, DeliveryOutcome, DeliveryStatus, WEBHOOK_PREFIX, Webhook,
+    WebhookDelivery, WebhookEventType,
+};
+use actix_web::rt::time::{sleep, timeout};
+use anyhow::{Context, Error, bail};
+use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::{
+    batch_get_item::BatchGetItemOutput,
+    batch_write_item::BatchWriteItemOutput,
+    create_table::CreateTableOutput,
+    delete_item::DeleteItemOutput,
+    describe_table::DescribeTableOutput,
+    describe_time_to_live::DescribeTimeToLiveOutput,
+    get_item::GetItemOutput,
+    put_item::{PutItemError, PutItemOutput},
+    query::QueryOutput,
+    scan::ScanOutput,
+    transact_write_items::{TransactWriteItemsError, TransactWriteItemsOutput},
+    update_item::UpdateItemOutput,
+    update_table::UpdateTableOutput,
+    update_time_to_live::UpdateTimeToLiveOutput,
+};
+use aws_sdk_dynamodb::types::{
+    AttributeValue, Delete, IndexStatus, KeysAndAttributes, Put, PutRequest,
+    ReturnConsumedCapacity, TableStatus, TransactWriteItem, Update, WriteRequest,
 };
-use anyhow::{Context, Error};
-use aws_sdk_dynamodb::types::{AttributeValue, ScalarAttributeType};
-use chrono::{Datelike, TimeZone, Utc};
+use aws_sdk_dynamodbstreams::operation::{
+    describe_stream::DescribeStreamOutput, get_records::GetRecordsOutput,
+    get_shard_iterator::GetShardIteratorOutput,
+};
+use chrono::{Datelike, NaiveDate, SecondsFormat, Utc};
+use futures::future::try_join_all;
 use rand::Rng;
-use serde_dynamo::aws_sdk_dynamodb_1::{from_item, from_items};
-use std::{collections::HashMap, time::SystemTime, vec};
-
-const TABLE_NAME: &str = "merchants";
-const PARTITION_KEY: &str = "pk";
-const SORT_KEY: &str = "sk";
-const GSI1_PARTITION_KEY: &str = "gsi1_pk";
-const GSI1_SORT_KEY: &str = "gsi1_sk";
-const TRANSACTION_PREFIX: &str = "TRANSACTION";
-const MERCHANT_PREFIX: &str = "MERCHANT";
-const PAYOUT_PREFIX: &str = "PAYOUT";
-
-/*
-A merchant_a_outlet_sb (outlet) S,B
-
-B                      merchant_b_group_s (group) S
-merchant_b_outlet1_b (outlet) B,       merchant_b_outlet2_b (outlet) B
-
-C                                                       merchant_c_group_b (group) B
-               merchant_c_chain1_s (chain) S                                             merchant_c_chain2 (chain)
-merchant_c_outlet1 (outlet),     merchant_c_outlet2 (outlet),       merchant_c_outlet3_s (outlet) S      merchant_c_outlet4_s (outlet) S
-*/
-
-pub async fn init_db(client: &aws_sdk_dynamodb::Client) {
-    create_table(client, &TABLE_NAME.to_string()).await;
-
-    let mut merchants: Vec<Merchant> = Vec::new();
-    let mut rng = rand::thread_rng();
-
-    let a_outlet = Merchant {
-        id: format!("{}#merchant_a_outlet_sb", MERCHANT_PREFIX),
-        name: "Merchant A_outlet_sb".to_string(),
-        founded_date: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string(),
-        industry: "Retail".to_string(),
-        vat_number: "VAT123".to_string(),
-        created_at: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64,
-        merchant_level: MerchantLevel::Outlet,
-        sub_merchants: Vec::new(),
-        has_settlement_permissions: true,
-        has_billing_permissions: true,
-    };
-
-    merchants.push(Merchant {
-        id: format!("{}#merchant_b_group_s", MERCHANT_PREFIX),
-        name: "Merchant B_group_s".to_string(),
-        founded_date: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string(),
-        industry: "Retail".to_string(),
-        vat_number: "VAT123".to_string(),
-        created_at: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64,
-        merchant_level: MerchantLevel::Group,
-        sub_merchants: vec![
-            format!("{}#merchant_b_outlet1_b", MERCHANT_PREFIX),
-            format!("{}#merchant_b_outlet2_b", MERCHANT_PREFIX),
-        ],
-        has_settlement_permissions: true,
-        has_billing_permissions: false,
-    });
-
-    let b_outlets = vec![
-        Merchant {
-            id: format!("{}#merchant_b_outlet1_s", MERCHANT_PREFIX),
-            name: "Merchant B_outlet1_s".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: Vec::new(),
-            has_settlement_permissions: false,
-            has_billing_permissions: true,
-        },
-        Merchant {
-            id: format!("{}#merchant_b_outlet2_s", MERCHANT_PREFIX),
-            name: "Merchant B_outlet2_s".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: Vec::new(),
-            has_settlement_permissions: false,
-            has_billing_permissions: true,
-        },
-    ];
+use serde::Serialize;
+use serde_dynamo::aws_sdk_dynamodb_1::{from_item, from_items, to_attribute_value, to_item};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+    vec,
+};
+use tracing::{Instrument, field::Empty};
 
-    merchants.push(Merchant {
-        id: format!("{}#merchant_c_group_b", MERCHANT_PREFIX),
-        name: "Merchant C_group_b".to_string(),
-        founded_date: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string(),
-        industry: "Retail".to_string(),
-        vat_number: "VAT123".to_string(),
-        created_at: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64,
-        merchant_level: MerchantLevel::Group,
-        sub_merchants: vec![
-            format!("{}#merchant_c_chain1_s", MERCHANT_PREFIX),
-            format!("{}#merchant_c_chain2_s", MERCHANT_PREFIX),
-        ],
-        has_settlement_permissions: false,
-        has_billing_permissions: true,
-    });
-
-    merchants.push(Merchant {
-        id: format!("{}#merchant_c_chain1_s", MERCHANT_PREFIX),
-        name: "Merchant C_chain1_s".to_string(),
-        founded_date: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string(),
-        industry: "Retail".to_string(),
-        vat_number: "VAT123".to_string(),
-        created_at: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64,
-        merchant_level: MerchantLevel::Chain,
-        sub_merchants: vec![
-            format!("{}#merchant_c_outlet1", MERCHANT_PREFIX),
-            format!("{}#merchant_c_outlet2", MERCHANT_PREFIX),
-        ],
-        has_settlement_permissions: true,
-        has_billing_permissions: false,
-    });
-
-    merchants.push(Merchant {
-        id: format!("{}#merchant_c_chain2", MERCHANT_PREFIX),
-        name: "Merchant C_chain2".to_string(),
-        founded_date: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string(),
-        industry: "Retail".to_string(),
-        vat_number: "VAT123".to_string(),
-        created_at: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64,
-        merchant_level: MerchantLevel::Chain,
-        sub_merchants: vec![
-            format!("{}#merchant_c_outlet3_s", MERCHANT_PREFIX),
-            format!("{}#merchant_c_outlet4_s", MERCHANT_PREFIX),
-        ],
-        has_settlement_permissions: false,
-        has_billing_permissions: false,
-    });
-
-    let c_outlets = vec![
-        Merchant {
-            id: format!("{}#merchant_c_outlet1", MERCHANT_PREFIX),
-            name: "Merchant C_outlet1".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: vec![],
-            has_settlement_permissions: false,
-            has_billing_permissions: false,
-        },
-        Merchant {
-            id: format!("{}#merchant_c_outlet2", MERCHANT_PREFIX),
-            name: "Merchant C_outlet2".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: vec![],
-            has_settlement_permissions: false,
-            has_billing_permissions: false,
-        },
-    ];
-    let c_outlets_settled = vec![
-        Merchant {
-            id: format!("{}#merchant_c_outlet3_s", MERCHANT_PREFIX),
-            name: "Merchant C_outlet3_s".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: vec![],
-            has_settlement_permissions: true,
-            has_billing_permissions: false,
-        },
-        Merchant {
-            id: format!("{}#merchant_c_outlet4_s", MERCHANT_PREFIX),
-            name: "Merchant C_outlet4_s".to_string(),
-            founded_date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-            industry: "Retail".to_string(),
-            vat_number: "VAT123".to_string(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-            merchant_level: MerchantLevel::Outlet,
-            sub_merchants: vec![],
-            has_settlement_permissions: true,
-            has_billing_permissions: false,
-        },
-    ];
+/// Table the service uses unless `--table` names another.
+pub const TABLE_NAME: &str = "merchants";
+pub const PARTITION_KEY: &str = "pk";
+pub const SORT_KEY: &str = "sk";
+pub const GSI1_PARTITION_KEY: &str = "gsi1_pk";
+pub const GSI1_SORT_KEY: &str = "gsi1_sk";
+pub const GSI1_INDEX: &str = "gsi1";
+pub const TRANSACTION_PREFIX: &str = "TRANSACTION";
+pub const MERCHANT_PREFIX: &str = "MERCHANT";
+pub const PAYOUT_PREFIX: &str = "PAYOUT";
+pub const HIERARCHY_PREFIX: &str = "HIERARCHY";
+/// Epoch-seconds attribute DynamoDB TTL expires items by.
+pub const TTL_ATTRIBUTE: &str = "expires_at";
+/// Most items DynamoDB accepts in one `BatchWriteItem`.
+pub const BATCH_WRITE_LIMIT: usize = 25;
+const BATCH_WRITE_MAX_ATTEMPTS: u32 = 8;
+const BATCH_WRITE_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+// Sparse `gsi1` partition holding the webhook deliveries still to be made.
+const OUTBOX_PARTITION: &str = "OUTBOX#PENDING";
+// Days finished webhook deliveries stay queryable before TTL removes them.
+const DELIVERY_RETENTION_DAYS: i64 = 30;
+/// Partition prefix of stream consumer checkpoints.
+pub const STREAM_PREFIX: &str = "STREAM";
+const SHARD_PREFIX: &str = "SHARD";
+const STREAM_CHECKPOINT_RETENTION_DAYS: i64 = 2;
+// Guards the ancestor walk against corrupted parent links.
+pub const MAX_HIERARCHY_DEPTH: usize = 16;
 
-    for merchant in merchants {
-        add_merchant(client, &merchant, &TABLE_NAME.to_string())
-            .await
-            .expect("Failed to add merchant");
-    }
+/// A DynamoDB client and the single table every item lives in.
+#[derive(Clone)]
+pub struct Table {
+    pub client: aws_sdk_dynamodb::Client,
+    pub name: String,
+    policy: Arc<CallPolicy>,
+}
 
-    add_merchant(client, &a_outlet, &TABLE_NAME.to_string())
-        .await
-        .expect("Failed to add merchant");
-
-    for i in 1..=5 {
-        let transaction_date = Utc.with_ymd_and_hms(2025, 1, i, 0, 0, 0).unwrap();
-        let settled_date = Utc
-            .with_ymd_and_hms(2025, 3 % i + 1, i + 1, 0, 0, 0)
-            .unwrap();
-        add_transaction(
+impl Table {
+    pub fn new(client: aws_sdk_dynamodb::Client, name: impl Into<String>) -> Self {
+        Self {
             client,
-            Transaction {
-                id: format!(
-                    "{}#{}#{}",
-                    TRANSACTION_PREFIX,
-                    transaction_date.to_rfc3339(),
-                    i
-                ),
-                merchant_id: a_outlet.id.clone(),
-                transaction_type: random_transaction_type(&mut rng),
-                status: random_transaction_status(&mut rng),
-                amount: rng.gen_range(10.0..100.0),
-                currency: "GBP".to_string(),
-                pan: rng.gen_range(1000i64..9999i64).to_string(),
-                card_brand: random_card_brand(&mut rng),
-                date_transaction: transaction_date.to_rfc3339(),
-                date_settlement: settled_date.to_rfc3339(),
-                settlement_merchant_id: a_outlet.id.clone(),
-                payout_id: format!("{}#{}#{}", PAYOUT_PREFIX, settled_date.to_rfc3339(), "1"),
-            },
-        )
-        .await
-        .expect("Failed to add transaction");
-    }
-
-    for merchant in b_outlets.into_iter() {
-        for i in 1..=5 {
-            let transaction_date = Utc.with_ymd_and_hms(2025, 3 % i + 1, i, 0, 0, 0).unwrap();
-            let settled_date = Utc
-                .with_ymd_and_hms(2025, 3 % i + 1, i + 1, 0, 0, 0)
-                .unwrap();
-            add_transaction(
-                client,
-                Transaction {
-                    id: format!(
-                        "{}#{}#{}",
-                        TRANSACTION_PREFIX,
-                        transaction_date.to_rfc3339(),
-                        i
-                    ),
-                    merchant_id: merchant.id.clone(),
-                    transaction_type: random_transaction_type(&mut rng),
-                    status: random_transaction_status(&mut rng),
-                    amount: rng.gen_range(10.0..100.0),
-                    currency: "GBP".to_string(),
-                    pan: rng.gen_range(1000i64..9999i64).to_string(),
-                    card_brand: random_card_brand(&mut rng),
-                    date_transaction: transaction_date.to_rfc3339(),
-                    date_settlement: settled_date.to_rfc3339(),
-                    settlement_merchant_id: "merchant_b_group_s".to_string(),
-                    payout_id: format!("{}#{}#{}", PAYOUT_PREFIX, settled_date.to_rfc3339(), "1"),
-                },
-            )
-            .await
-            .expect("Failed to add transaction");
-        }
-    }
-
-    for merchant in c_outlets.into_iter() {
-        for i in 1..=5 {
-            let transaction_date = Utc.with_ymd_and_hms(2025, 1, i, 0, 0, 0).unwrap();
-            let settled_date = Utc
-                .with_ymd_and_hms(2025, 3 % i + 1, i + 1, 0, 0, 0)
-                .unwrap();
-            add_transaction(
-                client,
-                Transaction {
-                    id: format!(
-                        "{}#{}#{}",
-                        TRANSACTION_PREFIX,
-                        transaction_date.to_rfc3339(),
-                        i
-                    ),
-                    merchant_id: merchant.id.clone(),
-                    transaction_type: random_transaction_type(&mut rng),
-                    status: random_transaction_status(&mut rng),
-                    amount: rng.gen_range(10.0..100.0),
-                    currency: "GBP".to_string(),
-                    pan: rng.gen_range(1000i64..9999i64).to_string(),
-                    card_brand: random_card_brand(&mut rng),
-                    date_transaction: transaction_date.to_rfc3339(),
-                    date_settlement: settled_date.to_rfc3339(),
-                    settlement_merchant_id: "merchant_c_chain1_s".to_string(),
-                    payout_id: format!("{}#{}#{}", PAYOUT_PREFIX, settled_date.to_rfc3339(), "1"),
-                },
-            )
-            .await
-            .expect("Failed to add transaction");
+            name: name.into(),
+            policy: Arc::new(CallPolicy::default()),
         }
     }
 
-    for merchant in c_outlets_settled.into_iter() {
-        for i in 1..=5 {
-            let transaction_date = Utc.with_ymd_and_hms(2025, 1, i, 0, 0, 0).unwrap();
-            let settled_date = Utc
-                .with_ymd_and_hms(2025, 3 % i + 1, i + 1, 0, 0, 0)
-                .unwrap();
-            add_transaction(
-                client,
-                Transaction {
-                    id: format!(
-                        "{}#{}#{}",
-                        TRANSACTION_PREFIX,
-                        transaction_date.to_rfc3339(),
-                        i
-                    ),
-                    merchant_id: merchant.id.clone(),
-                    transaction_type: random_transaction_type(&mut rng),
-                    status: random_transaction_status(&mut rng),
-                    amount: rng.gen_range(10.0..100.0),
-                    currency: "GBP".to_string(),
-                    pan: rng.gen_range(1000i64..9999i64).to_string(),
-                    card_brand: random_card_brand(&mut rng),
-                    date_transaction: transaction_date.to_rfc3339(),
-                    date_settlement: settled_date.to_rfc3339(),
-                    settlement_merchant_id: merchant.id.clone(),
-                    payout_id: format!("{}#{}#{}", PAYOUT_PREFIX, settled_date.to_rfc3339(), "1"),
-                },
-            )
-            .await
-            .expect("Failed to add transaction");
-        }
+    /// Replaces the default deadlines and circuit breaker of the table's calls.
+    pub fn with_policy(mut self, policy: CallPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
     }
 }
 
-async fn add_merchant(
-    client: &aws_sdk_dynamodb::Client,
-    merchant: &Merchant,
-    table: &String,
-) -> Result<(), Error> {
+pub async fn add_merchant(table: &Table, merchant: &Merchant) -> Result<(), Error> {
     let id_av = AttributeValue::S(merchant.id.clone());
     let name_av = AttributeValue::S(merchant.name.clone());
     let founded_date_av = AttributeValue::S(merchant.founded_date.clone());
@@ -430,9 +127,10 @@ async fn add_merchant(
     );
     let has_settlement_permissions_av = AttributeValue::Bool(merchant.has_settlement_permissions);
     let has_billing_permissions_av = AttributeValue::Bool(merchant.has_billing_permissions);
-    let request = client
+    let request = table
+        .client
         .put_item()
-        .table_name(table)
+        .table_name(&table.name)
         .item(PARTITION_KEY, id_av.clone())
         .item(SORT_KEY, id_av)
         .item("name", name_av)
@@ -444,240 +142,623 @@ async fn add_merchant(
         .item("sub_merchants", sub_merchants_av)
         .item("has_settlement_permissions", has_settlement_permissions_av)
         .item("has_billing_permissions", has_billing_permissions_av);
-    println!("👍Adding merchant {0}", merchant.id);
+    let request = match &merchant.parent_merchant_id {
+        Some(parent_merchant_id) => request.item(
+            "parent_merchant_id",
+            AttributeValue::S(parent_merchant_id.clone()),
+        ),
+        None => request,
+    };
+    tracing::debug!(merchant_id = %merchant.id, "Adding merchant");
 
-    request.send().await?;
+    table
+        .observe(
+            "add_merchant",
+            None,
+            request
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to add merchant")?;
     Ok(())
 }
 
-async fn create_table(client: &aws_sdk_dynamodb::Client, table_name: &String) {
-    println!("Creating table '{table_name}'...");
-
-    let create_resp = client
-        .create_table()
-        .table_name(table_name)
-        .key_schema(
-            aws_sdk_dynamodb::types::KeySchemaElement::builder()
-                .attribute_name(PARTITION_KEY)
-                .key_type(aws_sdk_dynamodb::types::KeyType::Hash)
-                .build()
-                .expect("Failed to build partition key KeySchemaElement"),
-        )
-        .key_schema(
-            aws_sdk_dynamodb::types::KeySchemaElement::builder()
-                .attribute_name(SORT_KEY)
-                .key_type(aws_sdk_dynamodb::types::KeyType::Range)
-                .build()
-                .expect("Failed to build sort key KeySchemaElement"),
-        )
-        .global_secondary_indexes(
-            aws_sdk_dynamodb::types::GlobalSecondaryIndex::builder()
-                .index_name("gsi1")
-                .key_schema(
-                    aws_sdk_dynamodb::types::KeySchemaElement::builder()
-                        .attribute_name(GSI1_PARTITION_KEY)
-                        .key_type(aws_sdk_dynamodb::types::KeyType::Hash)
-                        .build()
-                        .expect("Failed to build GSI1 partition key KeySchemaElement"),
-                )
-                .key_schema(
-                    aws_sdk_dynamodb::types::KeySchemaElement::builder()
-                        .attribute_name(GSI1_SORT_KEY)
-                        .key_type(aws_sdk_dynamodb::types::KeyType::Range)
-                        .build()
-                        .expect("Failed to build GSI1 sort key KeySchemaElement"),
-                )
-                .projection(
-                    aws_sdk_dynamodb::types::Projection::builder()
-                        .projection_type(aws_sdk_dynamodb::types::ProjectionType::All)
-                        .build(),
-                )
-                .build()
-                .expect("Failed to build GSI1 GlobalSecondaryIndex"),
-        )
-        .attribute_definitions(
-            aws_sdk_dynamodb::types::AttributeDefinition::builder()
-                .attribute_name(PARTITION_KEY)
-                .attribute_type(ScalarAttributeType::S)
-                .build()
-                .expect("Failed to build partition key AttributeDefinition"),
-        )
-        .attribute_definitions(
-            aws_sdk_dynamodb::types::AttributeDefinition::builder()
-                .attribute_name(SORT_KEY)
-                .attribute_type(ScalarAttributeType::S)
-                .build()
-                .expect("Failed to build sort key AttributeDefinition"),
-        )
-                .attribute_definitions(
-            aws_sdk_dynamodb::types::AttributeDefinition::builder()
-                .attribute_name(GSI1_PARTITION_KEY)
-                .attribute_type(ScalarAttributeType::S)
-                .build()
-                .expect("Failed to build GSI1 partition key AttributeDefinition"),
-        )
-        .attribute_definitions(
-            aws_sdk_dynamodb::types::AttributeDefinition::builder()
-                .attribute_name(GSI1_SORT_KEY)
-                .attribute_type(ScalarAttributeType::S)
-                .build()
-                .expect("Failed to build GSI1 sort key AttributeDefinition"),
-        )
-        .billing_mode(aws_sdk_dynamodb::types::BillingMode::PayPerRequest)
-        .send()
+/// Records a transaction and adds it to its aggregates. Returns false, changing nothing, when a
+/// transaction with its id is already stored.
+pub async fn add_transaction(table: &Table, transaction: Transaction) -> Result<bool, Error> {
+    tracing::debug!(
+        merchant_id = %transaction.merchant_id,
+        pan = %mask_pan(&transaction.pan),
+        "Adding transaction"
+    );
+    let put_item = table
+        .client
+        .put_item()
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .table_name(&table.name)
+        .set_item(Some(transaction_item(&transaction)))
+        .condition_expression("attribute_not_exists(#partition_key)")
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .send();
+    match table.observe("add_transaction", None, put_item).await {
+        Ok(_) => {}
+        Err(err)
+            if err
+                .as_service_error()
+                .is_some_and(PutItemError::is_conditional_check_failed_exception) =>
+        {
+            return Ok(false);
+        }
+        Err(err) => return Err(err).sdk_context("Failed to add transaction"),
+    }
+    add_to_aggregates(table, "add_transaction", fold([&transaction])).await?;
+    publish(TransactionEvent::Recorded(transaction));
+    Ok(true)
+}
+
+/// Records one transaction with its aggregates and its audit entry in a single DynamoDB
+/// transaction. Returns false, writing nothing, when a transaction with its id is already stored.
+pub async fn record_transaction(
+    table: &Table,
+    transaction: Transaction,
+    actor: &Actor,
+) -> Result<bool, Error> {
+    tracing::debug!(
+        merchant_id = %transaction.merchant_id,
+        pan = %mask_pan(&transaction.pan),
+        "Recording transaction"
+    );
+    let put = Put::builder()
+        .table_name(&table.name)
+        .set_item(Some(transaction_item(&transaction)))
+        .condition_expression("attribute_not_exists(#partition_key)")
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .build()?;
+    let mut items = vec![TransactWriteItem::builder().put(put).build()];
+    for (key, delta) in fold([&transaction]) {
+        items.push(
+            TransactWriteItem::builder()
+                .update(aggregate_update(table, &key, &delta)?)
+                .build(),
+        );
+    }
+    items.push(audit_entry_put(
+        table,
+        &transaction_audit_entry(actor, "recordTransaction", None, &transaction),
+    )?);
+
+    let result = table
+        .observe(
+            "record_transaction",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .set_transact_items(Some(items))
+                .send(),
+        )
         .await;
-    match create_resp {
-        Ok(_) => println!("Created table '{table_name}'"),
-        Err(err) => eprintln!("Failed to create table {table_name}: {err:?}"),
+    match result {
+        Ok(_) => {}
+        Err(err) if first_condition_failed(&err) => return Ok(false),
+        Err(err) => return Err(err).sdk_context("Failed to record transaction"),
+    }
+    publish(TransactionEvent::Recorded(transaction));
+    Ok(true)
+}
+
+fn transaction_item(transaction: &Transaction) -> HashMap<String, AttributeValue> {
+    let merchant_id_av = AttributeValue::S(transaction.merchant_id.to_string());
+    let id_av = AttributeValue::S(transaction.id.clone());
+    let settlement_merchant_id_av =
+        AttributeValue::S(transaction.settlement_merchant_id.to_string());
+    HashMap::from([
+        (PARTITION_KEY.to_string(), merchant_id_av),
+        (SORT_KEY.to_string(), id_av.clone()),
+        (
+            GSI1_PARTITION_KEY.to_string(),
+            settlement_merchant_id_av.clone(),
+        ),
+        (GSI1_SORT_KEY.to_string(), id_av),
+        (
+            "transaction_type".to_string(),
+            AttributeValue::S(transaction.transaction_type.to_string()),
+        ),
+        (
+            "status".to_string(),
+            AttributeValue::S(transaction.status.to_string()),
+        ),
+        (
+            "amount".to_string(),
+            AttributeValue::N(transaction.amount.to_string()),
+        ),
+        (
+            "currency".to_string(),
+            AttributeValue::S(transaction.currency.to_string()),
+        ),
+        (
+            "pan".to_string(),
+            AttributeValue::S(transaction.pan.to_string()),
+        ),
+        (
+            "card_brand".to_string(),
+            AttributeValue::S(transaction.card_brand.to_string()),
+        ),
+        (
+            "date_transaction".to_string(),
+            AttributeValue::S(transaction.date_transaction.to_string()),
+        ),
+        (
+            "date_settlement".to_string(),
+            AttributeValue::S(transaction.date_settlement.to_string()),
+        ),
+        (
+            "settlement_merchant_id".to_string(),
+            settlement_merchant_id_av,
+        ),
+        (
+            "payout_id".to_string(),
+            AttributeValue::S(transaction.payout_id.to_string()),
+        ),
+    ])
+}
+
+/// Records up to [`BATCH_WRITE_LIMIT`] transactions in one `BatchWriteItem`, then adds them to
+/// their aggregates, summed per aggregate. Ones already stored are skipped, so re-running a
+/// generator or an interrupted import counts nothing twice; a run stopped between the two steps
+/// leaves its last batch out of the aggregates. Returns how many were written.
+pub async fn add_transactions(table: &Table, transactions: &[Transaction]) -> Result<usize, Error> {
+    if transactions.len() > BATCH_WRITE_LIMIT {
+        bail!(AppError::validation(format!(
+            "A batch holds at most {BATCH_WRITE_LIMIT} items, got {}",
+            transactions.len()
+        )));
     }
+    let unstored = unstored_transactions(table, transactions).await?;
+    let requests = unstored
+        .iter()
+        .map(|transaction| {
+            let put = PutRequest::builder()
+                .set_item(Some(transaction_item(transaction)))
+                .build()?;
+            Ok(WriteRequest::builder().put_request(put).build())
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    batch_write(table, "add_transactions", requests).await?;
+    add_to_aggregates(table, "add_transactions", fold(unstored.iter().copied())).await?;
+    for transaction in &unstored {
+        publish(TransactionEvent::Recorded((*transaction).clone()));
+    }
+    Ok(unstored.len())
 }
 
-fn random_transaction_type(rng: &mut impl Rng) -> TransactionType {
-    match rng.gen_range(0..2) {
-        0 => TransactionType::Purchase,
-        _ => TransactionType::Refund,
+/// The transactions not stored yet, each once.
+async fn unstored_transactions<'a>(
+    table: &Table,
+    transactions: &'a [Transaction],
+) -> Result<Vec<&'a Transaction>, Error> {
+    let mut ids = HashSet::new();
+    let unique: Vec<&Transaction> = transactions
+        .iter()
+        .filter(|transaction| ids.insert((&transaction.merchant_id, &transaction.id)))
+        .collect();
+    let mut keys: Vec<HashMap<String, AttributeValue>> = unique
+        .iter()
+        .map(|transaction| {
+            HashMap::from([
+                (
+                    PARTITION_KEY.to_string(),
+                    AttributeValue::S(transaction.merchant_id.clone()),
+                ),
+                (
+                    SORT_KEY.to_string(),
+                    AttributeValue::S(transaction.id.clone()),
+                ),
+            ])
+        })
+        .collect();
+
+    let mut stored = HashSet::new();
+    let mut attempt = 1;
+    while !keys.is_empty() {
+        let request = KeysAndAttributes::builder()
+            .set_keys(Some(keys))
+            .projection_expression("#partition_key, #sort_key")
+            .expression_attribute_names("#partition_key", PARTITION_KEY)
+            .expression_attribute_names("#sort_key", SORT_KEY)
+            .consistent_read(true)
+            .build()?;
+        let batch_get_item = table
+            .client
+            .batch_get_item()
+            .request_items(&table.name, request)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let batch_resp = table
+            .observe("get_stored_transactions", None, batch_get_item)
+            .await
+            .sdk_context("Failed to look up stored transactions")?;
+        for item in batch_resp
+            .responses
+            .and_then(|mut responses| responses.remove(&table.name))
+            .unwrap_or_default()
+        {
+            stored.insert(item_key(&item));
+        }
+        keys = batch_resp
+            .unprocessed_keys
+            .and_then(|mut unprocessed| unprocessed.remove(&table.name))
+            .map(|unprocessed| unprocessed.keys)
+            .unwrap_or_default();
+        if !keys.is_empty() {
+            if attempt >= BATCH_WRITE_MAX_ATTEMPTS {
+                bail!(
+                    "{} transactions were still unread after {attempt} attempts",
+                    keys.len()
+                );
+            }
+            sleep(full_jitter(BATCH_WRITE_INITIAL_BACKOFF, attempt)).await;
+            attempt += 1;
+        }
     }
+    Ok(unique
+        .into_iter()
+        .filter(|transaction| {
+            !stored.contains(&format!("{}/{}", transaction.merchant_id, transaction.id))
+        })
+        .collect())
 }
 
-fn random_transaction_status(rng: &mut impl Rng) -> TransactionStatus {
-    match rng.gen_range(0..4) {
-        0 => TransactionStatus::Processed,
-        1 => TransactionStatus::Cleared,
-        2 => TransactionStatus::Chargebacked,
-        _ => TransactionStatus::Paid,
+/// Writes `requests` with `BatchWriteItem`, resending what DynamoDB leaves unprocessed after a
+/// jittered backoff. Fails naming the keys still unwritten after [`BATCH_WRITE_MAX_ATTEMPTS`].
+pub async fn batch_write(
+    table: &Table,
+    operation: &'static str,
+    requests: Vec<WriteRequest>,
+) -> Result<(), Error> {
+    for chunk in requests.chunks(BATCH_WRITE_LIMIT) {
+        let mut pending = chunk.to_vec();
+        let mut attempt = 1;
+        while !pending.is_empty() {
+            let batch_write_item = table
+                .client
+                .batch_write_item()
+                .request_items(&table.name, pending)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send();
+            let batch_resp = table
+                .observe(operation, None, batch_write_item)
+                .await
+                .sdk_context("Failed to write batch")?;
+            pending = batch_resp
+                .unprocessed_items
+                .and_then(|mut unprocessed| unprocessed.remove(&table.name))
+                .unwrap_or_default();
+            if pending.is_empty() {
+                break;
+            }
+            if attempt >= BATCH_WRITE_MAX_ATTEMPTS {
+                let keys: Vec<String> = pending.iter().map(write_request_key).collect();
+                bail!(
+                    "{} items were still unwritten after {attempt} attempts: {}",
+                    keys.len(),
+                    keys.join(", ")
+                );
+            }
+            tracing::debug!(
+                unprocessed = pending.len(),
+                attempt,
+                "Retrying unprocessed items"
+            );
+            sleep(full_jitter(BATCH_WRITE_INITIAL_BACKOFF, attempt)).await;
+            attempt += 1;
+        }
     }
+    Ok(())
+}
+
+/// A random delay between zero and `initial` doubled for every attempt before `attempt`, so
+/// writers that were throttled together do not retry together.
+fn full_jitter(initial: Duration, attempt: u32) -> Duration {
+    initial
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+/// `partition key/sort key` of an item, for messages.
+fn item_key(item: &HashMap<String, AttributeValue>) -> String {
+    let part = |name| match item.get(name) {
+        Some(AttributeValue::S(value)) => value.as_str(),
+        _ => "?",
+    };
+    format!("{}/{}", part(PARTITION_KEY), part(SORT_KEY))
 }
 
-fn random_card_brand(rng: &mut impl Rng) -> CardBrand {
-    match rng.gen_range(0..4) {
-        0 => CardBrand::Visa,
-        _ => CardBrand::Mastercard,
+fn write_request_key(request: &WriteRequest) -> String {
+    match (request.put_request(), request.delete_request()) {
+        (Some(put), _) => item_key(put.item()),
+        (None, Some(delete)) => item_key(delete.key()),
+        (None, None) => "?".to_string(),
     }
 }
 
-async fn add_transaction(
-    client: &aws_sdk_dynamodb::Client,
-    transaction: Transaction,
+/// Adds each delta to its aggregate with an `UpdateItem` of its own. `ADD` is atomic per item, so
+/// writers updating the same aggregates at once never conflict the way transactions do.
+async fn add_to_aggregates(
+    table: &Table,
+    operation: &'static str,
+    deltas: HashMap<AggregateKey, AggregateDelta>,
 ) -> Result<(), Error> {
-    let merchant_id_av = AttributeValue::S(transaction.merchant_id.to_string());
-    let id_av = AttributeValue::S(transaction.id);
-    let settlement_merchant_id_av =
-        AttributeValue::S(transaction.settlement_merchant_id.to_string());
-    let gsi1_partition_key_av = settlement_merchant_id_av.clone();
-    let gsi1_sort_key_av = id_av.clone();
-    let transaction_type_av = AttributeValue::S(transaction.transaction_type.to_string());
-    let status_av = AttributeValue::S(transaction.status.to_string());
-    let amount_av = AttributeValue::N(transaction.amount.to_string());
-    let currency_av = AttributeValue::S(transaction.currency.to_string());
-    let pan_av = AttributeValue::S(transaction.pan.to_string());
-    let card_brand_av = AttributeValue::S(transaction.card_brand.to_string());
-    let date_transaction_av = AttributeValue::S(transaction.date_transaction.to_string());
-    let date_settlement_av = AttributeValue::S(transaction.date_settlement.to_string());
-    let payout_id_av = AttributeValue::S(transaction.payout_id.to_string());
-    let request = client
-        .put_item()
-        .table_name(TABLE_NAME)
-        .item(PARTITION_KEY, merchant_id_av)
-        .item(SORT_KEY, id_av)
-        .item(GSI1_PARTITION_KEY, gsi1_partition_key_av)
-        .item(GSI1_SORT_KEY, gsi1_sort_key_av)
-        .item("transaction_type", transaction_type_av)
-        .item("status", status_av)
-        .item("amount", amount_av)
-        .item("currency", currency_av)
-        .item("pan", pan_av)
-        .item("card_brand", card_brand_av)
-        .item("date_transaction", date_transaction_av)
-        .item("date_settlement", date_settlement_av)
-        .item("settlement_merchant_id", settlement_merchant_id_av)
-        .item("payout_id", payout_id_av);
-    println!("Executing request [{request:?}] to add item...");
-
-    request.send().await?;
+    try_join_all(deltas.iter().map(|(key, delta)| async move {
+        let update = aggregate_update(table, key, delta)?;
+        let update_item = table
+            .client
+            .update_item()
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .table_name(&table.name)
+            .set_key(Some(update.key().clone()))
+            .update_expression(update.update_expression())
+            .set_expression_attribute_names(update.expression_attribute_names().cloned())
+            .set_expression_attribute_values(update.expression_attribute_values().cloned())
+            .send();
+        table
+            .observe(operation, None, update_item)
+            .await
+            .sdk_context("Failed to update transaction aggregates")?;
+        Ok::<_, Error>(())
+    }))
+    .await?;
     Ok(())
 }
 
+/// Adds `delta` to an aggregate item, creating it on first use.
+fn aggregate_update(
+    table: &Table,
+    key: &AggregateKey,
+    delta: &AggregateDelta,
+) -> Result<Update, Error> {
+    let counters = delta.counters();
+    let additions = counters
+        .iter()
+        .map(|(name, _)| format!("#{name} :{name}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut update = Update::builder()
+        .table_name(&table.name)
+        .key(PARTITION_KEY, AttributeValue::S(key.merchant_id.clone()))
+        .key(SORT_KEY, AttributeValue::S(key.sort_key()))
+        .update_expression(format!(
+            "SET #period = :period, #date = :date, #currency = :currency ADD {additions}"
+        ))
+        .expression_attribute_names("#period", "period")
+        .expression_attribute_names("#date", "date")
+        .expression_attribute_names("#currency", "currency")
+        .expression_attribute_values(":period", to_attribute_value(key.period)?)
+        .expression_attribute_values(":date", AttributeValue::S(key.date.clone()))
+        .expression_attribute_values(":currency", AttributeValue::S(key.currency.clone()));
+    for (name, value) in counters {
+        update = update
+            .expression_attribute_names(format!("#{name}"), name)
+            .expression_attribute_values(format!(":{name}"), AttributeValue::N(value));
+    }
+    Ok(update.build()?)
+}
+
+/// A merchant's aggregates for one period, one per currency.
+pub async fn get_transaction_aggregates(
+    table: &Table,
+    merchant_id: &str,
+    period: AggregatePeriod,
+    date: &str,
+) -> Result<Vec<TransactionAggregate>, Error> {
+    period.validate(date)?;
+    let items_resp = table
+        .observe(
+            "get_transaction_aggregates",
+            None,
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key_condition_expression(
+                    "#partition_key = :merchant_id AND begins_with(#sort_key, :aggregate_prefix)",
+                )
+                .expression_attribute_names("#partition_key", PARTITION_KEY)
+                .expression_attribute_names("#sort_key", SORT_KEY)
+                .expression_attribute_values(
+                    ":merchant_id",
+                    AttributeValue::S(merchant_id.to_string()),
+                )
+                .expression_attribute_values(
+                    ":aggregate_prefix",
+                    AttributeValue::S(period.sort_key_prefix(date)),
+                )
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get transaction aggregates")?;
+    let mut items = items_resp.items.unwrap_or_default();
+    replace_key_names(&mut items, "merchant_id", "id");
+    Ok(from_items(items)?)
+}
+
+/// The stored form of an aggregate, as written when rebuilding them.
+pub fn transaction_aggregate_item(
+    aggregate: &TransactionAggregate,
+) -> Result<HashMap<String, AttributeValue>, Error> {
+    let mut item: HashMap<String, AttributeValue> = to_item(aggregate)?;
+    if let Some(merchant_id) = item.remove("merchant_id") {
+        item.insert(PARTITION_KEY.to_string(), merchant_id);
+    }
+    if let Some(id) = item.remove("id") {
+        item.insert(SORT_KEY.to_string(), id);
+    }
+    Ok(item)
+}
+
+pub async fn get_transaction(
+    table: &Table,
+    merchant_id: &str,
+    transaction_id: &str,
+) -> Result<Transaction, Error> {
+    let item_resp = table
+        .observe(
+            "get_transaction",
+            None,
+            table
+                .client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key(PARTITION_KEY, AttributeValue::S(merchant_id.to_string()))
+                .key(SORT_KEY, AttributeValue::S(transaction_id.to_string()))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get transaction")?;
+
+    item_resp
+        .item
+        .map(|item| {
+            let mut modified_items = vec![item];
+            replace_key_names(&mut modified_items, "merchant_id", "id");
+            from_item(modified_items.remove(0)).context("failed to deserialise transaction")
+        })
+        .transpose()?
+        .with_context(|| AppError::not_found("Transaction not found"))
+}
+
+/// Sets the status of one transaction and returns it together with the status it replaced. A
+/// chargeback queues `TransactionChargebacked` webhooks for the outlet and its settlement
+/// merchant in the same transaction.
+pub async fn update_transaction_status(
+    table: &Table,
+    merchant_id: &str,
+    transaction_id: &str,
+    status: TransactionStatus,
+    actor: &Actor,
+) -> Result<TransactionStatusChange, Error> {
+    tracing::debug!(%merchant_id, %transaction_id, %status, "Updating transaction status");
+    let previous = get_transaction(table, merchant_id, transaction_id).await?;
+    let previous_status = previous.status;
+    let mut transaction = previous.clone();
+    transaction.status = status;
+
+    let update = Update::builder()
+        .table_name(&table.name)
+        .key(PARTITION_KEY, AttributeValue::S(merchant_id.to_string()))
+        .key(SORT_KEY, AttributeValue::S(transaction_id.to_string()))
+        .update_expression("SET #status = :status")
+        .condition_expression("#status = :previous_status")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S(status.to_string()))
+        .expression_attribute_values(
+            ":previous_status",
+            AttributeValue::S(previous_status.to_string()),
+        )
+        .build()?;
+    let mut items = vec![TransactWriteItem::builder().update(update).build()];
+    let delta = AggregateDelta::between(&previous, &transaction);
+    if !delta.is_zero() {
+        for key in AggregateKey::of(&transaction) {
+            items.push(
+                TransactWriteItem::builder()
+                    .update(aggregate_update(table, &key, &delta)?)
+                    .build(),
+            );
+        }
+    }
+    if status == TransactionStatus::Chargebacked && previous_status != status {
+        let mut masked = transaction.clone();
+        masked.pan = mask_pan(&masked.pan);
+        items.extend(
+            webhook_outbox(
+                table,
+                &[
+                    transaction.merchant_id.as_str(),
+                    transaction.settlement_merchant_id.as_str(),
+                ],
+                WebhookEventType::TransactionChargebacked,
+                &masked,
+            )
+            .await?,
+        );
+    }
+    items.push(audit_entry_put(
+        table,
+        &transaction_audit_entry(
+            actor,
+            "updateTransactionStatus",
+            Some(&previous),
+            &transaction,
+        ),
+    )?);
+
+    let result = table
+        .observe(
+            "update_transaction_status",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .set_transact_items(Some(items))
+                .send(),
+        )
+        .await;
+    match result {
+        Ok(_) => {}
+        Err(err) if first_condition_failed(&err) => {
+            bail!(AppError::conflict(
+                "Transaction status changed concurrently, read it again before updating"
+            ))
+        }
+        Err(err) => return Err(err).sdk_context("Failed to update transaction status"),
+    }
+
+    let change = TransactionStatusChange {
+        transaction,
+        previous_status,
+    };
+    publish(TransactionEvent::StatusChanged(change.clone()));
+    Ok(change)
+}
+
 pub async fn get_transactions(
-    client: &aws_sdk_dynamodb::Client,
+    table: &Table,
     merchant_id: String,
     year: Option<String>,
     month: Option<String>,
     day: Option<String>,
     card_brand: Option<CardBrand>,
-    after_pagination: Option<String>,
-    before_pagination: Option<String>,
-    limit: i32,
+    page: PageRequest<String>,
 ) -> Result<(Vec<Transaction>, bool), anyhow::Error> {
-    println!(
-        "Getting transactions for merchant_id={merchant_id}, after={after_pagination:?}, before={before_pagination:?}, limit={limit}..."
-    );
-
-    let (mut earlier_transaction, mut later_transaction) = match (year, month, day) {
-        // daily aggregate
-        (Some(year), Some(month), Some(day)) => (
-            format!("{}#{}-{}-{}", TRANSACTION_PREFIX, year, month, day),
-            format!("{}#{}-{}-{}T99", TRANSACTION_PREFIX, year, month, day),
-        ),
-        (_, _, Some(day)) => (
-            format!(
-                "{}#{}-{}-{}",
-                TRANSACTION_PREFIX,
-                Utc::now().year().to_string(),
-                Utc::now().month().to_string(),
-                day
-            ),
-            format!(
-                "{}#{}-{}-{}T99",
-                TRANSACTION_PREFIX,
-                Utc::now().year().to_string(),
-                Utc::now().month().to_string(),
-                day
-            ),
-        ),
-        // monthly aggregate
-        (Some(year), Some(month), _) => (
-            format!("{}#{}-{}", TRANSACTION_PREFIX, year, month),
-            format!("{}#{}-{}-{}", TRANSACTION_PREFIX, year, month, "99"),
-        ),
-        (_, Some(month), _) => (
-            format!(
-                "{}#{}-{}",
-                TRANSACTION_PREFIX,
-                Utc::now().year().to_string(),
-                month
-            ),
-            format!(
-                "{}#{}-{}-99",
-                TRANSACTION_PREFIX,
-                Utc::now().year().to_string(),
-                month
-            ),
-        ),
-        // yearly aggregate
-        (Some(year), None, None) => (
-            format!("{}#{}", TRANSACTION_PREFIX, year),
-            format!("{}#{}-99-99", TRANSACTION_PREFIX, year),
-        ),
-        (None, None, None) => (
-            format!("{}#", TRANSACTION_PREFIX),
-            format!("{}#{}-", TRANSACTION_PREFIX, Utc::now().to_rfc3339()),
-        ),
-    };
+    tracing::debug!(%merchant_id, ?page, "Getting transactions");
 
-    if after_pagination.is_some() && after_pagination.as_ref().unwrap() <= &later_transaction {
-        later_transaction = after_pagination.unwrap();
-    }
-    if before_pagination.is_some() && before_pagination.as_ref().unwrap() >= &earlier_transaction {
-        earlier_transaction = before_pagination.unwrap();
-    }
+    // The cursor transactions are read again and dropped below, so one more row is read past the
+    // cursor the page starts from to still fill it.
+    let limit = page.limit;
+    let read_limit = limit + usize::from(page.start().is_some());
+    let cursors = [page.after.clone(), page.before.clone()];
+    let (earlier_transaction, later_transaction) = narrow_to_cursors(
+        transaction_sort_key_range(year, month, day),
+        page.after,
+        page.before,
+    );
 
-    println!(
-        "Querying transactions with partition key '{merchant_id}' and sort key between '{earlier_transaction}' and '{later_transaction}'..."
+    tracing::debug!(
+        %merchant_id,
+        %earlier_transaction,
+        %later_transaction,
+        "Querying transactions"
     );
-    let query = client
+    let query = table.client
         .query()
-        .table_name(TABLE_NAME)
-        .limit(limit)
+        .table_name(&table.name)
+        .limit(read_limit as i32)
         .key_condition_expression(
             "#partition_key = :merchant_id AND #sort_key BETWEEN :earlier_transaction AND :later_transaction",
         )
@@ -686,7 +767,8 @@ pub async fn get_transactions(
         .expression_attribute_values(":merchant_id", AttributeValue::S(merchant_id))
         .expression_attribute_values(":earlier_transaction", AttributeValue::S(earlier_transaction))
         .expression_attribute_values(":later_transaction", AttributeValue::S(later_transaction))
-        .scan_index_forward(false);
+        // Backward pages start at `before` and read towards newer transactions.
+        .scan_index_forward(page.backward);
     let query = if let Some(card_brand) = card_brand {
         query
             .filter_expression("#card_brand = :card_brand")
@@ -696,110 +778,1871 @@ pub async fn get_transactions(
         query
     };
 
-    let items_resp = query.send().await.context("Failed to get transaction")?;
+    let items_resp = table
+        .observe(
+            "get_transactions",
+            None,
+            query
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get transaction")?;
 
     if let Some(items) = items_resp.items {
         let mut modified_items = items.clone();
         replace_key_names(&mut modified_items, "merchant_id", "id");
-        let transactions: Vec<Transaction> = from_items(modified_items)?;
-        return Ok((transactions, items_resp.last_evaluated_key.is_some()));
+        let mut transactions: Vec<Transaction> = from_items(modified_items)?;
+        transactions.retain(|transaction| !cursors.contains(&Some(transaction.id.clone())));
+        let has_more = items_resp.last_evaluated_key.is_some() || transactions.len() > limit;
+        transactions.truncate(limit);
+        if page.backward {
+            transactions.reverse();
+        }
+        return Ok((transactions, has_more));
     }
 
     Ok((Vec::new(), false))
 }
 
-pub async fn get_transactions_for_settlement_merchant(
-    client: &aws_sdk_dynamodb::Client,
-    settlement_merchant_id: String,
-    after: Option<String>,
-    before: Option<String>,
-    limit: i32,
-) -> Result<(Vec<Transaction>, bool), anyhow::Error> {
-    println!(
-        "Getting transactions for settlement_merchant_id={settlement_merchant_id}, after={after:?}, before={before:?}, limit={limit}..."
+/// Pages through the transactions of several merchants at once, merged newest first with ties
+/// broken by merchant id so that a [`TransactionCursor`] identifies a unique position.
+pub async fn get_transactions_for_merchants(
+    table: &Table,
+    merchant_ids: &[String],
+    year: Option<String>,
+    month: Option<String>,
+    day: Option<String>,
+    card_brand: Option<CardBrand>,
+    page: &PageRequest<TransactionCursor>,
+) -> Result<(Vec<Transaction>, bool), Error> {
+    tracing::debug!(
+        merchants = merchant_ids.len(),
+        ?page,
+        "Getting transactions for merchants"
     );
-    
-     let (mut earlier_transaction, mut later_transaction) = (
-            format!("{}#", TRANSACTION_PREFIX),
-            format!("{}#9999", TRANSACTION_PREFIX),
-        );
+    let (earlier_transaction, later_transaction) = transaction_sort_key_range(year, month, day);
+    get_transactions_for_merchants_between(
+        table,
+        merchant_ids,
+        &earlier_transaction,
+        &later_transaction,
+        card_brand,
+        page,
+    )
+    .await
+}
 
-    if after.is_some() && after.as_ref().unwrap() <= &later_transaction {
-        later_transaction = after.unwrap();
-    }
-    if before.is_some() && before.as_ref().unwrap() >= &earlier_transaction {
-        earlier_transaction = before.unwrap();
+/// Like [`get_transactions_for_merchants`], for the inclusive sort key range of
+/// [`transaction_date_range`].
+pub async fn get_transactions_for_merchants_between(
+    table: &Table,
+    merchant_ids: &[String],
+    earlier_transaction: &str,
+    later_transaction: &str,
+    card_brand: Option<CardBrand>,
+    page: &PageRequest<TransactionCursor>,
+) -> Result<(Vec<Transaction>, bool), Error> {
+    // Each merchant contributes at most limit + 1 rows, enough to fill the page and detect more.
+    let pages = try_join_all(merchant_ids.iter().map(|merchant_id| {
+        query_transactions_between(
+            table,
+            merchant_id,
+            earlier_transaction,
+            later_transaction,
+            card_brand,
+            page,
+        )
+    }))
+    .await?;
+
+    let mut transactions: Vec<Transaction> = pages.into_iter().flatten().collect();
+    transactions.sort_by(|a, b| merged_order(position(a), position(b)));
+    Ok(take_page(transactions, page))
+}
+
+/// The nearest merchant at or above the outlet that may settle, or the outlet itself when none
+/// can.
+pub async fn get_settlement_merchant_id(table: &Table, outlet_id: &str) -> Result<String, Error> {
+    let mut merchant_id = outlet_id.to_string();
+    for _ in 0..MAX_HIERARCHY_DEPTH {
+        let merchant = get_merchant(table, merchant_id.clone())
+            .await
+            .with_context(|| format!("Failed to get merchant {merchant_id}"))?;
+        if merchant.has_settlement_permissions {
+            return Ok(merchant.id);
+        }
+        match merchant.parent_merchant_id {
+            Some(parent_id) => merchant_id = parent_id,
+            None => return Ok(outlet_id.to_string()),
+        }
     }
+    bail!("Merchant hierarchy above {outlet_id} is deeper than expected")
+}
 
-    println!(
-        "Querying transactions with partition key '{settlement_merchant_id}' and sort key between '{earlier_transaction}' and '{later_transaction}'..."
-    );
-    let query = client
-        .query()
-        .table_name(TABLE_NAME)
-        .index_name("gsi1")
-        .limit(limit)
-        .key_condition_expression(
-            "#gsi1_partition_key = :settlement_merchant_id AND #gsi1_sort_key BETWEEN :earlier_transaction AND :later_transaction",
-        )
-        .expression_attribute_names("#gsi1_partition_key", GSI1_PARTITION_KEY)
-        .expression_attribute_names("#gsi1_sort_key", GSI1_SORT_KEY)
-        .expression_attribute_values(
-            ":settlement_merchant_id",
-            AttributeValue::S(settlement_merchant_id),
+/// Ids of the outlets below `merchant_id`, or the merchant itself when it is an outlet.
+pub async fn get_descendant_outlets(
+    table: &Table,
+    merchant_id: String,
+) -> Result<Vec<String>, Error> {
+    let mut outlets = Vec::new();
+    let mut visited = HashSet::new();
+    let mut level = vec![merchant_id.clone()];
+    for _ in 0..MAX_HIERARCHY_DEPTH {
+        if level.is_empty() {
+            return Ok(outlets);
+        }
+        let merchants = try_join_all(
+            level
+                .into_iter()
+                .filter(|id| visited.insert(id.clone()))
+                .map(|id| get_merchant(table, id)),
         )
-        .expression_attribute_values(":earlier_transaction", AttributeValue::S(earlier_transaction))
-        .expression_attribute_values(":later_transaction", AttributeValue::S(later_transaction))
-        .scan_index_forward(false);
+        .await?;
+        level = Vec::new();
+        for merchant in merchants {
+            match merchant.merchant_level {
+                MerchantLevel::Outlet => outlets.push(merchant.id),
+                MerchantLevel::Group | MerchantLevel::Chain => level.extend(merchant.sub_merchants),
+            }
+        }
+    }
+    bail!("Merchant hierarchy below {merchant_id} is deeper than expected")
+}
 
-    let items_resp = query.send().await.map_err(|err| anyhow::anyhow!("Failed to get transactions: {}", err))?;
+/// Orders `(sort key, merchant id)` positions newest first, ties broken by merchant id.
+pub fn merged_order(a: (&str, &str), b: (&str, &str)) -> Ordering {
+    b.0.cmp(a.0).then_with(|| a.1.cmp(b.1))
+}
 
-    if let Some(items) = items_resp.items {
-        let mut modified_items = items.clone();
-        replace_key_names(&mut modified_items, "merchant_id", "id");
-        let transactions: Vec<Transaction> = from_items(modified_items)?;
-        return Ok((transactions, items_resp.last_evaluated_key.is_some()));
-    }
+pub fn position(transaction: &Transaction) -> (&str, &str) {
+    (&transaction.id, &transaction.merchant_id)
+}
 
-    Ok((Vec::new(), false))
+pub fn is_after(transaction: &Transaction, cursor: &TransactionCursor) -> bool {
+    merged_order(position(transaction), (&cursor.id, &cursor.merchant_id)) == Ordering::Greater
 }
 
-pub async fn get_merchant(
-    client: &aws_sdk_dynamodb::Client,
-    merchant_id: String,
-) -> Result<Merchant, anyhow::Error> {
-    let item_resp = client
-        .get_item()
-        .table_name(TABLE_NAME)
-        .key(PARTITION_KEY, AttributeValue::S(merchant_id.clone()))
-        .key(SORT_KEY, AttributeValue::S(merchant_id.clone()))
-        .send()
-        .await
-        .context("Failed to get merchant")?;
+pub fn is_before(transaction: &Transaction, cursor: &TransactionCursor) -> bool {
+    merged_order(position(transaction), (&cursor.id, &cursor.merchant_id)) == Ordering::Less
+}
 
-    return Ok(item_resp
-        .item
-        .map(|item| {
-            let mut modified_items = vec![item.clone()];
-            replace_key_names(&mut modified_items, "id", "name");
-            let modified_item = modified_items.remove(0);
-            from_item(modified_item).context("failed to deserialise merchant")
-        })
-        .transpose()?
-        .context("Merchant not found")?);
+/// Keeps the `limit` rows of a merged, newest-first read nearest the cursor the page starts from,
+/// and whether more were read.
+pub fn take_page<T, C>(mut rows: Vec<T>, page: &PageRequest<C>) -> (Vec<T>, bool) {
+    let has_more = rows.len() > page.limit;
+    if page.backward {
+        rows.drain(..rows.len().saturating_sub(page.limit));
+    } else {
+        rows.truncate(page.limit);
+    }
+    (rows, has_more)
 }
 
-fn replace_key_names(
-    items: &mut Vec<HashMap<String, AttributeValue>>,
-    partition_key: &str,
-    sort_key: &str,
-) {
-    for item in items.iter_mut() {
-        if let Some(value) = item.remove(PARTITION_KEY) {
-            item.insert(partition_key.to_string(), value);
-        }
+/// Reads up to `limit + 1` transactions of one merchant inside the sort key range, starting from
+/// the page's cursor, following `LastEvaluatedKey` until enough rows survive the card brand and
+/// cursor filters.
+async fn query_transactions_between(
+    table: &Table,
+    merchant_id: &str,
+    earlier_transaction: &str,
+    later_transaction: &str,
+    card_brand: Option<CardBrand>,
+    page: &PageRequest<TransactionCursor>,
+) -> Result<Vec<Transaction>, Error> {
+    let wanted = page.limit + 1;
+    let (after, before) = (page.after.as_ref(), page.before.as_ref());
+    // Cursor sort keys narrow the range inclusively; ties are resolved on merchant id below.
+    let later_transaction = match after {
+        Some(cursor) if cursor.id.as_str() < later_transaction => cursor.id.as_str(),
+        _ => later_transaction,
+    };
+    let earlier_transaction = match before {
+        Some(cursor) if cursor.id.as_str() > earlier_transaction => cursor.id.as_str(),
+        _ => earlier_transaction,
+    };
+    if earlier_transaction > later_transaction {
+        return Ok(Vec::new());
+    }
+
+    let mut transactions = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let query = table.client
+            .query()
+            .table_name(&table.name)
+            .limit(wanted as i32)
+            .set_exclusive_start_key(exclusive_start_key)
+            .key_condition_expression(
+                "#partition_key = :merchant_id AND #sort_key BETWEEN :earlier_transaction AND :later_transaction",
+            )
+            .expression_attribute_names("#partition_key", PARTITION_KEY)
+            .expression_attribute_names("#sort_key", SORT_KEY)
+            .expression_attribute_values(":merchant_id", AttributeValue::S(merchant_id.to_string()))
+            .expression_attribute_values(
+                ":earlier_transaction",
+                AttributeValue::S(earlier_transaction.to_string()),
+            )
+            .expression_attribute_values(
+                ":later_transaction",
+                AttributeValue::S(later_transaction.to_string()),
+            )
+            .scan_index_forward(page.backward);
+        let query = if let Some(card_brand) = card_brand {
+            query
+                .filter_expression("#card_brand = :card_brand")
+                .expression_attribute_names("#card_brand", "card_brand")
+                .expression_attribute_values(
+                    ":card_brand",
+                    AttributeValue::S(card_brand.to_string()),
+                )
+        } else {
+            query
+        };
+
+        let items_resp = table
+            .observe(
+                "get_transactions_for_merchants",
+                None,
+                query
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send(),
+            )
+            .await
+            .sdk_context("Failed to get transactions")?;
+        let mut items = items_resp.items.unwrap_or_default();
+        replace_key_names(&mut items, "merchant_id", "id");
+        let page: Vec<Transaction> = from_items(items)?;
+        transactions.extend(page.into_iter().filter(|transaction| {
+            after.map_or(true, |cursor| is_after(transaction, cursor))
+                && before.map_or(true, |cursor| is_before(transaction, cursor))
+        }));
+
+        exclusive_start_key = items_resp.last_evaluated_key;
+        if transactions.len() >= wanted || exclusive_start_key.is_none() {
+            break;
+        }
+    }
+    transactions.truncate(wanted);
+    Ok(transactions)
+}
+
+/// Narrows an inclusive sort key range to the sort keys of the `after` and `before` cursors of a
+/// page. The bounds stay inclusive, so the cursor transactions themselves are read again; pages
+/// of one merchant drop them, as [`is_after`] and [`is_before`] do for several.
+pub fn narrow_to_cursors(
+    (mut earlier_transaction, mut later_transaction): (String, String),
+    after: Option<String>,
+    before: Option<String>,
+) -> (String, String) {
+    if let Some(after) = after
+        && after <= later_transaction
+    {
+        later_transaction = after;
+    }
+    if let Some(before) = before
+        && before >= earlier_transaction
+    {
+        earlier_transaction = before;
+    }
+    (earlier_transaction, later_transaction)
+}
+
+/// Inclusive sort key bounds covering the transactions of a day, month or year.
+pub fn transaction_sort_key_range(
+    year: Option<String>,
+    month: Option<String>,
+    day: Option<String>,
+) -> (String, String) {
+    match (year, month, day) {
+        // daily aggregate
+        (Some(year), Some(month), Some(day)) => (
+            format!("{}#{}-{}-{}", TRANSACTION_PREFIX, year, month, day),
+            format!("{}#{}-{}-{}T99", TRANSACTION_PREFIX, year, month, day),
+        ),
+        (_, _, Some(day)) => (
+            format!(
+                "{}#{}-{}-{}",
+                TRANSACTION_PREFIX,
+                Utc::now().year(),
+                Utc::now().month(),
+                day
+            ),
+            format!(
+                "{}#{}-{}-{}T99",
+                TRANSACTION_PREFIX,
+                Utc::now().year(),
+                Utc::now().month(),
+                day
+            ),
+        ),
+        // monthly aggregate
+        (Some(year), Some(month), _) => (
+            format!("{}#{}-{}", TRANSACTION_PREFIX, year, month),
+            format!("{}#{}-{}-{}", TRANSACTION_PREFIX, year, month, "99"),
+        ),
+        (_, Some(month), _) => (
+            format!("{}#{}-{}", TRANSACTION_PREFIX, Utc::now().year(), month),
+            format!("{}#{}-{}-99", TRANSACTION_PREFIX, Utc::now().year(), month),
+        ),
+        // yearly aggregate
+        (Some(year), None, None) => (
+            format!("{}#{}", TRANSACTION_PREFIX, year),
+            format!("{}#{}-99-99", TRANSACTION_PREFIX, year),
+        ),
+        (None, None, None) => (
+            format!("{}#", TRANSACTION_PREFIX),
+            format!("{}#{}-", TRANSACTION_PREFIX, Utc::now().to_rfc3339()),
+        ),
+    }
+}
+
+/// Inclusive sort key bounds covering the transactions from the start of `from` to the end of
+/// `to`; an open end is unbounded.
+pub fn transaction_date_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> (String, String) {
+    (
+        from.map_or_else(
+            || format!("{TRANSACTION_PREFIX}#"),
+            |from| format!("{TRANSACTION_PREFIX}#{from}"),
+        ),
+        to.map_or_else(
+            || format!("{TRANSACTION_PREFIX}#9999"),
+            |to| format!("{TRANSACTION_PREFIX}#{to}T99"),
+        ),
+    )
+}
+
+/// Inclusive sort key bounds covering the payouts settled from the start of `from` to the end of
+/// `to`, whose ids carry their settlement date; an open end is unbounded.
+pub fn payout_date_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> (String, String) {
+    (
+        from.map_or_else(
+            || format!("{PAYOUT_PREFIX}#"),
+            |from| format!("{PAYOUT_PREFIX}#{from}"),
+        ),
+        to.map_or_else(
+            || format!("{PAYOUT_PREFIX}#9999"),
+            |to| format!("{PAYOUT_PREFIX}#{to}T99"),
+        ),
+    )
+}
+
+pub async fn get_transactions_for_settlement_merchant(
+    table: &Table,
+    settlement_merchant_id: String,
+    page: PageRequest<String>,
+) -> Result<(Vec<Transaction>, bool), anyhow::Error> {
+    tracing::debug!(
+        %settlement_merchant_id,
+        ?page,
+        "Getting transactions for settlement merchant"
+    );
+
+    let (earlier_transaction, later_transaction) =
+        narrow_to_cursors(transaction_date_range(None, None), page.after, page.before);
+
+    tracing::debug!(
+        %settlement_merchant_id,
+        %earlier_transaction,
+        %later_transaction,
+        "Querying settlement merchant transactions"
+    );
+    let query = table.client
+        .query()
+        .table_name(&table.name)
+        .index_name(GSI1_INDEX)
+        .limit(page.limit as i32)
+        .key_condition_expression(
+            "#gsi1_partition_key = :settlement_merchant_id AND #gsi1_sort_key BETWEEN :earlier_transaction AND :later_transaction",
+        )
+        .expression_attribute_names("#gsi1_partition_key", GSI1_PARTITION_KEY)
+        .expression_attribute_names("#gsi1_sort_key", GSI1_SORT_KEY)
+        .expression_attribute_values(
+            ":settlement_merchant_id",
+            AttributeValue::S(settlement_merchant_id),
+        )
+        .expression_attribute_values(":earlier_transaction", AttributeValue::S(earlier_transaction))
+        .expression_attribute_values(":later_transaction", AttributeValue::S(later_transaction))
+        .scan_index_forward(page.backward);
+
+    let items_resp = table
+        .observe(
+            "get_transactions_for_settlement_merchant",
+            Some(GSI1_INDEX),
+            query
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get transactions")?;
+
+    if let Some(items) = items_resp.items {
+        let mut modified_items = items.clone();
+        replace_key_names(&mut modified_items, "merchant_id", "id");
+        let mut transactions: Vec<Transaction> = from_items(modified_items)?;
+        if page.backward {
+            transactions.reverse();
+        }
+        return Ok((transactions, items_resp.last_evaluated_key.is_some()));
+    }
+
+    Ok((Vec::new(), false))
+}
+
+/// Every transaction settled by `settlement_merchant_id` inside the sort key range, newest first,
+/// following `LastEvaluatedKey` to the end.
+pub async fn get_all_transactions_for_settlement_merchant(
+    table: &Table,
+    settlement_merchant_id: &str,
+    earlier_transaction: &str,
+    later_transaction: &str,
+) -> Result<Vec<Transaction>, Error> {
+    let mut transactions = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let items_resp = table.observe(
+            "get_all_transactions_for_settlement_merchant",
+            Some(GSI1_INDEX),
+            table.client
+                .query()
+                .table_name(&table.name)
+                .index_name(GSI1_INDEX)
+                .set_exclusive_start_key(exclusive_start_key)
+                .key_condition_expression(
+                    "#gsi1_partition_key = :settlement_merchant_id AND #gsi1_sort_key BETWEEN :earlier_transaction AND :later_transaction",
+                )
+                .expression_attribute_names("#gsi1_partition_key", GSI1_PARTITION_KEY)
+                .expression_attribute_names("#gsi1_sort_key", GSI1_SORT_KEY)
+                .expression_attribute_values(
+                    ":settlement_merchant_id",
+                    AttributeValue::S(settlement_merchant_id.to_string()),
+                )
+                .expression_attribute_values(
+                    ":earlier_transaction",
+                    AttributeValue::S(earlier_transaction.to_string()),
+                )
+                .expression_attribute_values(
+                    ":later_transaction",
+                    AttributeValue::S(later_transaction.to_string()),
+                )
+                .scan_index_forward(false)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get transactions")?;
+        let mut items = items_resp.items.unwrap_or_default();
+        replace_key_names(&mut items, "merchant_id", "id");
+        let page: Vec<Transaction> = from_items(items)?;
+        transactions.extend(page);
+
+        exclusive_start_key = items_resp.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            return Ok(transactions);
+        }
+    }
+}
+
+pub async fn get_merchant(table: &Table, merchant_id: String) -> Result<Merchant, anyhow::Error> {
+    let item_resp = table
+        .observe(
+            "get_merchant",
+            None,
+            table
+                .client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key(PARTITION_KEY, AttributeValue::S(merchant_id.clone()))
+                .key(SORT_KEY, AttributeValue::S(merchant_id.clone()))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get merchant")?;
+
+    item_resp
+        .item
+        .map(|item| {
+            // Merchant items repeat the id in the sort key.
+            let mut modified_items = vec![item.clone()];
+            replace_key_names(&mut modified_items, "id", "id");
+            let modified_item = modified_items.remove(0);
+            from_item(modified_item).context("failed to deserialise merchant")
+        })
+        .transpose()?
+        .with_context(|| AppError::not_found("Merchant not found"))
+}
+
+/// Fails unless the table and its `gsi1` index are both `ACTIVE`.
+pub async fn check_table_ready(table: &Table) -> Result<(), Error> {
+    let resp = table
+        .observe(
+            "check_table_ready",
+            None,
+            table.client.describe_table().table_name(&table.name).send(),
+        )
+        .await
+        .sdk_context("Failed to describe table")?;
+    let description = resp.table().context("Table description missing")?;
+
+    if description.table_status() != Some(&TableStatus::Active) {
+        bail!(
+            "Table {} is {:?}",
+            table.name,
+            description.table_status().map(|status| status.as_str())
+        );
+    }
+    let gsi1_status = description
+        .global_secondary_indexes()
+        .iter()
+        .find(|index| index.index_name() == Some(GSI1_INDEX))
+        .and_then(|index| index.index_status());
+    if gsi1_status != Some(&IndexStatus::Active) {
+        bail!(
+            "Index {GSI1_INDEX} is {:?}",
+            gsi1_status.map(|status| status.as_str())
+        );
+    }
+    Ok(())
+}
+
+/// Attaches `child_id` under `parent_id`. The child must not have a parent yet; use
+/// [`move_merchant`] to re-home an attached merchant.
+pub async fn attach_sub_merchant(
+    table: &Table,
+    parent_id: &str,
+    child_id: &str,
+    actor: &Actor,
+) -> Result<(), Error> {
+    let parent = get_merchant(table, parent_id.to_string()).await?;
+    let child = get_merchant(table, child_id.to_string()).await?;
+    if let Some(current_parent_id) = &child.parent_merchant_id {
+        bail!(AppError::conflict(format!(
+            "Merchant {child_id} is already attached to {current_parent_id}, use moveMerchant instead"
+        )));
+    }
+    validate_placement(table, &parent, &child).await?;
+    let audit_entry = hierarchy_audit_entry(actor, "attachSubMerchant", &child, Some(parent_id));
+
+    table
+        .observe(
+            "attach_sub_merchant",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .transact_items(append_sub_merchant(table, parent_id, child_id)?)
+                .transact_items(set_parent_merchant(table, child_id, None, Some(parent_id))?)
+                .transact_items(record_hierarchy_change(
+                    table,
+                    child_id,
+                    HierarchyAction::Attach,
+                    None,
+                    Some(parent_id),
+                    actor.role,
+                )?)
+                .transact_items(audit_entry_put(table, &audit_entry)?)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to attach sub merchant")?;
+    Ok(())
+}
+
+pub async fn detach_sub_merchant(
+    table: &Table,
+    parent_id: &str,
+    child_id: &str,
+    actor: &Actor,
+) -> Result<(), Error> {
+    let parent = get_merchant(table, parent_id.to_string()).await?;
+    let child = get_merchant(table, child_id.to_string()).await?;
+    if child.parent_merchant_id.as_deref() != Some(parent_id) {
+        bail!(AppError::conflict(format!(
+            "Merchant {child_id} is not a sub merchant of {parent_id}"
+        )));
+    }
+    let audit_entry = hierarchy_audit_entry(actor, "detachSubMerchant", &child, None);
+
+    table
+        .observe(
+            "detach_sub_merchant",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .transact_items(remove_sub_merchant(table, &parent, child_id)?)
+                .transact_items(set_parent_merchant(table, child_id, Some(parent_id), None)?)
+                .transact_items(record_hierarchy_change(
+                    table,
+                    child_id,
+                    HierarchyAction::Detach,
+                    Some(parent_id),
+                    None,
+                    actor.role,
+                )?)
+                .transact_items(audit_entry_put(table, &audit_entry)?)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to detach sub merchant")?;
+    Ok(())
+}
+
+pub async fn move_merchant(
+    table: &Table,
+    merchant_id: &str,
+    new_parent_id: &str,
+    actor: &Actor,
+) -> Result<(), Error> {
+    let merchant = get_merchant(table, merchant_id.to_string()).await?;
+    let Some(old_parent_id) = merchant.parent_merchant_id.clone() else {
+        bail!(AppError::conflict(format!(
+            "Merchant {merchant_id} has no parent, use attachSubMerchant instead"
+        )));
+    };
+    if old_parent_id == new_parent_id {
+        bail!(AppError::conflict(format!(
+            "Merchant {merchant_id} is already attached to {new_parent_id}"
+        )));
+    }
+    let old_parent = get_merchant(table, old_parent_id.clone()).await?;
+    let new_parent = get_merchant(table, new_parent_id.to_string()).await?;
+    validate_placement(table, &new_parent, &merchant).await?;
+    let audit_entry = hierarchy_audit_entry(actor, "moveMerchant", &merchant, Some(new_parent_id));
+
+    table
+        .observe(
+            "move_merchant",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .transact_items(remove_sub_merchant(table, &old_parent, merchant_id)?)
+                .transact_items(append_sub_merchant(table, new_parent_id, merchant_id)?)
+                .transact_items(set_parent_merchant(
+                    table,
+                    merchant_id,
+                    Some(&old_parent_id),
+                    Some(new_parent_id),
+                )?)
+                .transact_items(record_hierarchy_change(
+                    table,
+                    merchant_id,
+                    HierarchyAction::Move,
+                    Some(&old_parent_id),
+                    Some(new_parent_id),
+                    actor.role,
+                )?)
+                .transact_items(audit_entry_put(table, &audit_entry)?)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to move merchant")?;
+    Ok(())
+}
+
+pub async fn get_hierarchy_history(
+    table: &Table,
+    merchant_id: String,
+) -> Result<Vec<HierarchyChange>, Error> {
+    let items_resp = table
+        .observe(
+            "get_hierarchy_history",
+            None,
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key_condition_expression(
+                    "#partition_key = :merchant_id AND begins_with(#sort_key, :prefix)",
+                )
+                .expression_attribute_names("#partition_key", PARTITION_KEY)
+                .expression_attribute_names("#sort_key", SORT_KEY)
+                .expression_attribute_values(":merchant_id", AttributeValue::S(merchant_id))
+                .expression_attribute_values(
+                    ":prefix",
+                    AttributeValue::S(format!("{HIERARCHY_PREFIX}#")),
+                )
+                .scan_index_forward(false)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get hierarchy history")?;
+
+    Ok(from_items(items_resp.items.unwrap_or_default())?)
+}
+
+pub async fn add_audit_entry(table: &Table, audit_entry: &AuditEntry) -> Result<(), Error> {
+    table
+        .observe(
+            "add_audit_entry",
+            None,
+            table
+                .client
+                .put_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .set_item(Some(audit_entry_item(audit_entry)?))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to record audit entry")?;
+    Ok(())
+}
+
+/// Audit entries of one `YYYY-MM-DD` day, newest first, starting after the entry id `after`.
+pub async fn get_audit_log(
+    table: &Table,
+    date: String,
+    page: PageRequest<String>,
+) -> Result<(Vec<AuditEntry>, bool), Error> {
+    let partition_key = audit_partition_key(&date);
+    let exclusive_start_key = page
+        .start()
+        .map(|start| exclusive_start_key(&partition_key, start));
+    let items_resp = table
+        .observe(
+            "get_audit_log",
+            None,
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .limit(page.limit as i32)
+                .set_exclusive_start_key(exclusive_start_key)
+                .key_condition_expression("#partition_key = :partition_key")
+                .expression_attribute_names("#partition_key", PARTITION_KEY)
+                .expression_attribute_values(":partition_key", AttributeValue::S(partition_key))
+                .scan_index_forward(page.backward)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get audit log")?;
+
+    let mut audit_entries: Vec<AuditEntry> = from_items(items_resp.items.unwrap_or_default())?;
+    if page.backward {
+        audit_entries.reverse();
+    }
+    Ok((audit_entries, items_resp.last_evaluated_key.is_some()))
+}
+
+/// Key to resume a query of one partition from, exclusive of the `sort_key` item itself.
+fn exclusive_start_key(partition_key: &str, sort_key: &str) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            PARTITION_KEY.to_string(),
+            AttributeValue::S(partition_key.to_string()),
+        ),
+        (
+            SORT_KEY.to_string(),
+            AttributeValue::S(sort_key.to_string()),
+        ),
+    ])
+}
+
+fn audit_entry_item(audit_entry: &AuditEntry) -> Result<HashMap<String, AttributeValue>, Error> {
+    let mut item: HashMap<String, AttributeValue> = to_item(audit_entry)?;
+    item.insert(
+        PARTITION_KEY.to_string(),
+        AttributeValue::S(audit_entry.partition_key()),
+    );
+    item.insert(
+        SORT_KEY.to_string(),
+        AttributeValue::S(audit_entry.id.clone()),
+    );
+    Ok(item)
+}
+
+fn audit_entry_put(table: &Table, audit_entry: &AuditEntry) -> Result<TransactWriteItem, Error> {
+    let put = Put::builder()
+        .table_name(&table.name)
+        .set_item(Some(audit_entry_item(audit_entry)?))
+        .build()?;
+    Ok(TransactWriteItem::builder().put(put).build())
+}
+
+pub async fn add_webhook(table: &Table, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+    let mut item: HashMap<String, AttributeValue> = to_item(webhook)?;
+    item.insert(
+        PARTITION_KEY.to_string(),
+        AttributeValue::S(webhook.merchant_id.clone()),
+    );
+    item.insert(SORT_KEY.to_string(), AttributeValue::S(webhook.id.clone()));
+    tracing::debug!(merchant_id = %webhook.merchant_id, webhook_id = %webhook.id, "Adding webhook");
+    let put = Put::builder()
+        .table_name(&table.name)
+        .set_item(Some(item))
+        .condition_expression("attribute_not_exists(#partition_key)")
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .build()?;
+    let items = vec![
+        TransactWriteItem::builder().put(put).build(),
+        audit_entry_put(
+            table,
+            &webhook_audit_entry(actor, "registerWebhook", webhook)
+                .with_after(&webhook.audit_image()),
+        )?,
+    ];
+
+    let result = table
+        .observe(
+            "add_webhook",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .set_transact_items(Some(items))
+                .send(),
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if first_condition_failed(&err) => bail!(AppError::conflict(format!(
+            "Failed to add webhook: {} already exists",
+            webhook.id
+        ))),
+        Err(err) => Err(err).sdk_context("Failed to add webhook"),
+    }
+}
+
+/// Every webhook a merchant has registered.
+pub async fn get_webhooks(table: &Table, merchant_id: &str) -> Result<Vec<Webhook>, Error> {
+    let items_resp = table
+        .observe(
+            "get_webhooks",
+            None,
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key_condition_expression(
+                    "#partition_key = :merchant_id AND begins_with(#sort_key, :webhook_prefix)",
+                )
+                .expression_attribute_names("#partition_key", PARTITION_KEY)
+                .expression_attribute_names("#sort_key", SORT_KEY)
+                .expression_attribute_values(
+                    ":merchant_id",
+                    AttributeValue::S(merchant_id.to_string()),
+                )
+                .expression_attribute_values(
+                    ":webhook_prefix",
+                    AttributeValue::S(format!("{WEBHOOK_PREFIX}#")),
+                )
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get webhooks")?;
+    Ok(from_items(items_resp.items.unwrap_or_default())?)
+}
+
+pub async fn get_webhook(
+    table: &Table,
+    merchant_id: &str,
+    webhook_id: &str,
+) -> Result<Option<Webhook>, Error> {
+    let item_resp = table
+        .observe(
+            "get_webhook",
+            None,
+            table
+                .client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key(PARTITION_KEY, AttributeValue::S(merchant_id.to_string()))
+                .key(SORT_KEY, AttributeValue::S(webhook_id.to_string()))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get webhook")?;
+    Ok(item_resp.item.map(from_item).transpose()?)
+}
+
+/// Removes a webhook along with writing its audit entry; its pending deliveries fail on their
+/// next attempt.
+pub async fn delete_webhook(table: &Table, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+    let delete = Delete::builder()
+        .table_name(&table.name)
+        .key(
+            PARTITION_KEY,
+            AttributeValue::S(webhook.merchant_id.clone()),
+        )
+        .key(SORT_KEY, AttributeValue::S(webhook.id.clone()))
+        .condition_expression("attribute_exists(#partition_key)")
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .build()?;
+    let items = vec![
+        TransactWriteItem::builder().delete(delete).build(),
+        audit_entry_put(
+            table,
+            &webhook_audit_entry(actor, "deleteWebhook", webhook)
+                .with_before(&webhook.audit_image()),
+        )?,
+    ];
+
+    let result = table
+        .observe(
+            "delete_webhook",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .set_transact_items(Some(items))
+                .send(),
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if first_condition_failed(&err) => {
+            bail!(AppError::not_found("Webhook not found"))
+        }
+        Err(err) => Err(err).sdk_context("Failed to delete webhook"),
+    }
+}
+
+/// Outbox puts delivering one event to the matching webhooks of each merchant, to be written in
+/// the same transaction as the change that caused it.
+async fn webhook_outbox<T: Serialize>(
+    table: &Table,
+    merchant_ids: &[&str],
+    event_type: WebhookEventType,
+    data: &T,
+) -> Result<Vec<TransactWriteItem>, Error> {
+    let mut webhooks = Vec::new();
+    for merchant_id in merchant_ids.iter().collect::<HashSet<_>>() {
+        webhooks.extend(get_webhooks(table, merchant_id).await?);
+    }
+    WebhookDelivery::for_event(&webhooks, event_type, data)?
+        .iter()
+        .map(|delivery| {
+            let put = Put::builder()
+                .table_name(&table.name)
+                .set_item(Some(webhook_delivery_item(delivery)?))
+                .build()?;
+            Ok(TransactWriteItem::builder().put(put).build())
+        })
+        .collect()
+}
+
+/// Deliveries are stored under their webhook. While pending they are also in the sparse outbox
+/// partition of `gsi1`, sorted by when they are next due.
+fn webhook_delivery_item(
+    delivery: &WebhookDelivery,
+) -> Result<HashMap<String, AttributeValue>, Error> {
+    let mut item: HashMap<String, AttributeValue> = to_item(delivery)?;
+    item.insert(
+        PARTITION_KEY.to_string(),
+        AttributeValue::S(delivery.webhook_id.clone()),
+    );
+    item.insert(SORT_KEY.to_string(), AttributeValue::S(delivery.id.clone()));
+    if let Some(next_attempt_at) = &delivery.next_attempt_at {
+        item.insert(
+            GSI1_PARTITION_KEY.to_string(),
+            AttributeValue::S(OUTBOX_PARTITION.to_string()),
+        );
+        item.insert(
+            GSI1_SORT_KEY.to_string(),
+            AttributeValue::S(next_attempt_at.clone()),
+        );
+    }
+    Ok(item)
+}
+
+/// Pending deliveries due at or before `now`, oldest first.
+pub async fn get_due_webhook_deliveries(
+    table: &Table,
+    now: &str,
+    limit: i32,
+) -> Result<Vec<WebhookDelivery>, Error> {
+    let items_resp = table
+        .observe(
+            "get_due_webhook_deliveries",
+            Some(GSI1_INDEX),
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .index_name(GSI1_INDEX)
+                .limit(limit)
+                .key_condition_expression("#partition_key = :outbox AND #sort_key <= :now")
+                .expression_attribute_names("#partition_key", GSI1_PARTITION_KEY)
+                .expression_attribute_names("#sort_key", GSI1_SORT_KEY)
+                .expression_attribute_values(
+                    ":outbox",
+                    AttributeValue::S(OUTBOX_PARTITION.to_string()),
+                )
+                .expression_attribute_values(":now", AttributeValue::S(now.to_string()))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get due webhook deliveries")?;
+    Ok(from_items(items_resp.items.unwrap_or_default())?)
+}
+
+/// Pushes a due delivery back to `lease_until` so no other worker picks it up meanwhile. Returns
+/// false when another worker got there first.
+pub async fn claim_webhook_delivery(
+    table: &Table,
+    delivery: &WebhookDelivery,
+    lease_until: &str,
+) -> Result<bool, Error> {
+    let Some(next_attempt_at) = &delivery.next_attempt_at else {
+        return Ok(false);
+    };
+    let result = table
+        .observe(
+            "claim_webhook_delivery",
+            None,
+            table
+                .client
+                .update_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .key(
+                    PARTITION_KEY,
+                    AttributeValue::S(delivery.webhook_id.clone()),
+                )
+                .key(SORT_KEY, AttributeValue::S(delivery.id.clone()))
+                .update_expression("SET #gsi1_sk = :lease_until, #next_attempt_at = :lease_until")
+                .condition_expression("#gsi1_sk = :next_attempt_at")
+                .expression_attribute_names("#gsi1_sk", GSI1_SORT_KEY)
+                .expression_attribute_names("#next_attempt_at", "next_attempt_at")
+                .expression_attribute_values(
+                    ":lease_until",
+                    AttributeValue::S(lease_until.to_string()),
+                )
+                .expression_attribute_values(
+                    ":next_attempt_at",
+                    AttributeValue::S(next_attempt_at.clone()),
+                )
+                .send(),
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) if err.code() == Some("ConditionalCheckFailedException") => Ok(false),
+        Err(err) => Err(err).sdk_context("Failed to claim webhook delivery"),
+    }
+}
+
+/// Appends an attempt to a delivery. Finished deliveries leave the outbox and expire after
+/// [`DELIVERY_RETENTION_DAYS`].
+pub async fn record_webhook_delivery_attempt(
+    table: &Table,
+    delivery: &WebhookDelivery,
+    attempt: &DeliveryAttempt,
+    outcome: &DeliveryOutcome,
+) -> Result<(), Error> {
+    let request = table
+        .client
+        .update_item()
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .table_name(&table.name)
+        .key(
+            PARTITION_KEY,
+            AttributeValue::S(delivery.webhook_id.clone()),
+        )
+        .key(SORT_KEY, AttributeValue::S(delivery.id.clone()))
+        .expression_attribute_names("#attempts", "attempts")
+        .expression_attribute_names("#next_attempt_at", "next_attempt_at")
+        .expression_attribute_values(
+            ":attempt",
+            AttributeValue::L(vec![to_attribute_value(attempt)?]),
+        );
+    let request = match outcome {
+        DeliveryOutcome::Retry { next_attempt_at } => request
+            .update_expression(
+                "SET #attempts = list_append(#attempts, :attempt), \
+                 #next_attempt_at = :next_attempt_at, #gsi1_sk = :next_attempt_at",
+            )
+            .expression_attribute_names("#gsi1_sk", GSI1_SORT_KEY)
+            .expression_attribute_values(
+                ":next_attempt_at",
+                AttributeValue::S(next_attempt_at.clone()),
+            ),
+        DeliveryOutcome::Delivered | DeliveryOutcome::Failed => {
+            let status = match outcome {
+                DeliveryOutcome::Delivered => DeliveryStatus::Delivered,
+                _ => DeliveryStatus::Failed,
+            };
+            let expires_at = Utc::now() + chrono::Duration::days(DELIVERY_RETENTION_DAYS);
+            request
+                .update_expression(
+                    "SET #attempts = list_append(#attempts, :attempt), #status = :status, \
+                     #expires_at = :expires_at \
+                     REMOVE #next_attempt_at, #gsi1_pk, #gsi1_sk",
+                )
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_names("#expires_at", TTL_ATTRIBUTE)
+                .expression_attribute_names("#gsi1_pk", GSI1_PARTITION_KEY)
+                .expression_attribute_names("#gsi1_sk", GSI1_SORT_KEY)
+                .expression_attribute_values(":status", AttributeValue::S(status.to_string()))
+                .expression_attribute_values(
+                    ":expires_at",
+                    AttributeValue::N(expires_at.timestamp().to_string()),
+                )
+        }
+    };
+    table
+        .observe("record_webhook_delivery_attempt", None, request.send())
+        .await
+        .sdk_context("Failed to record webhook delivery attempt")?;
+    Ok(())
+}
+
+/// Deliveries of one webhook, newest first, starting after the delivery id `after`.
+pub async fn get_webhook_deliveries(
+    table: &Table,
+    webhook_id: &str,
+    page: PageRequest<String>,
+) -> Result<(Vec<WebhookDelivery>, bool), Error> {
+    let exclusive_start_key = page
+        .start()
+        .map(|start| exclusive_start_key(webhook_id, start));
+    let items_resp = table
+        .observe(
+            "get_webhook_deliveries",
+            None,
+            table
+                .client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .limit(page.limit as i32)
+                .set_exclusive_start_key(exclusive_start_key)
+                .key_condition_expression(
+                    "#partition_key = :webhook_id AND begins_with(#sort_key, :delivery_prefix)",
+                )
+                .expression_attribute_names("#partition_key", PARTITION_KEY)
+                .expression_attribute_names("#sort_key", SORT_KEY)
+                .expression_attribute_values(
+                    ":webhook_id",
+                    AttributeValue::S(webhook_id.to_string()),
+                )
+                .expression_attribute_values(
+                    ":delivery_prefix",
+                    AttributeValue::S(format!("{DELIVERY_PREFIX}#")),
+                )
+                .scan_index_forward(page.backward)
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to get webhook deliveries")?;
+    let mut deliveries: Vec<WebhookDelivery> = from_items(items_resp.items.unwrap_or_default())?;
+    if page.backward {
+        deliveries.reverse();
+    }
+    Ok((deliveries, items_resp.last_evaluated_key.is_some()))
+}
+
+/// Records a payout once, queueing `PayoutCreated` webhooks for its settlement merchant in the
+/// same transaction.
+pub async fn add_payout(table: &Table, payout: &Payout, actor: &Actor) -> Result<(), Error> {
+    let mut item: HashMap<String, AttributeValue> = to_item(payout)?;
+    item.insert(
+        PARTITION_KEY.to_string(),
+        AttributeValue::S(payout.merchant_id.clone()),
+    );
+    item.insert(SORT_KEY.to_string(), AttributeValue::S(payout.id.clone()));
+    let put = Put::builder()
+        .table_name(&table.name)
+        .set_item(Some(item))
+        .condition_expression("attribute_not_exists(#partition_key)")
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .build()?;
+    let mut items = vec![TransactWriteItem::builder().put(put).build()];
+    items.extend(
+        webhook_outbox(
+            table,
+            &[payout.merchant_id.as_str()],
+            WebhookEventType::PayoutCreated,
+            payout,
+        )
+        .await?,
+    );
+    items.push(audit_entry_put(
+        table,
+        &AuditEntry::new(
+            actor,
+            "createPayout",
+            vec![payout.merchant_id.clone(), payout.id.clone()],
+        )
+        .with_after(payout),
+    )?);
+    tracing::debug!(merchant_id = %payout.merchant_id, payout_id = %payout.id, "Adding payout");
+
+    let result = table
+        .observe(
+            "add_payout",
+            None,
+            table
+                .client
+                .transact_write_items()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .set_transact_items(Some(items))
+                .send(),
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if first_condition_failed(&err) => bail!(AppError::conflict(format!(
+            "Payout {} already exists",
+            payout.id
+        ))),
+        Err(err) => Err(err).sdk_context("Failed to add payout"),
+    }
+}
+
+/// The payouts made to a settlement merchant inside the sort key range, newest first.
+pub async fn get_payouts(
+    table: &Table,
+    settlement_merchant_id: &str,
+    earlier_payout: &str,
+    later_payout: &str,
+) -> Result<Vec<Payout>, Error> {
+    let mut payouts = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let items_resp = table
+            .observe(
+                "get_payouts",
+                None,
+                table
+                    .client
+                    .query()
+                    .table_name(&table.name)
+                    .set_exclusive_start_key(exclusive_start_key)
+                    .key_condition_expression(
+                        "#partition_key = :merchant_id AND #sort_key BETWEEN :earlier_payout AND :later_payout",
+                    )
+                    .expression_attribute_names("#partition_key", PARTITION_KEY)
+                    .expression_attribute_names("#sort_key", SORT_KEY)
+                    .expression_attribute_values(
+                        ":merchant_id",
+                        AttributeValue::S(settlement_merchant_id.to_string()),
+                    )
+                    .expression_attribute_values(
+                        ":earlier_payout",
+                        AttributeValue::S(earlier_payout.to_string()),
+                    )
+                    .expression_attribute_values(
+                        ":later_payout",
+                        AttributeValue::S(later_payout.to_string()),
+                    )
+                    .scan_index_forward(false)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send(),
+            )
+            .await
+            .sdk_context("Failed to get payouts")?;
+        let page: Vec<Payout> = from_items(items_resp.items.unwrap_or_default())?;
+        payouts.extend(page);
+
+        exclusive_start_key = items_resp.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            return Ok(payouts);
+        }
+    }
+}
+
+/// ARN of the table's current stream.
+pub async fn get_latest_stream_arn(table: &Table) -> Result<String, Error> {
+    let resp = table
+        .observe(
+            "get_latest_stream_arn",
+            None,
+            table.client.describe_table().table_name(&table.name).send(),
+        )
+        .await
+        .sdk_context("Failed to describe table")?;
+    resp.table()
+        .and_then(|description| description.latest_stream_arn())
+        .map(str::to_string)
+        .context("Table stream is not enabled, run migrate")
+}
+
+/// Shard positions of a stream consumer, by shard id.
+pub async fn get_stream_checkpoints(
+    table: &Table,
+    consumer: &str,
+) -> Result<HashMap<String, StreamCheckpoint>, Error> {
+    let mut checkpoints = HashMap::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let items_resp = table
+            .observe(
+                "get_stream_checkpoints",
+                None,
+                table
+                    .client
+                    .query()
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .table_name(&table.name)
+                    .key_condition_expression(
+                        "#partition_key = :consumer AND begins_with(#sort_key, :shard_prefix)",
+                    )
+                    .expression_attribute_names("#partition_key", PARTITION_KEY)
+                    .expression_attribute_names("#sort_key", SORT_KEY)
+                    .expression_attribute_values(
+                        ":consumer",
+                        AttributeValue::S(format!("{STREAM_PREFIX}#{consumer}")),
+                    )
+                    .expression_attribute_values(
+                        ":shard_prefix",
+                        AttributeValue::S(format!("{SHARD_PREFIX}#")),
+                    )
+                    .set_exclusive_start_key(exclusive_start_key)
+                    .send(),
+            )
+            .await
+            .sdk_context("Failed to get stream checkpoints")?;
+        let page: Vec<StreamCheckpoint> = from_items(items_resp.items.unwrap_or_default())?;
+        checkpoints.extend(
+            page.into_iter()
+                .map(|checkpoint| (checkpoint.shard_id.clone(), checkpoint)),
+        );
+        exclusive_start_key = items_resp.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            return Ok(checkpoints);
+        }
+    }
+}
+
+/// Saves a consumer's position in a shard. Checkpoints outlive the stream's 24 hour retention
+/// by a day and then expire with their shard.
+pub async fn put_stream_checkpoint(
+    table: &Table,
+    consumer: &str,
+    checkpoint: &StreamCheckpoint,
+) -> Result<(), Error> {
+    let mut item = to_item(checkpoint)?;
+    item.insert(
+        PARTITION_KEY.to_string(),
+        AttributeValue::S(format!("{STREAM_PREFIX}#{consumer}")),
+    );
+    item.insert(
+        SORT_KEY.to_string(),
+        AttributeValue::S(format!("{SHARD_PREFIX}#{}", checkpoint.shard_id)),
+    );
+    item.insert(
+        TTL_ATTRIBUTE.to_string(),
+        AttributeValue::N(
+            (Utc::now() + chrono::Duration::days(STREAM_CHECKPOINT_RETENTION_DAYS))
+                .timestamp()
+                .to_string(),
+        ),
+    );
+    table
+        .observe(
+            "put_stream_checkpoint",
+            None,
+            table
+                .client
+                .put_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&table.name)
+                .set_item(Some(item))
+                .send(),
+        )
+        .await
+        .sdk_context("Failed to save stream checkpoint")?;
+    Ok(())
+}
+
+/// Whether a transaction was cancelled because the condition of its first item failed.
+fn first_condition_failed<R>(err: &SdkError<TransactWriteItemsError, R>) -> bool {
+    cancellation_reasons(err).first() == Some(&Some("ConditionalCheckFailed"))
+}
+
+/// Why each item of a cancelled transaction failed, in item order; empty for other errors.
+fn cancellation_reasons<R>(err: &SdkError<TransactWriteItemsError, R>) -> Vec<Option<&str>> {
+    match err.as_service_error() {
+        Some(TransactWriteItemsError::TransactionCanceledException(canceled)) => canceled
+            .cancellation_reasons()
+            .iter()
+            .map(|reason| reason.code())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Audit entry for a hierarchy change, with the merchant re-parented as the after image.
+pub fn hierarchy_audit_entry(
+    actor: &Actor,
+    operation: &str,
+    merchant: &Merchant,
+    new_parent_id: Option<&str>,
+) -> AuditEntry {
+    let mut after = merchant.clone();
+    after.parent_merchant_id = new_parent_id.map(str::to_string);
+    let mut target_ids = vec![merchant.id.clone()];
+    target_ids.extend(merchant.parent_merchant_id.clone());
+    target_ids.extend(new_parent_id.map(str::to_string));
+    AuditEntry::new(actor, operation, target_ids)
+        .with_before(merchant)
+        .with_after(&after)
+}
+
+/// Audit entry for a transaction change, with card numbers masked in both images.
+pub fn transaction_audit_entry(
+    actor: &Actor,
+    operation: &str,
+    before: Option<&Transaction>,
+    after: &Transaction,
+) -> AuditEntry {
+    let masked = |transaction: &Transaction| Transaction {
+        pan: mask_pan(&transaction.pan),
+        ..transaction.clone()
+    };
+    let entry = AuditEntry::new(
+        actor,
+        operation,
+        vec![after.merchant_id.clone(), after.id.clone()],
+    );
+    match before {
+        Some(before) => entry.with_before(&masked(before)),
+        None => entry,
+    }
+    .with_after(&masked(after))
+}
+
+/// Audit entry naming a webhook, for the caller to add its image without the secret to.
+pub fn webhook_audit_entry(actor: &Actor, operation: &str, webhook: &Webhook) -> AuditEntry {
+    AuditEntry::new(
+        actor,
+        operation,
+        vec![webhook.merchant_id.clone(), webhook.id.clone()],
+    )
+}
+
+/// Checks the Group > Chain > Outlet ordering and that `parent` is not `child` or one of its
+/// descendants.
+async fn validate_placement(
+    table: &Table,
+    parent: &Merchant,
+    child: &Merchant,
+) -> Result<(), Error> {
+    if parent.id == child.id {
+        bail!(AppError::validation(format!(
+            "Merchant {} cannot be its own sub merchant",
+            child.id
+        )));
+    }
+    if !parent.merchant_level.can_contain(child.merchant_level) {
+        bail!(AppError::validation(format!(
+            "A {} merchant cannot contain a {} merchant",
+            parent.merchant_level, child.merchant_level
+        )));
+    }
+
+    let mut ancestor_id = parent.parent_merchant_id.clone();
+    for _ in 0..MAX_HIERARCHY_DEPTH {
+        let Some(id) = ancestor_id else {
+            return Ok(());
+        };
+        if id == child.id {
+            bail!(AppError::validation(format!(
+                "Attaching {} under {} would create a cycle",
+                child.id, parent.id
+            )));
+        }
+        ancestor_id = get_merchant(table, id).await?.parent_merchant_id;
+    }
+    bail!(
+        "Merchant hierarchy above {} is deeper than expected",
+        parent.id
+    )
+}
+
+fn merchant_key(merchant_id: &str) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            PARTITION_KEY.to_string(),
+            AttributeValue::S(merchant_id.to_string()),
+        ),
+        (
+            SORT_KEY.to_string(),
+            AttributeValue::S(merchant_id.to_string()),
+        ),
+    ])
+}
+
+fn append_sub_merchant(
+    table: &Table,
+    parent_id: &str,
+    child_id: &str,
+) -> Result<TransactWriteItem, Error> {
+    let update = Update::builder()
+        .table_name(&table.name)
+        .set_key(Some(merchant_key(parent_id)))
+        .update_expression(
+            "SET #sub_merchants = list_append(if_not_exists(#sub_merchants, :empty_list), :child_list)",
+        )
+        .condition_expression(
+            "attribute_exists(#partition_key) AND NOT contains(#sub_merchants, :child_id)",
+        )
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .expression_attribute_names("#sub_merchants", "sub_merchants")
+        .expression_attribute_values(":empty_list", AttributeValue::L(Vec::new()))
+        .expression_attribute_values(
+            ":child_list",
+            AttributeValue::L(vec![AttributeValue::S(child_id.to_string())]),
+        )
+        .expression_attribute_values(":child_id", AttributeValue::S(child_id.to_string()))
+        .build()?;
+    Ok(TransactWriteItem::builder().update(update).build())
+}
+
+fn remove_sub_merchant(
+    table: &Table,
+    parent: &Merchant,
+    child_id: &str,
+) -> Result<TransactWriteItem, Error> {
+    let Some(index) = parent.sub_merchants.iter().position(|id| id == child_id) else {
+        bail!(AppError::conflict(format!(
+            "Merchant {child_id} is not listed as a sub merchant of {}",
+            parent.id
+        )));
+    };
+    // The condition pins the index so a concurrent change to the list cancels the transaction.
+    let update = Update::builder()
+        .table_name(&table.name)
+        .set_key(Some(merchant_key(&parent.id)))
+        .update_expression(format!("REMOVE #sub_merchants[{index}]"))
+        .condition_expression(format!("#sub_merchants[{index}] = :child_id"))
+        .expression_attribute_names("#sub_merchants", "sub_merchants")
+        .expression_attribute_values(":child_id", AttributeValue::S(child_id.to_string()))
+        .build()?;
+    Ok(TransactWriteItem::builder().update(update).build())
+}
+
+fn set_parent_merchant(
+    table: &Table,
+    merchant_id: &str,
+    expected_parent_id: Option<&str>,
+    new_parent_id: Option<&str>,
+) -> Result<TransactWriteItem, Error> {
+    let update = Update::builder()
+        .table_name(&table.name)
+        .set_key(Some(merchant_key(merchant_id)))
+        .expression_attribute_names("#parent_merchant_id", "parent_merchant_id");
+    let update = match new_parent_id {
+        Some(new_parent_id) => update
+            .update_expression("SET #parent_merchant_id = :new_parent_id")
+            .expression_attribute_values(
+                ":new_parent_id",
+                AttributeValue::S(new_parent_id.to_string()),
+            ),
+        None => update.update_expression("REMOVE #parent_merchant_id"),
+    };
+    let update = match expected_parent_id {
+        Some(expected_parent_id) => update
+            .condition_expression("#parent_merchant_id = :expected_parent_id")
+            .expression_attribute_values(
+                ":expected_parent_id",
+                AttributeValue::S(expected_parent_id.to_string()),
+            ),
+        None => update
+            .condition_expression(
+                "attribute_exists(#partition_key) AND attribute_not_exists(#parent_merchant_id)",
+            )
+            .expression_attribute_names("#partition_key", PARTITION_KEY),
+    };
+    Ok(TransactWriteItem::builder().update(update.build()?).build())
+}
+
+fn record_hierarchy_change(
+    table: &Table,
+    merchant_id: &str,
+    action: HierarchyAction,
+    previous_parent_id: Option<&str>,
+    new_parent_id: Option<&str>,
+    role: Option<Role>,
+) -> Result<TransactWriteItem, Error> {
+    let changed_at = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+    let mut item = merchant_key(merchant_id);
+    item.insert(
+        SORT_KEY.to_string(),
+        AttributeValue::S(format!("{HIERARCHY_PREFIX}#{changed_at}")),
+    );
+    item.insert(
+        "merchant_id".to_string(),
+        AttributeValue::S(merchant_id.to_string()),
+    );
+    item.insert("action".to_string(), AttributeValue::S(action.to_string()));
+    if let Some(previous_parent_id) = previous_parent_id {
+        item.insert(
+            "previous_parent_id".to_string(),
+            AttributeValue::S(previous_parent_id.to_string()),
+        );
+    }
+    if let Some(new_parent_id) = new_parent_id {
+        item.insert(
+            "new_parent_id".to_string(),
+            AttributeValue::S(new_parent_id.to_string()),
+        );
+    }
+    item.insert("changed_at".to_string(), AttributeValue::S(changed_at));
+    if let Some(role) = role {
+        item.insert(
+            "changed_by_role".to_string(),
+            AttributeValue::S(role.to_string()),
+        );
+    }
+
+    let put = Put::builder()
+        .table_name(&table.name)
+        .set_item(Some(item))
+        .build()?;
+    Ok(TransactWriteItem::builder().put(put).build())
+}
+
+/// DynamoDB responses that report the capacity their call consumed.
+/// Capacity a call consumed, recorded by [`Table::observe`].
+pub trait ConsumedCapacityUnits {
+    const CAPACITY_KIND: CapacityKind;
+
+    fn capacity_units(&self) -> Option<f64>;
+}
+
+impl ConsumedCapacityUnits for QueryOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Read;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for GetItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Read;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for PutItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Write;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for DeleteItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Write;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for UpdateItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Write;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for TransactWriteItemsOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Write;
+
+    fn capacity_units(&self) -> Option<f64> {
+        let capacity = self.consumed_capacity();
+        (!capacity.is_empty()).then(|| {
+            capacity
+                .iter()
+                .filter_map(|capacity| capacity.capacity_units())
+                .sum()
+        })
+    }
+}
+
+impl ConsumedCapacityUnits for ScanOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Read;
+
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacityUnits for BatchWriteItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Write;
+
+    fn capacity_units(&self) -> Option<f64> {
+        let capacity = self.consumed_capacity();
+        (!capacity.is_empty()).then(|| {
+            capacity
+                .iter()
+                .filter_map(|capacity| capacity.capacity_units())
+                .sum()
+        })
+    }
+}
+
+impl ConsumedCapacityUnits for BatchGetItemOutput {
+    const CAPACITY_KIND: CapacityKind = CapacityKind::Read;
+
+    fn capacity_units(&self) -> Option<f64> {
+        let capacity = self.consumed_capacity();
+        (!capacity.is_empty()).then(|| {
+            capacity
+                .iter()
+                .filter_map(|capacity| capacity.capacity_units())
+                .sum()
+        })
+    }
+}
+
+/// Control plane and stream calls, which consume no table capacity.
+macro_rules! no_consumed_capacity {
+    ($($output:ty),* $(,)?) => {
+        $(
+            impl ConsumedCapacityUnits for $output {
+                const CAPACITY_KIND: CapacityKind = CapacityKind::Read;
+
+                fn capacity_units(&self) -> Option<f64> {
+                    None
+                }
+            }
+        )*
+    };
+}
+
+no_consumed_capacity!(
+    DescribeTableOutput,
+    CreateTableOutput,
+    UpdateTableOutput,
+    DescribeTimeToLiveOutput,
+    UpdateTimeToLiveOutput,
+    DescribeStreamOutput,
+    GetShardIteratorOutput,
+    GetRecordsOutput,
+);
+
+impl Table {
+    /// Runs one DynamoDB call inside a `dynamodb` span that records the operation, table, index,
+    /// latency and consumed capacity, and counts it in the Prometheus metrics. The call gets the
+    /// operation's deadline and passes the circuit breaker.
+    pub async fn observe<T, E>(
+        &self,
+        operation: &'static str,
+        index: Option<&'static str>,
+        call: impl Future<Output = Result<T, SdkError<E>>>,
+    ) -> Result<T, SdkError<E>>
+    where
+        T: ConsumedCapacityUnits,
+        E: std::error::Error + ProvideErrorMetadata + 'static,
+    {
+        let span = tracing::info_span!(
+            "dynamodb",
+            operation,
+            table = self.name.as_str(),
+            index,
+            consumed_capacity = Empty,
+            latency_ms = Empty,
+        );
+        if let Err(err) = self.policy.breaker.admit() {
+            metrics().observe_dynamodb_rejection(operation);
+            span.in_scope(|| tracing::warn!("dynamodb call refused, circuit open"));
+            return Err(SdkError::construction_failure(err));
+        }
+        let deadline = self.policy.timeout(operation);
+        let started = Instant::now();
+        let result = match timeout(deadline, call.instrument(span.clone())).await {
+            Ok(result) => result,
+            Err(_) => Err(SdkError::timeout_error(OperationTimedOut(deadline))),
+        };
+        let elapsed = started.elapsed();
+        // Throttling and rejected requests show the table is answering; only failures worth
+        // retrying for lack of an answer count towards opening the circuit.
+        self.policy
+            .breaker
+            .record(result.as_ref().is_err_and(|err| {
+                matches!(
+                    AppError::from_sdk(err, ""),
+                    AppError::Upstream {
+                        transient: true,
+                        ..
+                    }
+                )
+            }));
+        span.record("latency_ms", elapsed.as_millis() as u64);
+        span.in_scope(|| match &result {
+            Ok(output) => {
+                let units = output.capacity_units();
+                if let Some(units) = units {
+                    span.record("consumed_capacity", units);
+                }
+                metrics().observe_dynamodb(
+                    operation,
+                    elapsed,
+                    units.map(|units| (T::CAPACITY_KIND, units)),
+                );
+                tracing::debug!("dynamodb call completed");
+            }
+            Err(err) => {
+                let throttled = err
+                    .code()
+                    .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code));
+                metrics().observe_dynamodb(operation, elapsed, None);
+                metrics().observe_dynamodb_error(operation, throttled);
+                tracing::warn!(error = %DisplayErrorContext(err), throttled, "dynamodb call failed");
+            }
+        });
+        result
+    }
+}
+
+/// Renames the table's `pk` and `sk` attributes to the model's field names.
+pub fn replace_key_names<V>(items: &mut [HashMap<String, V>], partition_key: &str, sort_key: &str) {
+    for item in items.iter_mut() {
+        if let Some(value) = item.remove(PARTITION_KEY) {
+            item.insert(partition_key.to_string(), value);
+        }
         if let Some(value) = item.remove(SORT_KEY) {
             item.insert(sort_key.to_string(), value);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BATCH_WRITE_INITIAL_BACKOFF, full_jitter};
+    use std::time::Duration;
+
+    #[test]
+    fn full_jitter_stays_below_the_doubled_backoff() {
+        for attempt in 1..=8 {
+            let cap = BATCH_WRITE_INITIAL_BACKOFF * 2u32.pow(attempt - 1);
+            let delays: Vec<Duration> = (0..50)
+                .map(|_| full_jitter(BATCH_WRITE_INITIAL_BACKOFF, attempt))
+                .collect();
+            assert!(
+                delays.iter().all(|delay| *delay <= cap),
+                "attempt {attempt}"
+            );
+            assert!(
+                delays.iter().any(|delay| *delay != delays[0]),
+                "attempt {attempt} retried in lockstep"
+            );
+        }
+    }
+}
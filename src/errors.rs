@@ -0,0 +1,403 @@
+//! Domain errors and the GraphQL error codes clients see.
+//!
+//! Errors travel as `anyhow::Error` everywhere. Where the kind of failure is known an
+//! [`AppError`] is raised, or attached as context to a failed SDK call with
+//! [`SdkContext::sdk_context`]. [`ErrorCodes`] then reports the outermost [`AppError`] of every
+//! resolver error as `extensions.code` and `extensions.retryable`; errors of no known kind are
+//! `INTERNAL`.
+
+use crate::resilience::CircuitOpen;
+use async_graphql::{
+    ErrorExtensionValues, Response, ServerError,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute, NextSubscribe},
+};
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use futures::{StreamExt, stream::BoxStream};
+use std::{any::Any, fmt, sync::Arc, time::Duration};
+
+/// Error codes DynamoDB returns when a call is rejected for exceeding throughput.
+pub const THROTTLING_ERROR_CODES: [&str; 3] = [
+    "ProvisionedThroughputExceededException",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+];
+
+/// Error codes of DynamoDB failures that may succeed when the call is repeated.
+const TRANSIENT_ERROR_CODES: [&str; 4] = [
+    "InternalServerError",
+    "ServiceUnavailable",
+    "TransactionInProgressException",
+    "TransactionConflictException",
+];
+
+/// A failure of a kind clients handle differently, reported as `extensions.code`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppError {
+    /// The merchant, transaction or webhook asked for does not exist.
+    NotFound(String),
+    /// The request's role may not run the operation.
+    Forbidden,
+    /// The input was rejected; repeating it fails the same way.
+    Validation(String),
+    /// The stored state does not allow the change, e.g. an id already taken or a concurrent update.
+    Conflict(String),
+    /// DynamoDB rejected the call for exceeding throughput; retry after a backoff.
+    Throttled(String),
+    /// DynamoDB failed. `transient` failures, e.g. timeouts, may succeed when retried.
+    Upstream { message: String, transient: bool },
+    /// DynamoDB is failing and calls are refused until it recovers; retry later.
+    Unavailable(String),
+    /// The client sent more requests than its rate limit allows; retry after the given time.
+    RateLimited(Duration),
+    /// The client already holds the most subscriptions it may; one must end first.
+    TooManySubscriptions(usize),
+    /// The hash of a persisted query is unknown; send it again with the query.
+    PersistedQueryNotFound,
+    /// Only operations on the allow-list may run.
+    OperationNotAllowed,
+}
+
+impl AppError {
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound(message.into())
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::Validation(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::Conflict(message.into())
+    }
+
+    /// Classifies a failed SDK call. Failed conditions are conflicts and rejected parameters
+    /// validation errors. Calls refused by the open circuit breaker are unavailable. Timeouts,
+    /// dispatch failures, unreadable responses and DynamoDB's own transient errors are worth
+    /// retrying; any other failure, like a missing table, is permanent. A cancelled transaction
+    /// is classified by the reasons its items give.
+    pub fn from_sdk<E, R>(err: &SdkError<E, R>, message: impl Into<String>) -> Self
+    where
+        E: std::error::Error + ProvideErrorMetadata + 'static,
+        R: fmt::Debug,
+    {
+        let message = message.into();
+        let transient = match err {
+            SdkError::ConstructionFailure(_)
+                if std::error::Error::source(err)
+                    .is_some_and(|source| source.is::<CircuitOpen>()) =>
+            {
+                return Self::Unavailable(message);
+            }
+            SdkError::TimeoutError(_)
+            | SdkError::DispatchFailure(_)
+            | SdkError::ResponseError(_) => true,
+            SdkError::ServiceError(service) => {
+                let code = service.err().code();
+                match code {
+                    Some(code) if THROTTLING_ERROR_CODES.contains(&code) => {
+                        return Self::Throttled(message);
+                    }
+                    // A condition guarding the item failed: it changed since it was read.
+                    Some("ConditionalCheckFailedException") => return Self::Conflict(message),
+                    Some("TransactionCanceledException") => {
+                        return Self::from_cancellation(service.err(), message);
+                    }
+                    // DynamoDB rejected a parameter, e.g. a page size of zero.
+                    Some("ValidationException") => return Self::Validation(message),
+                    code => code.is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code)),
+                }
+            }
+            _ => false,
+        };
+        Self::Upstream { message, transient }
+    }
+
+    fn from_cancellation(err: &dyn Any, message: String) -> Self {
+        let reasons: Vec<&str> = match err.downcast_ref::<TransactWriteItemsError>() {
+            Some(TransactWriteItemsError::TransactionCanceledException(canceled)) => canceled
+                .cancellation_reasons()
+                .iter()
+                .filter_map(|reason| reason.code())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let any = |codes: &[&str]| reasons.iter().any(|reason| codes.contains(reason));
+        if any(&["ConditionalCheckFailed"]) {
+            Self::Conflict(message)
+        } else if any(&["ThrottlingError", "ProvisionedThroughputExceeded"]) {
+            Self::Throttled(message)
+        } else if any(&["ValidationError"]) {
+            Self::Validation(message)
+        } else {
+            Self::Upstream {
+                message,
+                transient: any(&["TransactionConflict"]),
+            }
+        }
+    }
+
+    /// The `extensions.code` of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "NOT_FOUND",
+            Self::Forbidden => "FORBIDDEN",
+            Self::Validation(_) => "VALIDATION",
+            Self::Conflict(_) => "CONFLICT",
+            Self::Throttled(_) => "THROTTLED",
+            Self::Upstream { .. } => "UPSTREAM",
+            Self::Unavailable(_) => "UNAVAILABLE",
+            Self::RateLimited(_) => "RATE_LIMITED",
+            Self::TooManySubscriptions(_) => "TOO_MANY_SUBSCRIPTIONS",
+            Self::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+            Self::OperationNotAllowed => "OPERATION_NOT_ALLOWED",
+        }
+    }
+
+    /// Whether repeating the same request may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Throttled(_)
+                | Self::Unavailable(_)
+                | Self::RateLimited(_)
+                | Self::Upstream {
+                    transient: true,
+                    ..
+                }
+        )
+    }
+
+    /// The outermost `AppError` of the error, raised as the error itself or added as context.
+    pub fn find(err: &anyhow::Error) -> Option<&Self> {
+        err.downcast_ref::<Self>()
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forbidden => f.write_str("Forbidden"),
+            // Apollo clients match this exact message to resend the query.
+            Self::PersistedQueryNotFound => f.write_str("PersistedQueryNotFound"),
+            Self::OperationNotAllowed => f.write_str("Operation is not on the allow-list"),
+            Self::RateLimited(retry_after) => write!(
+                f,
+                "Rate limit exceeded, retry in {}s",
+                retry_after.as_secs()
+            ),
+            Self::TooManySubscriptions(max) => {
+                write!(f, "Too many open subscriptions, at most {max} per client")
+            }
+            Self::NotFound(message)
+            | Self::Validation(message)
+            | Self::Conflict(message)
+            | Self::Throttled(message)
+            | Self::Unavailable(message)
+            | Self::Upstream { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// For errors raised outside resolvers, e.g. while validating a query, which get their codes here.
+impl From<AppError> for ServerError {
+    fn from(app_error: AppError) -> Self {
+        let mut error = ServerError::new(app_error.to_string(), None);
+        error.source = Some(Arc::new(app_error));
+        add_code(&mut error);
+        error
+    }
+}
+
+/// Like `anyhow::Context::context` for SDK calls, classifying the failure as an [`AppError`].
+pub trait SdkContext<T> {
+    fn sdk_context(self, message: impl Into<String>) -> Result<T, anyhow::Error>;
+}
+
+impl<T, E, R> SdkContext<T> for Result<T, SdkError<E, R>>
+where
+    E: std::error::Error + ProvideErrorMetadata + Send + Sync + 'static,
+    R: fmt::Debug + Send + Sync + 'static,
+{
+    fn sdk_context(self, message: impl Into<String>) -> Result<T, anyhow::Error> {
+        self.map_err(|err| {
+            let app_error = AppError::from_sdk(&err, message);
+            anyhow::Error::new(err).context(app_error)
+        })
+    }
+}
+
+/// Adds `code` and `retryable` extensions to the errors of every resolver.
+pub struct ErrorCodes;
+
+impl ExtensionFactory for ErrorCodes {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ErrorCodesExtension)
+    }
+}
+
+struct ErrorCodesExtension;
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for ErrorCodesExtension {
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        with_codes(next.run(ctx, operation_name).await)
+    }
+
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        next.run(ctx, stream).map(with_codes).boxed()
+    }
+}
+
+fn with_codes(mut response: Response) -> Response {
+    for error in &mut response.errors {
+        add_code(error);
+    }
+    response
+}
+
+// Errors without a source come from async-graphql itself, e.g. an invalid query, and keep theirs.
+fn add_code(error: &mut ServerError) {
+    let app_error = match (error.source::<AppError>(), error.source::<anyhow::Error>()) {
+        (Some(app_error), _) => Some(app_error),
+        (None, Some(err)) => AppError::find(err),
+        (None, None) if error.source.is_none() => return,
+        (None, None) => None,
+    };
+    let (code, retryable) = app_error.map_or(("INTERNAL", false), |app_error| {
+        (app_error.code(), app_error.retryable())
+    });
+    let extensions = error
+        .extensions
+        .get_or_insert_with(ErrorExtensionValues::default);
+    extensions.set("code", code);
+    extensions.set("retryable", retryable);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AppError;
+    use crate::resilience::{CircuitOpen, OperationTimedOut};
+    use aws_sdk_dynamodb::error::{ErrorMetadata, SdkError};
+    use aws_sdk_dynamodb::operation::get_item::GetItemError;
+    use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+    use aws_sdk_dynamodb::types::{CancellationReason, error::TransactionCanceledException};
+    use std::time::Duration;
+
+    fn service_error(code: &str) -> SdkError<GetItemError, ()> {
+        SdkError::service_error(
+            GetItemError::generic(ErrorMetadata::builder().code(code).build()),
+            (),
+        )
+    }
+
+    #[test]
+    fn sdk_failures_are_classified_by_whether_a_retry_can_succeed() {
+        let cases = [
+            (service_error("ThrottlingException"), "THROTTLED", true),
+            (
+                service_error("ProvisionedThroughputExceededException"),
+                "THROTTLED",
+                true,
+            ),
+            (service_error("InternalServerError"), "UPSTREAM", true),
+            (
+                service_error("ResourceNotFoundException"),
+                "UPSTREAM",
+                false,
+            ),
+            (
+                service_error("ConditionalCheckFailedException"),
+                "CONFLICT",
+                false,
+            ),
+            (service_error("ValidationException"), "VALIDATION", false),
+            (SdkError::timeout_error("timed out"), "UPSTREAM", true),
+            (
+                SdkError::timeout_error(OperationTimedOut(Duration::from_secs(5))),
+                "UPSTREAM",
+                true,
+            ),
+            (
+                SdkError::construction_failure(CircuitOpen),
+                "UNAVAILABLE",
+                true,
+            ),
+            (
+                SdkError::construction_failure("missing table name"),
+                "UPSTREAM",
+                false,
+            ),
+        ];
+        for (err, code, retryable) in cases {
+            let app_error = AppError::from_sdk(&err, "Failed to get merchant");
+            assert_eq!(app_error.code(), code, "{err:?}");
+            assert_eq!(app_error.retryable(), retryable, "{err:?}");
+            assert_eq!(app_error.to_string(), "Failed to get merchant");
+        }
+    }
+
+    #[test]
+    fn cancelled_transactions_are_classified_by_their_reasons() {
+        let cancelled = |codes: &[&str]| -> SdkError<TransactWriteItemsError, ()> {
+            let exception = TransactionCanceledException::builder()
+                .set_cancellation_reasons(Some(
+                    codes
+                        .iter()
+                        .map(|code| CancellationReason::builder().code(*code).build())
+                        .collect(),
+                ))
+                .meta(
+                    ErrorMetadata::builder()
+                        .code("TransactionCanceledException")
+                        .build(),
+                )
+                .build();
+            SdkError::service_error(
+                TransactWriteItemsError::TransactionCanceledException(exception),
+                (),
+            )
+        };
+        let cases = [
+            (
+                cancelled(&["None", "ConditionalCheckFailed"]),
+                "CONFLICT",
+                false,
+            ),
+            (cancelled(&["ThrottlingError", "None"]), "THROTTLED", true),
+            (cancelled(&["TransactionConflict"]), "UPSTREAM", true),
+            (cancelled(&["ValidationError"]), "VALIDATION", false),
+            (cancelled(&[]), "UPSTREAM", false),
+        ];
+        for (err, code, retryable) in cases {
+            let app_error = AppError::from_sdk(&err, "Failed to add payout");
+            assert_eq!(app_error.code(), code, "{err:?}");
+            assert_eq!(app_error.retryable(), retryable, "{err:?}");
+        }
+    }
+
+    #[test]
+    fn the_outermost_app_error_is_found_through_context() {
+        let err = anyhow::Error::new(AppError::not_found("Merchant not found"))
+            .context("Failed to get merchant MERCHANT#a")
+            .context(AppError::validation("Payout spans several currencies"))
+            .context("Failed to create payout");
+        assert_eq!(
+            AppError::find(&err),
+            Some(&AppError::validation("Payout spans several currencies"))
+        );
+        assert_eq!(AppError::find(&anyhow::anyhow!("unclassified")), None);
+    }
+}
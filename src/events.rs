@@ -0,0 +1,48 @@
+use crate::models::{Transaction, TransactionStatus};
+use async_graphql::SimpleObject;
+use futures::{Stream, stream};
+use std::sync::OnceLock;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Events a slow subscriber may fall behind by before it starts missing some.
+const BUS_CAPACITY: usize = 1024;
+
+/// A transaction whose status was changed, with the status it had before.
+#[derive(SimpleObject, Clone)]
+pub struct TransactionStatusChange {
+    pub transaction: Transaction,
+    pub previous_status: TransactionStatus,
+}
+
+#[derive(Clone)]
+pub enum TransactionEvent {
+    Recorded(Transaction),
+    StatusChanged(TransactionStatusChange),
+}
+
+/// Process-wide bus the write paths publish to and GraphQL subscriptions read from.
+fn bus() -> &'static broadcast::Sender<TransactionEvent> {
+    static BUS: OnceLock<broadcast::Sender<TransactionEvent>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+/// Hands the event to every current subscriber; without any it is dropped.
+pub fn publish(event: TransactionEvent) {
+    let _ = bus().send(event);
+}
+
+/// Every event published from now on. A subscriber that falls more than [`BUS_CAPACITY`] events
+/// behind skips the oldest ones rather than holding up the writers.
+pub fn subscribe() -> impl Stream<Item = TransactionEvent> {
+    stream::unfold(bus().subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Subscriber fell behind, skipping events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,281 @@
+use crate::audit::{ANONYMOUS_ACTOR, Actor, AuditEntry};
+use crate::dynamo::transaction_date_range;
+use crate::models::{CardBrand, PageRequest, Role, Transaction, TransactionCursor, env_role};
+use crate::output::Tabular;
+use crate::store::SharedStore;
+use crate::telemetry::{RequestId, mask_pan};
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Bytes},
+};
+use anyhow::Error;
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use futures::{Stream, TryStreamExt, stream};
+use serde::Deserialize;
+use serde_json::json;
+
+// Transactions fetched per page; only one page is held in memory at a time.
+const EXPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Filters of an export, matching those of the `transactions` query.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportQuery {
+    pub merchant_id: String,
+    #[serde(default)]
+    pub include_descendants: bool,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub card_brand: Option<CardBrand>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+struct ExportState {
+    store: SharedStore,
+    merchant_ids: Vec<String>,
+    earlier_transaction: String,
+    later_transaction: String,
+    card_brand: Option<CardBrand>,
+    format: ExportFormat,
+    mask_pans: bool,
+    after: Option<TransactionCursor>,
+    first_page: bool,
+    done: bool,
+}
+
+/// Every transaction matching the filters, newest first, encoded page by page. CSV starts with a
+/// header line and both formats keep the field order of [`Transaction`].
+pub async fn transactions_export(
+    store: SharedStore,
+    query: &ExportQuery,
+    mask_pans: bool,
+) -> Result<impl Stream<Item = Result<Bytes, Error>> + use<>, Error> {
+    let merchant_ids = if query.include_descendants {
+        store
+            .get_descendant_outlets(query.merchant_id.clone())
+            .await?
+    } else {
+        vec![query.merchant_id.clone()]
+    };
+    let (earlier_transaction, later_transaction) = transaction_date_range(query.from, query.to);
+    let state = ExportState {
+        store,
+        merchant_ids,
+        earlier_transaction,
+        later_transaction,
+        card_brand: query.card_brand,
+        format: query.format,
+        mask_pans,
+        after: None,
+        first_page: true,
+        done: false,
+    };
+    Ok(stream::try_unfold(state, |mut state| async move {
+        if state.done {
+            return Ok(None);
+        }
+        let (mut transactions, has_more) = state
+            .store
+            .get_transactions_for_merchants_between(
+                &state.merchant_ids,
+                &state.earlier_transaction,
+                &state.later_transaction,
+                state.card_brand,
+                &PageRequest::forward(state.after.take(), EXPORT_PAGE_SIZE),
+            )
+            .await?;
+        if state.mask_pans {
+            for transaction in &mut transactions {
+                transaction.pan = mask_pan(&transaction.pan);
+            }
+        }
+        let chunk = encode(&transactions, state.format, state.first_page)?;
+        state.after = transactions.last().map(TransactionCursor::of);
+        state.first_page = false;
+        state.done = !has_more;
+        Ok(Some((chunk, state)))
+    }))
+}
+
+fn encode(
+    transactions: &[Transaction],
+    format: ExportFormat,
+    with_header: bool,
+) -> Result<Bytes, Error> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            if with_header {
+                writer.write_record(Transaction::HEADERS)?;
+            }
+            for transaction in transactions {
+                writer.write_record(transaction.row())?;
+            }
+            Ok(Bytes::from(writer.into_inner()?))
+        }
+        ExportFormat::Ndjson => {
+            let mut buffer = Vec::new();
+            for transaction in transactions {
+                serde_json::to_writer(&mut buffer, transaction)?;
+                buffer.push(b'\n');
+            }
+            Ok(Bytes::from(buffer))
+        }
+    }
+}
+
+/// `GET /export/transactions`: streams a merchant's transactions as CSV or NDJSON. Authorization
+/// follows the `transactions` query, including the audit entry for Admin reads; every other caller
+/// gets masked PANs.
+pub async fn export_transactions(
+    req: HttpRequest,
+    store: web::Data<SharedStore>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let actor = request_actor(&req);
+    if actor.role == Some(Role::Admin)
+        && let Err(err) = store
+            .add_audit_entry(&AuditEntry::new(
+                &actor,
+                "exportTransactions",
+                vec![query.merchant_id.clone()],
+            ))
+            .await
+    {
+        tracing::error!(error = %format!("{err:#}"), "Failed to audit transaction export");
+        return HttpResponse::InternalServerError().json(json!({ "error": "audit failed" }));
+    }
+
+    let mask_pans = actor.role != Some(Role::Admin);
+    let body = match transactions_export(store.get_ref().clone(), &query, mask_pans).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(error = %format!("{err:#}"), "Failed to start transaction export");
+            return HttpResponse::InternalServerError().json(json!({ "error": "export failed" }));
+        }
+    };
+    let body = body.inspect_err(|err| {
+        tracing::error!(error = %format!("{err:#}"), "Transaction export aborted");
+    });
+    HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "transactions.{}",
+                query.format.extension()
+            ))],
+        })
+        .streaming(body.map_err(actix_web::error::ErrorInternalServerError))
+}
+
+fn request_actor(req: &HttpRequest) -> Actor {
+    Actor {
+        id: req
+            .headers()
+            .get("X-Actor-Id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(ANONYMOUS_ACTOR)
+            .to_string(),
+        role: env_role(),
+        request_id: req
+            .extensions()
+            .get::<RequestId>()
+            .map(|request_id| request_id.0.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::export_transactions;
+    use crate::memory::MemoryStore;
+    use crate::rate_limit::{RateLimitArgs, RateLimiter, rate_limit};
+    use crate::store::SharedStore;
+    use crate::testing::seed;
+    use actix_web::test::{TestRequest, call_service, init_service, read_body};
+    use actix_web::{App, http::StatusCode, middleware, web};
+    use std::{env, sync::Arc};
+
+    #[actix_web::test]
+    async fn exports_mask_pans_and_are_rate_limited_for_non_admins() {
+        assert!(
+            env::var("ROLE").is_err(),
+            "ROLE grants its role to every request, unset it to test anonymous exports"
+        );
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        seed(&store).await;
+        let limiter = RateLimiter::new(&RateLimitArgs {
+            admin_rate_limit: "10:10".parse().unwrap(),
+            reader_rate_limit: "10:10".parse().unwrap(),
+            anonymous_rate_limit: "1:1".parse().unwrap(),
+            max_subscriptions_per_client: 10,
+        });
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(store))
+                .app_data(web::Data::new(limiter))
+                .service(
+                    web::resource("/export/transactions")
+                        .wrap(middleware::from_fn(rate_limit))
+                        .to(export_transactions),
+                ),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/export/transactions?merchantId=MERCHANT%23merchant_b_group&includeDescendants=true")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+
+        let response = call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        let mut reader = csv::Reader::from_reader(body.as_ref());
+        let pan = reader
+            .headers()
+            .unwrap()
+            .iter()
+            .position(|header| header == "pan")
+            .expect("pan column");
+        let pans: Vec<String> = reader
+            .records()
+            .map(|record| record.unwrap()[pan].to_string())
+            .collect();
+        assert_eq!(pans.len(), 10);
+        // Seeded PANs are four digits, too short to keep any of them visible.
+        for pan in &pans {
+            assert_eq!(pan, "****", "unmasked PAN");
+        }
+
+        let response = call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
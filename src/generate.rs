@@ -0,0 +1,297 @@
+use crate::dynamo::{
+    BATCH_WRITE_LIMIT, PAYOUT_PREFIX, TRANSACTION_PREFIX, Table, add_transactions,
+    get_descendant_outlets, get_settlement_merchant_id,
+};
+use crate::models::{CardBrand, Transaction, TransactionStatus, TransactionType};
+use anyhow::{Context, Error, bail};
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc, Weekday};
+use clap::{Args, ValueEnum};
+use futures::{StreamExt, TryStreamExt, stream};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+// Progress is logged every time this many more transactions have been written.
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Shape of the daily transaction volume over the generated span.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum VolumeCurve {
+    /// The same volume every day.
+    Flat,
+    /// Retail week: quiet Sundays, busy Fridays and Saturdays.
+    Weekly,
+    /// Grows linearly from half to one and a half times the daily volume.
+    Growth,
+}
+
+impl VolumeCurve {
+    fn factor(self, date: NaiveDate, from: NaiveDate, to: NaiveDate) -> f64 {
+        match self {
+            VolumeCurve::Flat => 1.0,
+            VolumeCurve::Weekly => match date.weekday() {
+                Weekday::Fri => 1.2,
+                Weekday::Sat => 1.5,
+                Weekday::Sun => 0.6,
+                _ => 0.9,
+            },
+            VolumeCurve::Growth => {
+                let span = (to - from).num_days().max(1) as f64;
+                0.5 + (date - from).num_days() as f64 / span
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct GenerateArgs {
+    /// Merchant whose outlets receive transactions; repeat for several hierarchies.
+    #[arg(long = "merchant", required = true)]
+    pub merchants: Vec<String>,
+    /// First day to generate, e.g. 2025-01-01.
+    #[arg(long)]
+    pub from: NaiveDate,
+    /// Last day to generate, inclusive.
+    #[arg(long)]
+    pub to: NaiveDate,
+    /// Average transactions per outlet per day before the curve is applied.
+    #[arg(long, default_value_t = 1000)]
+    pub daily_volume: u32,
+    #[arg(long, value_enum, default_value_t = VolumeCurve::Weekly)]
+    pub curve: VolumeCurve,
+    /// Share of transactions that are refunds.
+    #[arg(long, default_value_t = 0.05)]
+    pub refund_ratio: f64,
+    /// Share of purchases that end up charged back.
+    #[arg(long, default_value_t = 0.01)]
+    pub chargeback_ratio: f64,
+    /// Share of transactions paid with Visa; the rest are Mastercard.
+    #[arg(long, default_value_t = 0.6)]
+    pub visa_share: f64,
+    /// Days between a transaction and its settlement.
+    #[arg(long, default_value_t = 1)]
+    pub settlement_delay_days: u64,
+    #[arg(long, default_value = "GBP")]
+    pub currency: String,
+    /// RNG seed; the same arguments and seed always produce the same transactions.
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Moment statuses are decided against, e.g. 2025-01-31T12:00:00Z: transactions settled by
+    /// then are Paid, and ones made by then Cleared. Defaults to the end of --to.
+    #[arg(long)]
+    pub now: Option<DateTime<Utc>>,
+    /// Outlet-days generated and written concurrently.
+    #[arg(long, default_value_t = 8)]
+    pub concurrency: usize,
+}
+
+impl GenerateArgs {
+    fn validate(&self) -> Result<(), Error> {
+        if self.from > self.to {
+            bail!("--from {} is after --to {}", self.from, self.to);
+        }
+        for (name, ratio) in [
+            ("--refund-ratio", self.refund_ratio),
+            ("--chargeback-ratio", self.chargeback_ratio),
+            ("--visa-share", self.visa_share),
+        ] {
+            if !(0.0..=1.0).contains(&ratio) {
+                bail!("{name} must be between 0 and 1, got {ratio}");
+            }
+        }
+        if self.concurrency == 0 {
+            bail!("--concurrency must be at least 1");
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> Result<DateTime<Utc>, Error> {
+        match self.now {
+            Some(now) => Ok(now),
+            None => Ok(self
+                .to
+                .and_hms_opt(23, 59, 59)
+                .context("Invalid --to date")?
+                .and_utc()),
+        }
+    }
+}
+
+/// One outlet with the merchant its transactions settle to.
+struct Outlet {
+    id: String,
+    settlement_merchant_id: String,
+}
+
+/// Generates transactions for every outlet below the given merchants, one day per outlet at a
+/// time, and writes them with their aggregates. Transactions an earlier run with the same seed
+/// already wrote are skipped.
+pub async fn run(table: &Table, args: &GenerateArgs) -> Result<(), Error> {
+    args.validate()?;
+
+    let mut outlets = Vec::new();
+    for merchant_id in &args.merchants {
+        for outlet_id in get_descendant_outlets(table, merchant_id.clone()).await? {
+            let settlement_merchant_id = get_settlement_merchant_id(table, &outlet_id).await?;
+            outlets.push(Outlet {
+                id: outlet_id,
+                settlement_merchant_id,
+            });
+        }
+    }
+    let days: Vec<NaiveDate> = args
+        .from
+        .iter_days()
+        .take_while(|day| *day <= args.to)
+        .collect();
+    tracing::info!(
+        outlets = outlets.len(),
+        days = days.len(),
+        daily_volume = args.daily_volume,
+        curve = ?args.curve,
+        seed = args.seed,
+        "Generating transactions"
+    );
+
+    let started = Instant::now();
+    let written = AtomicU64::new(0);
+    let units = outlets
+        .iter()
+        .flat_map(|outlet| days.iter().map(move |day| (outlet, *day)))
+        .enumerate();
+    stream::iter(units)
+        .map(|(unit, (outlet, day))| {
+            let written = &written;
+            async move {
+                // Seeding per outlet-day keeps the output independent of write concurrency.
+                let mut rng = StdRng::seed_from_u64(
+                    args.seed ^ (unit as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+                );
+                let transactions = outlet_day(args, outlet, day, &mut rng)?;
+                let mut count = 0;
+                for batch in transactions.chunks(BATCH_WRITE_LIMIT) {
+                    count += add_transactions(table, batch).await? as u64;
+                }
+                let total = written.fetch_add(count, Ordering::Relaxed) + count;
+                if total / PROGRESS_INTERVAL != (total - count) / PROGRESS_INTERVAL {
+                    tracing::info!(written = total, "Generating transactions");
+                }
+                Ok::<_, Error>(())
+            }
+        })
+        .buffer_unordered(args.concurrency)
+        .try_collect::<()>()
+        .await?;
+
+    let elapsed = started.elapsed();
+    let written = written.into_inner();
+    tracing::info!(
+        written,
+        elapsed_secs = elapsed.as_secs(),
+        per_second = (written as f64 / elapsed.as_secs_f64()).round(),
+        "Generated transactions"
+    );
+    Ok(())
+}
+
+fn outlet_day(
+    args: &GenerateArgs,
+    outlet: &Outlet,
+    day: NaiveDate,
+    rng: &mut impl Rng,
+) -> Result<Vec<Transaction>, Error> {
+    let volume = f64::from(args.daily_volume)
+        * args.curve.factor(day, args.from, args.to)
+        * rng.gen_range(0.8..1.2);
+    let midnight = day
+        .and_hms_opt(0, 0, 0)
+        .context("Invalid transaction date")?
+        .and_utc();
+    let settled_date = midnight
+        .checked_add_days(Days::new(args.settlement_delay_days))
+        .context("Settlement date out of range")?;
+    let now = args.now()?;
+
+    let mut seconds: Vec<i64> = (0..volume.round() as usize)
+        .map(|_| rng.gen_range(0..86_400))
+        .collect();
+    seconds.sort_unstable();
+    Ok(seconds
+        .into_iter()
+        .enumerate()
+        .map(|(n, second)| {
+            let transaction_date = midnight + chrono::Duration::seconds(second);
+            random_transaction(
+                args,
+                outlet,
+                transaction_date,
+                settled_date,
+                now,
+                n + 1,
+                rng,
+            )
+        })
+        .collect())
+}
+
+fn random_transaction(
+    args: &GenerateArgs,
+    outlet: &Outlet,
+    transaction_date: DateTime<Utc>,
+    settled_date: DateTime<Utc>,
+    now: DateTime<Utc>,
+    n: usize,
+    rng: &mut impl Rng,
+) -> Transaction {
+    let transaction_type = if rng.gen_bool(args.refund_ratio) {
+        TransactionType::Refund
+    } else {
+        TransactionType::Purchase
+    };
+    let status =
+        if transaction_type == TransactionType::Purchase && rng.gen_bool(args.chargeback_ratio) {
+            TransactionStatus::Chargebacked
+        } else if settled_date <= now {
+            TransactionStatus::Paid
+        } else if transaction_date <= now {
+            TransactionStatus::Cleared
+        } else {
+            TransactionStatus::Processed
+        };
+    let card_brand = if rng.gen_bool(args.visa_share) {
+        CardBrand::Visa
+    } else {
+        CardBrand::Mastercard
+    };
+    // Skewed towards small baskets, like real card spend.
+    let amount = (rng.r#gen::<f64>().powi(2) * 250.0 + 1.0) * 100.0;
+
+    Transaction {
+        id: format!("{TRANSACTION_PREFIX}#{}#{n}", transaction_date.to_rfc3339()),
+        merchant_id: outlet.id.clone(),
+        date_transaction: transaction_date.to_rfc3339(),
+        date_settlement: settled_date.to_rfc3339(),
+        transaction_type,
+        status,
+        amount: amount.round() / 100.0,
+        currency: args.currency.clone(),
+        pan: random_pan(card_brand, rng),
+        card_brand,
+        payout_id: format!("{PAYOUT_PREFIX}#{}#1", settled_date.to_rfc3339()),
+        settlement_merchant_id: outlet.settlement_merchant_id.clone(),
+    }
+}
+
+/// A 16 digit card number with the brand's prefix.
+fn random_pan(card_brand: CardBrand, rng: &mut impl Rng) -> String {
+    let prefix = match card_brand {
+        CardBrand::Visa => "4".to_string(),
+        CardBrand::Mastercard => rng.gen_range(51..=55).to_string(),
+    };
+    let digits: String = (prefix.len()..16)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect();
+    prefix + &digits
+}
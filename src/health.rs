@@ -0,0 +1,20 @@
+use crate::store::SharedStore;
+use actix_web::{HttpResponse, Result, web};
+use serde_json::json;
+
+/// Liveness: the process is up and serving requests.
+pub async fn healthz() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+/// Readiness: the store can serve requests; for DynamoDB, the table and `gsi1` are `ACTIVE`.
+pub async fn readyz(store: web::Data<SharedStore>) -> Result<HttpResponse> {
+    match store.check_ready().await {
+        Ok(()) => Ok(HttpResponse::Ok().json(json!({ "status": "ready" }))),
+        Err(err) => {
+            tracing::warn!(error = %format!("{err:#}"), "Readiness check failed");
+            Ok(HttpResponse::ServiceUnavailable()
+                .json(json!({ "status": "unavailable", "reason": format!("{err:#}") })))
+        }
+    }
+}
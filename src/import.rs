@@ -0,0 +1,532 @@
+//! Bulk import of historic transactions from CSV.
+//!
+//! The file needs a header line; columns may come in any order and match the CSV export:
+//!
+//! | column                   | format                                                        |
+//! |--------------------------|---------------------------------------------------------------|
+//! | `merchant_id`            | `MERCHANT#…`, an existing merchant                            |
+//! | `id`                     | `TRANSACTION#<date_transaction>#…`; generated when left empty |
+//! | `date_transaction`       | RFC 3339, e.g. `2024-03-01T12:30:00+00:00`                    |
+//! | `date_settlement`        | RFC 3339, not before `date_transaction`                       |
+//! | `transaction_type`       | `Purchase` or `Refund`                                        |
+//! | `status`                 | `Processed`, `Cleared`, `Chargebacked` or `Paid`              |
+//! | `amount`                 | positive, at most two decimals                                |
+//! | `currency`               | ISO 4217 code, e.g. `GBP`                                     |
+//! | `card_brand`             | `Visa` or `Mastercard`                                        |
+//! | `pan`                    | 12 to 19 digits, unmasked                                     |
+//! | `settlement_merchant_id` | `MERCHANT#…`, an existing merchant                            |
+//! | `payout_id`              | `PAYOUT#…`                                                    |
+//!
+//! Rows that fail validation are copied to the rejects file with their row number and error;
+//! rows that cannot be read as CSV at all are recorded there with empty columns. Progress is
+//! checkpointed after every batch, so re-running the same command after a crash continues where
+//! it stopped; rows of the last unfinished batch may be written twice, and the rejects file is cut
+//! back to the checkpoint so they are not rejected twice.
+
+use crate::dynamo::{
+    BATCH_WRITE_LIMIT, MERCHANT_PREFIX, PAYOUT_PREFIX, TRANSACTION_PREFIX, Table, add_transactions,
+    get_merchant,
+};
+use crate::errors::AppError;
+use crate::models::Transaction;
+use actix_web::rt::time::sleep;
+use anyhow::{Context, Error, bail};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize, de::IntoDeserializer};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const BATCH_MAX_ATTEMPTS: u32 = 5;
+const BATCH_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+/// One CSV row, kept as text so every problem can be reported rather than only the first
+/// unparseable field.
+#[derive(Deserialize)]
+struct ImportRow {
+    merchant_id: String,
+    #[serde(default)]
+    id: String,
+    date_transaction: String,
+    date_settlement: String,
+    transaction_type: String,
+    status: String,
+    amount: String,
+    currency: String,
+    card_brand: String,
+    pan: String,
+    settlement_merchant_id: String,
+    payout_id: String,
+}
+
+/// How far an import got. Rows up to `rows` have been written or rejected, and the rejects file
+/// held `rejects_len` bytes at the time.
+#[derive(Default, Deserialize, Serialize)]
+struct Checkpoint {
+    file: PathBuf,
+    rows: u64,
+    imported: u64,
+    rejected: u64,
+    rejects_len: u64,
+}
+
+impl Checkpoint {
+    fn load(path: &Path, file: &Path) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let checkpoint: Checkpoint = serde_json::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Invalid checkpoint {}", path.display()))?;
+        if checkpoint.file != file {
+            bail!(
+                "Checkpoint {} belongs to {}, not {}",
+                path.display(),
+                checkpoint.file.display(),
+                file.display()
+            );
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Replaces the checkpoint atomically so a crash never leaves half a file behind.
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(self)?)?;
+        fs::rename(&partial, path)
+            .with_context(|| format!("Failed to write checkpoint {}", path.display()))
+    }
+}
+
+/// Imports `file`, writing rejected rows to `rejects` and progress to `checkpoint`, which
+/// default to `<file>.rejects.csv` and `<file>.checkpoint`. Without a checkpoint the rejects file
+/// starts over. The checkpoint is removed once the whole file is done.
+pub async fn run(
+    table: &Table,
+    file: &Path,
+    rejects: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+) -> Result<(), Error> {
+    let rejects_path = rejects.unwrap_or_else(|| sibling(file, "rejects.csv"));
+    let checkpoint_path = checkpoint.unwrap_or_else(|| sibling(file, "checkpoint"));
+    let mut progress = match Checkpoint::load(&checkpoint_path, file)? {
+        Some(progress) => {
+            tracing::info!(
+                rows = progress.rows,
+                imported = progress.imported,
+                rejected = progress.rejected,
+                "Resuming import"
+            );
+            progress
+        }
+        None => Checkpoint {
+            file: file.to_path_buf(),
+            ..Checkpoint::default()
+        },
+    };
+
+    let mut reader = csv::Reader::from_path(file)
+        .with_context(|| format!("Failed to open {}", file.display()))?;
+    let headers = reader.headers()?.clone();
+    let mut rejects = RejectsWriter::open(&rejects_path, &headers, progress.rejects_len)?;
+    let mut merchants = MerchantCheck::default();
+    let mut batch = Vec::with_capacity(BATCH_WRITE_LIMIT);
+    let mut row = 0;
+
+    for record in reader.records() {
+        row += 1;
+        if row <= progress.rows {
+            continue;
+        }
+        let record = match record {
+            Ok(record) => record,
+            Err(err) if !err.is_io_error() => {
+                rejects.write_unreadable(row, &err.to_string())?;
+                progress.rejected += 1;
+                continue;
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to read row {row}")),
+        };
+        let transaction = match record.deserialize::<ImportRow>(Some(&headers)) {
+            Ok(import_row) => match validate(import_row, row) {
+                Ok(transaction) => merchants.check(table, transaction).await?,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err.to_string()),
+        };
+        match transaction {
+            Ok(transaction) => batch.push(transaction),
+            Err(err) => {
+                rejects.write(&record, row, &err)?;
+                progress.rejected += 1;
+            }
+        }
+
+        if batch.len() == BATCH_WRITE_LIMIT {
+            write_batch(table, &batch).await?;
+            progress.imported += batch.len() as u64;
+            batch.clear();
+            progress.rows = row;
+            progress.rejects_len = rejects.flush()?;
+            progress.save(&checkpoint_path)?;
+        }
+    }
+    if !batch.is_empty() {
+        write_batch(table, &batch).await?;
+        progress.imported += batch.len() as u64;
+    }
+    progress.rows = row;
+    rejects.flush()?;
+    if checkpoint_path.exists() {
+        fs::remove_file(&checkpoint_path)?;
+    }
+
+    tracing::info!(
+        rows = progress.rows,
+        imported = progress.imported,
+        rejected = progress.rejected,
+        rejects = %rejects_path.display(),
+        "Import finished"
+    );
+    Ok(())
+}
+
+fn sibling(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+    file.with_file_name(name)
+}
+
+/// Checks a row's fields and turns it into a [`Transaction`], or describes everything wrong
+/// with it.
+fn validate(row: ImportRow, row_number: u64) -> Result<Transaction, String> {
+    let mut errors = Vec::new();
+
+    let date_transaction = DateTime::parse_from_rfc3339(&row.date_transaction)
+        .map_err(|err| errors.push(format!("date_transaction: {err}")))
+        .ok();
+    let date_settlement = DateTime::parse_from_rfc3339(&row.date_settlement)
+        .map_err(|err| errors.push(format!("date_settlement: {err}")))
+        .ok();
+    if let (Some(date_transaction), Some(date_settlement)) = (date_transaction, date_settlement)
+        && date_settlement < date_transaction
+    {
+        errors.push("date_settlement is before date_transaction".to_string());
+    }
+    let transaction_type = parse_enum(&row.transaction_type)
+        .map_err(|err| errors.push(format!("transaction_type: {err}")))
+        .ok();
+    let status = parse_enum(&row.status)
+        .map_err(|err| errors.push(format!("status: {err}")))
+        .ok();
+    let card_brand = parse_enum(&row.card_brand)
+        .map_err(|err| errors.push(format!("card_brand: {err}")))
+        .ok();
+    let amount = match row.amount.parse::<f64>() {
+        Ok(amount) if !amount.is_finite() || amount <= 0.0 => {
+            errors.push(format!("amount: {amount} is not positive"));
+            None
+        }
+        Ok(amount)
+            if row
+                .amount
+                .split_once('.')
+                .is_some_and(|(_, pence)| pence.len() > 2) =>
+        {
+            errors.push(format!("amount: {amount} has more than two decimals"));
+            None
+        }
+        Ok(amount) => Some(amount),
+        Err(err) => {
+            errors.push(format!("amount: {err}"));
+            None
+        }
+    };
+    if row.currency.len() != 3 || !row.currency.chars().all(|c| c.is_ascii_uppercase()) {
+        errors.push(format!(
+            "currency: {:?} is not an ISO 4217 code",
+            row.currency
+        ));
+    }
+    if !(12..=19).contains(&row.pan.len()) || !row.pan.chars().all(|c| c.is_ascii_digit()) {
+        errors.push("pan: expected 12 to 19 digits".to_string());
+    }
+    for (column, value) in [
+        ("merchant_id", &row.merchant_id),
+        ("settlement_merchant_id", &row.settlement_merchant_id),
+    ] {
+        if !value.starts_with(&format!("{MERCHANT_PREFIX}#")) {
+            errors.push(format!("{column}: must start with {MERCHANT_PREFIX}#"));
+        }
+    }
+    if !row.payout_id.starts_with(&format!("{PAYOUT_PREFIX}#")) {
+        errors.push(format!("payout_id: must start with {PAYOUT_PREFIX}#"));
+    }
+    let id = if row.id.is_empty() {
+        format!("{TRANSACTION_PREFIX}#{}#{row_number}", row.date_transaction)
+    } else {
+        if !row
+            .id
+            .starts_with(&format!("{TRANSACTION_PREFIX}#{}", row.date_transaction))
+        {
+            errors.push(format!(
+                "id: must start with {TRANSACTION_PREFIX}#<date_transaction>"
+            ));
+        }
+        row.id
+    };
+
+    match (transaction_type, status, card_brand, amount) {
+        (Some(transaction_type), Some(status), Some(card_brand), Some(amount))
+            if errors.is_empty() =>
+        {
+            Ok(Transaction {
+                id,
+                merchant_id: row.merchant_id,
+                date_transaction: row.date_transaction,
+                date_settlement: row.date_settlement,
+                transaction_type,
+                status,
+                amount,
+                currency: row.currency,
+                pan: row.pan,
+                card_brand,
+                payout_id: row.payout_id,
+                settlement_merchant_id: row.settlement_merchant_id,
+            })
+        }
+        _ => Err(errors.join("; ")),
+    }
+}
+
+fn parse_enum<T: for<'de> Deserialize<'de>>(value: &str) -> Result<T, String> {
+    T::deserialize(value.into_deserializer())
+        .map_err(|err: serde::de::value::Error| err.to_string())
+}
+
+/// Remembers which merchants exist so each is looked up once per import.
+#[derive(Default)]
+struct MerchantCheck {
+    known: HashMap<String, bool>,
+}
+
+impl MerchantCheck {
+    async fn check(
+        &mut self,
+        table: &Table,
+        transaction: Transaction,
+    ) -> Result<Result<Transaction, String>, Error> {
+        for merchant_id in [
+            &transaction.merchant_id,
+            &transaction.settlement_merchant_id,
+        ] {
+            if !self.exists(table, merchant_id).await? {
+                return Ok(Err(format!("merchant {merchant_id} does not exist")));
+            }
+        }
+        Ok(Ok(transaction))
+    }
+
+    async fn exists(&mut self, table: &Table, merchant_id: &str) -> Result<bool, Error> {
+        if let Some(exists) = self.known.get(merchant_id) {
+            return Ok(*exists);
+        }
+        let exists = match get_merchant(table, merchant_id.to_string()).await {
+            Ok(_) => true,
+            Err(err) if matches!(AppError::find(&err), Some(AppError::NotFound(_))) => false,
+            Err(err) => return Err(err),
+        };
+        self.known.insert(merchant_id.to_string(), exists);
+        Ok(exists)
+    }
+}
+
+/// Writes one batch, retrying the whole call with backoff when DynamoDB fails outright;
+/// unprocessed items are already resent by [`add_transactions`], and rows an interrupted run
+/// stored after its last checkpoint are skipped there.
+async fn write_batch(table: &Table, batch: &[Transaction]) -> Result<(), Error> {
+    let mut backoff = BATCH_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match add_transactions(table, batch).await {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        if attempt >= BATCH_MAX_ATTEMPTS {
+            return Err(err.context(format!("Batch failed after {attempt} attempts")));
+        }
+        tracing::warn!(error = %format!("{err:#}"), attempt, "Retrying batch");
+        sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// The rejects file: the original columns followed by `row` and `error`. Resumes from the length
+/// the checkpoint recorded, dropping rejects of the batch that was not checkpointed.
+struct RejectsWriter {
+    writer: csv::Writer<File>,
+    columns: usize,
+}
+
+impl RejectsWriter {
+    fn open(path: &Path, headers: &csv::StringRecord, len: u64) -> Result<Self, Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open rejects file {}", path.display()))?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        let mut writer = csv::Writer::from_writer(file);
+        if len == 0 {
+            writer.write_record(headers.iter().chain(["row", "error"]))?;
+        }
+        Ok(Self {
+            writer,
+            columns: headers.len(),
+        })
+    }
+
+    fn write(&mut self, record: &csv::StringRecord, row: u64, error: &str) -> Result<(), Error> {
+        let row = row.to_string();
+        self.writer
+            .write_record(record.iter().chain([row.as_str(), error]))?;
+        Ok(())
+    }
+
+    /// Records a row the CSV reader could not parse, with its columns left empty.
+    fn write_unreadable(&mut self, row: u64, error: &str) -> Result<(), Error> {
+        self.write(&csv::StringRecord::from(vec![""; self.columns]), row, error)
+    }
+
+    /// Flushes the rows written so far and returns the file's length.
+    fn flush(&mut self) -> Result<u64, Error> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RejectsWriter, run, sibling};
+    use crate::aggregates::AggregatePeriod;
+    use crate::dynamo::{get_transaction, get_transaction_aggregates};
+    use crate::testing::TestApp;
+    use csv::StringRecord;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    const HEADER: &str = "merchant_id,id,date_transaction,date_settlement,transaction_type,status,\
+                          amount,currency,card_brand,pan,settlement_merchant_id,payout_id";
+
+    #[actix_web::test]
+    #[ignore = "needs DynamoDB Local"]
+    async fn imports_count_every_row_towards_its_aggregates_once() {
+        // Each row is settled by its group in a year of its own, so the batch of 25 adds to 150
+        // aggregates.
+        let mut csv = format!("{HEADER}\n");
+        for year in 2000..2025 {
+            let date = format!("{year}-01-15T12:00:00+00:00");
+            csv.push_str(&format!(
+                "MERCHANT#merchant_b_outlet1,,{date},{date},Purchase,Paid,12.50,GBP,Visa,\
+                 4111111111111111,MERCHANT#merchant_b_group,PAYOUT#{date}#1\n"
+            ));
+        }
+        let file = env::temp_dir().join(format!("import_{}.csv", Uuid::new_v4().simple()));
+        fs::write(&file, csv).unwrap();
+
+        let app = TestApp::dynamodb_only().await;
+        let table = app.table().expect("DynamoDB table");
+        // The second run finds every row stored and must count none of them again.
+        for _ in 0..2 {
+            run(table, &file, None, None).await.unwrap();
+        }
+        for (row, year) in (2000..2025).enumerate() {
+            let id = format!("TRANSACTION#{year}-01-15T12:00:00+00:00#{}", row + 1);
+            get_transaction(table, "MERCHANT#merchant_b_outlet1", &id)
+                .await
+                .unwrap();
+            for merchant_id in ["MERCHANT#merchant_b_outlet1", "MERCHANT#merchant_b_group"] {
+                let aggregates = get_transaction_aggregates(
+                    table,
+                    merchant_id,
+                    AggregatePeriod::Year,
+                    &year.to_string(),
+                )
+                .await
+                .unwrap();
+                assert_eq!(aggregates.len(), 1, "{merchant_id} {year}");
+                assert_eq!(aggregates[0].transactions, 1, "{merchant_id} {year}");
+                assert_eq!(aggregates[0].amount, 12.5, "{merchant_id} {year}");
+            }
+        }
+        app.finish().await;
+        fs::remove_file(&file).unwrap();
+        let _ = fs::remove_file(sibling(&file, "rejects.csv"));
+    }
+
+    #[test]
+    fn resumed_rejects_drop_the_rows_after_the_checkpoint() {
+        let path = env::temp_dir().join(format!("rejects_{}.csv", Uuid::new_v4().simple()));
+        fs::write(&path, "left over from an earlier import\n").unwrap();
+        let headers = StringRecord::from(vec!["merchant_id", "amount"]);
+
+        // Without a checkpoint the file starts over.
+        let mut rejects = RejectsWriter::open(&path, &headers, 0).unwrap();
+        rejects
+            .write(&StringRecord::from(vec!["MERCHANT#a", "-1"]), 1, "amount")
+            .unwrap();
+        let checkpointed = rejects.flush().unwrap();
+        rejects.write_unreadable(2, "unequal lengths").unwrap();
+        rejects.flush().unwrap();
+        drop(rejects);
+
+        // Resuming from the checkpoint rejects row 2 again without repeating it.
+        let mut rejects = RejectsWriter::open(&path, &headers, checkpointed).unwrap();
+        rejects.write_unreadable(2, "unequal lengths").unwrap();
+        rejects.flush().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "merchant_id,amount,row,error\n\
+             MERCHANT#a,-1,1,amount\n\
+             ,,2,unequal lengths\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DynamoDB Local"]
+    async fn malformed_rows_are_rejected_without_stopping_the_import() {
+        let date = "2024-03-01T12:30:00+00:00";
+        let valid = format!(
+            "MERCHANT#merchant_b_outlet1,,{date},{date},Purchase,Paid,12.50,GBP,Visa,\
+             4111111111111111,MERCHANT#merchant_b_group,PAYOUT#{date}#1"
+        );
+        let csv = format!("{HEADER}\n{valid}\nMERCHANT#merchant_b_outlet1,too,short\n{valid}\n");
+        let file = env::temp_dir().join(format!("import_{}.csv", Uuid::new_v4().simple()));
+        fs::write(&file, csv).unwrap();
+
+        let app = TestApp::dynamodb_only().await;
+        let table = app.table().expect("DynamoDB table");
+        run(table, &file, None, None).await.unwrap();
+        for row in [1, 3] {
+            let id = format!("TRANSACTION#{date}#{row}");
+            get_transaction(table, "MERCHANT#merchant_b_outlet1", &id)
+                .await
+                .unwrap();
+        }
+        let rejects = fs::read_to_string(sibling(&file, "rejects.csv")).unwrap();
+        let rejected: Vec<&str> = rejects.lines().skip(1).collect();
+        assert_eq!(rejected.len(), 1, "{rejects}");
+        assert!(rejected[0].starts_with(",,,,,,,,,,,,2,"), "{rejects}");
+        app.finish().await;
+
+        fs::remove_file(&file).unwrap();
+        fs::remove_file(sibling(&file, "rejects.csv")).unwrap();
+    }
+}
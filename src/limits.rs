@@ -0,0 +1,161 @@
+//! Limits on the cost of a GraphQL query.
+//!
+//! Every query is scored before it runs: a field costs 1 and a connection costs its selection
+//! once per requested edge, so `transactions(first: 100)` weighs a hundred times its node. Queries
+//! nested deeper or scoring higher than configured are rejected with a `VALIDATION` error, as are
+//! pages larger than the maximum page size.
+
+use crate::errors::AppError;
+use async_graphql::{
+    ServerError, ValidationResult,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextValidation},
+};
+use clap::Args;
+use std::sync::Arc;
+
+/// Page size of a connection queried without `first` or `last`.
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+const DEFAULT_MAX_QUERY_DEPTH: usize = 10;
+const DEFAULT_MAX_QUERY_COMPLEXITY: usize = 5000;
+const DEFAULT_MAX_PAGE_SIZE: usize = 100;
+// A merchant's descendants are read outlet by outlet; their number is unknown when scoring.
+const DESCENDANTS_FAN_OUT: usize = 4;
+
+/// Limits on the cost of GraphQL queries.
+#[derive(Args, Clone, Copy, Debug)]
+pub struct QueryLimits {
+    /// Deepest nesting of fields a query may select.
+    #[arg(long, global = true, env = "GRAPHQL_MAX_DEPTH", default_value_t = DEFAULT_MAX_QUERY_DEPTH)]
+    pub max_query_depth: usize,
+
+    /// Highest complexity score a query may have; see `src/limits.rs` for the scoring.
+    #[arg(long, global = true, env = "GRAPHQL_MAX_COMPLEXITY", default_value_t = DEFAULT_MAX_QUERY_COMPLEXITY)]
+    pub max_query_complexity: usize,
+
+    /// Largest `first` or `last` a connection accepts.
+    #[arg(long, global = true, env = "GRAPHQL_MAX_PAGE_SIZE", default_value_t = DEFAULT_MAX_PAGE_SIZE)]
+    pub max_page_size: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_query_depth: DEFAULT_MAX_QUERY_DEPTH,
+            max_query_complexity: DEFAULT_MAX_QUERY_COMPLEXITY,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+        }
+    }
+}
+
+impl QueryLimits {
+    /// The page size of a connection, rejecting pages above the maximum.
+    pub fn page_size(&self, first: Option<usize>, last: Option<usize>) -> Result<usize, AppError> {
+        let (argument, size) = match (first, last) {
+            (Some(first), _) => ("first", first),
+            (None, Some(last)) => ("last", last),
+            (None, None) => return Ok(DEFAULT_PAGE_SIZE),
+        };
+        if size > self.max_page_size {
+            return Err(AppError::validation(format!(
+                "{argument} is {size}, above the maximum page size of {}",
+                self.max_page_size
+            )));
+        }
+        Ok(size)
+    }
+}
+
+/// Complexity of a connection field: its selection once per requested edge.
+pub fn connection_complexity(
+    first: Option<i32>,
+    last: Option<i32>,
+    child_complexity: usize,
+) -> usize {
+    let page_size = first
+        .or(last)
+        .map_or(DEFAULT_PAGE_SIZE, |size| size.max(1) as usize);
+    page_size.saturating_mul(child_complexity).saturating_add(1)
+}
+
+/// Complexity of a connection that may merge the pages of a merchant's descendants.
+pub fn hierarchy_complexity(
+    include_descendants: Option<bool>,
+    first: Option<i32>,
+    last: Option<i32>,
+    child_complexity: usize,
+) -> usize {
+    let complexity = connection_complexity(first, last, child_complexity);
+    if include_descendants.unwrap_or(false) {
+        complexity.saturating_mul(DESCENDANTS_FAN_OUT)
+    } else {
+        complexity
+    }
+}
+
+/// Rejects queries nested deeper or scoring higher than the [`QueryLimits`].
+pub struct LimitQueries(pub QueryLimits);
+
+impl ExtensionFactory for LimitQueries {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(LimitQueriesExtension(self.0))
+    }
+}
+
+struct LimitQueriesExtension(QueryLimits);
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for LimitQueriesExtension {
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+        let limits = &self.0;
+        let mut errors = Vec::new();
+        if result.depth > limits.max_query_depth {
+            errors.push(ServerError::from(AppError::validation(format!(
+                "Query is nested {} levels deep, above the limit of {}",
+                result.depth, limits.max_query_depth
+            ))));
+        }
+        if result.complexity > limits.max_query_complexity {
+            errors.push(ServerError::from(AppError::validation(format!(
+                "Query has a complexity of {}, above the limit of {}; request smaller pages",
+                result.complexity, limits.max_query_complexity
+            ))));
+        }
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryLimits, connection_complexity, hierarchy_complexity};
+
+    #[test]
+    fn connections_cost_their_selection_per_requested_edge() {
+        assert_eq!(connection_complexity(Some(50), None, 4), 201);
+        assert_eq!(connection_complexity(None, Some(5), 4), 21);
+        assert_eq!(connection_complexity(None, None, 4), 41);
+        assert_eq!(connection_complexity(Some(-3), None, 4), 5);
+        assert_eq!(hierarchy_complexity(Some(true), Some(50), None, 4), 804);
+        assert_eq!(hierarchy_complexity(Some(false), Some(50), None, 4), 201);
+    }
+
+    #[test]
+    fn pages_above_the_maximum_are_rejected() {
+        let limits = QueryLimits::default();
+        assert_eq!(limits.page_size(None, None), Ok(10));
+        assert_eq!(limits.page_size(Some(100), None), Ok(100));
+        assert_eq!(limits.page_size(None, Some(7)), Ok(7));
+        assert_eq!(
+            limits.page_size(Some(101), None).unwrap_err().code(),
+            "VALIDATION"
+        );
+    }
+}
@@ -1,61 +1,291 @@
-use crate::dynamo::init_db;
-use actix_web::{App, HttpResponse, HttpServer, Result, guard, web};
-use async_graphql::{EmptyMutation, EmptySubscription, Schema, http::GraphiQLSource};
-use async_graphql_actix_web::GraphQL;
-use aws_config::Region;
-use models::Query;
+use crate::audit::ActorId;
+use crate::cache::{CacheArgs, CacheInvalidation, CachingStore, MerchantCache};
+use crate::cli::{Cli, Command};
+use crate::dynamo::Table;
+use crate::limits::QueryLimits;
+use crate::memory::MemoryStore;
+use crate::persisted_queries::PersistedQueries;
+use crate::rate_limit::{RateLimitArgs, RateLimiter, SubscriptionClient};
+use crate::store::{DynamoStore, SharedStore, StoreKind};
+use crate::streams::ChangeHandler;
+use crate::telemetry::RequestId;
+use actix_web::{
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Result, guard, middleware, web,
+};
+use async_graphql::{Data, Schema, http::GraphiQLSource};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use aws_config::{Region, SdkConfig};
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use clap::Parser;
+use models::{Mutation, Query, Subscription};
+use std::sync::Arc;
 
+mod aggregates;
+mod audit;
+mod cache;
+mod cli;
+mod commands;
 mod dynamo;
+mod errors;
+mod events;
+mod export;
+mod generate;
+mod health;
+mod import;
+mod limits;
+mod memory;
+mod metrics;
+mod migrations;
 mod models;
+mod output;
+mod persisted_queries;
+mod rate_limit;
+mod resilience;
+mod sdl;
+mod seed;
+mod store;
+mod streams;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod webhooks;
+
+type AppSchema = Schema<Query, Mutation, Subscription>;
+
+async fn graphql(
+    schema: web::Data<AppSchema>,
+    req: HttpRequest,
+    gql_request: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut request = gql_request.into_inner();
+    if let Some(request_id) = req.extensions().get::<RequestId>() {
+        request = request.data(request_id.clone());
+    }
+    if let Some(actor_id) = req
+        .headers()
+        .get("X-Actor-Id")
+        .and_then(|value| value.to_str().ok())
+    {
+        request = request.data(ActorId(actor_id.to_string()));
+    }
+    schema.execute(request).await.into()
+}
+
+/// Subscriptions over the `graphql-ws` protocol, carrying the same request data as queries and
+/// the client their count is held against.
+async fn graphql_ws(
+    schema: web::Data<AppSchema>,
+    rate_limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse> {
+    let mut data = Data::default();
+    data.insert(SubscriptionClient::new(
+        rate_limiter.into_inner(),
+        req.peer_addr(),
+    ));
+    if let Some(request_id) = req.extensions().get::<RequestId>() {
+        data.insert(request_id.clone());
+    }
+    if let Some(actor_id) = req
+        .headers()
+        .get("X-Actor-Id")
+        .and_then(|value| value.to_str().ok())
+    {
+        data.insert(ActorId(actor_id.to_string()));
+    }
+    GraphQLSubscription::new(AppSchema::clone(&schema))
+        .with_data(data)
+        .start(&req, payload)
+}
 
 async fn index_graphiql() -> Result<HttpResponse> {
     Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(GraphiQLSource::build().endpoint("/").finish()))
+        .body(
+            GraphiQLSource::build()
+                .endpoint("/")
+                .subscription_endpoint("/")
+                .finish(),
+        ))
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt::init();
+async fn main() -> Result<(), anyhow::Error> {
+    telemetry::init_logging();
+    let cli = Cli::parse();
 
     let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
         .test_credentials()
         .region(Region::new("eu-west-1"))
-        .endpoint_url("http://db:8000")
+        .endpoint_url(&cli.dynamodb_endpoint)
+        .retry_config(cli.resilience.retry_config())
+        .timeout_config(cli.resilience.timeout_config())
         .load()
         .await;
     let dynamodb_local_config = aws_sdk_dynamodb::config::Builder::from(&config).build();
 
     let client = aws_sdk_dynamodb::Client::from_conf(dynamodb_local_config);
+    let table = Table::new(client, cli.table).with_policy(cli.resilience.call_policy());
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let store: SharedStore = match cli.store {
+                StoreKind::Dynamodb => {
+                    prepare_table(&table).await;
+                    let store = Arc::new(DynamoStore::new(table.clone()));
+                    cache_merchants(store, &table, &config, &cli.cache)
+                }
+                StoreKind::Memory => {
+                    let store = Arc::new(MemoryStore::default());
+                    seed::run(store.as_ref(), &seed::Fixture::default_fixture()?, None).await?;
+                    store
+                }
+            };
+            let persisted_queries = PersistedQueries::load(&cli.persisted_queries)?;
+            Ok(serve(store, cli.limits, &cli.rate_limits, persisted_queries).await?)
+        }
+        Command::Migrate { dry_run } => migrations::run(&table, dry_run).await,
+        Command::Seed { fixture, seed } => {
+            let fixture = match fixture {
+                Some(path) => seed::Fixture::load(&path)?,
+                None => seed::Fixture::default_fixture()?,
+            };
+            seed::run(&DynamoStore::new(table), &fixture, seed).await
+        }
+        Command::Generate(args) => generate::run(&table, &args).await,
+        Command::Import {
+            file,
+            rejects,
+            checkpoint,
+        } => import::run(&table, &file, rejects, checkpoint).await,
+        Command::Stream { consumer } => {
+            let streams_client = aws_sdk_dynamodbstreams::Client::from_conf(
+                aws_sdk_dynamodbstreams::config::Builder::from(&config).build(),
+            );
+            streams::run(
+                &table,
+                &streams_client,
+                &consumer,
+                &[Box::new(streams::LogHandler)],
+            )
+            .await
+        }
+        Command::Schema { check } => sdl::run(check.as_deref()),
+        Command::Merchant { command } => commands::merchant(&table, command).await,
+        Command::Transactions { command } => commands::transactions(&table, command).await,
+        Command::Payouts { command } => commands::payouts(&table, command).await,
+    }
+}
+
+/// Puts the merchant cache in front of DynamoDB, following the table stream to invalidate it
+/// when a consumer name is given.
+fn cache_merchants(
+    store: SharedStore,
+    table: &Table,
+    config: &SdkConfig,
+    args: &CacheArgs,
+) -> SharedStore {
+    if args.merchant_cache_ttl_secs == 0 {
+        return store;
+    }
+    let cache = Arc::new(MerchantCache::new(args));
+    if let Some(consumer) = args.merchant_cache_stream_consumer.clone() {
+        let streams_client = aws_sdk_dynamodbstreams::Client::from_conf(
+            aws_sdk_dynamodbstreams::config::Builder::from(config).build(),
+        );
+        let table = table.clone();
+        let handlers: Vec<Box<dyn ChangeHandler>> =
+            vec![Box::new(CacheInvalidation(cache.clone()))];
+        actix_web::rt::spawn(async move {
+            if let Err(err) = streams::run(&table, &streams_client, &consumer, &handlers).await {
+                tracing::error!(
+                    error = %format!("{err:#}"),
+                    "Stopped following the table stream, merchants changed elsewhere stay cached until they expire"
+                );
+            }
+        });
+    }
+    Arc::new(CachingStore::new(store, cache))
+}
 
-    let list_resp = client.list_tables().send().await;
+/// Creates the table on first start when DynamoDB does not have it yet.
+async fn prepare_table(table: &Table) {
+    let list_resp = table.client.list_tables().send().await;
     match list_resp {
         Ok(resp) => {
-            println!("Found {} tables", resp.table_names().len());
-            for name in resp.table_names() {
-                println!("  {}", name);
-            }
-            if resp.table_names().is_empty() {
-                println!("No tables found, initializing db...");
-                init_db(&client).await;
+            tracing::info!(tables = ?resp.table_names(), "Found {} tables", resp.table_names().len());
+            if !resp.table_names().contains(&table.name) {
+                tracing::info!("Table {} not found, running migrations...", table.name);
+                if let Err(err) = migrations::run(table, false).await {
+                    tracing::error!(error = %format!("{err:#}"), "Failed to migrate db");
+                }
             }
         }
-        Err(err) => eprintln!("Failed to list local dynamodb tables: {err:?}"),
+        Err(err) => tracing::error!(
+            error = %DisplayErrorContext(&err),
+            "Failed to list local dynamodb tables"
+        ),
     }
+}
 
-    println!("GraphiQL IDE: http://localhost:8080");
+/// The GraphQL schema, resolving against `store`.
+fn build_schema(
+    store: SharedStore,
+    limits: QueryLimits,
+    persisted_queries: PersistedQueries,
+) -> AppSchema {
+    Schema::build(Query, Mutation, Subscription)
+        .data(store)
+        .data(limits)
+        .extension(persisted_queries)
+        .extension(telemetry::OperationTracing)
+        .extension(errors::ErrorCodes)
+        .extension(limits::LimitQueries(limits))
+        .finish()
+}
+
+async fn serve(
+    store: SharedStore,
+    limits: QueryLimits,
+    rate_limits: &RateLimitArgs,
+    persisted_queries: PersistedQueries,
+) -> std::io::Result<()> {
+    actix_web::rt::spawn(webhooks::run_worker(store.clone()));
+    // Created once so every worker draws from the same buckets.
+    let rate_limiter = web::Data::new(RateLimiter::new(rate_limits));
+    tracing::info!("GraphiQL IDE: http://localhost:8080");
 
     HttpServer::new(move || {
-        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
-            .data(client.clone())
-            .finish();
+        let schema = build_schema(store.clone(), limits, persisted_queries.clone());
         App::new()
+            .wrap(middleware::from_fn(telemetry::request_span))
+            .app_data(web::Data::new(schema))
+            .app_data(web::Data::new(store.clone()))
+            .app_data(rate_limiter.clone())
             .service(
                 web::resource("/")
                     .guard(guard::Post())
-                    .to(GraphQL::new(schema.clone())),
+                    .wrap(middleware::from_fn(rate_limit::rate_limit))
+                    .to(graphql),
+            )
+            .service(
+                web::resource("/")
+                    .guard(guard::Get())
+                    .guard(guard::Header("upgrade", "websocket"))
+                    .wrap(middleware::from_fn(rate_limit::rate_limit))
+                    .to(graphql_ws),
             )
             .service(web::resource("/").guard(guard::Get()).to(index_graphiql))
+            .service(
+                web::resource("/export/transactions")
+                    .guard(guard::Get())
+                    .wrap(middleware::from_fn(rate_limit::rate_limit))
+                    .to(export::export_transactions),
+            )
+            .service(web::resource("/metrics").to(metrics::metrics_endpoint))
+            .service(web::resource("/healthz").to(health::healthz))
+            .service(web::resource("/readyz").to(health::readyz))
     })
     .bind("0.0.0.0:8080")?
     .run()
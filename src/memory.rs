@@ -0,0 +1,977 @@
+//! In-process [`Store`] for running the API and its tests without DynamoDB.
+//!
+//! Items live in ordered maps keyed like the table, so reads come back in the order DynamoDB
+//! returns them. Paged reads read at most `limit` rows before filtering and report more rows
+//! whenever the limit was reached, as `LastEvaluatedKey` does, and every write that is a single
+//! DynamoDB transaction happens under one lock. TTL is not applied.
+
+use crate::aggregates::{AggregateDelta, AggregateKey, AggregatePeriod, TransactionAggregate};
+use crate::audit::{Actor, AuditEntry, audit_partition_key};
+use crate::dynamo::{
+    HIERARCHY_PREFIX, MAX_HIERARCHY_DEPTH, hierarchy_audit_entry, is_after, is_before,
+    merged_order, narrow_to_cursors, position, take_page, transaction_audit_entry,
+    transaction_date_range, transaction_sort_key_range, webhook_audit_entry,
+};
+use crate::errors::AppError;
+use crate::events::{TransactionEvent, TransactionStatusChange, publish};
+use crate::models::{
+    CardBrand, HierarchyAction, HierarchyChange, Merchant, MerchantLevel, PageRequest, Payout,
+    Transaction, TransactionCursor, TransactionStatus,
+};
+use crate::store::{AuditStore, MerchantStore, Store, TransactionStore};
+use crate::telemetry::mask_pan;
+use crate::webhooks::{
+    DeliveryAttempt, DeliveryOutcome, DeliveryStatus, Webhook, WebhookDelivery, WebhookEventType,
+};
+use anyhow::{Context, Error, bail};
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+/// Items by partition, then by sort key.
+#[derive(Default)]
+struct Tables {
+    merchants: HashMap<String, Merchant>,
+    hierarchy_changes: HashMap<String, BTreeMap<String, HierarchyChange>>,
+    transactions: HashMap<String, BTreeMap<String, Transaction>>,
+    aggregates: HashMap<String, BTreeMap<String, TransactionAggregate>>,
+    payouts: HashMap<String, BTreeMap<String, Payout>>,
+    webhooks: HashMap<String, BTreeMap<String, Webhook>>,
+    deliveries: HashMap<String, BTreeMap<String, WebhookDelivery>>,
+    audit_log: HashMap<String, BTreeMap<String, AuditEntry>>,
+}
+
+impl MemoryStore {
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // Writes check everything before changing anything, so a panic leaves the maps whole.
+        self.tables.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Reads at most `limit` rows like a DynamoDB `Limit`, reporting more whenever the limit was
+/// reached even if nothing is left.
+fn read_limited<T>(rows: impl Iterator<Item = T>, limit: i32) -> Result<(Vec<T>, bool), Error> {
+    if limit < 1 {
+        bail!(AppError::validation("limit must be at least 1"));
+    }
+    let rows: Vec<T> = rows.take(limit as usize).collect();
+    let has_more = rows.len() == limit as usize;
+    Ok((rows, has_more))
+}
+
+/// [`read_limited`] over newest-first `rows`, reading backward pages from the oldest row up as
+/// a query scanning forward from `before` does, and returning the page newest first.
+fn read_page<T, C>(
+    rows: impl Iterator<Item = T>,
+    page: &PageRequest<C>,
+) -> Result<(Vec<T>, bool), Error> {
+    if !page.backward {
+        return read_limited(rows, page.limit as i32);
+    }
+    let mut rows: Vec<T> = rows.collect();
+    rows.reverse();
+    let (mut rows, has_more) = read_limited(rows.into_iter(), page.limit as i32)?;
+    rows.reverse();
+    Ok((rows, has_more))
+}
+
+impl Tables {
+    fn merchant(&self, merchant_id: &str) -> Result<&Merchant, Error> {
+        self.merchants
+            .get(merchant_id)
+            .with_context(|| AppError::not_found("Merchant not found"))
+    }
+
+    fn merchant_mut(&mut self, merchant_id: &str) -> Result<&mut Merchant, Error> {
+        self.merchants
+            .get_mut(merchant_id)
+            .with_context(|| AppError::not_found("Merchant not found"))
+    }
+
+    /// Transactions of one merchant inside an inclusive sort key range, newest first.
+    fn transactions_between<'a>(
+        &'a self,
+        merchant_id: &str,
+        earlier_transaction: &'a str,
+        later_transaction: &'a str,
+    ) -> Result<impl Iterator<Item = &'a Transaction>, Error> {
+        if earlier_transaction > later_transaction {
+            bail!(AppError::validation(format!(
+                "Invalid transaction range {earlier_transaction} to {later_transaction}"
+            )));
+        }
+        Ok(self
+            .transactions
+            .get(merchant_id)
+            .into_iter()
+            .flat_map(move |transactions| {
+                transactions
+                    .range(earlier_transaction.to_string()..=later_transaction.to_string())
+                    .rev()
+                    .map(|(_, transaction)| transaction)
+            }))
+    }
+
+    /// Transactions settled by a merchant inside an inclusive sort key range, in `gsi1` order.
+    fn settled_between(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_transaction: &str,
+        later_transaction: &str,
+    ) -> Result<Vec<&Transaction>, Error> {
+        if earlier_transaction > later_transaction {
+            bail!(AppError::validation(format!(
+                "Invalid transaction range {earlier_transaction} to {later_transaction}"
+            )));
+        }
+        let mut transactions: Vec<&Transaction> = self
+            .transactions
+            .values()
+            .flat_map(|transactions| transactions.values())
+            .filter(|transaction| {
+                transaction.settlement_merchant_id == settlement_merchant_id
+                    && (earlier_transaction..=later_transaction).contains(&transaction.id.as_str())
+            })
+            .collect();
+        transactions.sort_by(|a, b| merged_order(position(a), position(b)));
+        Ok(transactions)
+    }
+
+    fn add_aggregates(&mut self, transaction: &Transaction, delta: &AggregateDelta) {
+        for key in AggregateKey::of(transaction) {
+            self.aggregates
+                .entry(key.merchant_id.clone())
+                .or_default()
+                .entry(key.sort_key())
+                .and_modify(|aggregate| aggregate.add(delta))
+                .or_insert_with(|| TransactionAggregate::new(&key, delta));
+        }
+    }
+
+    /// Queues one event for the matching webhooks of each merchant.
+    fn queue_webhook_deliveries<T: Serialize>(
+        &mut self,
+        merchant_ids: &[&str],
+        event_type: WebhookEventType,
+        data: &T,
+    ) -> Result<(), Error> {
+        let mut webhooks = Vec::new();
+        for merchant_id in merchant_ids.iter().collect::<HashSet<_>>() {
+            webhooks.extend(
+                self.webhooks
+                    .get(*merchant_id)
+                    .into_iter()
+                    .flat_map(|webhooks| webhooks.values().cloned()),
+            );
+        }
+        for delivery in WebhookDelivery::for_event(&webhooks, event_type, data)? {
+            self.deliveries
+                .entry(delivery.webhook_id.clone())
+                .or_default()
+                .insert(delivery.id.clone(), delivery);
+        }
+        Ok(())
+    }
+
+    fn add_audit_entry(&mut self, audit_entry: AuditEntry) {
+        self.audit_log
+            .entry(audit_entry.partition_key())
+            .or_default()
+            .insert(audit_entry.id.clone(), audit_entry);
+    }
+
+    fn record_hierarchy_change(
+        &mut self,
+        merchant_id: &str,
+        action: HierarchyAction,
+        previous_parent_id: Option<&str>,
+        new_parent_id: Option<&str>,
+        actor: &Actor,
+    ) {
+        let changed_at = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        self.hierarchy_changes
+            .entry(merchant_id.to_string())
+            .or_default()
+            .insert(
+                format!("{HIERARCHY_PREFIX}#{changed_at}"),
+                HierarchyChange {
+                    merchant_id: merchant_id.to_string(),
+                    action,
+                    previous_parent_id: previous_parent_id.map(str::to_string),
+                    new_parent_id: new_parent_id.map(str::to_string),
+                    changed_at,
+                    changed_by_role: actor.role.map(|role| role.to_string()),
+                },
+            );
+    }
+
+    /// Checks the Group > Chain > Outlet ordering and that `parent` is not `child` or one of its
+    /// descendants.
+    fn validate_placement(&self, parent: &Merchant, child: &Merchant) -> Result<(), Error> {
+        if parent.id == child.id {
+            bail!(AppError::validation(format!(
+                "Merchant {} cannot be its own sub merchant",
+                child.id
+            )));
+        }
+        if !parent.merchant_level.can_contain(child.merchant_level) {
+            bail!(AppError::validation(format!(
+                "A {} merchant cannot contain a {} merchant",
+                parent.merchant_level, child.merchant_level
+            )));
+        }
+
+        let mut ancestor_id = parent.parent_merchant_id.clone();
+        for _ in 0..MAX_HIERARCHY_DEPTH {
+            let Some(id) = ancestor_id else {
+                return Ok(());
+            };
+            if id == child.id {
+                bail!(AppError::validation(format!(
+                    "Attaching {} under {} would create a cycle",
+                    child.id, parent.id
+                )));
+            }
+            ancestor_id = self.merchant(&id)?.parent_merchant_id.clone();
+        }
+        bail!(
+            "Merchant hierarchy above {} is deeper than expected",
+            parent.id
+        )
+    }
+}
+
+#[async_trait]
+impl MerchantStore for MemoryStore {
+    async fn add_merchant(&self, merchant: &Merchant) -> Result<(), Error> {
+        tracing::debug!(merchant_id = %merchant.id, "Adding merchant");
+        self.tables()
+            .merchants
+            .insert(merchant.id.clone(), merchant.clone());
+        Ok(())
+    }
+
+    async fn get_merchant(&self, merchant_id: String) -> Result<Merchant, Error> {
+        self.tables().merchant(&merchant_id).cloned()
+    }
+
+    async fn get_settlement_merchant_id(&self, outlet_id: &str) -> Result<String, Error> {
+        let tables = self.tables();
+        let mut merchant_id = outlet_id.to_string();
+        for _ in 0..MAX_HIERARCHY_DEPTH {
+            let merchant = tables
+                .merchant(&merchant_id)
+                .with_context(|| format!("Failed to get merchant {merchant_id}"))?;
+            if merchant.has_settlement_permissions {
+                return Ok(merchant.id.clone());
+            }
+            match &merchant.parent_merchant_id {
+                Some(parent_id) => merchant_id = parent_id.clone(),
+                None => return Ok(outlet_id.to_string()),
+            }
+        }
+        bail!("Merchant hierarchy above {outlet_id} is deeper than expected")
+    }
+
+    async fn get_descendant_outlets(&self, merchant_id: String) -> Result<Vec<String>, Error> {
+        let tables = self.tables();
+        let mut outlets = Vec::new();
+        let mut visited = HashSet::new();
+        let mut level = vec![merchant_id.clone()];
+        for _ in 0..MAX_HIERARCHY_DEPTH {
+            if level.is_empty() {
+                return Ok(outlets);
+            }
+            let mut next_level = Vec::new();
+            for id in level {
+                if !visited.insert(id.clone()) {
+                    continue;
+                }
+                let merchant = tables.merchant(&id)?;
+                match merchant.merchant_level {
+                    MerchantLevel::Outlet => outlets.push(merchant.id.clone()),
+                    MerchantLevel::Group | MerchantLevel::Chain => {
+                        next_level.extend(merchant.sub_merchants.iter().cloned())
+                    }
+                }
+            }
+            level = next_level;
+        }
+        bail!("Merchant hierarchy below {merchant_id} is deeper than expected")
+    }
+
+    async fn attach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let parent = tables.merchant(parent_id)?;
+        let child = tables.merchant(child_id)?;
+        if let Some(current_parent_id) = &child.parent_merchant_id {
+            bail!(AppError::conflict(format!(
+                "Merchant {child_id} is already attached to {current_parent_id}, use moveMerchant instead"
+            )));
+        }
+        tables.validate_placement(parent, child)?;
+        if parent.sub_merchants.iter().any(|id| id == child_id) {
+            bail!(AppError::conflict(format!(
+                "Failed to attach sub merchant: {child_id} is already listed under {parent_id}"
+            )));
+        }
+        let audit_entry = hierarchy_audit_entry(actor, "attachSubMerchant", child, Some(parent_id));
+
+        tables
+            .merchant_mut(parent_id)?
+            .sub_merchants
+            .push(child_id.to_string());
+        tables.merchant_mut(child_id)?.parent_merchant_id = Some(parent_id.to_string());
+        tables.record_hierarchy_change(
+            child_id,
+            HierarchyAction::Attach,
+            None,
+            Some(parent_id),
+            actor,
+        );
+        tables.add_audit_entry(audit_entry);
+        Ok(())
+    }
+
+    async fn detach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let parent = tables.merchant(parent_id)?;
+        let child = tables.merchant(child_id)?;
+        if child.parent_merchant_id.as_deref() != Some(parent_id) {
+            bail!(AppError::conflict(format!(
+                "Merchant {child_id} is not a sub merchant of {parent_id}"
+            )));
+        }
+        let Some(index) = parent.sub_merchants.iter().position(|id| id == child_id) else {
+            bail!(AppError::conflict(format!(
+                "Merchant {child_id} is not listed as a sub merchant of {parent_id}"
+            )));
+        };
+        let audit_entry = hierarchy_audit_entry(actor, "detachSubMerchant", child, None);
+
+        tables.merchant_mut(parent_id)?.sub_merchants.remove(index);
+        tables.merchant_mut(child_id)?.parent_merchant_id = None;
+        tables.record_hierarchy_change(
+            child_id,
+            HierarchyAction::Detach,
+            Some(parent_id),
+            None,
+            actor,
+        );
+        tables.add_audit_entry(audit_entry);
+        Ok(())
+    }
+
+    async fn move_merchant(
+        &self,
+        merchant_id: &str,
+        new_parent_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let merchant = tables.merchant(merchant_id)?;
+        let Some(old_parent_id) = merchant.parent_merchant_id.clone() else {
+            bail!(AppError::conflict(format!(
+                "Merchant {merchant_id} has no parent, use attachSubMerchant instead"
+            )));
+        };
+        if old_parent_id == new_parent_id {
+            bail!(AppError::conflict(format!(
+                "Merchant {merchant_id} is already attached to {new_parent_id}"
+            )));
+        }
+        let old_parent = tables.merchant(&old_parent_id)?;
+        let new_parent = tables.merchant(new_parent_id)?;
+        tables.validate_placement(new_parent, merchant)?;
+        let Some(index) = old_parent
+            .sub_merchants
+            .iter()
+            .position(|id| id == merchant_id)
+        else {
+            bail!(AppError::conflict(format!(
+                "Merchant {merchant_id} is not listed as a sub merchant of {old_parent_id}"
+            )));
+        };
+        if new_parent.sub_merchants.iter().any(|id| id == merchant_id) {
+            bail!(AppError::conflict(format!(
+                "Failed to move merchant: {merchant_id} is already listed under {new_parent_id}"
+            )));
+        }
+        let audit_entry =
+            hierarchy_audit_entry(actor, "moveMerchant", merchant, Some(new_parent_id));
+
+        tables
+            .merchant_mut(&old_parent_id)?
+            .sub_merchants
+            .remove(index);
+        tables
+            .merchant_mut(new_parent_id)?
+            .sub_merchants
+            .push(merchant_id.to_string());
+        tables.merchant_mut(merchant_id)?.parent_merchant_id = Some(new_parent_id.to_string());
+        tables.record_hierarchy_change(
+            merchant_id,
+            HierarchyAction::Move,
+            Some(&old_parent_id),
+            Some(new_parent_id),
+            actor,
+        );
+        tables.add_audit_entry(audit_entry);
+        Ok(())
+    }
+
+    async fn get_hierarchy_history(
+        &self,
+        merchant_id: String,
+    ) -> Result<Vec<HierarchyChange>, Error> {
+        Ok(self
+            .tables()
+            .hierarchy_changes
+            .get(&merchant_id)
+            .map(|changes| changes.values().rev().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        tracing::debug!(merchant_id = %webhook.merchant_id, webhook_id = %webhook.id, "Adding webhook");
+        let mut tables = self.tables();
+        let webhooks = tables
+            .webhooks
+            .entry(webhook.merchant_id.clone())
+            .or_default();
+        if webhooks.contains_key(&webhook.id) {
+            bail!(AppError::conflict(format!(
+                "Failed to add webhook: {} already exists",
+                webhook.id
+            )));
+        }
+        webhooks.insert(webhook.id.clone(), webhook.clone());
+        tables.add_audit_entry(
+            webhook_audit_entry(actor, "registerWebhook", webhook)
+                .with_after(&webhook.audit_image()),
+        );
+        Ok(())
+    }
+
+    async fn get_webhooks(&self, merchant_id: &str) -> Result<Vec<Webhook>, Error> {
+        Ok(self
+            .tables()
+            .webhooks
+            .get(merchant_id)
+            .map(|webhooks| webhooks.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_webhook(
+        &self,
+        merchant_id: &str,
+        webhook_id: &str,
+    ) -> Result<Option<Webhook>, Error> {
+        Ok(self
+            .tables()
+            .webhooks
+            .get(merchant_id)
+            .and_then(|webhooks| webhooks.get(webhook_id))
+            .cloned())
+    }
+
+    async fn delete_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        let mut tables = self.tables();
+        tables
+            .webhooks
+            .get_mut(&webhook.merchant_id)
+            .and_then(|webhooks| webhooks.remove(&webhook.id))
+            .with_context(|| AppError::not_found("Webhook not found"))?;
+        tables.add_audit_entry(
+            webhook_audit_entry(actor, "deleteWebhook", webhook)
+                .with_before(&webhook.audit_image()),
+        );
+        Ok(())
+    }
+
+    async fn get_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<WebhookDelivery>, bool), Error> {
+        let tables = self.tables();
+        let deliveries = tables
+            .deliveries
+            .get(webhook_id)
+            .into_iter()
+            .flat_map(|deliveries| deliveries.values().rev())
+            .filter(|delivery| {
+                page.after
+                    .as_ref()
+                    .map_or(true, |after| delivery.id < *after)
+                    && page
+                        .before
+                        .as_ref()
+                        .map_or(true, |before| delivery.id > *before)
+            })
+            .cloned();
+        read_page(deliveries, &page)
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: &str,
+        limit: i32,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        let tables = self.tables();
+        let mut due: Vec<&WebhookDelivery> = tables
+            .deliveries
+            .values()
+            .flat_map(|deliveries| deliveries.values())
+            .filter(|delivery| {
+                delivery
+                    .next_attempt_at
+                    .as_deref()
+                    .is_some_and(|next_attempt_at| next_attempt_at <= now)
+            })
+            .collect();
+        due.sort_by(|a, b| a.next_attempt_at.cmp(&b.next_attempt_at));
+        let (due, _) = read_limited(due.into_iter().cloned(), limit)?;
+        Ok(due)
+    }
+
+    async fn claim_webhook_delivery(
+        &self,
+        delivery: &WebhookDelivery,
+        lease_until: &str,
+    ) -> Result<bool, Error> {
+        let Some(next_attempt_at) = &delivery.next_attempt_at else {
+            return Ok(false);
+        };
+        let mut tables = self.tables();
+        let Some(stored) = tables
+            .deliveries
+            .get_mut(&delivery.webhook_id)
+            .and_then(|deliveries| deliveries.get_mut(&delivery.id))
+        else {
+            return Ok(false);
+        };
+        if stored.next_attempt_at.as_ref() != Some(next_attempt_at) {
+            return Ok(false);
+        }
+        stored.next_attempt_at = Some(lease_until.to_string());
+        Ok(true)
+    }
+
+    async fn record_webhook_delivery_attempt(
+        &self,
+        delivery: &WebhookDelivery,
+        attempt: &DeliveryAttempt,
+        outcome: &DeliveryOutcome,
+    ) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let stored = tables
+            .deliveries
+            .get_mut(&delivery.webhook_id)
+            .and_then(|deliveries| deliveries.get_mut(&delivery.id))
+            .with_context(|| {
+                AppError::not_found("Failed to record webhook delivery attempt: delivery not found")
+            })?;
+        stored.attempts.push(attempt.clone());
+        match outcome {
+            DeliveryOutcome::Retry { next_attempt_at } => {
+                stored.next_attempt_at = Some(next_attempt_at.clone());
+            }
+            DeliveryOutcome::Delivered => {
+                stored.status = DeliveryStatus::Delivered;
+                stored.next_attempt_at = None;
+            }
+            DeliveryOutcome::Failed => {
+                stored.status = DeliveryStatus::Failed;
+                stored.next_attempt_at = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TransactionStore for MemoryStore {
+    async fn add_transaction(&self, transaction: Transaction) -> Result<bool, Error> {
+        tracing::debug!(
+            merchant_id = %transaction.merchant_id,
+            pan = %mask_pan(&transaction.pan),
+            "Adding transaction"
+        );
+        {
+            let mut tables = self.tables();
+            let transactions = tables
+                .transactions
+                .entry(transaction.merchant_id.clone())
+                .or_default();
+            if transactions.contains_key(&transaction.id) {
+                return Ok(false);
+            }
+            transactions.insert(transaction.id.clone(), transaction.clone());
+            tables.add_aggregates(&transaction, &AggregateDelta::of(&transaction));
+        }
+        publish(TransactionEvent::Recorded(transaction));
+        Ok(true)
+    }
+
+    async fn record_transaction(
+        &self,
+        transaction: Transaction,
+        actor: &Actor,
+    ) -> Result<bool, Error> {
+        let audit_entry = transaction_audit_entry(actor, "recordTransaction", None, &transaction);
+        {
+            let mut tables = self.tables();
+            let transactions = tables
+                .transactions
+                .entry(transaction.merchant_id.clone())
+                .or_default();
+            if transactions.contains_key(&transaction.id) {
+                return Ok(false);
+            }
+            transactions.insert(transaction.id.clone(), transaction.clone());
+            tables.add_aggregates(&transaction, &AggregateDelta::of(&transaction));
+            tables.add_audit_entry(audit_entry);
+        }
+        publish(TransactionEvent::Recorded(transaction));
+        Ok(true)
+    }
+
+    async fn update_transaction_status(
+        &self,
+        merchant_id: &str,
+        transaction_id: &str,
+        status: TransactionStatus,
+        actor: &Actor,
+    ) -> Result<TransactionStatusChange, Error> {
+        tracing::debug!(%merchant_id, %transaction_id, %status, "Updating transaction status");
+        let change = {
+            let mut tables = self.tables();
+            let previous = tables
+                .transactions
+                .get(merchant_id)
+                .and_then(|transactions| transactions.get(transaction_id))
+                .cloned()
+                .with_context(|| AppError::not_found("Transaction not found"))?;
+            let previous_status = previous.status;
+            let mut transaction = previous.clone();
+            transaction.status = status;
+
+            if status == TransactionStatus::Chargebacked && previous_status != status {
+                let mut masked = transaction.clone();
+                masked.pan = mask_pan(&masked.pan);
+                tables.queue_webhook_deliveries(
+                    &[
+                        transaction.merchant_id.as_str(),
+                        transaction.settlement_merchant_id.as_str(),
+                    ],
+                    WebhookEventType::TransactionChargebacked,
+                    &masked,
+                )?;
+            }
+            let delta = AggregateDelta::between(&previous, &transaction);
+            if !delta.is_zero() {
+                tables.add_aggregates(&transaction, &delta);
+            }
+            tables.add_audit_entry(transaction_audit_entry(
+                actor,
+                "updateTransactionStatus",
+                Some(&previous),
+                &transaction,
+            ));
+            if let Some(stored) = tables
+                .transactions
+                .get_mut(merchant_id)
+                .and_then(|transactions| transactions.get_mut(transaction_id))
+            {
+                stored.status = status;
+            }
+            TransactionStatusChange {
+                transaction,
+                previous_status,
+            }
+        };
+        publish(TransactionEvent::StatusChanged(change.clone()));
+        Ok(change)
+    }
+
+    async fn get_transactions(
+        &self,
+        merchant_id: String,
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        let cursors = [page.after.clone(), page.before.clone()];
+        let (earlier_transaction, later_transaction) = narrow_to_cursors(
+            transaction_sort_key_range(year, month, day),
+            page.after.clone(),
+            page.before.clone(),
+        );
+        let tables = self.tables();
+        let (transactions, has_more) = read_page(
+            tables
+                .transactions_between(&merchant_id, &earlier_transaction, &later_transaction)?
+                .filter(|transaction| !cursors.contains(&Some(transaction.id.clone()))),
+            &page,
+        )?;
+        Ok((
+            transactions
+                .into_iter()
+                .filter(|transaction| {
+                    card_brand.map_or(true, |brand| transaction.card_brand == brand)
+                })
+                .cloned()
+                .collect(),
+            has_more,
+        ))
+    }
+
+    async fn get_transactions_for_merchants(
+        &self,
+        merchant_ids: &[String],
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        let (earlier_transaction, later_transaction) = transaction_sort_key_range(year, month, day);
+        self.get_transactions_for_merchants_between(
+            merchant_ids,
+            &earlier_transaction,
+            &later_transaction,
+            card_brand,
+            page,
+        )
+        .await
+    }
+
+    async fn get_transactions_for_merchants_between(
+        &self,
+        merchant_ids: &[String],
+        earlier_transaction: &str,
+        later_transaction: &str,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        let tables = self.tables();
+        let mut transactions = Vec::new();
+        for merchant_id in merchant_ids {
+            // Cursor sort keys narrow the range inclusively; ties are resolved on merchant id.
+            let (earlier_transaction, later_transaction) = narrow_to_cursors(
+                (
+                    earlier_transaction.to_string(),
+                    later_transaction.to_string(),
+                ),
+                page.after.as_ref().map(|cursor| cursor.id.clone()),
+                page.before.as_ref().map(|cursor| cursor.id.clone()),
+            );
+            if earlier_transaction > later_transaction {
+                continue;
+            }
+            let mut rows: Vec<&Transaction> = tables
+                .transactions_between(merchant_id, &earlier_transaction, &later_transaction)?
+                .filter(|transaction| {
+                    card_brand.map_or(true, |brand| transaction.card_brand == brand)
+                        && page
+                            .after
+                            .as_ref()
+                            .map_or(true, |cursor| is_after(transaction, cursor))
+                        && page
+                            .before
+                            .as_ref()
+                            .map_or(true, |cursor| is_before(transaction, cursor))
+                })
+                .collect();
+            // Each merchant contributes the limit + 1 rows nearest the page's cursor.
+            if page.backward {
+                rows.drain(..rows.len().saturating_sub(page.limit + 1));
+            } else {
+                rows.truncate(page.limit + 1);
+            }
+            transactions.extend(rows.into_iter().cloned());
+        }
+        transactions.sort_by(|a, b| merged_order(position(a), position(b)));
+        Ok(take_page(transactions, page))
+    }
+
+    async fn get_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        let (earlier_transaction, later_transaction) = narrow_to_cursors(
+            transaction_date_range(None, None),
+            page.after.clone(),
+            page.before.clone(),
+        );
+        let tables = self.tables();
+        let (transactions, has_more) = read_page(
+            tables
+                .settled_between(
+                    &settlement_merchant_id,
+                    &earlier_transaction,
+                    &later_transaction,
+                )?
+                .into_iter()
+                .cloned(),
+            &page,
+        )?;
+        Ok((transactions, has_more))
+    }
+
+    async fn get_all_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_transaction: &str,
+        later_transaction: &str,
+    ) -> Result<Vec<Transaction>, Error> {
+        Ok(self
+            .tables()
+            .settled_between(
+                settlement_merchant_id,
+                earlier_transaction,
+                later_transaction,
+            )?
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    async fn get_transaction_aggregates(
+        &self,
+        merchant_id: &str,
+        period: AggregatePeriod,
+        date: &str,
+    ) -> Result<Vec<TransactionAggregate>, Error> {
+        period.validate(date)?;
+        let prefix = period.sort_key_prefix(date);
+        Ok(self
+            .tables()
+            .aggregates
+            .get(merchant_id)
+            .into_iter()
+            .flat_map(|aggregates| aggregates.range(prefix.clone()..))
+            .take_while(|(sort_key, _)| sort_key.starts_with(&prefix))
+            .map(|(_, aggregate)| aggregate.clone())
+            .collect())
+    }
+
+    async fn add_payout(&self, payout: &Payout, actor: &Actor) -> Result<(), Error> {
+        tracing::debug!(merchant_id = %payout.merchant_id, payout_id = %payout.id, "Adding payout");
+        let mut tables = self.tables();
+        if tables
+            .payouts
+            .get(&payout.merchant_id)
+            .is_some_and(|payouts| payouts.contains_key(&payout.id))
+        {
+            bail!(AppError::conflict(format!(
+                "Payout {} already exists",
+                payout.id
+            )));
+        }
+        tables.queue_webhook_deliveries(
+            &[payout.merchant_id.as_str()],
+            WebhookEventType::PayoutCreated,
+            payout,
+        )?;
+        tables
+            .payouts
+            .entry(payout.merchant_id.clone())
+            .or_default()
+            .insert(payout.id.clone(), payout.clone());
+        tables.add_audit_entry(
+            AuditEntry::new(
+                actor,
+                "createPayout",
+                vec![payout.merchant_id.clone(), payout.id.clone()],
+            )
+            .with_after(payout),
+        );
+        Ok(())
+    }
+
+    async fn get_payouts(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_payout: &str,
+        later_payout: &str,
+    ) -> Result<Vec<Payout>, Error> {
+        if earlier_payout > later_payout {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .tables()
+            .payouts
+            .get(settlement_merchant_id)
+            .map(|payouts| {
+                payouts
+                    .range(earlier_payout.to_string()..=later_payout.to_string())
+                    .rev()
+                    .map(|(_, payout)| payout.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl AuditStore for MemoryStore {
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), Error> {
+        self.tables().add_audit_entry(audit_entry.clone());
+        Ok(())
+    }
+
+    async fn get_audit_log(
+        &self,
+        date: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<AuditEntry>, bool), Error> {
+        let tables = self.tables();
+        let audit_entries = tables
+            .audit_log
+            .get(&audit_partition_key(&date))
+            .into_iter()
+            .flat_map(|audit_entries| audit_entries.values().rev())
+            .filter(|audit_entry| {
+                page.after
+                    .as_ref()
+                    .map_or(true, |after| audit_entry.id < *after)
+                    && page
+                        .before
+                        .as_ref()
+                        .map_or(true, |before| audit_entry.id > *before)
+            })
+            .cloned();
+        read_page(audit_entries, &page)
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn check_ready(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use actix_web::{HttpResponse, Result};
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::OnceLock, time::Duration};
+
+pub struct Metrics {
+    registry: Registry,
+    graphql_requests: IntCounterVec,
+    graphql_latency: HistogramVec,
+    graphql_rate_limited: IntCounterVec,
+    dynamodb_calls: IntCounterVec,
+    dynamodb_errors: IntCounterVec,
+    dynamodb_throttles: IntCounterVec,
+    dynamodb_latency: HistogramVec,
+    dynamodb_consumed_capacity: CounterVec,
+    dynamodb_rejections: IntCounterVec,
+    dynamodb_circuit_open: IntGauge,
+    cache_lookups: IntCounterVec,
+}
+
+/// Whether a DynamoDB call consumes read or write capacity.
+#[derive(Copy, Clone)]
+pub enum CapacityKind {
+    Read,
+    Write,
+}
+
+impl CapacityKind {
+    fn label(self) -> &'static str {
+        match self {
+            CapacityKind::Read => "read",
+            CapacityKind::Write => "write",
+        }
+    }
+}
+
+/// Process-wide metrics, registered on first use.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let graphql_requests = IntCounterVec::new(
+            Opts::new("graphql_requests_total", "GraphQL operations executed"),
+            &["operation", "outcome"],
+        )
+        .expect("valid graphql_requests_total metric");
+        let graphql_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "graphql_request_duration_seconds",
+                "GraphQL operation execution time",
+            ),
+            &["operation"],
+        )
+        .expect("valid graphql_request_duration_seconds metric");
+        let graphql_rate_limited = IntCounterVec::new(
+            Opts::new(
+                "graphql_rate_limited_total",
+                "GraphQL requests rejected by the rate limit",
+            ),
+            &["role"],
+        )
+        .expect("valid graphql_rate_limited_total metric");
+        let dynamodb_calls = IntCounterVec::new(
+            Opts::new("dynamodb_calls_total", "DynamoDB calls made"),
+            &["operation"],
+        )
+        .expect("valid dynamodb_calls_total metric");
+        let dynamodb_errors = IntCounterVec::new(
+            Opts::new("dynamodb_errors_total", "DynamoDB calls that failed"),
+            &["operation"],
+        )
+        .expect("valid dynamodb_errors_total metric");
+        let dynamodb_throttles = IntCounterVec::new(
+            Opts::new(
+                "dynamodb_throttles_total",
+                "DynamoDB calls rejected by throttling",
+            ),
+            &["operation"],
+        )
+        .expect("valid dynamodb_throttles_total metric");
+        let dynamodb_latency = HistogramVec::new(
+            HistogramOpts::new("dynamodb_call_duration_seconds", "DynamoDB call latency"),
+            &["operation"],
+        )
+        .expect("valid dynamodb_call_duration_seconds metric");
+        let dynamodb_consumed_capacity = CounterVec::new(
+            Opts::new(
+                "dynamodb_consumed_capacity_units_total",
+                "Capacity units consumed by DynamoDB calls",
+            ),
+            &["operation", "kind"],
+        )
+        .expect("valid dynamodb_consumed_capacity_units_total metric");
+        let dynamodb_rejections = IntCounterVec::new(
+            Opts::new(
+                "dynamodb_circuit_rejections_total",
+                "DynamoDB calls refused by the open circuit breaker",
+            ),
+            &["operation"],
+        )
+        .expect("valid dynamodb_circuit_rejections_total metric");
+        let dynamodb_circuit_open = IntGauge::new(
+            "dynamodb_circuit_open",
+            "Whether the DynamoDB circuit breaker refuses calls",
+        )
+        .expect("valid dynamodb_circuit_open metric");
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Lookups of the merchant cache"),
+            &["cache", "outcome"],
+        )
+        .expect("valid cache_lookups_total metric");
+
+        for collector in [
+            Box::new(graphql_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(graphql_latency.clone()),
+            Box::new(graphql_rate_limited.clone()),
+            Box::new(dynamodb_calls.clone()),
+            Box::new(dynamodb_errors.clone()),
+            Box::new(dynamodb_throttles.clone()),
+            Box::new(dynamodb_latency.clone()),
+            Box::new(dynamodb_consumed_capacity.clone()),
+            Box::new(dynamodb_rejections.clone()),
+            Box::new(dynamodb_circuit_open.clone()),
+            Box::new(cache_lookups.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric registered once");
+        }
+
+        Self {
+            registry,
+            graphql_requests,
+            graphql_latency,
+            graphql_rate_limited,
+            dynamodb_calls,
+            dynamodb_errors,
+            dynamodb_throttles,
+            dynamodb_latency,
+            dynamodb_consumed_capacity,
+            dynamodb_rejections,
+            dynamodb_circuit_open,
+            cache_lookups,
+        }
+    }
+
+    pub fn observe_graphql(&self, operation: &str, failed: bool, elapsed: Duration) {
+        let outcome = if failed { "error" } else { "ok" };
+        self.graphql_requests
+            .with_label_values(&[operation, outcome])
+            .inc();
+        self.graphql_latency
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_rate_limited(&self, role: &str) {
+        self.graphql_rate_limited.with_label_values(&[role]).inc();
+    }
+
+    pub fn observe_dynamodb(
+        &self,
+        operation: &str,
+        elapsed: Duration,
+        consumed_capacity: Option<(CapacityKind, f64)>,
+    ) {
+        self.dynamodb_calls.with_label_values(&[operation]).inc();
+        self.dynamodb_latency
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+        if let Some((kind, units)) = consumed_capacity {
+            self.dynamodb_consumed_capacity
+                .with_label_values(&[operation, kind.label()])
+                .inc_by(units);
+        }
+    }
+
+    pub fn observe_dynamodb_error(&self, operation: &str, throttled: bool) {
+        self.dynamodb_errors.with_label_values(&[operation]).inc();
+        if throttled {
+            self.dynamodb_throttles
+                .with_label_values(&[operation])
+                .inc();
+        }
+    }
+
+    pub fn observe_dynamodb_rejection(&self, operation: &str) {
+        self.dynamodb_rejections
+            .with_label_values(&[operation])
+            .inc();
+    }
+
+    pub fn set_dynamodb_circuit_open(&self, open: bool) {
+        self.dynamodb_circuit_open.set(open.into());
+    }
+
+    pub fn observe_cache_lookup(&self, cache: &str, hit: bool) {
+        let outcome = if hit { "hit" } else { "miss" };
+        self.cache_lookups
+            .with_label_values(&[cache, outcome])
+            .inc();
+    }
+}
+
+/// Serves the registry in the Prometheus text format.
+pub async fn metrics_endpoint() -> Result<HttpResponse> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics().registry.gather(), &mut buffer)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(buffer))
+}
@@ -0,0 +1,598 @@
+use crate::aggregates::{
+    AGGREGATE_PREFIX, AggregateDelta, AggregateKey, TransactionAggregate, fold,
+};
+use crate::dynamo::{
+    GSI1_INDEX, GSI1_PARTITION_KEY, GSI1_SORT_KEY, MERCHANT_PREFIX, PARTITION_KEY, SORT_KEY,
+    TRANSACTION_PREFIX, TTL_ATTRIBUTE, Table, batch_write, replace_key_names,
+    transaction_aggregate_item,
+};
+use crate::errors::SdkContext;
+use crate::models::Transaction;
+use actix_web::rt::time::sleep;
+use anyhow::{Context, Error, bail};
+use aws_sdk_dynamodb::error::ProvideErrorMetadata;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, AttributeValue, BillingMode, CreateGlobalSecondaryIndexAction,
+    DeleteRequest, GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType, Projection,
+    ProjectionType, PutRequest, ReturnConsumedCapacity, ScalarAttributeType, StreamSpecification,
+    StreamViewType, TableDescription, TableStatus, TimeToLiveSpecification, TimeToLiveStatus,
+    WriteRequest,
+};
+use chrono::Utc;
+use serde_dynamo::aws_sdk_dynamodb_1::from_item;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+const SCHEMA_PARTITION_KEY: &str = "SCHEMA";
+const SCHEMA_SORT_KEY: &str = "VERSION";
+const ACTIVE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const ACTIVE_POLL_ATTEMPTS: u32 = 120;
+
+/// Schema changes in the order they are applied. Every step checks the live table first, so
+/// re-running a migration that was interrupted is safe.
+#[derive(Clone, Copy, Debug)]
+enum Migration {
+    CreateTable,
+    AddGsi1,
+    BackfillParentMerchantIds,
+    EnableTtl,
+    EnableStreams,
+    BackfillTransactionAggregates,
+}
+
+const MIGRATIONS: [Migration; 6] = [
+    Migration::CreateTable,
+    Migration::AddGsi1,
+    Migration::BackfillParentMerchantIds,
+    Migration::EnableTtl,
+    Migration::EnableStreams,
+    Migration::BackfillTransactionAggregates,
+];
+
+impl Migration {
+    fn version(self) -> u32 {
+        match self {
+            Migration::CreateTable => 1,
+            Migration::AddGsi1 => 2,
+            Migration::BackfillParentMerchantIds => 3,
+            Migration::EnableTtl => 4,
+            Migration::EnableStreams => 5,
+            Migration::BackfillTransactionAggregates => 6,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Migration::CreateTable => "create the merchants table",
+            Migration::AddGsi1 => "add the gsi1 settlement merchant index",
+            Migration::BackfillParentMerchantIds => {
+                "backfill parent_merchant_id from parents' sub_merchants"
+            }
+            Migration::EnableTtl => "enable TTL on expires_at",
+            Migration::EnableStreams => "enable the table stream with old and new images",
+            Migration::BackfillTransactionAggregates => {
+                "backfill the day, month and year transaction aggregates"
+            }
+        }
+    }
+
+    async fn apply(self, table: &Table, dry_run: bool) -> Result<(), Error> {
+        match self {
+            Migration::CreateTable => create_table(table, dry_run).await,
+            Migration::AddGsi1 => add_gsi1(table, dry_run).await,
+            Migration::BackfillParentMerchantIds => {
+                backfill_parent_merchant_ids(table, dry_run).await
+            }
+            Migration::EnableTtl => enable_ttl(table, dry_run).await,
+            Migration::EnableStreams => enable_streams(table, dry_run).await,
+            Migration::BackfillTransactionAggregates => {
+                backfill_transaction_aggregates(table, dry_run).await
+            }
+        }
+    }
+}
+
+/// Applies every migration newer than the stored schema version. With `dry_run` nothing is
+/// written; each pending step only logs what it would do.
+pub async fn run(table: &Table, dry_run: bool) -> Result<(), Error> {
+    let current_version = schema_version(table).await?;
+    let pending: Vec<Migration> = MIGRATIONS
+        .into_iter()
+        .filter(|migration| migration.version() > current_version)
+        .collect();
+    tracing::info!(
+        current_version,
+        pending = pending.len(),
+        dry_run,
+        "Running migrations"
+    );
+
+    for migration in pending {
+        tracing::info!(
+            version = migration.version(),
+            description = migration.description(),
+            "Applying migration"
+        );
+        migration
+            .apply(table, dry_run)
+            .await
+            .with_context(|| format!("Migration {} failed", migration.version()))?;
+        if !dry_run {
+            record_schema_version(table, migration).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The last applied migration, or 0 when the table does not exist yet.
+async fn schema_version(table: &Table) -> Result<u32, Error> {
+    let Some(description) = describe_table(table).await? else {
+        return Ok(0);
+    };
+    let get_item = table
+        .client
+        .get_item()
+        .table_name(&table.name)
+        .key(
+            PARTITION_KEY,
+            AttributeValue::S(SCHEMA_PARTITION_KEY.to_string()),
+        )
+        .key(SORT_KEY, AttributeValue::S(SCHEMA_SORT_KEY.to_string()))
+        .consistent_read(true)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send();
+    let item_resp = table
+        .observe("get_schema_version", None, get_item)
+        .await
+        .sdk_context("Failed to read schema version")?;
+    match item_resp.item.as_ref().and_then(|item| item.get("version")) {
+        Some(AttributeValue::N(version)) => version.parse().context("Invalid schema version"),
+        // Tables created before migrations existed have the table, and gsi1 unless it was never
+        // added by hand.
+        None if index_status(&description, GSI1_INDEX).is_some() => {
+            Ok(Migration::AddGsi1.version())
+        }
+        None => Ok(Migration::CreateTable.version()),
+        Some(other) => bail!("Invalid schema version {other:?}"),
+    }
+}
+
+async fn record_schema_version(table: &Table, migration: Migration) -> Result<(), Error> {
+    let put_item = table
+        .client
+        .put_item()
+        .table_name(&table.name)
+        .item(
+            PARTITION_KEY,
+            AttributeValue::S(SCHEMA_PARTITION_KEY.to_string()),
+        )
+        .item(SORT_KEY, AttributeValue::S(SCHEMA_SORT_KEY.to_string()))
+        .item(
+            "version",
+            AttributeValue::N(migration.version().to_string()),
+        )
+        .item(
+            "description",
+            AttributeValue::S(migration.description().to_string()),
+        )
+        .item("applied_at", AttributeValue::S(Utc::now().to_rfc3339()))
+        .condition_expression("attribute_not_exists(#version) OR #version < :version")
+        .expression_attribute_names("#version", "version")
+        .expression_attribute_values(
+            ":version",
+            AttributeValue::N(migration.version().to_string()),
+        )
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send();
+    table
+        .observe("put_schema_version", None, put_item)
+        .await
+        .sdk_context("Failed to record schema version")?;
+    Ok(())
+}
+
+async fn describe_table(table: &Table) -> Result<Option<TableDescription>, Error> {
+    let describe_table = table.client.describe_table().table_name(&table.name).send();
+    match table.observe("describe_table", None, describe_table).await {
+        Ok(resp) => Ok(resp.table),
+        Err(err) if err.code() == Some("ResourceNotFoundException") => Ok(None),
+        Err(err) => Err(err).sdk_context("Failed to describe table"),
+    }
+}
+
+fn index_status<'a>(description: &'a TableDescription, index: &str) -> Option<&'a IndexStatus> {
+    description
+        .global_secondary_indexes()
+        .iter()
+        .find(|description| description.index_name() == Some(index))
+        .and_then(|description| description.index_status())
+}
+
+/// Polls until the table, and `index` when given, report `ACTIVE`.
+async fn wait_until_active(table: &Table, index: Option<&str>) -> Result<(), Error> {
+    for _ in 0..ACTIVE_POLL_ATTEMPTS {
+        if let Some(description) = describe_table(table).await? {
+            let index_active = index.map_or(true, |index| {
+                index_status(&description, index) == Some(&IndexStatus::Active)
+            });
+            if description.table_status() == Some(&TableStatus::Active) && index_active {
+                return Ok(());
+            }
+        }
+        sleep(ACTIVE_POLL_INTERVAL).await;
+    }
+    bail!("Table {} did not become ACTIVE", table.name)
+}
+
+fn key_element(attribute_name: &str, key_type: KeyType) -> Result<KeySchemaElement, Error> {
+    Ok(KeySchemaElement::builder()
+        .attribute_name(attribute_name)
+        .key_type(key_type)
+        .build()?)
+}
+
+fn string_attribute(attribute_name: &str) -> Result<AttributeDefinition, Error> {
+    Ok(AttributeDefinition::builder()
+        .attribute_name(attribute_name)
+        .attribute_type(ScalarAttributeType::S)
+        .build()?)
+}
+
+async fn create_table(table: &Table, dry_run: bool) -> Result<(), Error> {
+    if describe_table(table).await?.is_some() {
+        tracing::info!("Table {} already exists", table.name);
+        return Ok(());
+    }
+    if dry_run {
+        tracing::info!("Would create table {}", table.name);
+        return Ok(());
+    }
+
+    let create_table = table
+        .client
+        .create_table()
+        .table_name(&table.name)
+        .key_schema(key_element(PARTITION_KEY, KeyType::Hash)?)
+        .key_schema(key_element(SORT_KEY, KeyType::Range)?)
+        .attribute_definitions(string_attribute(PARTITION_KEY)?)
+        .attribute_definitions(string_attribute(SORT_KEY)?)
+        .billing_mode(BillingMode::PayPerRequest)
+        .send();
+    table
+        .observe("create_table", None, create_table)
+        .await
+        .sdk_context("Failed to create table")?;
+    wait_until_active(table, None).await?;
+    tracing::info!("Created table {}", table.name);
+    Ok(())
+}
+
+async fn add_gsi1(table: &Table, dry_run: bool) -> Result<(), Error> {
+    let description = describe_table(table).await?;
+    if description
+        .as_ref()
+        .is_some_and(|description| index_status(description, GSI1_INDEX).is_some())
+    {
+        tracing::info!("Index {GSI1_INDEX} already exists");
+        return Ok(());
+    }
+    if dry_run {
+        tracing::info!("Would add index {GSI1_INDEX}");
+        return Ok(());
+    }
+
+    let create_index = CreateGlobalSecondaryIndexAction::builder()
+        .index_name(GSI1_INDEX)
+        .key_schema(key_element(GSI1_PARTITION_KEY, KeyType::Hash)?)
+        .key_schema(key_element(GSI1_SORT_KEY, KeyType::Range)?)
+        .projection(
+            Projection::builder()
+                .projection_type(ProjectionType::All)
+                .build(),
+        )
+        .build()?;
+    let update_table = table
+        .client
+        .update_table()
+        .table_name(&table.name)
+        .attribute_definitions(string_attribute(GSI1_PARTITION_KEY)?)
+        .attribute_definitions(string_attribute(GSI1_SORT_KEY)?)
+        .global_secondary_index_updates(
+            GlobalSecondaryIndexUpdate::builder()
+                .create(create_index)
+                .build(),
+        )
+        .send();
+    table
+        .observe("add_index", Some(GSI1_INDEX), update_table)
+        .await
+        .sdk_context("Failed to add index")?;
+    wait_until_active(table, Some(GSI1_INDEX)).await?;
+    tracing::info!("Added index {GSI1_INDEX}");
+    Ok(())
+}
+
+/// Sets `parent_merchant_id` on every merchant listed in a parent's `sub_merchants` that does not
+/// have one yet.
+async fn backfill_parent_merchant_ids(table: &Table, dry_run: bool) -> Result<(), Error> {
+    // In a dry run against a fresh environment the table was never created.
+    if dry_run && describe_table(table).await?.is_none() {
+        tracing::info!("Would backfill parent_merchant_id");
+        return Ok(());
+    }
+
+    let mut exclusive_start_key = None;
+    let mut updated = 0;
+    loop {
+        let scan = table.client
+            .scan()
+            .table_name(&table.name)
+            .set_exclusive_start_key(exclusive_start_key)
+            .filter_expression(
+                "begins_with(#partition_key, :merchant_prefix) AND #partition_key = #sort_key AND size(#sub_merchants) > :zero",
+            )
+            .expression_attribute_names("#partition_key", PARTITION_KEY)
+            .expression_attribute_names("#sort_key", SORT_KEY)
+            .expression_attribute_names("#sub_merchants", "sub_merchants")
+            .expression_attribute_values(
+                ":merchant_prefix",
+                AttributeValue::S(format!("{MERCHANT_PREFIX}#")),
+            )
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let scan_resp = table
+            .observe("scan_parent_merchants", None, scan)
+            .await
+            .sdk_context("Failed to scan merchants")?;
+
+        for parent in scan_resp.items() {
+            let Some(AttributeValue::S(parent_id)) = parent.get(PARTITION_KEY) else {
+                continue;
+            };
+            let Some(AttributeValue::L(sub_merchants)) = parent.get("sub_merchants") else {
+                continue;
+            };
+            for sub_merchant in sub_merchants {
+                let AttributeValue::S(child_id) = sub_merchant else {
+                    continue;
+                };
+                if dry_run {
+                    tracing::info!(%child_id, %parent_id, "Would set parent_merchant_id");
+                    continue;
+                }
+                if set_parent_if_missing(table, child_id, parent_id).await? {
+                    updated += 1;
+                }
+            }
+        }
+
+        exclusive_start_key = scan_resp.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+    tracing::info!(updated, "Backfilled parent_merchant_id");
+    Ok(())
+}
+
+/// Returns whether the merchant was updated; existing parents and missing merchants are left
+/// alone.
+async fn set_parent_if_missing(
+    table: &Table,
+    child_id: &str,
+    parent_id: &str,
+) -> Result<bool, Error> {
+    let update_item = table
+        .client
+        .update_item()
+        .table_name(&table.name)
+        .key(PARTITION_KEY, AttributeValue::S(child_id.to_string()))
+        .key(SORT_KEY, AttributeValue::S(child_id.to_string()))
+        .update_expression("SET #parent_merchant_id = :parent_id")
+        .condition_expression(
+            "attribute_exists(#partition_key) AND attribute_not_exists(#parent_merchant_id)",
+        )
+        .expression_attribute_names("#partition_key", PARTITION_KEY)
+        .expression_attribute_names("#parent_merchant_id", "parent_merchant_id")
+        .expression_attribute_values(":parent_id", AttributeValue::S(parent_id.to_string()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send();
+    match table
+        .observe("set_parent_merchant_id", None, update_item)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(err) if err.code() == Some("ConditionalCheckFailedException") => Ok(false),
+        Err(err) => Err(err).sdk_context("Failed to backfill parent_merchant_id"),
+    }
+}
+
+async fn enable_ttl(table: &Table, dry_run: bool) -> Result<(), Error> {
+    if dry_run && describe_table(table).await?.is_none() {
+        tracing::info!("Would enable TTL on {TTL_ATTRIBUTE}");
+        return Ok(());
+    }
+    let describe_time_to_live = table
+        .client
+        .describe_time_to_live()
+        .table_name(&table.name)
+        .send();
+    let ttl_resp = table
+        .observe("describe_time_to_live", None, describe_time_to_live)
+        .await
+        .sdk_context("Failed to describe TTL")?;
+    let ttl = ttl_resp.time_to_live_description();
+    let status = ttl.and_then(|ttl| ttl.time_to_live_status());
+    if matches!(
+        status,
+        Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+    ) {
+        tracing::info!("TTL already enabled");
+        return Ok(());
+    }
+    if dry_run {
+        tracing::info!("Would enable TTL on {TTL_ATTRIBUTE}");
+        return Ok(());
+    }
+
+    let update_time_to_live = table
+        .client
+        .update_time_to_live()
+        .table_name(&table.name)
+        .time_to_live_specification(
+            TimeToLiveSpecification::builder()
+                .attribute_name(TTL_ATTRIBUTE)
+                .enabled(true)
+                .build()?,
+        )
+        .send();
+    table
+        .observe("update_time_to_live", None, update_time_to_live)
+        .await
+        .sdk_context("Failed to enable TTL")?;
+    tracing::info!("Enabled TTL on {TTL_ATTRIBUTE}");
+    Ok(())
+}
+
+async fn enable_streams(table: &Table, dry_run: bool) -> Result<(), Error> {
+    let stream = describe_table(table)
+        .await?
+        .and_then(|description| description.stream_specification().cloned());
+    if let Some(stream) = stream
+        && stream.stream_enabled()
+    {
+        if stream.stream_view_type() != Some(&StreamViewType::NewAndOldImages) {
+            bail!("The table stream is enabled without old and new images");
+        }
+        tracing::info!("Stream already enabled");
+        return Ok(());
+    }
+    if dry_run {
+        tracing::info!("Would enable the table stream with old and new images");
+        return Ok(());
+    }
+
+    let update_table = table
+        .client
+        .update_table()
+        .table_name(&table.name)
+        .stream_specification(
+            StreamSpecification::builder()
+                .stream_enabled(true)
+                .stream_view_type(StreamViewType::NewAndOldImages)
+                .build()?,
+        )
+        .send();
+    table
+        .observe("enable_stream", None, update_table)
+        .await
+        .sdk_context("Failed to enable the table stream")?;
+    wait_until_active(table, None).await?;
+    tracing::info!("Enabled the table stream");
+    Ok(())
+}
+
+/// Recomputes every aggregate from the stored transactions and overwrites it, deleting those no
+/// transaction counts towards. Transactions written while it runs may be missed, so run it before
+/// taking traffic.
+async fn backfill_transaction_aggregates(table: &Table, dry_run: bool) -> Result<(), Error> {
+    if dry_run && describe_table(table).await?.is_none() {
+        tracing::info!("Would backfill transaction aggregates");
+        return Ok(());
+    }
+
+    let mut deltas: HashMap<AggregateKey, AggregateDelta> = HashMap::new();
+    let mut stored: HashSet<(String, String)> = HashSet::new();
+    let mut transactions = 0;
+    let mut exclusive_start_key = None;
+    loop {
+        let scan = table.client
+            .scan()
+            .table_name(&table.name)
+            .set_exclusive_start_key(exclusive_start_key)
+            .filter_expression(
+                "begins_with(#sort_key, :transaction_prefix) OR begins_with(#sort_key, :aggregate_prefix)",
+            )
+            .expression_attribute_names("#sort_key", SORT_KEY)
+            .expression_attribute_values(
+                ":transaction_prefix",
+                AttributeValue::S(format!("{TRANSACTION_PREFIX}#")),
+            )
+            .expression_attribute_values(
+                ":aggregate_prefix",
+                AttributeValue::S(format!("{AGGREGATE_PREFIX}#")),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let scan_resp = table
+            .observe("scan_transactions", None, scan)
+            .await
+            .sdk_context("Failed to scan transactions")?;
+
+        let mut page: Vec<Transaction> = Vec::new();
+        for item in scan_resp.items.unwrap_or_default() {
+            let (Some(AttributeValue::S(partition_key)), Some(AttributeValue::S(sort_key))) =
+                (item.get(PARTITION_KEY), item.get(SORT_KEY))
+            else {
+                continue;
+            };
+            if sort_key.starts_with(&format!("{AGGREGATE_PREFIX}#")) {
+                stored.insert((partition_key.clone(), sort_key.clone()));
+                continue;
+            }
+            let mut items = vec![item];
+            replace_key_names(&mut items, "merchant_id", "id");
+            page.push(from_item(items.remove(0)).context("failed to deserialise transaction")?);
+        }
+        transactions += page.len();
+        for (key, delta) in fold(&page) {
+            deltas.entry(key).or_default().add(&delta);
+        }
+
+        exclusive_start_key = scan_resp.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    let mut requests = Vec::new();
+    for (key, delta) in &deltas {
+        let aggregate = TransactionAggregate::new(key, delta);
+        stored.remove(&(aggregate.merchant_id.clone(), aggregate.id.clone()));
+        let put = PutRequest::builder()
+            .set_item(Some(transaction_aggregate_item(&aggregate)?))
+            .build()?;
+        requests.push(WriteRequest::builder().put_request(put).build());
+    }
+    let removed = stored.len();
+    for (partition_key, sort_key) in stored {
+        let delete = DeleteRequest::builder()
+            .key(PARTITION_KEY, AttributeValue::S(partition_key))
+            .key(SORT_KEY, AttributeValue::S(sort_key))
+            .build()?;
+        requests.push(WriteRequest::builder().delete_request(delete).build());
+    }
+    if dry_run {
+        tracing::info!(
+            transactions,
+            aggregates = deltas.len(),
+            removed,
+            "Would write transaction aggregates"
+        );
+        return Ok(());
+    }
+
+    batch_write(table, "write_transaction_aggregates", requests)
+        .await
+        .context("Failed to write transaction aggregates")?;
+    tracing::info!(
+        transactions,
+        aggregates = deltas.len(),
+        removed,
+        "Backfilled transaction aggregates"
+    );
+    Ok(())
+}
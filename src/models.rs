@@ -1,9 +1,26 @@
-use crate::dynamo::{get_merchant, get_transactions, get_transactions_for_settlement_merchant};
-use anyhow::Error;
-use std::env;
+use crate::aggregates::{AggregatePeriod, TransactionAggregate};
+use crate::audit::{ANONYMOUS_ACTOR, Actor, ActorId, AuditEntry};
+use crate::dynamo::{PAYOUT_PREFIX, TRANSACTION_PREFIX, transaction_date_range};
+use crate::errors::AppError;
+use crate::events::{self, TransactionEvent, TransactionStatusChange};
+use crate::limits::{self, QueryLimits};
+use crate::rate_limit::{SubscriptionClient, SubscriptionSlot};
+use crate::store::{SharedStore, Store};
+use crate::telemetry::{RequestId, mask_pan};
+use crate::webhooks::{
+    Webhook, WebhookDelivery, WebhookEventType, WebhookRegistration, validate_url,
+};
+use anyhow::{Context, Error, anyhow, bail};
+use futures::{Stream, StreamExt, future};
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
 
 use async_graphql::types::connection::{Connection, Edge, EmptyFields, OpaqueCursor, query};
-use async_graphql::{Enum, Guard, Object, SimpleObject};
+use async_graphql::{Enum, Guard, InputObject, Object, SimpleObject, Subscription};
+use chrono::{SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 use strum_macros::Display;
 
@@ -14,7 +31,22 @@ pub enum MerchantLevel {
     Outlet,
 }
 
-#[derive(SimpleObject, Deserialize, Serialize)]
+impl MerchantLevel {
+    fn rank(self) -> u8 {
+        match self {
+            MerchantLevel::Group => 0,
+            MerchantLevel::Chain => 1,
+            MerchantLevel::Outlet => 2,
+        }
+    }
+
+    /// A merchant may only contain merchants of a strictly lower level (Group > Chain > Outlet).
+    pub fn can_contain(self, child: MerchantLevel) -> bool {
+        self.rank() < child.rank()
+    }
+}
+
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
 pub struct Merchant {
     pub id: String,
     pub name: String,
@@ -24,10 +56,29 @@ pub struct Merchant {
     pub created_at: i64,
     pub merchant_level: MerchantLevel,
     pub sub_merchants: Vec<String>,
+    #[serde(default)]
+    pub parent_merchant_id: Option<String>,
     pub has_settlement_permissions: bool,
     pub has_billing_permissions: bool,
 }
 
+#[derive(Enum, Deserialize, Serialize, Copy, Clone, PartialEq, Eq, Display)]
+pub enum HierarchyAction {
+    Attach,
+    Detach,
+    Move,
+}
+
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct HierarchyChange {
+    pub merchant_id: String,
+    pub action: HierarchyAction,
+    pub previous_parent_id: Option<String>,
+    pub new_parent_id: Option<String>,
+    pub changed_at: String,
+    pub changed_by_role: Option<String>,
+}
+
 #[derive(Enum, Deserialize, Serialize, Copy, Clone, PartialEq, Eq, Display)]
 pub enum TransactionType {
     Purchase,
@@ -48,7 +99,7 @@ pub enum CardBrand {
     Mastercard,
 }
 
-#[derive(SimpleObject, Deserialize, Serialize)]
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
 pub struct Transaction {
     pub id: String,
     pub merchant_id: String,
@@ -64,7 +115,104 @@ pub struct Transaction {
     pub settlement_merchant_id: String,
 }
 
-#[derive(SimpleObject, Deserialize, Serialize)]
+// Days between a recorded transaction and its payout.
+const SETTLEMENT_DELAY_DAYS: i64 = 1;
+
+/// A card transaction taken by an outlet, as recorded by `recordTransaction`.
+#[derive(InputObject)]
+pub struct RecordTransactionInput {
+    pub merchant_id: String,
+    pub transaction_type: TransactionType,
+    pub amount: f64,
+    pub currency: String,
+    pub pan: String,
+    pub card_brand: CardBrand,
+}
+
+/// Cursor of the `transactions` connection. The merchant id keeps positions unique when the
+/// transactions of several outlets are merged.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TransactionCursor {
+    pub merchant_id: String,
+    pub id: String,
+}
+
+impl TransactionCursor {
+    pub fn of(transaction: &Transaction) -> Self {
+        Self {
+            merchant_id: transaction.merchant_id.clone(),
+            id: transaction.id.clone(),
+        }
+    }
+}
+
+/// One page of a newest-first connection: the `limit` rows after `after`, or when `backward`, the
+/// `limit` rows just before `before`. Stores return the rows newest first either way, with
+/// whether more lie past the page in the direction read.
+#[derive(Clone, Debug)]
+pub struct PageRequest<C> {
+    pub after: Option<C>,
+    pub before: Option<C>,
+    pub limit: usize,
+    pub backward: bool,
+}
+
+impl<C> PageRequest<C> {
+    /// Reads backward for `last`, or for `before` without `first`.
+    pub fn new(
+        after: Option<C>,
+        before: Option<C>,
+        first: Option<usize>,
+        last: Option<usize>,
+        limit: usize,
+    ) -> Self {
+        Self {
+            backward: last.is_some() || (before.is_some() && first.is_none()),
+            after,
+            before,
+            limit,
+        }
+    }
+
+    /// The first `limit` rows after `after`.
+    pub fn forward(after: Option<C>, limit: usize) -> Self {
+        Self {
+            after,
+            before: None,
+            limit,
+            backward: false,
+        }
+    }
+
+    pub fn map<D>(self, f: impl Fn(C) -> D) -> PageRequest<D> {
+        PageRequest {
+            after: self.after.map(&f),
+            before: self.before.map(&f),
+            limit: self.limit,
+            backward: self.backward,
+        }
+    }
+
+    /// The cursor the page is read from: `before` when reading backward, else `after`.
+    pub fn start(&self) -> Option<&C> {
+        if self.backward {
+            self.before.as_ref()
+        } else {
+            self.after.as_ref()
+        }
+    }
+
+    /// `hasPreviousPage` and `hasNextPage` of a page read with `has_more` rows past it.
+    pub fn page_info(&self, has_more: bool) -> (bool, bool) {
+        if self.backward {
+            (has_more, self.before.is_some())
+        } else {
+            (self.after.is_some(), has_more)
+        }
+    }
+}
+
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
 pub struct Payout {
     pub id: String,
     pub merchant_id: String,
@@ -77,47 +225,208 @@ pub struct Payout {
     pub bank_name: String,
 }
 
+/// Net amount of one payout, summed from the transactions that carry its id.
+#[derive(Serialize, Clone)]
+pub struct PayoutSummary {
+    pub id: String,
+    pub settlement_merchant_id: String,
+    pub date_settlement: String,
+    pub currency: String,
+    pub transactions: usize,
+    pub amount: f64,
+}
+
+impl Payout {
+    /// The payout of the settlement merchant's transactions carrying `payout_id`, dated by the
+    /// latest of them.
+    pub async fn from_transactions(
+        store: &dyn Store,
+        settlement_merchant_id: &str,
+        payout_id: &str,
+        bank_account: String,
+        bank_name: String,
+    ) -> Result<Payout, Error> {
+        let (earlier_transaction, later_transaction) = transaction_date_range(None, None);
+        let transactions: Vec<Transaction> = store
+            .get_all_transactions_for_settlement_merchant(
+                settlement_merchant_id,
+                &earlier_transaction,
+                &later_transaction,
+            )
+            .await?
+            .into_iter()
+            .filter(|transaction| transaction.payout_id == payout_id)
+            .collect();
+        let summary = match PayoutSummary::from_transactions(&transactions).as_slice() {
+            [] => bail!(AppError::not_found(format!(
+                "No transactions carry payout {payout_id}"
+            ))),
+            [summary] => summary.clone(),
+            _ => bail!(AppError::validation(format!(
+                "Payout {payout_id} spans several currencies"
+            ))),
+        };
+        let date_transaction = transactions
+            .iter()
+            .map(|transaction| transaction.date_transaction.as_str())
+            .max()
+            .unwrap_or_default()
+            .to_string();
+        Ok(Payout {
+            id: summary.id,
+            merchant_id: summary.settlement_merchant_id,
+            date_transaction,
+            date_settlement: summary.date_settlement,
+            status: TransactionStatus::Paid,
+            amount: summary.amount,
+            currency: summary.currency,
+            bank_account,
+            bank_name,
+        })
+    }
+}
+
+impl PayoutSummary {
+    /// Groups transactions by payout, newest settlement first. Refunds are subtracted and
+    /// charged back purchases left out of the amount.
+    pub fn from_transactions(transactions: &[Transaction]) -> Vec<Self> {
+        let mut payouts: Vec<PayoutSummary> = Vec::new();
+        let mut positions: HashMap<(&str, &str), usize> = HashMap::new();
+        for transaction in transactions {
+            let key = (
+                transaction.payout_id.as_str(),
+                transaction.currency.as_str(),
+            );
+            let position = *positions.entry(key).or_insert_with(|| {
+                payouts.push(PayoutSummary {
+                    id: transaction.payout_id.clone(),
+                    settlement_merchant_id: transaction.settlement_merchant_id.clone(),
+                    date_settlement: transaction.date_settlement.clone(),
+                    currency: transaction.currency.clone(),
+                    transactions: 0,
+                    amount: 0.0,
+                });
+                payouts.len() - 1
+            });
+            let payout = &mut payouts[position];
+            payout.transactions += 1;
+            payout.amount += match (transaction.transaction_type, transaction.status) {
+                (_, TransactionStatus::Chargebacked) => 0.0,
+                (TransactionType::Purchase, _) => transaction.amount,
+                (TransactionType::Refund, _) => -transaction.amount,
+            };
+        }
+        for payout in &mut payouts {
+            payout.amount = (payout.amount * 100.0).round() / 100.0;
+        }
+        payouts.sort_by(|a, b| b.date_settlement.cmp(&a.date_settlement));
+        payouts
+    }
+}
+
 impl Transaction {
+    /// The transaction with its card number masked when `mask` is set.
+    pub fn masked_if(self, mask: bool) -> Self {
+        if !mask {
+            return self;
+        }
+        Transaction {
+            pan: mask_pan(&self.pan),
+            ..self
+        }
+    }
+
+    /// Records a new transaction for an outlet. It starts `Processed` and settles to the outlet's
+    /// settlement merchant the next day.
+    pub async fn record(
+        store: &dyn Store,
+        input: RecordTransactionInput,
+        actor: &Actor,
+    ) -> Result<Transaction, Error> {
+        if !input.amount.is_finite() || input.amount <= 0.0 {
+            bail!(AppError::validation("amount must be positive"));
+        }
+        if input.currency.len() != 3 || !input.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            bail!(AppError::validation("currency must be an ISO 4217 code"));
+        }
+        if !(12..=19).contains(&input.pan.len()) || !input.pan.chars().all(|c| c.is_ascii_digit()) {
+            bail!(AppError::validation("pan must be 12 to 19 digits"));
+        }
+        let merchant = store.get_merchant(input.merchant_id.clone()).await?;
+        if merchant.merchant_level != MerchantLevel::Outlet {
+            bail!(AppError::validation("Only outlets take transactions"));
+        }
+        let settlement_merchant_id = store.get_settlement_merchant_id(&merchant.id).await?;
+
+        let now = Utc::now();
+        let date_transaction = now.to_rfc3339_opts(SecondsFormat::Micros, true);
+        let date_settlement = (now + chrono::Duration::days(SETTLEMENT_DELAY_DAYS))
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .context("Invalid settlement date")?
+            .and_utc()
+            .to_rfc3339();
+        let suffix: u32 = rand::thread_rng().r#gen();
+        let transaction = Transaction {
+            id: format!("{TRANSACTION_PREFIX}#{date_transaction}#{suffix:08x}"),
+            merchant_id: merchant.id,
+            date_transaction,
+            payout_id: format!("{PAYOUT_PREFIX}#{date_settlement}#1"),
+            date_settlement,
+            transaction_type: input.transaction_type,
+            status: TransactionStatus::Processed,
+            amount: (input.amount * 100.0).round() / 100.0,
+            currency: input.currency,
+            pan: input.pan,
+            card_brand: input.card_brand,
+            settlement_merchant_id,
+        };
+        if !store.record_transaction(transaction.clone(), actor).await? {
+            bail!(AppError::conflict(format!(
+                "Transaction {} already exists",
+                transaction.id
+            )));
+        }
+        Ok(transaction)
+    }
+
     pub async fn read_all(
-        client: &aws_sdk_dynamodb::Client,
+        store: &dyn Store,
         merchant_id: String,
         year: Option<String>,
         month: Option<String>,
         day: Option<String>,
         card_brand: Option<CardBrand>,
-        after: Option<String>,
-        before: Option<String>,
-        limit: i32,
+        page: PageRequest<String>,
     ) -> Result<(Vec<Transaction>, bool), Error> {
-        get_transactions(
-            client,
-            merchant_id,
-            year,
-            month,
-            day,
-            card_brand,
-            after,
-            before,
-            limit,
-        )
-        .await
+        store
+            .get_transactions(merchant_id, year, month, day, card_brand, page)
+            .await
     }
 
     pub async fn read_all_for_settlement_merchant(
-        client: &aws_sdk_dynamodb::Client,
+        store: &dyn Store,
         settlement_merchant_id: String,
-        after: Option<String>,
-        before: Option<String>,
-        limit: i32,
+        page: PageRequest<String>,
     ) -> Result<(Vec<Transaction>, bool), Error> {
-        get_transactions_for_settlement_merchant(
-            client,
-            settlement_merchant_id,
-            after,
-            before,
-            limit,
-        )
-        .await
+        store
+            .get_transactions_for_settlement_merchant(settlement_merchant_id, page)
+            .await
+    }
+
+    pub async fn read_all_for_hierarchy(
+        store: &dyn Store,
+        merchant_id: String,
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        let outlet_ids = store.get_descendant_outlets(merchant_id).await?;
+        store
+            .get_transactions_for_merchants(&outlet_ids, year, month, day, card_brand, page)
+            .await
     }
 }
 
@@ -125,20 +434,45 @@ pub struct Query;
 
 #[Object]
 impl Query {
-    #[graphql(guard = "RoleGuard::new(Role::Admin).or(RoleGuard::new(Role::Reader))")]
+    #[graphql(
+        guard = "RoleGuard::new(Role::Admin).or(RoleGuard::new(Role::Reader))",
+        cache_control(max_age = 60, private)
+    )]
     async fn merchant(
         &self,
         ctx: &async_graphql::Context<'_>,
         merchant_id: String,
     ) -> Result<Merchant, async_graphql::Error> {
-        let client: &aws_sdk_dynamodb::Client = ctx.data::<aws_sdk_dynamodb::Client>().unwrap();
-        Ok(get_merchant(client, merchant_id).await?)
+        let store = ctx.data::<SharedStore>()?;
+        Ok(store.get_merchant(merchant_id).await?)
+    }
+
+    #[graphql(
+        guard = "RoleGuard::new(Role::Admin).or(RoleGuard::new(Role::Reader))",
+        cache_control(no_cache)
+    )]
+    async fn merchant_hierarchy_history(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+    ) -> Result<Vec<HierarchyChange>, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        Ok(store.get_hierarchy_history(merchant_id).await?)
     }
 
+    /// With `includeDescendants` the transactions of every outlet below the merchant are merged
+    /// into one connection.
+    #[graphql(
+        complexity = "limits::hierarchy_complexity(include_descendants, first, last, child_complexity)",
+        cache_control(no_cache)
+    )]
+    // Each argument is a field argument of the schema; grouping them would change the SDL.
+    #[allow(clippy::too_many_arguments)]
     async fn transactions(
         &self,
         ctx: &async_graphql::Context<'_>,
         merchant_id: String,
+        include_descendants: Option<bool>,
         year: Option<String>,
         month: Option<String>,
         day: Option<String>,
@@ -148,7 +482,7 @@ impl Query {
         first: Option<i32>,
         last: Option<i32>,
     ) -> Result<
-        Connection<OpaqueCursor<String>, Transaction, EmptyFields, EmptyFields>,
+        Connection<OpaqueCursor<TransactionCursor>, Transaction, EmptyFields, EmptyFields>,
         async_graphql::Error,
     > {
         query(
@@ -156,33 +490,50 @@ impl Query {
             before,
             first,
             last,
-            |after: Option<OpaqueCursor<String>>,
-             before: Option<OpaqueCursor<String>>,
+            |after: Option<OpaqueCursor<TransactionCursor>>,
+             before: Option<OpaqueCursor<TransactionCursor>>,
              first: Option<usize>,
              last: Option<usize>| async move {
-                let has_prev_page = after.is_some();
-                let after: Option<String> = after.map(|c| c.0);
-                let before = before.map(|c| c.0);
-                let limit = first.unwrap_or(last.unwrap_or(10)) as i32;
-
-                let client: &aws_sdk_dynamodb::Client =
-                    ctx.data::<aws_sdk_dynamodb::Client>().unwrap();
-                let (transactions, has_more) = Transaction::read_all(
-                    client,
-                    merchant_id,
-                    year,
-                    month,
-                    day,
-                    card_brand,
-                    after,
-                    before,
-                    limit,
-                )
-                .await?;
-                let mut connection = Connection::new(has_prev_page, has_more);
+                let limit = ctx.data::<QueryLimits>()?.page_size(first, last)?;
+                let page =
+                    PageRequest::new(after.map(|c| c.0), before.map(|c| c.0), first, last, limit);
+
+                let store = ctx.data::<SharedStore>()?;
+                audit_card_data_read(ctx, store.as_ref(), "transactions", &merchant_id).await?;
+                let (transactions, has_more) = if include_descendants.unwrap_or(false) {
+                    Transaction::read_all_for_hierarchy(
+                        store.as_ref(),
+                        merchant_id,
+                        year,
+                        month,
+                        day,
+                        card_brand,
+                        &page,
+                    )
+                    .await?
+                } else {
+                    Transaction::read_all(
+                        store.as_ref(),
+                        merchant_id,
+                        year,
+                        month,
+                        day,
+                        card_brand,
+                        page.clone().map(|c| c.id),
+                    )
+                    .await?
+                };
+                let (has_previous_page, has_next_page) = page.page_info(has_more);
+                let mut connection = Connection::new(has_previous_page, has_next_page);
+                let mask_pans = masks_pans(ctx);
                 connection.edges = transactions
                     .into_iter()
-                    .map(|transaction| Edge::new(OpaqueCursor(transaction.id.clone()), transaction))
+                    .map(|transaction| {
+                        Edge::new(
+                            OpaqueCursor(TransactionCursor::of(&transaction)),
+                            transaction.masked_if(mask_pans),
+                        )
+                    })
                     .collect();
                 Ok::<_, async_graphql::Error>(connection)
             },
@@ -190,6 +541,10 @@ impl Query {
         .await
     }
 
+    #[graphql(
+        complexity = "limits::connection_complexity(first, last, child_complexity)",
+        cache_control(no_cache)
+    )]
     async fn transactions_for_settlement_merchant(
         &self,
         ctx: &async_graphql::Context<'_>,
@@ -199,7 +554,7 @@ impl Query {
         first: Option<i32>,
         last: Option<i32>,
     ) -> Result<
-        Connection<OpaqueCursor<String>, Transaction, EmptyFields, EmptyFields>,
+        Connection<OpaqueCursor<TransactionCursor>, Transaction, EmptyFields, EmptyFields>,
         async_graphql::Error,
     > {
         query(
@@ -207,29 +562,176 @@ impl Query {
             before,
             first,
             last,
-            |after: Option<OpaqueCursor<String>>,
-             before: Option<OpaqueCursor<String>>,
+            |after: Option<OpaqueCursor<TransactionCursor>>,
+             before: Option<OpaqueCursor<TransactionCursor>>,
              first: Option<usize>,
              last: Option<usize>| async move {
-                let has_prev_page = after.is_some();
-                let after: Option<String> = after.map(|c| c.0);
-                let before = before.map(|c| c.0);
-                let limit = first.unwrap_or(last.unwrap_or(10)) as i32;
+                let limit = ctx.data::<QueryLimits>()?.page_size(first, last)?;
+                let page = PageRequest::new(
+                    after.map(|c| c.0.id),
+                    before.map(|c| c.0.id),
+                    first,
+                    last,
+                    limit,
+                );
 
-                let client: &aws_sdk_dynamodb::Client =
-                    ctx.data::<aws_sdk_dynamodb::Client>().unwrap();
+                let store = ctx.data::<SharedStore>()?;
+                audit_card_data_read(
+                    ctx,
+                    store.as_ref(),
+                    "transactionsForSettlementMerchant",
+                    &settlement_merchant_id,
+                )
+                .await?;
                 let (transactions, has_more) = Transaction::read_all_for_settlement_merchant(
-                    client,
+                    store.as_ref(),
                     settlement_merchant_id,
-                    after,
-                    before,
-                    limit,
+                    page.clone(),
                 )
                 .await?;
-                let mut connection = Connection::new(has_prev_page, has_more);
+                let (has_previous_page, has_next_page) = page.page_info(has_more);
+                let mut connection = Connection::new(has_previous_page, has_next_page);
+                let mask_pans = masks_pans(ctx);
                 connection.edges = transactions
                     .into_iter()
-                    .map(|transaction| Edge::new(OpaqueCursor(transaction.id.clone()), transaction))
+                    .map(|transaction| {
+                        Edge::new(
+                            OpaqueCursor(TransactionCursor::of(&transaction)),
+                            transaction.masked_if(mask_pans),
+                        )
+                    })
+                    .collect();
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    /// Totals of a merchant's transactions, or of those it settles, over one day (`2025-01-05`),
+    /// month (`2025-01`) or year (`2025`), one entry per currency.
+    #[graphql(
+        guard = "RoleGuard::new(Role::Admin).or(RoleGuard::new(Role::Reader))",
+        cache_control(no_cache)
+    )]
+    async fn transaction_summary(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        period: AggregatePeriod,
+        date: String,
+    ) -> Result<Vec<TransactionAggregate>, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        Ok(store
+            .get_transaction_aggregates(&merchant_id, period, &date)
+            .await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)", cache_control(no_cache))]
+    async fn webhooks(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+    ) -> Result<Vec<Webhook>, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        Ok(store.get_webhooks(&merchant_id).await?)
+    }
+
+    /// Deliveries of one of a merchant's webhooks, newest first, with every attempt made.
+    #[graphql(
+        guard = "RoleGuard::new(Role::Admin)",
+        complexity = "limits::connection_complexity(first, last, child_complexity)",
+        cache_control(no_cache)
+    )]
+    // Each argument is a field argument of the schema; grouping them would change the SDL.
+    #[allow(clippy::too_many_arguments)]
+    async fn webhook_deliveries(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        webhook_id: String,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<
+        Connection<OpaqueCursor<String>, WebhookDelivery, EmptyFields, EmptyFields>,
+        async_graphql::Error,
+    > {
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<OpaqueCursor<String>>,
+             before: Option<OpaqueCursor<String>>,
+             first: Option<usize>,
+             last: Option<usize>| async move {
+                let limit = ctx.data::<QueryLimits>()?.page_size(first, last)?;
+                let page =
+                    PageRequest::new(after.map(|c| c.0), before.map(|c| c.0), first, last, limit);
+
+                let store = ctx.data::<SharedStore>()?;
+                if store
+                    .get_webhook(&merchant_id, &webhook_id)
+                    .await?
+                    .is_none()
+                {
+                    return Err(AppError::not_found("Webhook not found").into());
+                }
+                let (deliveries, has_more) = store
+                    .get_webhook_deliveries(&webhook_id, page.clone())
+                    .await?;
+                let (has_previous_page, has_next_page) = page.page_info(has_more);
+                let mut connection = Connection::new(has_previous_page, has_next_page);
+                connection.edges = deliveries
+                    .into_iter()
+                    .map(|delivery| Edge::new(OpaqueCursor(delivery.id.clone()), delivery))
+                    .collect();
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    /// Audit entries of a `YYYY-MM-DD` day, defaulting to today, newest first.
+    #[graphql(
+        guard = "RoleGuard::new(Role::Admin)",
+        complexity = "limits::connection_complexity(first, last, child_complexity)",
+        cache_control(no_cache)
+    )]
+    async fn audit_log(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        date: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<
+        Connection<OpaqueCursor<String>, AuditEntry, EmptyFields, EmptyFields>,
+        async_graphql::Error,
+    > {
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<OpaqueCursor<String>>,
+             before: Option<OpaqueCursor<String>>,
+             first: Option<usize>,
+             last: Option<usize>| async move {
+                let limit = ctx.data::<QueryLimits>()?.page_size(first, last)?;
+                let page =
+                    PageRequest::new(after.map(|c| c.0), before.map(|c| c.0), first, last, limit);
+                let date = date.unwrap_or_else(|| Utc::now().format("%Y-%m-%d").to_string());
+
+                let store = ctx.data::<SharedStore>()?;
+                let (audit_entries, has_more) = store.get_audit_log(date, page.clone()).await?;
+                let (has_previous_page, has_next_page) = page.page_info(has_more);
+                let mut connection = Connection::new(has_previous_page, has_next_page);
+                connection.edges = audit_entries
+                    .into_iter()
+                    .map(|audit_entry| Edge::new(OpaqueCursor(audit_entry.id.clone()), audit_entry))
                     .collect();
                 Ok::<_, async_graphql::Error>(connection)
             },
@@ -238,7 +740,239 @@ impl Query {
     }
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
+pub struct Mutation;
+
+#[Object(cache_control(no_cache))]
+impl Mutation {
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn attach_sub_merchant(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        parent_id: String,
+        child_id: String,
+    ) -> Result<Merchant, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        store
+            .attach_sub_merchant(&parent_id, &child_id, &current_actor(ctx))
+            .await?;
+        Ok(store.get_merchant(child_id).await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn detach_sub_merchant(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        parent_id: String,
+        child_id: String,
+    ) -> Result<Merchant, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        store
+            .detach_sub_merchant(&parent_id, &child_id, &current_actor(ctx))
+            .await?;
+        Ok(store.get_merchant(child_id).await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn move_merchant(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        new_parent_id: String,
+    ) -> Result<Merchant, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        store
+            .move_merchant(&merchant_id, &new_parent_id, &current_actor(ctx))
+            .await?;
+        Ok(store.get_merchant(merchant_id).await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn record_transaction(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        input: RecordTransactionInput,
+    ) -> Result<Transaction, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        Ok(Transaction::record(store.as_ref(), input, &current_actor(ctx)).await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn update_transaction_status(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        transaction_id: String,
+        status: TransactionStatus,
+    ) -> Result<TransactionStatusChange, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        Ok(store
+            .update_transaction_status(&merchant_id, &transaction_id, status, &current_actor(ctx))
+            .await?)
+    }
+
+    /// Records the payout of every transaction carrying `payoutId`, notifying the settlement
+    /// merchant's `PayoutCreated` webhooks.
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn create_payout(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        settlement_merchant_id: String,
+        payout_id: String,
+        bank_account: String,
+        bank_name: String,
+    ) -> Result<Payout, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        let payout = Payout::from_transactions(
+            store.as_ref(),
+            &settlement_merchant_id,
+            &payout_id,
+            bank_account,
+            bank_name,
+        )
+        .await?;
+        store.add_payout(&payout, &current_actor(ctx)).await?;
+        Ok(payout)
+    }
+
+    /// Registers an endpoint for some of a merchant's events. The response carries the signing
+    /// secret, which cannot be read again.
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn register_webhook(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        url: String,
+        events: Vec<WebhookEventType>,
+    ) -> Result<WebhookRegistration, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        validate_url(&url)?;
+        if events.is_empty() {
+            return Err(AppError::validation("events must not be empty").into());
+        }
+        store.get_merchant(merchant_id.clone()).await?;
+        let webhook = Webhook::new(merchant_id, url, events);
+        store.add_webhook(&webhook, &current_actor(ctx)).await?;
+        Ok(WebhookRegistration {
+            secret: webhook.secret.clone(),
+            webhook,
+        })
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn delete_webhook(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        webhook_id: String,
+    ) -> Result<bool, async_graphql::Error> {
+        let store = ctx.data::<SharedStore>()?;
+        let webhook = store
+            .get_webhook(&merchant_id, &webhook_id)
+            .await?
+            .ok_or_else(|| AppError::not_found("Webhook not found"))?;
+        store.delete_webhook(&webhook, &current_actor(ctx)).await?;
+        Ok(true)
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Transactions recorded from now on for the merchant or, with `includeDescendants`, for the
+    /// outlets below it when the subscription starts.
+    async fn transaction_recorded(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        include_descendants: Option<bool>,
+    ) -> Result<impl Stream<Item = Transaction> + use<>, async_graphql::Error> {
+        let slot = subscription_slot(ctx)?;
+        let merchant_ids =
+            subscribed_merchant_ids(ctx, "transactionRecorded", merchant_id, include_descendants)
+                .await?;
+        let mask_pans = masks_pans(ctx);
+        Ok(events::subscribe().filter_map(move |event| {
+            let _slot = &slot;
+            future::ready(match event {
+                TransactionEvent::Recorded(transaction)
+                    if merchant_ids.contains(&transaction.merchant_id) =>
+                {
+                    Some(transaction.masked_if(mask_pans))
+                }
+                _ => None,
+            })
+        }))
+    }
+
+    /// Status changes of the transactions of the merchant or, with `includeDescendants`, of the
+    /// outlets below it when the subscription starts.
+    async fn transaction_status_changed(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        merchant_id: String,
+        include_descendants: Option<bool>,
+    ) -> Result<impl Stream<Item = TransactionStatusChange> + use<>, async_graphql::Error> {
+        let slot = subscription_slot(ctx)?;
+        let merchant_ids = subscribed_merchant_ids(
+            ctx,
+            "transactionStatusChanged",
+            merchant_id,
+            include_descendants,
+        )
+        .await?;
+        let mask_pans = masks_pans(ctx);
+        Ok(events::subscribe().filter_map(move |event| {
+            let _slot = &slot;
+            future::ready(match event {
+                TransactionEvent::StatusChanged(change)
+                    if merchant_ids.contains(&change.transaction.merchant_id) =>
+                {
+                    Some(TransactionStatusChange {
+                        transaction: change.transaction.masked_if(mask_pans),
+                        ..change
+                    })
+                }
+                _ => None,
+            })
+        }))
+    }
+}
+
+/// Counts a websocket subscription against its client's limit. The stream holds the slot until
+/// it ends; subscriptions run without a websocket client, as in tests, are not counted.
+fn subscription_slot(
+    ctx: &async_graphql::Context<'_>,
+) -> Result<Option<SubscriptionSlot>, AppError> {
+    ctx.data_opt::<SubscriptionClient>()
+        .map(SubscriptionClient::open)
+        .transpose()
+}
+
+/// Authorizes a subscription like the `transactions` query, auditing Admin subscribers, and
+/// returns the merchants whose events it receives.
+async fn subscribed_merchant_ids(
+    ctx: &async_graphql::Context<'_>,
+    operation: &str,
+    merchant_id: String,
+    include_descendants: Option<bool>,
+) -> Result<HashSet<String>, Error> {
+    let store = ctx
+        .data::<SharedStore>()
+        .map_err(|err| anyhow!(err.message))?;
+    audit_card_data_read(ctx, store.as_ref(), operation, &merchant_id).await?;
+    if include_descendants.unwrap_or(false) {
+        Ok(store
+            .get_descendant_outlets(merchant_id)
+            .await?
+            .into_iter()
+            .collect())
+    } else {
+        Ok(HashSet::from([merchant_id]))
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Display)]
 pub enum Role {
     Admin,
     Reader,
@@ -254,19 +988,714 @@ impl RoleGuard {
     }
 }
 
+pub fn env_role() -> Option<Role> {
+    let env_role = env::var("ROLE").ok();
+    env_role.as_deref().and_then(|role_str| match role_str {
+        "Admin" => Some(Role::Admin),
+        "Reader" => Some(Role::Reader),
+        _ => None,
+    })
+}
+
+/// The role the request runs as: one attached to the request context wins over the `ROLE` env var.
+fn current_role(ctx: &async_graphql::Context<'_>) -> Option<Role> {
+    ctx.data_opt::<Role>().copied().or_else(env_role)
+}
+
+fn current_actor(ctx: &async_graphql::Context<'_>) -> Actor {
+    Actor {
+        id: ctx
+            .data_opt::<ActorId>()
+            .map(|actor_id| actor_id.0.clone())
+            .unwrap_or_else(|| ANONYMOUS_ACTOR.to_string()),
+        role: current_role(ctx),
+        request_id: ctx
+            .data_opt::<RequestId>()
+            .map(|request_id| request_id.0.clone()),
+    }
+}
+
+/// Only Admins, whose reads are audited, see full card numbers; everyone else gets them masked
+/// as in the export.
+fn masks_pans(ctx: &async_graphql::Context<'_>) -> bool {
+    current_role(ctx) != Some(Role::Admin)
+}
+
+/// Admin reads expose full card data, so they are audited before the results are returned.
+async fn audit_card_data_read(
+    ctx: &async_graphql::Context<'_>,
+    store: &dyn Store,
+    operation: &str,
+    target_id: &str,
+) -> Result<(), Error> {
+    let actor = current_actor(ctx);
+    if actor.role != Some(Role::Admin) {
+        return Ok(());
+    }
+    store
+        .add_audit_entry(&AuditEntry::new(
+            &actor,
+            operation,
+            vec![target_id.to_string()],
+        ))
+        .await
+}
+
 impl Guard for RoleGuard {
     async fn check(&self, ctx: &async_graphql::Context<'_>) -> Result<(), async_graphql::Error> {
-        let env_role = env::var("ROLE").ok();
-        let env_role_parsed = env_role.as_deref().and_then(|role_str| match role_str {
-            "Admin" => Some(Role::Admin),
-            "Reader" => Some(Role::Reader),
-            _ => None,
-        });
-
-        if ctx.data_opt::<Role>() == Some(&self.role) || env_role_parsed == Some(self.role) {
+        if ctx.data_opt::<Role>() == Some(&self.role) || env_role() == Some(self.role) {
             Ok(())
         } else {
-            Err("Forbidden".into())
+            Err(AppError::Forbidden.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Role;
+    use crate::telemetry::mask_pan;
+    use crate::testing::TestApp;
+    use actix_web::rt::time::{sleep, timeout};
+    use futures::{StreamExt, future::join};
+    use serde_json::{Value, json};
+    use std::{collections::HashSet, env, time::Duration};
+
+    const TRANSACTIONS: &str = r#"
+        query Transactions(
+            $merchantId: String!
+            $includeDescendants: Boolean
+            $year: String
+            $month: String
+            $day: String
+            $after: String
+            $before: String
+            $first: Int
+            $last: Int
+        ) {
+            transactions(
+                merchantId: $merchantId
+                includeDescendants: $includeDescendants
+                year: $year
+                month: $month
+                day: $day
+                after: $after
+                before: $before
+                first: $first
+                last: $last
+            ) {
+                edges { node { id merchantId dateTransaction } }
+                pageInfo { hasNextPage hasPreviousPage startCursor endCursor }
+            }
+        }
+    "#;
+
+    fn ids(connection: &Value) -> Vec<(String, String)> {
+        connection["edges"]
+            .as_array()
+            .expect("edges")
+            .iter()
+            .map(|edge| {
+                let node = &edge["node"];
+                (
+                    node["merchantId"].as_str().unwrap().to_string(),
+                    node["id"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn descendant_pages_return_every_transaction_once_newest_first() {
+        for app in TestApp::all().await {
+            let mut seen = Vec::new();
+            let mut after: Option<String> = None;
+            let mut pages = 0;
+            loop {
+                let data = app
+                    .data(
+                        None,
+                        TRANSACTIONS,
+                        json!({
+                            "merchantId": "MERCHANT#merchant_b_group",
+                            "includeDescendants": true,
+                            "after": after,
+                            "first": 3,
+                        }),
+                    )
+                    .await;
+                let connection = &data["transactions"];
+                let page_info = &connection["pageInfo"];
+                assert_eq!(page_info["hasPreviousPage"], pages > 0, "{}", app.backend);
+                seen.extend(ids(connection));
+                pages += 1;
+                if page_info["hasNextPage"] == false {
+                    break;
+                }
+                after = page_info["endCursor"].as_str().map(str::to_string);
+                assert!(pages < 10, "{}: paging did not end", app.backend);
+            }
+
+            assert_eq!(pages, 4, "{}", app.backend);
+            assert_eq!(seen.len(), 10, "{}", app.backend);
+            assert_eq!(
+                seen.iter().collect::<HashSet<_>>().len(),
+                10,
+                "{}: a transaction was returned twice",
+                app.backend
+            );
+            let mut newest_first = seen.clone();
+            newest_first.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            assert_eq!(seen, newest_first, "{}", app.backend);
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn consecutive_pages_cover_every_transaction_once() {
+        for app in TestApp::all().await {
+            let merchant_id = "MERCHANT#merchant_a_outlet";
+            let everything = app
+                .data(
+                    None,
+                    TRANSACTIONS,
+                    json!({ "merchantId": merchant_id, "first": 100 }),
+                )
+                .await;
+            let all_ids = ids(&everything["transactions"]);
+            assert!(all_ids.len() > 4, "{}", app.backend);
+
+            let mut paged_ids = Vec::new();
+            let mut after = Value::Null;
+            loop {
+                let page = app
+                    .data(
+                        None,
+                        TRANSACTIONS,
+                        json!({ "merchantId": merchant_id, "after": after, "first": 3 }),
+                    )
+                    .await;
+                let page_ids = ids(&page["transactions"]);
+                assert!(
+                    !page_ids.iter().any(|id| paged_ids.contains(id)),
+                    "{}: page {page_ids:?} overlaps {paged_ids:?}",
+                    app.backend
+                );
+                assert_eq!(
+                    page["transactions"]["pageInfo"]["hasPreviousPage"],
+                    !after.is_null(),
+                    "{}",
+                    app.backend
+                );
+                paged_ids.extend(page_ids);
+                let page_info = &page["transactions"]["pageInfo"];
+                if page_info["hasNextPage"] == false || paged_ids.len() > all_ids.len() {
+                    break;
+                }
+                after = page_info["endCursor"].clone();
+            }
+            assert_eq!(paged_ids, all_ids, "{}", app.backend);
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn backward_pages_end_at_the_cursor_and_cover_every_transaction_once() {
+        for app in TestApp::all().await {
+            for (merchant_id, include_descendants) in [
+                ("MERCHANT#merchant_a_outlet", false),
+                ("MERCHANT#merchant_b_group", true),
+            ] {
+                let everything = app
+                    .data(
+                        None,
+                        TRANSACTIONS,
+                        json!({
+                            "merchantId": merchant_id,
+                            "includeDescendants": include_descendants,
+                            "first": 100,
+                        }),
+                    )
+                    .await;
+                let all_ids = ids(&everything["transactions"]);
+                assert!(all_ids.len() > 4, "{}", app.backend);
+
+                let mut paged_ids = Vec::new();
+                let mut before = Value::Null;
+                loop {
+                    let page = app
+                        .data(
+                            None,
+                            TRANSACTIONS,
+                            json!({
+                                "merchantId": merchant_id,
+                                "includeDescendants": include_descendants,
+                                "before": before,
+                                "last": 3,
+                            }),
+                        )
+                        .await;
+                    let page_ids = ids(&page["transactions"]);
+                    let page_info = &page["transactions"]["pageInfo"];
+                    assert_eq!(
+                        page_info["hasNextPage"],
+                        !before.is_null(),
+                        "{}",
+                        app.backend
+                    );
+                    // Each backward page is the three transactions just newer than the cursor.
+                    let end = all_ids.len() - paged_ids.len();
+                    assert_eq!(
+                        page_ids,
+                        all_ids[end.saturating_sub(3)..end],
+                        "{}: {merchant_id}",
+                        app.backend
+                    );
+                    paged_ids.splice(0..0, page_ids);
+                    if page_info["hasPreviousPage"] == false || paged_ids.len() >= all_ids.len() {
+                        break;
+                    }
+                    before = page_info["startCursor"].clone();
+                }
+                assert_eq!(paged_ids, all_ids, "{}: {merchant_id}", app.backend);
+            }
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn an_empty_page_size_is_rejected() {
+        for app in TestApp::all().await {
+            let response = app
+                .execute(
+                    None,
+                    TRANSACTIONS,
+                    json!({ "merchantId": "MERCHANT#merchant_a_outlet", "first": 0 }),
+                )
+                .await;
+            assert_eq!(
+                response["errors"][0]["extensions"]["code"], "VALIDATION",
+                "{}: {response}",
+                app.backend
+            );
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_pages_and_queries_are_rejected_before_reading() {
+        let everything = r#"
+            query Everything($first: Int) {
+                transactions(
+                    merchantId: "MERCHANT#merchant_b_group"
+                    includeDescendants: true
+                    first: $first
+                ) {
+                    edges {
+                        node {
+                            id merchantId dateTransaction dateSettlement transactionType status
+                            amount currency pan cardBrand payoutId settlementMerchantId
+                        }
+                    }
+                }
+            }
+        "#;
+        for app in TestApp::all().await {
+            let response = app
+                .execute(
+                    None,
+                    TRANSACTIONS,
+                    json!({ "merchantId": "MERCHANT#merchant_a_outlet", "first": 101 }),
+                )
+                .await;
+            assert_eq!(
+                response["errors"][0]["extensions"]["code"], "VALIDATION",
+                "{}: {response}",
+                app.backend
+            );
+            assert!(
+                response["errors"][0]["message"]
+                    .as_str()
+                    .unwrap()
+                    .contains("maximum page size of 100"),
+                "{}: {response}",
+                app.backend
+            );
+
+            let response = app.execute(None, everything, json!({ "first": 100 })).await;
+            assert_eq!(
+                response["errors"][0]["extensions"]["code"], "VALIDATION",
+                "{}: {response}",
+                app.backend
+            );
+            assert!(
+                response["errors"][0]["message"]
+                    .as_str()
+                    .unwrap()
+                    .contains("complexity"),
+                "{}: {response}",
+                app.backend
+            );
+            assert!(response["data"].is_null(), "{}: {response}", app.backend);
+
+            app.data(None, everything, json!({ "first": 20 })).await;
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn year_month_and_day_filters_select_their_transactions() {
+        let cases = [
+            (json!({ "year": "2024" }), 12),
+            (json!({ "year": "2025" }), 33),
+            (json!({ "year": "2024", "month": "12" }), 12),
+            (json!({ "year": "2025", "month": "01" }), 31),
+            (json!({ "year": "2025", "month": "02" }), 2),
+            (json!({ "year": "2025", "month": "03" }), 0),
+            (json!({ "year": "2024", "month": "12", "day": "31" }), 1),
+            (json!({ "year": "2025", "month": "02", "day": "01" }), 1),
+        ];
+        for app in TestApp::all().await {
+            for (filter, expected) in &cases {
+                let mut variables =
+                    json!({ "merchantId": "MERCHANT#merchant_a_outlet", "first": 50 });
+                variables
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(filter.as_object().unwrap().clone());
+                let data = app.data(None, TRANSACTIONS, variables).await;
+                let dates: Vec<&str> = data["transactions"]["edges"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|edge| edge["node"]["dateTransaction"].as_str().unwrap())
+                    .collect();
+                assert_eq!(dates.len(), *expected, "{}: {filter}", app.backend);
+                let prefix = ["year", "month", "day"]
+                    .iter()
+                    .filter_map(|part| filter[part].as_str())
+                    .collect::<Vec<_>>()
+                    .join("-");
+                assert!(
+                    dates.iter().all(|date| date.starts_with(&prefix)),
+                    "{}: {filter} returned {dates:?}",
+                    app.backend
+                );
+            }
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn transaction_summary_totals_the_month() {
+        for app in TestApp::all().await {
+            let data = app
+                .data(
+                    Some(Role::Reader),
+                    r#"{
+                        transactionSummary(
+                            merchantId: "MERCHANT#merchant_a_outlet"
+                            period: MONTH
+                            date: "2025-01"
+                        ) { currency transactions }
+                    }"#,
+                    json!({}),
+                )
+                .await;
+            assert_eq!(
+                data["transactionSummary"],
+                json!([{ "currency": "GBP", "transactions": 31 }]),
+                "{}",
+                app.backend
+            );
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn role_guards_allow_only_their_roles() {
+        assert!(
+            env::var("ROLE").is_err(),
+            "ROLE grants its role to every request, unset it to test the guards"
+        );
+        let merchant = r#"{ merchant(merchantId: "MERCHANT#merchant_a_outlet") { name } }"#;
+        let webhooks = r#"{ webhooks(merchantId: "MERCHANT#merchant_a_outlet") { id } }"#;
+        let attach = r#"mutation {
+            attachSubMerchant(parentId: "MERCHANT#merchant_b_group", childId: "MERCHANT#merchant_a_outlet") { id }
+        }"#;
+        for app in TestApp::all().await {
+            let forbidden = |response: Value| {
+                let error = &response["errors"][0];
+                assert_eq!(error["message"], "Forbidden", "{}", app.backend);
+                assert_eq!(
+                    error["extensions"],
+                    json!({ "code": "FORBIDDEN", "retryable": false }),
+                    "{}",
+                    app.backend
+                );
+            };
+            forbidden(app.execute(None, merchant, json!({})).await);
+            forbidden(app.execute(Some(Role::Reader), webhooks, json!({})).await);
+            forbidden(app.execute(Some(Role::Reader), attach, json!({})).await);
+
+            for role in [Role::Reader, Role::Admin] {
+                let data = app.data(Some(role), merchant, json!({})).await;
+                assert_eq!(
+                    data["merchant"]["name"], "Merchant A_outlet",
+                    "{}",
+                    app.backend
+                );
+            }
+            let data = app.data(Some(Role::Admin), webhooks, json!({})).await;
+            assert_eq!(data["webhooks"], json!([]), "{}", app.backend);
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn errors_carry_the_code_of_their_failure() {
+        let cases = [
+            (
+                r#"{ merchant(merchantId: "MERCHANT#missing") { id } }"#,
+                "NOT_FOUND",
+            ),
+            (
+                r#"mutation {
+                    recordTransaction(input: {
+                        merchantId: "MERCHANT#merchant_a_outlet"
+                        transactionType: PURCHASE
+                        amount: -1
+                        currency: "GBP"
+                        pan: "4111111111111111"
+                        cardBrand: VISA
+                    }) { id }
+                }"#,
+                "VALIDATION",
+            ),
+            (
+                r#"mutation {
+                    attachSubMerchant(
+                        parentId: "MERCHANT#merchant_b_group"
+                        childId: "MERCHANT#merchant_b_outlet1"
+                    ) { id }
+                }"#,
+                "CONFLICT",
+            ),
+        ];
+        for app in TestApp::all().await {
+            for (query, code) in cases {
+                let response = app.execute(Some(Role::Admin), query, json!({})).await;
+                assert_eq!(
+                    response["errors"][0]["extensions"],
+                    json!({ "code": code, "retryable": false }),
+                    "{}: {response}",
+                    app.backend
+                );
+            }
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_admin_transaction_reads_are_audited() {
+        let audited_reads = r#"{ auditLog(first: 50) { edges { node { operation targetIds } } } }"#;
+        for app in TestApp::all().await {
+            let variables = json!({ "merchantId": "MERCHANT#merchant_a_outlet", "first": 1 });
+            app.data(Some(Role::Reader), TRANSACTIONS, variables.clone())
+                .await;
+            let data = app.data(Some(Role::Admin), audited_reads, json!({})).await;
+            assert_eq!(data["auditLog"]["edges"], json!([]), "{}", app.backend);
+
+            app.data(Some(Role::Admin), TRANSACTIONS, variables).await;
+            let data = app.data(Some(Role::Admin), audited_reads, json!({})).await;
+            assert_eq!(
+                data["auditLog"]["edges"],
+                json!([{
+                    "node": {
+                        "operation": "transactions",
+                        "targetIds": ["MERCHANT#merchant_a_outlet"],
+                    }
+                }]),
+                "{}",
+                app.backend
+            );
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_admins_see_full_card_numbers() {
+        let pans = r#"{
+            transactions(merchantId: "MERCHANT#merchant_a_outlet", first: 5) {
+                edges { node { pan } }
+            }
+        }"#;
+        let read_pans = |data: Value| -> Vec<String> {
+            data["transactions"]["edges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|edge| edge["node"]["pan"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert!(env::var("ROLE").is_err(), "ROLE must be unset");
+        for app in TestApp::all().await {
+            let full = read_pans(app.data(Some(Role::Admin), pans, json!({})).await);
+            let masked: Vec<String> = full.iter().map(|pan| mask_pan(pan)).collect();
+            assert_ne!(full, masked, "{}", app.backend);
+            for role in [Some(Role::Reader), None] {
+                let pans = read_pans(app.data(role, pans, json!({})).await);
+                assert_eq!(pans, masked, "{}: {role:?}", app.backend);
+            }
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn subscriptions_mask_card_numbers_for_non_admins() {
+        let subscription = r#"subscription {
+            transactionRecorded(merchantId: "MERCHANT#merchant_a_outlet") { pan }
+        }"#;
+        let record = r#"mutation {
+            recordTransaction(input: {
+                merchantId: "MERCHANT#merchant_a_outlet"
+                transactionType: PURCHASE
+                amount: 12.5
+                currency: "GBP"
+                pan: "4111111111111111"
+                cardBrand: VISA
+            }) { id }
+        }"#;
+        for app in TestApp::all().await {
+            let mut events = app.subscribe(Some(Role::Reader), subscription, json!({}));
+            // The subscription starts on its first poll, so the transaction is recorded after it.
+            let (event, _) = join(timeout(Duration::from_secs(10), events.next()), async {
+                sleep(Duration::from_millis(100)).await;
+                app.data(Some(Role::Admin), record, json!({})).await
+            })
+            .await;
+            let event = event.expect("no event").expect("subscription ended");
+            assert_eq!(
+                event["data"]["transactionRecorded"]["pan"], "************1111",
+                "{}: {event}",
+                app.backend
+            );
+            drop(events);
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn transaction_mutations_are_audited_with_masked_images() {
+        let record = r#"mutation {
+            recordTransaction(input: {
+                merchantId: "MERCHANT#merchant_a_outlet"
+                transactionType: PURCHASE
+                amount: 12.5
+                currency: "GBP"
+                pan: "4111111111111111"
+                cardBrand: VISA
+            }) { id }
+        }"#;
+        let update = r#"mutation Update($transactionId: String!) {
+            updateTransactionStatus(
+                merchantId: "MERCHANT#merchant_a_outlet"
+                transactionId: $transactionId
+                status: CLEARED
+            ) { previousStatus }
+        }"#;
+        let audit_log =
+            r#"{ auditLog(first: 50) { edges { node { operation targetIds before after } } } }"#;
+        for app in TestApp::all().await {
+            let data = app.data(Some(Role::Admin), record, json!({})).await;
+            let transaction_id = data["recordTransaction"]["id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            app.data(
+                Some(Role::Admin),
+                update,
+                json!({ "transactionId": transaction_id }),
+            )
+            .await;
+
+            let data = app.data(Some(Role::Admin), audit_log, json!({})).await;
+            let entries = data["auditLog"]["edges"].as_array().unwrap();
+            let image = |entry: &Value, side: &str| -> Value {
+                serde_json::from_str(entry["node"][side].as_str().unwrap()).unwrap()
+            };
+            let (updated, recorded) = (&entries[0], &entries[1]);
+            assert_eq!(
+                recorded["node"]["operation"], "recordTransaction",
+                "{}",
+                app.backend
+            );
+            assert_eq!(
+                recorded["node"]["targetIds"],
+                json!(["MERCHANT#merchant_a_outlet", transaction_id]),
+                "{}",
+                app.backend
+            );
+            assert!(recorded["node"]["before"].is_null(), "{}", app.backend);
+            assert_eq!(image(recorded, "after")["pan"], "************1111");
+            assert_eq!(
+                updated["node"]["operation"], "updateTransactionStatus",
+                "{}",
+                app.backend
+            );
+            assert_eq!(image(updated, "before")["status"], "Processed");
+            assert_eq!(image(updated, "after")["status"], "Cleared");
+            assert_eq!(image(updated, "before")["pan"], "************1111");
+            app.finish().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn webhook_changes_are_audited_without_the_secret() {
+        let register = r#"mutation {
+            registerWebhook(
+                merchantId: "MERCHANT#merchant_a_outlet"
+                url: "https://example.com/hook"
+                events: [PAYOUT_CREATED]
+            ) { webhook { id } secret }
+        }"#;
+        let delete = r#"mutation Delete($webhookId: String!) {
+            deleteWebhook(merchantId: "MERCHANT#merchant_a_outlet", webhookId: $webhookId)
+        }"#;
+        let audit_log = r#"{ auditLog(first: 50) { edges { node { operation before after } } } }"#;
+        for app in TestApp::all().await {
+            let data = app.data(Some(Role::Admin), register, json!({})).await;
+            let webhook_id = data["registerWebhook"]["webhook"]["id"].clone();
+            let secret = data["registerWebhook"]["secret"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            app.data(
+                Some(Role::Admin),
+                delete,
+                json!({ "webhookId": webhook_id }),
+            )
+            .await;
+
+            let data = app.data(Some(Role::Admin), audit_log, json!({})).await;
+            let entries = &data["auditLog"]["edges"];
+            let (deleted, registered) = (&entries[0]["node"], &entries[1]["node"]);
+            assert_eq!(
+                registered["operation"], "registerWebhook",
+                "{}",
+                app.backend
+            );
+            assert_eq!(deleted["operation"], "deleteWebhook", "{}", app.backend);
+            for image in [&registered["after"], &deleted["before"]] {
+                let image: Value = serde_json::from_str(image.as_str().unwrap()).unwrap();
+                assert_eq!(image["id"], webhook_id, "{}", app.backend);
+                assert_eq!(image["url"], "https://example.com/hook", "{}", app.backend);
+                assert!(image.get("secret").is_none(), "{}", app.backend);
+            }
+            assert!(registered["before"].is_null(), "{}", app.backend);
+            assert!(deleted["after"].is_null(), "{}", app.backend);
+            assert!(!entries.to_string().contains(&secret), "{}", app.backend);
+            app.finish().await;
         }
     }
 }
@@ -0,0 +1,194 @@
+use crate::aggregates::TransactionAggregate;
+use crate::models::{Merchant, Payout, Transaction};
+use anyhow::Error;
+use clap::ValueEnum;
+use comfy_table::{Table, presets::UTF8_FULL_CONDENSED};
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+/// Rows the CLI can print as a table or CSV as well as JSON.
+pub trait Tabular: Serialize {
+    const HEADERS: &'static [&'static str];
+
+    fn row(&self) -> Vec<String>;
+}
+
+/// Writes `rows` to stdout: a table, a pretty printed JSON array or CSV with a header line. A
+/// reader that stops early, like `head`, is not an error.
+pub fn print<T: Tabular>(rows: &[T], format: OutputFormat) -> Result<(), Error> {
+    match write(rows, format) {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn write<T: Tabular>(rows: &[T], format: OutputFormat) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    match format {
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL_CONDENSED)
+                .set_header(T::HEADERS);
+            for row in rows {
+                table.add_row(row.row());
+            }
+            writeln!(stdout, "{table}")?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, rows)?;
+            writeln!(stdout)?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout);
+            writer.write_record(T::HEADERS).map_err(csv_io_error)?;
+            for row in rows {
+                writer.write_record(row.row()).map_err(csv_io_error)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the kind of I/O errors, which `csv` would otherwise report as `Other`.
+fn csv_io_error(err: csv::Error) -> io::Error {
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => err,
+        kind => io::Error::new(io::ErrorKind::InvalidData, format!("{kind:?}")),
+    }
+}
+
+impl Tabular for Merchant {
+    const HEADERS: &'static [&'static str] = &[
+        "id",
+        "name",
+        "merchant_level",
+        "parent_merchant_id",
+        "sub_merchants",
+        "industry",
+        "vat_number",
+        "has_settlement_permissions",
+        "has_billing_permissions",
+    ];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.name.clone(),
+            self.merchant_level.to_string(),
+            self.parent_merchant_id.clone().unwrap_or_default(),
+            self.sub_merchants.join(" "),
+            self.industry.clone(),
+            self.vat_number.clone(),
+            self.has_settlement_permissions.to_string(),
+            self.has_billing_permissions.to_string(),
+        ]
+    }
+}
+
+impl Tabular for Transaction {
+    const HEADERS: &'static [&'static str] = &[
+        "merchant_id",
+        "id",
+        "date_transaction",
+        "date_settlement",
+        "transaction_type",
+        "status",
+        "amount",
+        "currency",
+        "card_brand",
+        "pan",
+        "settlement_merchant_id",
+        "payout_id",
+    ];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.merchant_id.clone(),
+            self.id.clone(),
+            self.date_transaction.clone(),
+            self.date_settlement.clone(),
+            self.transaction_type.to_string(),
+            self.status.to_string(),
+            format!("{:.2}", self.amount),
+            self.currency.clone(),
+            self.card_brand.to_string(),
+            self.pan.clone(),
+            self.settlement_merchant_id.clone(),
+            self.payout_id.clone(),
+        ]
+    }
+}
+
+impl Tabular for Payout {
+    const HEADERS: &'static [&'static str] = &[
+        "id",
+        "merchant_id",
+        "date_transaction",
+        "date_settlement",
+        "status",
+        "currency",
+        "amount",
+        "bank_name",
+        "bank_account",
+    ];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.merchant_id.clone(),
+            self.date_transaction.clone(),
+            self.date_settlement.clone(),
+            self.status.to_string(),
+            self.currency.clone(),
+            format!("{:.2}", self.amount),
+            self.bank_name.clone(),
+            self.bank_account.clone(),
+        ]
+    }
+}
+
+impl Tabular for TransactionAggregate {
+    const HEADERS: &'static [&'static str] = &[
+        "merchant_id",
+        "period",
+        "date",
+        "currency",
+        "transactions",
+        "amount",
+        "purchases",
+        "refunds",
+        "processed",
+        "cleared",
+        "chargebacked",
+        "paid",
+        "chargebacked_amount",
+    ];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.merchant_id.clone(),
+            self.period.to_string(),
+            self.date.clone(),
+            self.currency.clone(),
+            self.transactions.to_string(),
+            format!("{:.2}", self.amount),
+            self.purchases.to_string(),
+            self.refunds.to_string(),
+            self.processed.to_string(),
+            self.cleared.to_string(),
+            self.chargebacked.to_string(),
+            self.paid.to_string(),
+            format!("{:.2}", self.chargebacked_amount),
+        ]
+    }
+}
@@ -0,0 +1,251 @@
+//! Automatic Persisted Queries and the operation allow-list.
+//!
+//! A client sends the SHA-256 of its query in the `persistedQuery` request extension and leaves
+//! the query out. An unknown hash is answered with `PersistedQueryNotFound`, upon which the client
+//! resends hash and query, and the query is remembered for next time.
+//!
+//! The allow-list is a JSON file mapping hashes to queries, as generated by persisted query
+//! tooling: `{ "<sha256>": "query Merchant { ... }" }`. Its queries are always known by hash. In
+//! strict mode only they may run; any other operation is rejected with `OPERATION_NOT_ALLOWED`.
+
+use crate::errors::AppError;
+use anyhow::{Context, Error, bail};
+use async_graphql::{
+    Request, ServerError, ServerResult,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest},
+};
+use clap::Args;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+const PERSISTED_QUERY_EXTENSION: &str = "persistedQuery";
+// Remembered queries are dropped wholesale beyond this, bounding what clients can make us hold.
+const MAX_CACHED_QUERIES: usize = 1000;
+
+/// Which operations may run.
+#[derive(Args, Clone, Debug)]
+pub struct PersistedQueryArgs {
+    /// JSON file mapping the SHA-256 of each allowed query to the query.
+    #[arg(long, global = true, env = "OPERATION_ALLOW_LIST")]
+    pub operation_allow_list: Option<PathBuf>,
+
+    /// Run only operations on the allow-list.
+    #[arg(long, global = true, env = "STRICT_OPERATIONS")]
+    pub strict_operations: bool,
+}
+
+#[derive(Deserialize)]
+struct PersistedQuery {
+    version: i32,
+    #[serde(rename = "sha256Hash")]
+    sha256_hash: String,
+}
+
+/// Queries known by hash, shared by the schemas of every worker.
+#[derive(Clone, Default)]
+pub struct PersistedQueries(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    allowed: HashMap<String, String>,
+    strict: bool,
+    cached: Mutex<HashMap<String, String>>,
+}
+
+impl PersistedQueries {
+    /// Loads the allow-list, checking every query matches its hash.
+    pub fn load(args: &PersistedQueryArgs) -> Result<Self, Error> {
+        let allowed = match &args.operation_allow_list {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let allowed: HashMap<String, String> = serde_json::from_str(&content)
+                    .with_context(|| format!("Invalid operation allow-list {}", path.display()))?;
+                for (hash, query) in &allowed {
+                    if *hash != sha256(query) {
+                        bail!("Hash {hash} in {} does not match its query", path.display());
+                    }
+                }
+                tracing::info!(operations = allowed.len(), "Loaded operation allow-list");
+                allowed
+            }
+            None if args.strict_operations => {
+                bail!("--strict-operations needs an --operation-allow-list")
+            }
+            None => HashMap::new(),
+        };
+        Ok(Self::new(allowed, args.strict_operations))
+    }
+
+    fn new(allowed: HashMap<String, String>, strict: bool) -> Self {
+        Self(Arc::new(Inner {
+            allowed,
+            strict,
+            cached: Mutex::default(),
+        }))
+    }
+
+    fn lookup(&self, hash: &str) -> Option<String> {
+        if let Some(query) = self.0.allowed.get(hash) {
+            return Some(query.clone());
+        }
+        self.0.cached.lock().unwrap().get(hash).cloned()
+    }
+
+    fn remember(&self, hash: String, query: &str) {
+        let mut cached = self.0.cached.lock().unwrap();
+        if cached.len() >= MAX_CACHED_QUERIES {
+            cached.clear();
+        }
+        cached.insert(hash, query.to_string());
+    }
+
+    fn check_allowed(&self, hash: &str) -> Result<(), AppError> {
+        if self.0.strict && !self.0.allowed.contains_key(hash) {
+            return Err(AppError::OperationNotAllowed);
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, request: &mut Request) -> Result<(), AppError> {
+        let Some(value) = request.extensions.remove(PERSISTED_QUERY_EXTENSION) else {
+            return self.check_allowed(&sha256(&request.query));
+        };
+        let persisted: PersistedQuery = async_graphql::from_value(value).map_err(|_| {
+            AppError::validation(
+                "Invalid persistedQuery extension, expected version and sha256Hash",
+            )
+        })?;
+        if persisted.version != 1 {
+            return Err(AppError::validation(format!(
+                "Only version 1 of persisted queries is supported, got {}",
+                persisted.version
+            )));
+        }
+        if request.query.is_empty() {
+            self.check_allowed(&persisted.sha256_hash)?;
+            request.query = self
+                .lookup(&persisted.sha256_hash)
+                .ok_or(AppError::PersistedQueryNotFound)?;
+            return Ok(());
+        }
+        let hash = sha256(&request.query);
+        if hash != persisted.sha256_hash {
+            return Err(AppError::validation("sha256Hash does not match the query"));
+        }
+        self.check_allowed(&hash)?;
+        if !self.0.allowed.contains_key(&hash) {
+            self.remember(hash, &request.query);
+        }
+        Ok(())
+    }
+}
+
+fn sha256(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+impl ExtensionFactory for PersistedQueries {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(PersistedQueriesExtension(self.clone()))
+    }
+}
+
+struct PersistedQueriesExtension(PersistedQueries);
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for PersistedQueriesExtension {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        mut request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        self.0.resolve(&mut request).map_err(ServerError::from)?;
+        next.run(ctx, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PersistedQueries, sha256};
+    use crate::build_schema;
+    use crate::limits::QueryLimits;
+    use crate::memory::MemoryStore;
+    use crate::models::Role;
+    use async_graphql::{Request, Value};
+    use serde_json::json;
+    use std::{collections::HashMap, sync::Arc};
+
+    const QUERY: &str = r#"{ merchant(merchantId: "MERCHANT#none") { id } }"#;
+    const OTHER_QUERY: &str = r#"{ transactionSummary(merchantId: "MERCHANT#none", period: DAY, date: "2025-01-01") { transactions } }"#;
+
+    async fn execute(
+        persisted_queries: &PersistedQueries,
+        query: &str,
+        hash: Option<&str>,
+    ) -> serde_json::Value {
+        let schema = build_schema(
+            Arc::new(MemoryStore::default()),
+            QueryLimits::default(),
+            persisted_queries.clone(),
+        );
+        let mut request = Request::new(query).data(Role::Reader);
+        if let Some(hash) = hash {
+            request.extensions.insert(
+                "persistedQuery".to_string(),
+                Value::from_json(json!({ "version": 1, "sha256Hash": hash })).unwrap(),
+            );
+        }
+        serde_json::to_value(schema.execute(request).await).unwrap()
+    }
+
+    fn code(response: &serde_json::Value) -> &serde_json::Value {
+        &response["errors"][0]["extensions"]["code"]
+    }
+
+    #[actix_web::test]
+    async fn queries_are_remembered_by_hash_once_sent() {
+        let persisted_queries = PersistedQueries::default();
+        let hash = sha256(QUERY);
+
+        let response = execute(&persisted_queries, "", Some(&hash)).await;
+        assert_eq!(code(&response), "PERSISTED_QUERY_NOT_FOUND", "{response}");
+        assert_eq!(response["errors"][0]["message"], "PersistedQueryNotFound");
+
+        let response = execute(&persisted_queries, QUERY, Some(&sha256(OTHER_QUERY))).await;
+        assert_eq!(code(&response), "VALIDATION", "{response}");
+
+        // Resolving runs the query, which fails in the resolver for the missing merchant.
+        let response = execute(&persisted_queries, QUERY, Some(&hash)).await;
+        assert_eq!(code(&response), "NOT_FOUND", "{response}");
+        let response = execute(&persisted_queries, "", Some(&hash)).await;
+        assert_eq!(code(&response), "NOT_FOUND", "{response}");
+    }
+
+    #[actix_web::test]
+    async fn strict_mode_runs_only_allowed_operations() {
+        let persisted_queries =
+            PersistedQueries::new(HashMap::from([(sha256(QUERY), QUERY.to_string())]), true);
+
+        for (query, hash) in [(QUERY, None), ("", Some(sha256(QUERY)))] {
+            let response = execute(&persisted_queries, query, hash.as_deref()).await;
+            assert_eq!(code(&response), "NOT_FOUND", "{response}");
+        }
+        for (query, hash) in [
+            (OTHER_QUERY, None),
+            (OTHER_QUERY, Some(sha256(OTHER_QUERY))),
+            ("", Some(sha256(OTHER_QUERY))),
+        ] {
+            let response = execute(&persisted_queries, query, hash.as_deref()).await;
+            assert_eq!(code(&response), "OPERATION_NOT_ALLOWED", "{response}");
+            assert!(response["data"].is_null(), "{response}");
+        }
+    }
+}
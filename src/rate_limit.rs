@@ -0,0 +1,402 @@
+//! Per-client rate limiting of GraphQL requests, subscriptions and transaction exports.
+//!
+//! Every client gets a token bucket per role: a request takes a token, and tokens refill at the
+//! role's rate up to its burst. Clients are told apart by their peer IP address: `X-Actor-Id` is
+//! set by the caller and not authenticated, so keying on it would hand out a fresh bucket per
+//! header value. A request finding the bucket empty is answered with `429 Too Many Requests`, a
+//! `Retry-After` header and a GraphQL error coded `RATE_LIMITED`.
+//!
+//! Opening a websocket takes a token like any request, and a client may hold at most
+//! `--max-subscriptions-per-client` subscriptions open at once across its connections.
+
+use crate::errors::AppError;
+use crate::metrics::metrics;
+use crate::models::{Role, env_role};
+use actix_web::{
+    HttpResponse,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::RETRY_AFTER,
+    middleware::Next,
+    web,
+};
+use async_graphql::{Response, ServerError};
+use clap::Args;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Buckets left idle long enough to refill are dropped once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Request rates of each role.
+#[derive(Args, Clone, Debug)]
+pub struct RateLimitArgs {
+    /// Requests per second and burst of an admin client, as `RATE:BURST`.
+    #[arg(
+        long,
+        global = true,
+        env = "ADMIN_RATE_LIMIT",
+        default_value = "50:100"
+    )]
+    pub admin_rate_limit: RateLimit,
+
+    /// Requests per second and burst of a reader client, as `RATE:BURST`.
+    #[arg(
+        long,
+        global = true,
+        env = "READER_RATE_LIMIT",
+        default_value = "20:40"
+    )]
+    pub reader_rate_limit: RateLimit,
+
+    /// Requests per second and burst of a client without a role, as `RATE:BURST`.
+    #[arg(
+        long,
+        global = true,
+        env = "ANONYMOUS_RATE_LIMIT",
+        default_value = "5:10"
+    )]
+    pub anonymous_rate_limit: RateLimit,
+
+    /// Subscriptions a client may hold open at once.
+    #[arg(
+        long,
+        global = true,
+        env = "MAX_SUBSCRIPTIONS_PER_CLIENT",
+        default_value_t = 10
+    )]
+    pub max_subscriptions_per_client: usize,
+}
+
+/// Tokens a bucket refills per second and holds at most.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (per_second, burst) = value
+            .split_once(':')
+            .ok_or_else(|| format!("expected RATE:BURST, got {value}"))?;
+        let per_second: f64 = per_second
+            .parse()
+            .map_err(|err| format!("invalid rate {per_second}: {err}"))?;
+        let burst: u32 = burst
+            .parse()
+            .map_err(|err| format!("invalid burst {burst}: {err}"))?;
+        if !(per_second > 0.0 && burst >= 1) {
+            return Err(format!("rate and burst must be positive, got {value}"));
+        }
+        Ok(Self { per_second, burst })
+    }
+}
+
+/// The token buckets of every client, shared by the server's workers.
+pub struct RateLimiter {
+    admin: RateLimit,
+    reader: RateLimit,
+    anonymous: RateLimit,
+    buckets: Mutex<HashMap<(Option<Role>, String), Bucket>>,
+    max_subscriptions: usize,
+    subscriptions: Mutex<HashMap<String, usize>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(args: &RateLimitArgs) -> Self {
+        Self {
+            admin: args.admin_rate_limit,
+            reader: args.reader_rate_limit,
+            anonymous: args.anonymous_rate_limit,
+            buckets: Mutex::new(HashMap::new()),
+            max_subscriptions: args.max_subscriptions_per_client,
+            subscriptions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit(&self, role: Option<Role>) -> RateLimit {
+        match role {
+            Some(Role::Admin) => self.admin,
+            Some(Role::Reader) => self.reader,
+            None => self.anonymous,
+        }
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one is available.
+    pub fn acquire(&self, role: Option<Role>, client: &str) -> Result<(), Duration> {
+        self.acquire_at(role, client, Instant::now())
+    }
+
+    fn acquire_at(&self, role: Option<Role>, client: &str, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(role);
+        let burst = f64::from(limit.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|(role, _), bucket| {
+                let limit = self.limit(*role);
+                bucket.tokens
+                    + now.duration_since(bucket.refilled_at).as_secs_f64() * limit.per_second
+                    < f64::from(limit.burst)
+            });
+        }
+        let bucket = buckets.entry((role, client.to_string())).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+/// The client a websocket connection belongs to, for counting the subscriptions it opens.
+#[derive(Clone)]
+pub struct SubscriptionClient {
+    limiter: Arc<RateLimiter>,
+    client: String,
+}
+
+impl SubscriptionClient {
+    pub fn new(limiter: Arc<RateLimiter>, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            limiter,
+            client: client(peer_addr),
+        }
+    }
+
+    /// Counts one more open subscription, unless the client already holds its maximum.
+    pub fn open(&self) -> Result<SubscriptionSlot, AppError> {
+        let mut subscriptions = self.limiter.subscriptions.lock().unwrap();
+        let open = subscriptions.entry(self.client.clone()).or_default();
+        if *open >= self.limiter.max_subscriptions {
+            tracing::warn!(client = self.client, "Subscription limit exceeded");
+            return Err(AppError::TooManySubscriptions(
+                self.limiter.max_subscriptions,
+            ));
+        }
+        *open += 1;
+        Ok(SubscriptionSlot {
+            owner: self.clone(),
+        })
+    }
+}
+
+/// An open subscription, counted against its client until dropped.
+pub struct SubscriptionSlot {
+    owner: SubscriptionClient,
+}
+
+impl Drop for SubscriptionSlot {
+    fn drop(&mut self) {
+        let mut subscriptions = self.owner.limiter.subscriptions.lock().unwrap();
+        if let Some(open) = subscriptions.get_mut(&self.owner.client) {
+            *open -= 1;
+            if *open == 0 {
+                subscriptions.remove(&self.owner.client);
+            }
+        }
+    }
+}
+
+/// Clients are keyed by peer IP; see the module docs.
+fn client(peer_addr: Option<SocketAddr>) -> String {
+    peer_addr.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+/// Rejects requests of clients that ran out of tokens.
+pub async fn rate_limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let client = client(req.peer_addr());
+    let role = env_role();
+    let Err(wait) = limiter.acquire(role, &client) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let role_label = role.map_or_else(|| "anonymous".to_string(), |role| role.to_string());
+    metrics().observe_rate_limited(&role_label);
+    tracing::warn!(client, role = role_label, "Rate limit exceeded");
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let error = ServerError::from(AppError::RateLimited(Duration::from_secs(retry_after)));
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, retry_after.to_string()))
+        .json(Response::from_errors(vec![error]));
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, RateLimitArgs, RateLimiter, SubscriptionClient, rate_limit};
+    use crate::models::Role;
+    use actix_web::test::{TestRequest, call_service, init_service, read_body_json};
+    use actix_web::{App, HttpResponse, http::StatusCode, middleware, web};
+    use serde_json::Value;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(&RateLimitArgs {
+            admin_rate_limit: "10:5".parse().unwrap(),
+            reader_rate_limit: "2:2".parse().unwrap(),
+            anonymous_rate_limit: "1:1".parse().unwrap(),
+            max_subscriptions_per_client: 2,
+        })
+    }
+
+    #[test]
+    fn a_client_gets_its_burst_then_tokens_at_its_role_rate() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let reader = Some(Role::Reader);
+        assert_eq!(limiter.acquire_at(reader, "10.0.0.1", start), Ok(()));
+        assert_eq!(limiter.acquire_at(reader, "10.0.0.1", start), Ok(()));
+        assert_eq!(
+            limiter.acquire_at(reader, "10.0.0.1", start),
+            Err(Duration::from_millis(500))
+        );
+        assert_eq!(
+            limiter.acquire_at(reader, "10.0.0.2", start),
+            Ok(()),
+            "other clients have their own bucket"
+        );
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.acquire_at(reader, "10.0.0.1", later), Ok(()));
+        assert!(limiter.acquire_at(reader, "10.0.0.1", later).is_err());
+
+        for _ in 0..5 {
+            assert_eq!(
+                limiter.acquire_at(Some(Role::Admin), "10.0.0.1", start),
+                Ok(())
+            );
+        }
+        assert_eq!(limiter.acquire_at(None, "10.0.0.3", start), Ok(()));
+        assert_eq!(
+            limiter.acquire_at(None, "10.0.0.3", start),
+            Err(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn a_client_holds_at_most_its_subscriptions_across_connections() {
+        let limiter = Arc::new(limiter());
+        let peer = |port| Some(format!("10.0.0.1:{port}").parse().unwrap());
+        let first_connection = SubscriptionClient::new(limiter.clone(), peer(4000));
+        let second_connection = SubscriptionClient::new(limiter.clone(), peer(4001));
+        let first = first_connection.open().unwrap();
+        let _second = second_connection.open().unwrap();
+        let err = first_connection.open().err().unwrap();
+        assert_eq!(err.code(), "TOO_MANY_SUBSCRIPTIONS");
+        assert!(
+            SubscriptionClient::new(limiter.clone(), Some("10.0.0.2:4000".parse().unwrap()))
+                .open()
+                .is_ok(),
+            "other clients count their own subscriptions"
+        );
+
+        drop(first);
+        assert!(
+            second_connection.open().is_ok(),
+            "an ended subscription frees its slot"
+        );
+    }
+
+    #[test]
+    fn rate_limits_parse_as_rate_and_burst() {
+        assert_eq!(
+            "0.5:3".parse::<RateLimit>(),
+            Ok(RateLimit {
+                per_second: 0.5,
+                burst: 3
+            })
+        );
+        assert!("5".parse::<RateLimit>().is_err());
+        assert!("0:3".parse::<RateLimit>().is_err());
+        assert!("5:0".parse::<RateLimit>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn clients_over_their_limit_get_429_with_retry_after() {
+        let app = init_service(
+            App::new().app_data(web::Data::new(limiter())).service(
+                web::resource("/")
+                    .wrap(middleware::from_fn(rate_limit))
+                    .to(HttpResponse::Ok),
+            ),
+        )
+        .await;
+        let request = |peer: &str, actor: &str| {
+            TestRequest::post()
+                .uri("/")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("X-Actor-Id", actor.to_string()))
+                .to_request()
+        };
+
+        // `ROLE` is unset in tests, so the anonymous limit of one request applies.
+        let response = call_service(&app, request("10.0.0.1:4000", "a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call_service(&app, request("10.0.0.1:4000", "a")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+        let body: Value = read_body_json(response).await;
+        assert_eq!(body["errors"][0]["extensions"]["code"], "RATE_LIMITED");
+        assert_eq!(body["errors"][0]["extensions"]["retryable"], true);
+
+        let response = call_service(&app, request("10.0.0.2:4000", "a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn rotating_the_actor_id_does_not_reset_the_limit() {
+        let app = init_service(
+            App::new().app_data(web::Data::new(limiter())).service(
+                web::resource("/")
+                    .wrap(middleware::from_fn(rate_limit))
+                    .to(HttpResponse::Ok),
+            ),
+        )
+        .await;
+        let mut statuses = Vec::new();
+        for actor in ["a", "b", "c"] {
+            let request = TestRequest::post()
+                .uri("/")
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .insert_header(("X-Actor-Id", actor))
+                .to_request();
+            statuses.push(call_service(&app, request).await.status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Timeouts, retries and circuit breaking for DynamoDB calls.
+//!
+//! The SDK bounds every attempt and retries throttled and transient failures with jittered
+//! exponential backoff. On top of that each call made through `Table::observe` gets an overall
+//! deadline, retries included, and passes a [`CircuitBreaker`] that fails calls fast with an
+//! `UNAVAILABLE` error once the table stops answering, instead of stalling every request behind it.
+
+use crate::metrics::metrics;
+use aws_config::{retry::RetryConfig, timeout::TimeoutConfig};
+use clap::Args;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_OPERATION_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_ATTEMPT_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 50;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 2000;
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_MS: u64 = 10_000;
+
+/// Timeouts, retries and circuit breaking of DynamoDB calls.
+#[derive(Args, Clone, Debug)]
+pub struct ResilienceArgs {
+    /// Deadline of one DynamoDB operation, retries included, in milliseconds.
+    #[arg(long, global = true, env = "DYNAMODB_OPERATION_TIMEOUT_MS", default_value_t = DEFAULT_OPERATION_TIMEOUT_MS)]
+    pub dynamodb_operation_timeout_ms: u64,
+
+    /// Deadlines of single operations overriding the default, e.g.
+    /// `get_transactions=2000,add_payout=10000`.
+    #[arg(
+        long,
+        global = true,
+        env = "DYNAMODB_OPERATION_TIMEOUTS",
+        value_delimiter = ','
+    )]
+    pub dynamodb_operation_timeouts: Vec<OperationTimeout>,
+
+    /// Deadline of each attempt of an operation, in milliseconds.
+    #[arg(long, global = true, env = "DYNAMODB_ATTEMPT_TIMEOUT_MS", default_value_t = DEFAULT_ATTEMPT_TIMEOUT_MS)]
+    pub dynamodb_attempt_timeout_ms: u64,
+
+    /// Attempts per operation, the first included. Throttled and transient failures are retried.
+    #[arg(long, global = true, env = "DYNAMODB_MAX_ATTEMPTS", default_value_t = DEFAULT_MAX_ATTEMPTS)]
+    pub dynamodb_max_attempts: u32,
+
+    /// Backoff before the first retry in milliseconds, doubling with jitter for later ones.
+    #[arg(long, global = true, env = "DYNAMODB_INITIAL_BACKOFF_MS", default_value_t = DEFAULT_INITIAL_BACKOFF_MS)]
+    pub dynamodb_initial_backoff_ms: u64,
+
+    /// Consecutive failed operations that open the circuit breaker.
+    #[arg(long, global = true, env = "DYNAMODB_FAILURE_THRESHOLD", default_value_t = DEFAULT_FAILURE_THRESHOLD)]
+    pub dynamodb_failure_threshold: u32,
+
+    /// How long an open circuit fails calls fast before one probes the table, in milliseconds.
+    #[arg(long, global = true, env = "DYNAMODB_COOLDOWN_MS", default_value_t = DEFAULT_COOLDOWN_MS)]
+    pub dynamodb_cooldown_ms: u64,
+}
+
+impl ResilienceArgs {
+    /// Retry policy of the SDK clients.
+    pub fn retry_config(&self) -> RetryConfig {
+        RetryConfig::standard()
+            .with_max_attempts(self.dynamodb_max_attempts)
+            .with_initial_backoff(Duration::from_millis(self.dynamodb_initial_backoff_ms))
+            .with_max_backoff(Duration::from_millis(DEFAULT_MAX_BACKOFF_MS))
+    }
+
+    /// Per-attempt timeouts of the SDK clients. The operation deadline is enforced per call.
+    pub fn timeout_config(&self) -> TimeoutConfig {
+        TimeoutConfig::builder()
+            .connect_timeout(Duration::from_millis(DEFAULT_CONNECT_TIMEOUT_MS))
+            .operation_attempt_timeout(Duration::from_millis(self.dynamodb_attempt_timeout_ms))
+            .build()
+    }
+
+    pub fn call_policy(&self) -> CallPolicy {
+        CallPolicy {
+            default_timeout: Duration::from_millis(self.dynamodb_operation_timeout_ms),
+            timeouts: self
+                .dynamodb_operation_timeouts
+                .iter()
+                .map(|timeout| (timeout.operation.clone(), timeout.timeout))
+                .collect(),
+            breaker: CircuitBreaker::new(
+                self.dynamodb_failure_threshold,
+                Duration::from_millis(self.dynamodb_cooldown_ms),
+            ),
+        }
+    }
+}
+
+/// `operation=milliseconds`, the deadline of one `Table::observe` operation.
+#[derive(Clone, Debug)]
+pub struct OperationTimeout {
+    pub operation: String,
+    pub timeout: Duration,
+}
+
+impl FromStr for OperationTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (operation, millis) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected operation=milliseconds, got {value}"))?;
+        let millis: u64 = millis
+            .parse()
+            .map_err(|err| format!("invalid timeout of {operation}: {err}"))?;
+        Ok(Self {
+            operation: operation.to_string(),
+            timeout: Duration::from_millis(millis),
+        })
+    }
+}
+
+/// Deadlines and the circuit breaker shared by every call to one table.
+pub struct CallPolicy {
+    default_timeout: Duration,
+    timeouts: HashMap<String, Duration>,
+    pub breaker: CircuitBreaker,
+}
+
+impl CallPolicy {
+    /// Deadline of `operation`, retries included.
+    pub fn timeout(&self, operation: &str) -> Duration {
+        self.timeouts
+            .get(operation)
+            .copied()
+            .unwrap_or(self.default_timeout)
+    }
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        Self {
+            default_timeout: Duration::from_millis(DEFAULT_OPERATION_TIMEOUT_MS),
+            timeouts: HashMap::new(),
+            breaker: CircuitBreaker::new(
+                DEFAULT_FAILURE_THRESHOLD,
+                Duration::from_millis(DEFAULT_COOLDOWN_MS),
+            ),
+        }
+    }
+}
+
+/// Opens after `threshold` consecutive outages, failing calls fast for `cooldown`. Then one call
+/// is let through as a probe: its success closes the circuit, its failure opens it again. Only
+/// outages count; a rejected request shows the table is answering. Outcomes of calls that were
+/// admitted before the circuit opened and end while it is open are ignored.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A probe that never reports back, e.g. because its request was dropped, is replaced once
+    // the cooldown passes again.
+    HalfOpen { probe_started: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Admits a call, or refuses it while the circuit is open or a probe is in flight.
+    pub fn admit(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            CircuitState::Closed { .. } => Ok(()),
+            CircuitState::Open { until } if now >= until => {
+                *state = CircuitState::HalfOpen { probe_started: now };
+                tracing::info!("DynamoDB circuit half-open, probing");
+                Ok(())
+            }
+            CircuitState::HalfOpen { probe_started } if now >= probe_started + self.cooldown => {
+                *state = CircuitState::HalfOpen { probe_started: now };
+                Ok(())
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => Err(CircuitOpen),
+        }
+    }
+
+    /// Records how an admitted call ended.
+    pub fn record(&self, outage: bool) {
+        let mut state = self.state.lock().unwrap();
+        let opened = CircuitState::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        *state = match (&*state, outage) {
+            (CircuitState::Open { .. }, _) | (CircuitState::Closed { failures: 0 }, false) => {
+                return;
+            }
+            (CircuitState::Closed { .. }, false) => CircuitState::Closed { failures: 0 },
+            (CircuitState::HalfOpen { .. }, false) => {
+                tracing::info!("DynamoDB circuit closed");
+                metrics().set_dynamodb_circuit_open(false);
+                CircuitState::Closed { failures: 0 }
+            }
+            (CircuitState::Closed { failures }, true) if failures + 1 < self.threshold => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (CircuitState::Closed { .. } | CircuitState::HalfOpen { .. }, true) => {
+                tracing::warn!(
+                    cooldown_ms = self.cooldown.as_millis() as u64,
+                    "DynamoDB circuit opened"
+                );
+                metrics().set_dynamodb_circuit_open(true);
+                opened
+            }
+        };
+    }
+}
+
+/// Returned instead of calling DynamoDB while the circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DynamoDB circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// An operation that ran past its deadline.
+#[derive(Debug)]
+pub struct OperationTimedOut(pub Duration);
+
+impl fmt::Display for OperationTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DynamoDB operation timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for OperationTimedOut {}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallPolicy, CircuitBreaker, OperationTimeout};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn the_circuit_opens_after_consecutive_outages_and_closes_after_a_good_probe() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(true);
+        assert!(breaker.admit().is_ok(), "a success resets the count");
+        breaker.record(true);
+        assert!(breaker.admit().is_err(), "open after two outages in a row");
+        breaker.record(false);
+        assert!(
+            breaker.admit().is_err(),
+            "a slow call ending while open does not close it"
+        );
+
+        thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit().is_ok(), "one probe after the cooldown");
+        assert!(breaker.admit().is_err(), "no second call while probing");
+        breaker.record(true);
+        assert!(breaker.admit().is_err(), "a failed probe opens it again");
+
+        thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit().is_ok());
+        breaker.record(false);
+        assert!(breaker.admit().is_ok(), "a good probe closes it");
+        assert!(breaker.admit().is_ok());
+    }
+
+    #[test]
+    fn operations_without_an_override_get_the_default_deadline() {
+        let mut policy = CallPolicy::default();
+        let timeout: OperationTimeout = "get_transactions=250".parse().unwrap();
+        policy.timeouts.insert(timeout.operation, timeout.timeout);
+        assert_eq!(
+            policy.timeout("get_transactions"),
+            Duration::from_millis(250)
+        );
+        assert_eq!(policy.timeout("get_merchant"), policy.default_timeout);
+        assert!("get_transactions".parse::<OperationTimeout>().is_err());
+    }
+}
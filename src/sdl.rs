@@ -0,0 +1,278 @@
+//! The schema as SDL, for client codegen, and the check that keeps it compatible.
+//!
+//! `schema` prints the SDL of the schema the server runs. `schema --check schema.graphql` compares
+//! it against the committed file instead and fails on changes that break existing clients:
+//! removed types, fields, arguments, enum values and union members, output fields that may now be
+//! null or changed type, and arguments or input fields that became required or changed type.
+//! Additions pass; regenerate the file to publish them.
+
+use crate::build_schema;
+use crate::limits::QueryLimits;
+use crate::memory::MemoryStore;
+use crate::persisted_queries::PersistedQueries;
+use anyhow::{Context, Error, bail};
+use async_graphql::parser::{
+    parse_schema,
+    types::{
+        BaseType, FieldDefinition, InputValueDefinition, Type, TypeDefinition, TypeKind,
+        TypeSystemDefinition,
+    },
+};
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+/// SDL of the schema built by `build_schema`.
+pub fn current() -> String {
+    build_schema(
+        Arc::new(MemoryStore::default()),
+        QueryLimits::default(),
+        PersistedQueries::default(),
+    )
+    .sdl()
+}
+
+/// Prints the SDL, or checks it against the committed SDL at `check`.
+pub fn run(check: Option<&Path>) -> Result<(), Error> {
+    let sdl = current();
+    let Some(path) = check else {
+        print!("{sdl}");
+        return Ok(());
+    };
+    let committed =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let breaking = breaking_changes(&committed, &sdl)
+        .with_context(|| format!("Failed to compare against {}", path.display()))?;
+    if !breaking.is_empty() {
+        for change in &breaking {
+            println!("{change}");
+        }
+        bail!(
+            "{} breaking changes against {}",
+            breaking.len(),
+            path.display()
+        );
+    }
+    if committed == sdl {
+        tracing::info!("Schema matches {}", path.display());
+    } else {
+        tracing::info!(
+            "Schema has compatible changes, regenerate {} to publish them",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Changes from `old` to `new` SDL that break clients written against `old`.
+pub fn breaking_changes(old: &str, new: &str) -> Result<Vec<String>, Error> {
+    let old_document = parse_schema(old).context("Invalid old SDL")?;
+    let new_document = parse_schema(new).context("Invalid new SDL")?;
+    let old_types = types(&old_document.definitions);
+    let new_types = types(&new_document.definitions);
+
+    let mut breaking = Vec::new();
+    let mut names: Vec<&&str> = old_types.keys().collect();
+    names.sort();
+    for name in names {
+        let old_type = &old_types[*name].kind;
+        let Some(new_type) = new_types.get(*name).map(|definition| &definition.kind) else {
+            breaking.push(format!("Type {name} was removed"));
+            continue;
+        };
+        match (old_type, new_type) {
+            (TypeKind::Object(old), TypeKind::Object(new)) => {
+                compare_fields(name, &old.fields, &new.fields, &mut breaking)
+            }
+            (TypeKind::Interface(old), TypeKind::Interface(new)) => {
+                compare_fields(name, &old.fields, &new.fields, &mut breaking)
+            }
+            (TypeKind::InputObject(old), TypeKind::InputObject(new)) => compare_inputs(
+                &format!("Input field {name}."),
+                &old.fields,
+                &new.fields,
+                &mut breaking,
+            ),
+            (TypeKind::Enum(old), TypeKind::Enum(new)) => {
+                for value in &old.values {
+                    let value = &value.node.value.node;
+                    if !new.values.iter().any(|new| new.node.value.node == *value) {
+                        breaking.push(format!("Enum value {name}.{value} was removed"));
+                    }
+                }
+            }
+            (TypeKind::Union(old), TypeKind::Union(new)) => {
+                for member in &old.members {
+                    if !new.members.iter().any(|new| new.node == member.node) {
+                        breaking.push(format!("Union {name} no longer includes {}", member.node));
+                    }
+                }
+            }
+            (TypeKind::Scalar, TypeKind::Scalar) => {}
+            (old, new) => breaking.push(format!(
+                "Type {name} changed from {} to {}",
+                kind_label(old),
+                kind_label(new)
+            )),
+        }
+    }
+    Ok(breaking)
+}
+
+fn types(definitions: &[TypeSystemDefinition]) -> HashMap<&str, &TypeDefinition> {
+    definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            TypeSystemDefinition::Type(definition) => {
+                Some((definition.node.name.node.as_str(), &definition.node))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn kind_label(kind: &TypeKind) -> &'static str {
+    match kind {
+        TypeKind::Scalar => "scalar",
+        TypeKind::Object(_) => "object",
+        TypeKind::Interface(_) => "interface",
+        TypeKind::Union(_) => "union",
+        TypeKind::Enum(_) => "enum",
+        TypeKind::InputObject(_) => "input object",
+    }
+}
+
+fn compare_fields(
+    type_name: &str,
+    old_fields: &[async_graphql::Positioned<FieldDefinition>],
+    new_fields: &[async_graphql::Positioned<FieldDefinition>],
+    breaking: &mut Vec<String>,
+) {
+    for old in old_fields {
+        let old = &old.node;
+        let name = &old.name.node;
+        let Some(new) = new_fields.iter().find(|new| new.node.name.node == *name) else {
+            breaking.push(format!("Field {type_name}.{name} was removed"));
+            continue;
+        };
+        let new = &new.node;
+        if !output_compatible(&old.ty.node, &new.ty.node) {
+            breaking.push(format!(
+                "Field {type_name}.{name} changed type from {} to {}",
+                old.ty.node, new.ty.node
+            ));
+        }
+        compare_inputs(
+            &format!("Argument {type_name}.{name}("),
+            &old.arguments,
+            &new.arguments,
+            breaking,
+        );
+    }
+}
+
+// `label` names the owner of the inputs, e.g. `Argument Query.merchant(`.
+fn compare_inputs(
+    label: &str,
+    old_inputs: &[async_graphql::Positioned<InputValueDefinition>],
+    new_inputs: &[async_graphql::Positioned<InputValueDefinition>],
+    breaking: &mut Vec<String>,
+) {
+    let suffix = if label.ends_with('(') { ":)" } else { "" };
+    for old in old_inputs {
+        let old = &old.node;
+        let name = &old.name.node;
+        match new_inputs.iter().find(|new| new.node.name.node == *name) {
+            None => breaking.push(format!("{label}{name}{suffix} was removed")),
+            Some(new) if !input_compatible(&old.ty.node, &new.node.ty.node) => {
+                breaking.push(format!(
+                    "{label}{name}{suffix} changed type from {} to {}",
+                    old.ty.node, new.node.ty.node
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    for new in new_inputs {
+        let new = &new.node;
+        let name = &new.name.node;
+        let required = !new.ty.node.nullable && new.default_value.is_none();
+        if required && !old_inputs.iter().any(|old| old.node.name.node == *name) {
+            breaking.push(format!("{label}{name}{suffix} was added as required"));
+        }
+    }
+}
+
+/// Clients reading a field typed `old` can read `new`: the same types, nullable ones possibly
+/// made non-null.
+fn output_compatible(old: &Type, new: &Type) -> bool {
+    (old.nullable || !new.nullable)
+        && match (&old.base, &new.base) {
+            (BaseType::Named(old), BaseType::Named(new)) => old == new,
+            (BaseType::List(old), BaseType::List(new)) => output_compatible(old, new),
+            _ => false,
+        }
+}
+
+/// Clients sending a value typed `old` can send it as `new`: the same types, non-null ones
+/// possibly made nullable.
+fn input_compatible(old: &Type, new: &Type) -> bool {
+    (new.nullable || !old.nullable)
+        && match (&old.base, &new.base) {
+            (BaseType::Named(old), BaseType::Named(new)) => old == new,
+            (BaseType::List(old), BaseType::List(new)) => input_compatible(old, new),
+            _ => false,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{breaking_changes, current};
+
+    const OLD: &str = r#"
+        type Query {
+            merchant(merchantId: String!, verbose: Boolean): Merchant!
+            legacy: String
+        }
+        type Merchant { id: String! name: String level: Level! parent: String! }
+        enum Level { GROUP OUTLET }
+        input Filter { year: String! month: String }
+    "#;
+
+    #[test]
+    fn breaking_changes_are_reported_and_additions_pass() {
+        let new = r#"
+            type Query {
+                merchant(merchantId: String, verbose: Boolean!, page: Int!, limit: Int = 10): Merchant!
+                added: String
+            }
+            type Merchant { id: String! name: String! level: Level! parent: String extra: Int }
+            enum Level { GROUP }
+            input Filter { year: String month: Int day: String! }
+        "#;
+        assert_eq!(
+            breaking_changes(OLD, new).unwrap(),
+            [
+                "Input field Filter.month changed type from String to Int",
+                "Input field Filter.day was added as required",
+                "Enum value Level.OUTLET was removed",
+                "Field Merchant.parent changed type from String! to String",
+                "Argument Query.merchant(verbose:) changed type from Boolean to Boolean!",
+                "Argument Query.merchant(page:) was added as required",
+                "Field Query.legacy was removed",
+            ]
+        );
+        assert!(breaking_changes(OLD, OLD).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_committed_schema_is_current() {
+        let committed = include_str!("../schema.graphql");
+        assert_eq!(
+            breaking_changes(committed, &current()).unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(
+            committed == current(),
+            "schema.graphql is outdated, regenerate it with `cargo run -- schema > schema.graphql`"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::dynamo::{MERCHANT_PREFIX, PAYOUT_PREFIX, TRANSACTION_PREFIX};
+use crate::models::{CardBrand, Merchant, Transaction, TransactionStatus, TransactionType};
+use crate::store::Store;
+use anyhow::{Context, Error, bail};
+use chrono::{Days, NaiveDate};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+const DEFAULT_FIXTURE: &str = include_str!("../fixtures/default.yaml");
+
+/// Seed data loaded by the `seed` subcommand. Merchants and transactions are written as given;
+/// `generated_transactions` are filled in from an RNG seeded with `seed`, so loading the same
+/// fixture twice writes the same items.
+#[derive(Deserialize)]
+pub struct Fixture {
+    #[serde(default)]
+    pub seed: u64,
+    pub merchants: Vec<Merchant>,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub generated_transactions: Vec<GeneratedTransactions>,
+}
+
+/// `count` daily transactions for one merchant, starting on `first_date`.
+#[derive(Deserialize)]
+pub struct GeneratedTransactions {
+    pub merchant_id: String,
+    pub settlement_merchant_id: String,
+    pub count: u32,
+    pub first_date: NaiveDate,
+    #[serde(default = "default_settlement_delay_days")]
+    pub settlement_delay_days: u64,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+fn default_settlement_delay_days() -> u64 {
+    1
+}
+
+fn default_currency() -> String {
+    "GBP".to_string()
+}
+
+impl Fixture {
+    /// Reads a fixture file, as JSON when it ends in `.json` and as YAML otherwise.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read fixture {}", path.display()))?;
+        let fixture = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&contents)?
+        } else {
+            serde_yaml::from_str(&contents)?
+        };
+        Ok(fixture)
+    }
+
+    /// The fixture shipped in `fixtures/default.yaml`.
+    pub fn default_fixture() -> Result<Self, Error> {
+        serde_yaml::from_str(DEFAULT_FIXTURE).context("Invalid default fixture")
+    }
+
+    /// Checks that ids are unique, that parent and child links agree and that every transaction
+    /// belongs to a merchant of the fixture.
+    fn validate(&self) -> Result<(), Error> {
+        let mut merchants = HashMap::new();
+        for merchant in &self.merchants {
+            if !merchant.id.starts_with(&format!("{MERCHANT_PREFIX}#")) {
+                bail!(
+                    "Merchant id {} must start with {MERCHANT_PREFIX}#",
+                    merchant.id
+                );
+            }
+            if merchants.insert(merchant.id.as_str(), merchant).is_some() {
+                bail!("Duplicate merchant {}", merchant.id);
+            }
+        }
+
+        for merchant in &self.merchants {
+            for child_id in &merchant.sub_merchants {
+                let Some(child) = merchants.get(child_id.as_str()) else {
+                    bail!("Sub-merchant {child_id} of {} is not defined", merchant.id);
+                };
+                if !merchant.merchant_level.can_contain(child.merchant_level) {
+                    bail!(
+                        "A {} cannot contain the {} {child_id}",
+                        merchant.merchant_level,
+                        child.merchant_level
+                    );
+                }
+                if child.parent_merchant_id.as_deref() != Some(merchant.id.as_str()) {
+                    bail!(
+                        "{child_id} is listed under {} but names another parent",
+                        merchant.id
+                    );
+                }
+            }
+            if let Some(parent_id) = &merchant.parent_merchant_id
+                && !merchants
+                    .get(parent_id.as_str())
+                    .is_some_and(|parent| parent.sub_merchants.contains(&merchant.id))
+            {
+                bail!(
+                    "{} names {parent_id} as parent but is not one of its sub-merchants",
+                    merchant.id
+                );
+            }
+        }
+
+        let transaction_merchants =
+            self.transactions
+                .iter()
+                .flat_map(|transaction| {
+                    [
+                        &transaction.merchant_id,
+                        &transaction.settlement_merchant_id,
+                    ]
+                })
+                .chain(self.generated_transactions.iter().flat_map(|generated| {
+                    [&generated.merchant_id, &generated.settlement_merchant_id]
+                }));
+        for merchant_id in transaction_merchants {
+            if !merchants.contains_key(merchant_id.as_str()) {
+                bail!("Transaction merchant {merchant_id} is not defined");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes every merchant and transaction of the fixture. Items are put by key, so seeding again
+/// overwrites rather than duplicates. `seed` overrides the fixture's own RNG seed.
+pub async fn run(store: &dyn Store, fixture: &Fixture, seed: Option<u64>) -> Result<(), Error> {
+    fixture.validate()?;
+    let seed = seed.unwrap_or(fixture.seed);
+    tracing::info!(merchants = fixture.merchants.len(), seed, "Seeding");
+
+    for merchant in &fixture.merchants {
+        store
+            .add_merchant(merchant)
+            .await
+            .with_context(|| format!("Failed to add merchant {}", merchant.id))?;
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let generated: Vec<Transaction> = fixture
+        .generated_transactions
+        .iter()
+        .map(|generated| generate_transactions(generated, &mut rng))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Transactions from an earlier run are left alone so their aggregates are not counted twice.
+    let mut transactions = 0;
+    let mut existing = 0;
+    for transaction in fixture.transactions.iter().cloned().chain(generated) {
+        if store.add_transaction(transaction).await? {
+            transactions += 1;
+        } else {
+            existing += 1;
+        }
+    }
+    tracing::info!(transactions, existing, "Seeded");
+    Ok(())
+}
+
+fn generate_transactions(
+    generated: &GeneratedTransactions,
+    rng: &mut impl Rng,
+) -> Result<Vec<Transaction>, Error> {
+    (1..=generated.count)
+        .map(|n| {
+            let transaction_date = generated
+                .first_date
+                .checked_add_days(Days::new(u64::from(n - 1)))
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .context("Transaction date out of range")?
+                .and_utc();
+            let settled_date = transaction_date
+                .checked_add_days(Days::new(generated.settlement_delay_days))
+                .context("Settlement date out of range")?;
+            Ok(Transaction {
+                id: format!("{TRANSACTION_PREFIX}#{}#{n}", transaction_date.to_rfc3339()),
+                merchant_id: generated.merchant_id.clone(),
+                transaction_type: random_transaction_type(rng),
+                status: random_transaction_status(rng),
+                amount: f64::from(rng.gen_range(1000..10000)) / 100.0,
+                currency: generated.currency.clone(),
+                pan: rng.gen_range(1000i64..9999i64).to_string(),
+                card_brand: random_card_brand(rng),
+                date_transaction: transaction_date.to_rfc3339(),
+                date_settlement: settled_date.to_rfc3339(),
+                settlement_merchant_id: generated.settlement_merchant_id.clone(),
+                payout_id: format!("{PAYOUT_PREFIX}#{}#1", settled_date.to_rfc3339()),
+            })
+        })
+        .collect()
+}
+
+fn random_transaction_type(rng: &mut impl Rng) -> TransactionType {
+    match rng.gen_range(0..2) {
+        0 => TransactionType::Purchase,
+        _ => TransactionType::Refund,
+    }
+}
+
+fn random_transaction_status(rng: &mut impl Rng) -> TransactionStatus {
+    match rng.gen_range(0..4) {
+        0 => TransactionStatus::Processed,
+        1 => TransactionStatus::Cleared,
+        2 => TransactionStatus::Chargebacked,
+        _ => TransactionStatus::Paid,
+    }
+}
+
+fn random_card_brand(rng: &mut impl Rng) -> CardBrand {
+    match rng.gen_range(0..4) {
+        0 => CardBrand::Visa,
+        _ => CardBrand::Mastercard,
+    }
+}
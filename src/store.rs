@@ -0,0 +1,502 @@
+//! Storage behind the GraphQL API.
+//!
+//! Resolvers, the export endpoint and the webhook worker reach storage through [`Store`], which
+//! the server picks at startup with `--store`: [`DynamoStore`] hands every call to the functions
+//! in [`crate::dynamo`], while [`MemoryStore`](crate::memory::MemoryStore) keeps everything in
+//! process and returns the same ordering, pages and errors, so the API runs without DynamoDB.
+
+use crate::aggregates::{AggregatePeriod, TransactionAggregate};
+use crate::audit::{Actor, AuditEntry};
+use crate::dynamo;
+use crate::events::TransactionStatusChange;
+use crate::models::{
+    CardBrand, HierarchyChange, Merchant, PageRequest, Payout, Transaction, TransactionCursor,
+    TransactionStatus,
+};
+use crate::webhooks::{DeliveryAttempt, DeliveryOutcome, Webhook, WebhookDelivery};
+use anyhow::Error;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use std::sync::Arc;
+
+/// The store shared by every request of a server.
+pub type SharedStore = Arc<dyn Store>;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum StoreKind {
+    /// The `--table` table at `--dynamodb-endpoint`.
+    #[default]
+    Dynamodb,
+    /// Process memory, seeded from the bundled fixture and lost on exit.
+    Memory,
+}
+
+/// Merchants, their place in the hierarchy and the webhooks they registered.
+#[async_trait]
+pub trait MerchantStore: Send + Sync {
+    /// Writes a merchant as given, replacing any stored under its id.
+    async fn add_merchant(&self, merchant: &Merchant) -> Result<(), Error>;
+
+    async fn get_merchant(&self, merchant_id: String) -> Result<Merchant, Error>;
+
+    /// The nearest merchant at or above the outlet that may settle, or the outlet itself when
+    /// none can.
+    async fn get_settlement_merchant_id(&self, outlet_id: &str) -> Result<String, Error>;
+
+    /// Ids of the outlets below `merchant_id`, or the merchant itself when it is an outlet.
+    async fn get_descendant_outlets(&self, merchant_id: String) -> Result<Vec<String>, Error>;
+
+    async fn attach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error>;
+
+    async fn detach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error>;
+
+    async fn move_merchant(
+        &self,
+        merchant_id: &str,
+        new_parent_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error>;
+
+    /// Hierarchy changes of one merchant, newest first.
+    async fn get_hierarchy_history(
+        &self,
+        merchant_id: String,
+    ) -> Result<Vec<HierarchyChange>, Error>;
+
+    /// Adds a webhook and its audit entry in one write.
+    async fn add_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error>;
+
+    async fn get_webhooks(&self, merchant_id: &str) -> Result<Vec<Webhook>, Error>;
+
+    async fn get_webhook(
+        &self,
+        merchant_id: &str,
+        webhook_id: &str,
+    ) -> Result<Option<Webhook>, Error>;
+
+    /// Removes a webhook and writes its audit entry, with `webhook` as the before image, in one
+    /// write.
+    async fn delete_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error>;
+
+    /// One page of a webhook's deliveries by delivery id, newest first.
+    async fn get_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<WebhookDelivery>, bool), Error>;
+
+    /// Pending deliveries due at or before `now`, oldest first.
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: &str,
+        limit: i32,
+    ) -> Result<Vec<WebhookDelivery>, Error>;
+
+    /// Pushes a due delivery back to `lease_until`. Returns false when another worker claimed it
+    /// first.
+    async fn claim_webhook_delivery(
+        &self,
+        delivery: &WebhookDelivery,
+        lease_until: &str,
+    ) -> Result<bool, Error>;
+
+    async fn record_webhook_delivery_attempt(
+        &self,
+        delivery: &WebhookDelivery,
+        attempt: &DeliveryAttempt,
+        outcome: &DeliveryOutcome,
+    ) -> Result<(), Error>;
+}
+
+/// Transactions, their running aggregates and the payouts made from them.
+#[async_trait]
+pub trait TransactionStore: Send + Sync {
+    /// Records a transaction and adds it to its aggregates. Returns false, changing nothing, when
+    /// a transaction with its id is already stored.
+    async fn add_transaction(&self, transaction: Transaction) -> Result<bool, Error>;
+
+    /// Like [`TransactionStore::add_transaction`], auditing the transaction as recorded by
+    /// `actor` in the same write.
+    async fn record_transaction(
+        &self,
+        transaction: Transaction,
+        actor: &Actor,
+    ) -> Result<bool, Error>;
+
+    /// Sets the status of one transaction and returns it together with the status it replaced.
+    async fn update_transaction_status(
+        &self,
+        merchant_id: &str,
+        transaction_id: &str,
+        status: TransactionStatus,
+        actor: &Actor,
+    ) -> Result<TransactionStatusChange, Error>;
+
+    /// One page of a merchant's transactions, newest first. The card brand is filtered after
+    /// `page.limit` rows are read, so a page may come back short while more remain.
+    async fn get_transactions(
+        &self,
+        merchant_id: String,
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error>;
+
+    /// Pages through the transactions of several merchants at once, merged newest first with
+    /// ties broken by merchant id.
+    async fn get_transactions_for_merchants(
+        &self,
+        merchant_ids: &[String],
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error>;
+
+    /// Like [`TransactionStore::get_transactions_for_merchants`], for the inclusive sort key
+    /// range of [`dynamo::transaction_date_range`].
+    async fn get_transactions_for_merchants_between(
+        &self,
+        merchant_ids: &[String],
+        earlier_transaction: &str,
+        later_transaction: &str,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error>;
+
+    async fn get_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error>;
+
+    /// Every transaction settled by `settlement_merchant_id` inside the sort key range, newest
+    /// first.
+    async fn get_all_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_transaction: &str,
+        later_transaction: &str,
+    ) -> Result<Vec<Transaction>, Error>;
+
+    /// A merchant's aggregates for one period, one per currency.
+    async fn get_transaction_aggregates(
+        &self,
+        merchant_id: &str,
+        period: AggregatePeriod,
+        date: &str,
+    ) -> Result<Vec<TransactionAggregate>, Error>;
+
+    /// Records a payout once, queueing `PayoutCreated` webhooks for its settlement merchant.
+    async fn add_payout(&self, payout: &Payout, actor: &Actor) -> Result<(), Error>;
+
+    /// The payouts made to a settlement merchant inside an inclusive sort key range, newest first.
+    async fn get_payouts(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_payout: &str,
+        later_payout: &str,
+    ) -> Result<Vec<Payout>, Error>;
+}
+
+/// The audit log of writes and privileged reads.
+#[async_trait]
+pub trait AuditStore: Send + Sync {
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), Error>;
+
+    /// One page of the audit entries of a `YYYY-MM-DD` day by entry id, newest first.
+    async fn get_audit_log(
+        &self,
+        date: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<AuditEntry>, bool), Error>;
+}
+
+#[async_trait]
+pub trait Store: MerchantStore + TransactionStore + AuditStore {
+    /// Fails while the store cannot serve requests.
+    async fn check_ready(&self) -> Result<(), Error>;
+}
+
+/// A DynamoDB table, `merchants` unless `--table` names another.
+#[derive(Clone)]
+pub struct DynamoStore {
+    table: dynamo::Table,
+}
+
+impl DynamoStore {
+    pub fn new(table: dynamo::Table) -> Self {
+        Self { table }
+    }
+}
+
+#[async_trait]
+impl MerchantStore for DynamoStore {
+    async fn add_merchant(&self, merchant: &Merchant) -> Result<(), Error> {
+        dynamo::add_merchant(&self.table, merchant).await
+    }
+
+    async fn get_merchant(&self, merchant_id: String) -> Result<Merchant, Error> {
+        dynamo::get_merchant(&self.table, merchant_id).await
+    }
+
+    async fn get_settlement_merchant_id(&self, outlet_id: &str) -> Result<String, Error> {
+        dynamo::get_settlement_merchant_id(&self.table, outlet_id).await
+    }
+
+    async fn get_descendant_outlets(&self, merchant_id: String) -> Result<Vec<String>, Error> {
+        dynamo::get_descendant_outlets(&self.table, merchant_id).await
+    }
+
+    async fn attach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        dynamo::attach_sub_merchant(&self.table, parent_id, child_id, actor).await
+    }
+
+    async fn detach_sub_merchant(
+        &self,
+        parent_id: &str,
+        child_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        dynamo::detach_sub_merchant(&self.table, parent_id, child_id, actor).await
+    }
+
+    async fn move_merchant(
+        &self,
+        merchant_id: &str,
+        new_parent_id: &str,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        dynamo::move_merchant(&self.table, merchant_id, new_parent_id, actor).await
+    }
+
+    async fn get_hierarchy_history(
+        &self,
+        merchant_id: String,
+    ) -> Result<Vec<HierarchyChange>, Error> {
+        dynamo::get_hierarchy_history(&self.table, merchant_id).await
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        dynamo::add_webhook(&self.table, webhook, actor).await
+    }
+
+    async fn get_webhooks(&self, merchant_id: &str) -> Result<Vec<Webhook>, Error> {
+        dynamo::get_webhooks(&self.table, merchant_id).await
+    }
+
+    async fn get_webhook(
+        &self,
+        merchant_id: &str,
+        webhook_id: &str,
+    ) -> Result<Option<Webhook>, Error> {
+        dynamo::get_webhook(&self.table, merchant_id, webhook_id).await
+    }
+
+    async fn delete_webhook(&self, webhook: &Webhook, actor: &Actor) -> Result<(), Error> {
+        dynamo::delete_webhook(&self.table, webhook, actor).await
+    }
+
+    async fn get_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<WebhookDelivery>, bool), Error> {
+        dynamo::get_webhook_deliveries(&self.table, webhook_id, page).await
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: &str,
+        limit: i32,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        dynamo::get_due_webhook_deliveries(&self.table, now, limit).await
+    }
+
+    async fn claim_webhook_delivery(
+        &self,
+        delivery: &WebhookDelivery,
+        lease_until: &str,
+    ) -> Result<bool, Error> {
+        dynamo::claim_webhook_delivery(&self.table, delivery, lease_until).await
+    }
+
+    async fn record_webhook_delivery_attempt(
+        &self,
+        delivery: &WebhookDelivery,
+        attempt: &DeliveryAttempt,
+        outcome: &DeliveryOutcome,
+    ) -> Result<(), Error> {
+        dynamo::record_webhook_delivery_attempt(&self.table, delivery, attempt, outcome).await
+    }
+}
+
+#[async_trait]
+impl TransactionStore for DynamoStore {
+    async fn add_transaction(&self, transaction: Transaction) -> Result<bool, Error> {
+        dynamo::add_transaction(&self.table, transaction).await
+    }
+
+    async fn record_transaction(
+        &self,
+        transaction: Transaction,
+        actor: &Actor,
+    ) -> Result<bool, Error> {
+        dynamo::record_transaction(&self.table, transaction, actor).await
+    }
+
+    async fn update_transaction_status(
+        &self,
+        merchant_id: &str,
+        transaction_id: &str,
+        status: TransactionStatus,
+        actor: &Actor,
+    ) -> Result<TransactionStatusChange, Error> {
+        dynamo::update_transaction_status(&self.table, merchant_id, transaction_id, status, actor)
+            .await
+    }
+
+    async fn get_transactions(
+        &self,
+        merchant_id: String,
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        dynamo::get_transactions(&self.table, merchant_id, year, month, day, card_brand, page).await
+    }
+
+    async fn get_transactions_for_merchants(
+        &self,
+        merchant_ids: &[String],
+        year: Option<String>,
+        month: Option<String>,
+        day: Option<String>,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        dynamo::get_transactions_for_merchants(
+            &self.table,
+            merchant_ids,
+            year,
+            month,
+            day,
+            card_brand,
+            page,
+        )
+        .await
+    }
+
+    async fn get_transactions_for_merchants_between(
+        &self,
+        merchant_ids: &[String],
+        earlier_transaction: &str,
+        later_transaction: &str,
+        card_brand: Option<CardBrand>,
+        page: &PageRequest<TransactionCursor>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        dynamo::get_transactions_for_merchants_between(
+            &self.table,
+            merchant_ids,
+            earlier_transaction,
+            later_transaction,
+            card_brand,
+            page,
+        )
+        .await
+    }
+
+    async fn get_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<Transaction>, bool), Error> {
+        dynamo::get_transactions_for_settlement_merchant(&self.table, settlement_merchant_id, page)
+            .await
+    }
+
+    async fn get_all_transactions_for_settlement_merchant(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_transaction: &str,
+        later_transaction: &str,
+    ) -> Result<Vec<Transaction>, Error> {
+        dynamo::get_all_transactions_for_settlement_merchant(
+            &self.table,
+            settlement_merchant_id,
+            earlier_transaction,
+            later_transaction,
+        )
+        .await
+    }
+
+    async fn get_transaction_aggregates(
+        &self,
+        merchant_id: &str,
+        period: AggregatePeriod,
+        date: &str,
+    ) -> Result<Vec<TransactionAggregate>, Error> {
+        dynamo::get_transaction_aggregates(&self.table, merchant_id, period, date).await
+    }
+
+    async fn add_payout(&self, payout: &Payout, actor: &Actor) -> Result<(), Error> {
+        dynamo::add_payout(&self.table, payout, actor).await
+    }
+
+    async fn get_payouts(
+        &self,
+        settlement_merchant_id: &str,
+        earlier_payout: &str,
+        later_payout: &str,
+    ) -> Result<Vec<Payout>, Error> {
+        dynamo::get_payouts(
+            &self.table,
+            settlement_merchant_id,
+            earlier_payout,
+            later_payout,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl AuditStore for DynamoStore {
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), Error> {
+        dynamo::add_audit_entry(&self.table, audit_entry).await
+    }
+
+    async fn get_audit_log(
+        &self,
+        date: String,
+        page: PageRequest<String>,
+    ) -> Result<(Vec<AuditEntry>, bool), Error> {
+        dynamo::get_audit_log(&self.table, date, page).await
+    }
+}
+
+#[async_trait]
+impl Store for DynamoStore {
+    async fn check_ready(&self) -> Result<(), Error> {
+        dynamo::check_table_ready(&self.table).await
+    }
+}
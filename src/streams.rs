@@ -0,0 +1,387 @@
+//! Change data capture from the table's DynamoDB stream.
+//!
+//! [`run`] follows every shard of the stream, decodes merchant, transaction and payout records
+//! with the same `serde_dynamo` mapping as the queries, and hands each change to a list of
+//! [`ChangeHandler`]s in stream order. Positions are checkpointed per consumer name in the table
+//! itself, so a restarted consumer continues where it stopped. Delivery is at least once: a batch
+//! whose handlers fail is read again, so handlers must be idempotent.
+
+use crate::dynamo::{
+    MERCHANT_PREFIX, PARTITION_KEY, PAYOUT_PREFIX, SORT_KEY, STREAM_PREFIX, TRANSACTION_PREFIX,
+    Table, get_latest_stream_arn, get_stream_checkpoints, put_stream_checkpoint, replace_key_names,
+};
+use crate::errors::SdkContext;
+use crate::models::{Merchant, Payout, Transaction};
+use crate::telemetry::mask_pan;
+use actix_web::rt::time::sleep;
+use anyhow::{Context, Error};
+use aws_sdk_dynamodbstreams::types::{
+    AttributeValue, OperationType, Record, Shard, ShardIteratorType,
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_dynamo::aws_sdk_dynamodbstreams_1::from_item;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use strum_macros::Display;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RECORDS_PER_CALL: i32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum ChangeKind {
+    Insert,
+    Modify,
+    Remove,
+}
+
+/// One item change. `old` is missing for inserts and `new` for removals.
+pub struct Change<T> {
+    pub kind: ChangeKind,
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+impl<T> Change<T> {
+    /// The item after the change, or before it for removals.
+    pub fn latest(&self) -> Option<&T> {
+        self.new.as_ref().or(self.old.as_ref())
+    }
+}
+
+pub enum TableChange {
+    Merchant(Change<Merchant>),
+    Transaction(Change<Transaction>),
+    Payout(Change<Payout>),
+}
+
+/// Reacts to table changes, e.g. by updating a search index or a cache.
+pub trait ChangeHandler: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn handle<'a>(&'a self, change: &'a TableChange) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Logs every change, with card numbers masked.
+pub struct LogHandler;
+
+impl ChangeHandler for LogHandler {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn handle<'a>(&'a self, change: &'a TableChange) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            match change {
+                TableChange::Merchant(change) => {
+                    if let Some(merchant) = change.latest() {
+                        tracing::info!(
+                            kind = %change.kind,
+                            merchant_id = %merchant.id,
+                            parent_merchant_id = merchant.parent_merchant_id.as_deref(),
+                            "Merchant changed"
+                        );
+                    }
+                }
+                TableChange::Transaction(change) => {
+                    if let Some(transaction) = change.latest() {
+                        tracing::info!(
+                            kind = %change.kind,
+                            merchant_id = %transaction.merchant_id,
+                            transaction_id = %transaction.id,
+                            previous_status = change.old.as_ref().map(|old| old.status.to_string()),
+                            status = %transaction.status,
+                            pan = %mask_pan(&transaction.pan),
+                            "Transaction changed"
+                        );
+                    }
+                }
+                TableChange::Payout(change) => {
+                    if let Some(payout) = change.latest() {
+                        tracing::info!(
+                            kind = %change.kind,
+                            merchant_id = %payout.merchant_id,
+                            payout_id = %payout.id,
+                            amount = payout.amount,
+                            "Payout changed"
+                        );
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Position of one consumer in one shard.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct StreamCheckpoint {
+    pub shard_id: String,
+    /// Last record handled; the shard is read from its start when missing.
+    pub sequence_number: Option<String>,
+    /// The shard was closed and every record in it handled.
+    #[serde(default)]
+    pub finished: bool,
+}
+
+/// Follows the table stream until the process exits, dispatching every change to `handlers`.
+/// Consumers with different names keep separate checkpoints.
+pub async fn run(
+    table: &Table,
+    streams: &aws_sdk_dynamodbstreams::Client,
+    consumer: &str,
+    handlers: &[Box<dyn ChangeHandler>],
+) -> Result<(), Error> {
+    let stream_arn = get_latest_stream_arn(table).await?;
+    let mut checkpoints = get_stream_checkpoints(table, consumer).await?;
+    let mut iterators: HashMap<String, String> = HashMap::new();
+    tracing::info!(
+        consumer,
+        %stream_arn,
+        handlers = ?handlers.iter().map(|handler| handler.name()).collect::<Vec<_>>(),
+        "Following table stream"
+    );
+
+    loop {
+        let shards = list_shards(table, streams, &stream_arn).await?;
+        let shard_ids: HashSet<&str> = shards.iter().filter_map(Shard::shard_id).collect();
+        let finished: HashSet<String> = checkpoints
+            .values()
+            .filter(|checkpoint| checkpoint.finished)
+            .map(|checkpoint| checkpoint.shard_id.clone())
+            .collect();
+        let mut handled = 0;
+        for shard in &shards {
+            let Some(shard_id) = shard.shard_id() else {
+                continue;
+            };
+            // Children of a split wait for their parent so each item's changes stay in order.
+            if finished.contains(shard_id)
+                || shard.parent_shard_id().is_some_and(|parent_id| {
+                    shard_ids.contains(parent_id) && !finished.contains(parent_id)
+                })
+            {
+                continue;
+            }
+            let checkpoint =
+                checkpoints
+                    .entry(shard_id.to_string())
+                    .or_insert_with(|| StreamCheckpoint {
+                        shard_id: shard_id.to_string(),
+                        ..StreamCheckpoint::default()
+                    });
+            let iterator = iterators.remove(shard_id);
+            match follow_shard(
+                table,
+                streams,
+                &stream_arn,
+                consumer,
+                handlers,
+                checkpoint,
+                iterator,
+            )
+            .await
+            {
+                Ok((count, next_iterator)) => {
+                    handled += count;
+                    if let Some(next_iterator) = next_iterator {
+                        iterators.insert(shard_id.to_string(), next_iterator);
+                    }
+                }
+                Err(err) => tracing::warn!(
+                    error = %format!("{err:#}"),
+                    shard_id,
+                    "Failed to process stream records, retrying from the checkpoint"
+                ),
+            }
+        }
+        if handled == 0 {
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Every shard of the stream, oldest first.
+async fn list_shards(
+    table: &Table,
+    streams: &aws_sdk_dynamodbstreams::Client,
+    stream_arn: &str,
+) -> Result<Vec<Shard>, Error> {
+    let mut shards = Vec::new();
+    let mut exclusive_start_shard_id = None;
+    loop {
+        let describe_stream = streams
+            .describe_stream()
+            .stream_arn(stream_arn)
+            .set_exclusive_start_shard_id(exclusive_start_shard_id)
+            .send();
+        let stream_resp = table
+            .observe("describe_stream", None, describe_stream)
+            .await
+            .sdk_context("Failed to describe the table stream")?;
+        let Some(description) = stream_resp.stream_description else {
+            return Ok(shards);
+        };
+        shards.extend(description.shards.unwrap_or_default());
+        exclusive_start_shard_id = description.last_evaluated_shard_id;
+        if exclusive_start_shard_id.is_none() {
+            return Ok(shards);
+        }
+    }
+}
+
+/// Reads one batch of a shard, continuing from `iterator` or else from the checkpoint, and
+/// dispatches it. Returns how many changes were handled and the iterator of the next batch, which
+/// is missing once a closed shard has been read to its end.
+async fn follow_shard(
+    table: &Table,
+    streams: &aws_sdk_dynamodbstreams::Client,
+    stream_arn: &str,
+    consumer: &str,
+    handlers: &[Box<dyn ChangeHandler>],
+    checkpoint: &mut StreamCheckpoint,
+    iterator: Option<String>,
+) -> Result<(usize, Option<String>), Error> {
+    let iterator = match iterator {
+        Some(iterator) => iterator,
+        None => {
+            let request = streams
+                .get_shard_iterator()
+                .stream_arn(stream_arn)
+                .shard_id(&checkpoint.shard_id);
+            let request = match &checkpoint.sequence_number {
+                Some(sequence_number) => request
+                    .shard_iterator_type(ShardIteratorType::AfterSequenceNumber)
+                    .sequence_number(sequence_number),
+                None => request.shard_iterator_type(ShardIteratorType::TrimHorizon),
+            };
+            let Some(iterator) = table
+                .observe("get_shard_iterator", None, request.send())
+                .await
+                .sdk_context("Failed to get a shard iterator")?
+                .shard_iterator
+            else {
+                return Ok((0, None));
+            };
+            iterator
+        }
+    };
+    let get_records = streams
+        .get_records()
+        .shard_iterator(iterator)
+        .limit(RECORDS_PER_CALL)
+        .send();
+    let records_resp = table
+        .observe("get_stream_records", None, get_records)
+        .await
+        .sdk_context("Failed to get stream records")?;
+
+    let records = records_resp.records.unwrap_or_default();
+    let mut handled = 0;
+    // Checkpoints are written to the table too; a batch holding only those is not checkpointed,
+    // or the consumer would keep reading its own writes.
+    let mut checkpoint_due = false;
+    for record in &records {
+        if is_checkpoint(record) {
+            continue;
+        }
+        checkpoint_due = true;
+        if let Some(change) = decode(record)? {
+            for handler in handlers {
+                handler
+                    .handle(&change)
+                    .await
+                    .with_context(|| format!("Handler {} failed", handler.name()))?;
+            }
+            handled += 1;
+        }
+    }
+    let next_iterator = records_resp.next_shard_iterator;
+    let last_sequence_number = records
+        .last()
+        .and_then(|record| record.dynamodb())
+        .and_then(|stream_record| stream_record.sequence_number());
+    if checkpoint_due || next_iterator.is_none() {
+        if let Some(sequence_number) = last_sequence_number {
+            checkpoint.sequence_number = Some(sequence_number.to_string());
+        }
+        checkpoint.finished = next_iterator.is_none();
+        put_stream_checkpoint(table, consumer, checkpoint).await?;
+    }
+    Ok((handled, next_iterator))
+}
+
+fn record_key<'a>(record: &'a Record, key: &str) -> Option<&'a str> {
+    record
+        .dynamodb()
+        .and_then(|stream_record| stream_record.keys())
+        .and_then(|keys| keys.get(key))
+        .and_then(|value| value.as_s().ok())
+        .map(String::as_str)
+}
+
+fn is_checkpoint(record: &Record) -> bool {
+    record_key(record, PARTITION_KEY)
+        .is_some_and(|partition_key| partition_key.starts_with(&format!("{STREAM_PREFIX}#")))
+}
+
+/// The typed change a record describes, or `None` for items that are not merchants,
+/// transactions or payouts.
+fn decode(record: &Record) -> Result<Option<TableChange>, Error> {
+    let kind = match record.event_name() {
+        Some(OperationType::Insert) => ChangeKind::Insert,
+        Some(OperationType::Modify) => ChangeKind::Modify,
+        Some(OperationType::Remove) => ChangeKind::Remove,
+        _ => return Ok(None),
+    };
+    let (Some(partition_key), Some(sort_key), Some(stream_record)) = (
+        record_key(record, PARTITION_KEY),
+        record_key(record, SORT_KEY),
+        record.dynamodb(),
+    ) else {
+        return Ok(None);
+    };
+    let old_image = stream_record.old_image();
+    let new_image = stream_record.new_image();
+    let change =
+        if sort_key.starts_with(&format!("{MERCHANT_PREFIX}#")) && sort_key == partition_key {
+            // Merchant items repeat the id in the sort key.
+            TableChange::Merchant(Change {
+                kind,
+                old: image(old_image, "id", "id")?,
+                new: image(new_image, "id", "id")?,
+            })
+        } else if sort_key.starts_with(&format!("{TRANSACTION_PREFIX}#")) {
+            TableChange::Transaction(Change {
+                kind,
+                old: image(old_image, "merchant_id", "id")?,
+                new: image(new_image, "merchant_id", "id")?,
+            })
+        } else if sort_key.starts_with(&format!("{PAYOUT_PREFIX}#")) {
+            TableChange::Payout(Change {
+                kind,
+                old: image(old_image, "merchant_id", "id")?,
+                new: image(new_image, "merchant_id", "id")?,
+            })
+        } else {
+            return Ok(None);
+        };
+    Ok(Some(change))
+}
+
+fn image<T: DeserializeOwned>(
+    image: Option<&HashMap<String, AttributeValue>>,
+    partition_key: &str,
+    sort_key: &str,
+) -> Result<Option<T>, Error> {
+    let Some(image) = image else {
+        return Ok(None);
+    };
+    let mut items = vec![image.clone()];
+    replace_key_names(&mut items, partition_key, sort_key);
+    Ok(Some(
+        from_item(items.remove(0)).context("failed to deserialise stream image")?,
+    ))
+}
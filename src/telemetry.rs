@@ -0,0 +1,186 @@
+use crate::metrics::metrics;
+use actix_web::{
+    HttpMessage,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+};
+use async_graphql::{
+    Response, ServerResult, Value, Variables,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery},
+    parser::types::{ExecutableDocument, Selection},
+};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::{Instrument, field::Empty};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longer incoming ids are replaced rather than echoed back into logs and headers.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Id of the HTTP request, taken from `X-Request-Id` or generated.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Installs the global subscriber, writing to stderr so command output on stdout stays clean.
+/// `LOG_FORMAT=json` switches to JSON lines, `RUST_LOG` sets the filter (default `info`).
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+        _ => builder.init(),
+    }
+}
+
+/// Masks a card number down to its last four digits, and entirely when it is too short for the
+/// last four to be safe to show.
+pub fn mask_pan(pan: &str) -> String {
+    let digits = pan.chars().count();
+    if digits < 12 {
+        return "*".repeat(digits);
+    }
+    let visible: String = pan.chars().skip(digits - 4).collect();
+    format!("{}{visible}", "*".repeat(digits - 4))
+}
+
+/// Wraps every HTTP request in a span carrying its request id and echoes the id back in the
+/// `X-Request-Id` response header.
+pub async fn request_span(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        status = Empty,
+        latency_ms = Empty,
+    );
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let started = Instant::now();
+    let mut res = next.call(req).instrument(span.clone()).await?;
+    span.record("status", res.status().as_u16());
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.in_scope(|| tracing::info!("request completed"));
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+/// Name used for an operation the client did not name explicitly: the document's operation name,
+/// otherwise its root fields, e.g. `merchant,transactions`.
+pub fn operation_label(document: &ExecutableDocument) -> String {
+    let Some((name, operation)) = document.operations.iter().next() else {
+        return "unknown".to_string();
+    };
+    if let Some(name) = name {
+        return name.to_string();
+    }
+    operation
+        .node
+        .selection_set
+        .node
+        .items
+        .iter()
+        .filter_map(|selection| match &selection.node {
+            Selection::Field(field) => Some(field.node.name.node.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Opens a span and records metrics per executed GraphQL operation. Variables are never logged since they may hold
+/// card data.
+pub struct OperationTracing;
+
+impl ExtensionFactory for OperationTracing {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(OperationTracingExtension::default())
+    }
+}
+
+#[derive(Default)]
+struct OperationTracingExtension {
+    operation: Mutex<Option<String>>,
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for OperationTracingExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        *self.operation.lock().unwrap() = Some(operation_label(&document));
+        Ok(document)
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let operation = operation_name
+            .map(str::to_string)
+            .or_else(|| self.operation.lock().unwrap().clone())
+            .unwrap_or_else(|| "unknown".to_string());
+        let span = tracing::info_span!(
+            "graphql",
+            operation = %operation,
+            errors = Empty,
+            latency_ms = Empty,
+        );
+
+        let started = Instant::now();
+        let response = next.run(ctx, operation_name).instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+        span.record("errors", response.errors.len());
+        span.record("latency_ms", elapsed.as_millis() as u64);
+        metrics().observe_graphql(&operation, response.is_err(), elapsed);
+        span.in_scope(|| {
+            for error in &response.errors {
+                let code = match error
+                    .extensions
+                    .as_ref()
+                    .and_then(|extensions| extensions.get("code"))
+                {
+                    Some(Value::String(code)) => code.as_str(),
+                    _ => "",
+                };
+                tracing::warn!(error = %error.message, code, "graphql error");
+            }
+            tracing::info!("graphql operation completed");
+        });
+        response
+    }
+}
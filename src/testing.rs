@@ -0,0 +1,221 @@
+//! Harness running GraphQL operations against the real schema in-process.
+//!
+//! [`TestApp::all`] gives a test one store per backend, each seeded from `fixtures/test.yaml`:
+//! always the in-memory store, and a uniquely named, migrated table in DynamoDB Local when one is
+//! available. That is the endpoint in `DYNAMODB_TEST_ENDPOINT`, e.g. `http://localhost:8000` for
+//! the docker-compose `db` service, or a process started from the jar in `DYNAMODB_LOCAL_JAR` and
+//! shared by the whole test run. With neither set, only the in-memory store is tested.
+//!
+//! Tests of code that only runs against DynamoDB use [`TestApp::dynamodb_only`] and are
+//! `#[ignore]`d, so `cargo test -- --ignored` runs them and fails when DynamoDB is unavailable.
+
+use crate::dynamo::Table;
+use crate::limits::QueryLimits;
+use crate::memory::MemoryStore;
+use crate::models::Role;
+use crate::persisted_queries::PersistedQueries;
+use crate::seed::{self, Fixture};
+use crate::store::{DynamoStore, SharedStore};
+use crate::{AppSchema, build_schema, migrations};
+use async_graphql::{Request, Variables};
+use aws_config::{BehaviorVersion, Region};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::{
+    env,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{Arc, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+const TEST_FIXTURE: &str = include_str!("../fixtures/test.yaml");
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A seeded store and the schema resolving against it.
+pub struct TestApp {
+    /// `memory` or `dynamodb`, naming the run in assertion messages.
+    pub backend: &'static str,
+    schema: AppSchema,
+    table: Option<Table>,
+}
+
+impl TestApp {
+    /// A freshly seeded app for every available backend.
+    pub async fn all() -> Vec<TestApp> {
+        let mut apps = vec![Self::memory().await];
+        match dynamodb_local() {
+            Some(dynamodb_local) => apps.push(Self::dynamodb(&dynamodb_local.endpoint).await),
+            None => tracing::warn!(
+                "Neither DYNAMODB_TEST_ENDPOINT nor DYNAMODB_LOCAL_JAR is set, skipping DynamoDB"
+            ),
+        }
+        apps
+    }
+
+    /// A freshly seeded DynamoDB app, panicking when DynamoDB Local is unavailable.
+    pub async fn dynamodb_only() -> TestApp {
+        let dynamodb_local = dynamodb_local()
+            .expect("Set DYNAMODB_TEST_ENDPOINT or DYNAMODB_LOCAL_JAR to run DynamoDB tests");
+        Self::dynamodb(&dynamodb_local.endpoint).await
+    }
+
+    async fn memory() -> Self {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        seed(&store).await;
+        Self {
+            backend: "memory",
+            schema: build_schema(store, QueryLimits::default(), PersistedQueries::default()),
+            table: None,
+        }
+    }
+
+    async fn dynamodb(endpoint: &str) -> Self {
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .test_credentials()
+            .region(Region::new("eu-west-1"))
+            .endpoint_url(endpoint)
+            .load()
+            .await;
+        let table = Table::new(
+            aws_sdk_dynamodb::Client::new(&config),
+            format!("test_{}", Uuid::new_v4().simple()),
+        );
+        migrations::run(&table, false)
+            .await
+            .expect("Failed to migrate the test table");
+        let store: SharedStore = Arc::new(DynamoStore::new(table.clone()));
+        seed(&store).await;
+        Self {
+            backend: "dynamodb",
+            schema: build_schema(store, QueryLimits::default(), PersistedQueries::default()),
+            table: Some(table),
+        }
+    }
+
+    /// Runs an operation as `role` and returns the whole response, `data` and `errors`, as JSON.
+    pub async fn execute(&self, role: Option<Role>, query: &str, variables: Value) -> Value {
+        let mut request = Request::new(query).variables(Variables::from_json(variables));
+        if let Some(role) = role {
+            request = request.data(role);
+        }
+        serde_json::to_value(self.schema.execute(request).await)
+            .expect("Failed to serialize the response")
+    }
+
+    /// Starts a subscription as `role`; every response it yields comes back as JSON.
+    pub fn subscribe(
+        &self,
+        role: Option<Role>,
+        query: &str,
+        variables: Value,
+    ) -> impl Stream<Item = Value> + use<> {
+        let mut request = Request::new(query).variables(Variables::from_json(variables));
+        if let Some(role) = role {
+            request = request.data(role);
+        }
+        self.schema.execute_stream(request).map(|response| {
+            serde_json::to_value(response).expect("Failed to serialize the response")
+        })
+    }
+
+    /// The `data` of an operation that must succeed.
+    pub async fn data(&self, role: Option<Role>, query: &str, variables: Value) -> Value {
+        let response = self.execute(role, query, variables).await;
+        assert!(
+            response.get("errors").is_none(),
+            "{}: unexpected errors {}",
+            self.backend,
+            response["errors"]
+        );
+        response["data"].clone()
+    }
+
+    /// The app's DynamoDB table, for code that only runs against DynamoDB.
+    pub fn table(&self) -> Option<&Table> {
+        self.table.as_ref()
+    }
+
+    /// Deletes the app's DynamoDB table. A failed test leaves its table behind.
+    pub async fn finish(self) {
+        if let Some(table) = self.table {
+            table
+                .client
+                .delete_table()
+                .table_name(&table.name)
+                .send()
+                .await
+                .expect("Failed to delete the test table");
+        }
+    }
+}
+
+/// Loads `fixtures/test.yaml` into `store`.
+pub async fn seed(store: &SharedStore) {
+    let fixture: Fixture = serde_yaml::from_str(TEST_FIXTURE).expect("Invalid test fixture");
+    seed::run(store.as_ref(), &fixture, None)
+        .await
+        .expect("Failed to seed the test store");
+}
+
+/// A DynamoDB Local the tests share.
+struct DynamoDbLocal {
+    endpoint: String,
+    // Held so the process outlives every test; see `start_dynamodb_local`.
+    _process: Option<Child>,
+}
+
+fn dynamodb_local() -> Option<&'static DynamoDbLocal> {
+    static DYNAMODB_LOCAL: OnceLock<Option<DynamoDbLocal>> = OnceLock::new();
+    DYNAMODB_LOCAL
+        .get_or_init(|| {
+            if let Ok(endpoint) = env::var("DYNAMODB_TEST_ENDPOINT") {
+                return Some(DynamoDbLocal {
+                    endpoint,
+                    _process: None,
+                });
+            }
+            let jar = env::var("DYNAMODB_LOCAL_JAR").ok()?;
+            Some(start_dynamodb_local(Path::new(&jar)))
+        })
+        .as_ref()
+}
+
+/// Starts an in-memory DynamoDB Local on a free port. A shell runs it in the background and kills
+/// it once its stdin closes, which happens when the test binary exits.
+fn start_dynamodb_local(jar: &Path) -> DynamoDbLocal {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("No free port for DynamoDB Local")
+        .port();
+    let libraries = jar.with_file_name("DynamoDBLocal_lib");
+    let process = Command::new("sh")
+        .arg("-c")
+        .arg(r#"java -Djava.library.path="$1" -jar "$2" -inMemory -port "$3" & read _; kill $!"#)
+        .arg("sh")
+        .arg(&libraries)
+        .arg(jar)
+        .arg(port.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start DynamoDB Local");
+
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let started = Instant::now();
+    while TcpStream::connect_timeout(&address, Duration::from_millis(100)).is_err() {
+        assert!(
+            started.elapsed() < STARTUP_TIMEOUT,
+            "DynamoDB Local did not start listening on port {port}"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    DynamoDbLocal {
+        endpoint: format!("http://127.0.0.1:{port}"),
+        _process: Some(process),
+    }
+}
@@ -0,0 +1,493 @@
+//! Outbound webhooks.
+//!
+//! Merchants register endpoints for the events they care about. The write that causes an event
+//! also writes one pending delivery per matching endpoint to an outbox in the same DynamoDB
+//! transaction, and [`run_worker`] posts the deliveries, retrying failures with exponential
+//! backoff.
+//!
+//! Each request carries the JSON event as its body and a `Webhook-Signature` header of the form
+//! `t=<unix seconds>,v1=<hex>`, where `v1` is the HMAC-SHA256 of `<t>.<body>` keyed with the
+//! endpoint's secret. Receivers should recompute it and reject stale timestamps.
+
+use crate::errors::AppError;
+use crate::store::{SharedStore, Store};
+use actix_web::rt::time::sleep;
+use anyhow::{Error, bail};
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{StreamExt, stream};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, Instant};
+use strum_macros::Display;
+
+pub const WEBHOOK_PREFIX: &str = "WEBHOOK";
+pub const DELIVERY_PREFIX: &str = "DELIVERY";
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+const MAX_ATTEMPTS: usize = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a worker owns a delivery it has claimed before others may retry it.
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_BATCH: i32 = 25;
+const CONCURRENCY: usize = 8;
+
+#[derive(Enum, Deserialize, Serialize, Copy, Clone, PartialEq, Eq, Display)]
+pub enum WebhookEventType {
+    PayoutCreated,
+    TransactionChargebacked,
+}
+
+/// An endpoint registered by a merchant. The secret is only returned when registering.
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub merchant_id: String,
+    pub url: String,
+    pub events: Vec<WebhookEventType>,
+    #[graphql(skip)]
+    pub secret: String,
+    pub created_at: String,
+}
+
+impl Webhook {
+    pub fn new(merchant_id: String, url: String, events: Vec<WebhookEventType>) -> Self {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill(&mut secret);
+        Self {
+            id: format!("{WEBHOOK_PREFIX}#{}", uuid::Uuid::new_v4()),
+            merchant_id,
+            url,
+            events,
+            secret: format!("whsec_{}", hex::encode(secret)),
+            created_at: timestamp(Utc::now()),
+        }
+    }
+
+    /// The webhook as written to the audit log, without its secret.
+    pub fn audit_image(&self) -> serde_json::Value {
+        let mut image = serde_json::to_value(self).unwrap_or_default();
+        if let Some(image) = image.as_object_mut() {
+            image.remove("secret");
+        }
+        image
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct WebhookRegistration {
+    pub webhook: Webhook,
+    /// Signing secret of the endpoint; it cannot be read again later.
+    pub secret: String,
+}
+
+#[derive(Enum, Deserialize, Serialize, Copy, Clone, PartialEq, Eq, Display)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct DeliveryAttempt {
+    pub attempted_at: String,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// One event to post to one endpoint, with every attempt made so far.
+#[derive(SimpleObject, Deserialize, Serialize, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub merchant_id: String,
+    pub event_id: String,
+    pub event_type: WebhookEventType,
+    /// The exact body that is signed and posted.
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub created_at: String,
+    pub next_attempt_at: Option<String>,
+}
+
+/// Body of a webhook request.
+#[derive(Serialize)]
+struct WebhookEvent<'a, T: Serialize> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    event_type: WebhookEventType,
+    created_at: &'a str,
+    merchant_id: &'a str,
+    data: &'a T,
+}
+
+impl WebhookDelivery {
+    /// Pending deliveries of one event to every endpoint that subscribed to its type.
+    pub fn for_event<T: Serialize>(
+        webhooks: &[Webhook],
+        event_type: WebhookEventType,
+        data: &T,
+    ) -> Result<Vec<Self>, Error> {
+        let created_at = timestamp(Utc::now());
+        let event_id = uuid::Uuid::new_v4().to_string();
+        webhooks
+            .iter()
+            .filter(|webhook| webhook.events.contains(&event_type))
+            .map(|webhook| {
+                let payload = serde_json::to_string(&WebhookEvent {
+                    id: &event_id,
+                    event_type,
+                    created_at: &created_at,
+                    merchant_id: &webhook.merchant_id,
+                    data,
+                })?;
+                let suffix: u32 = rand::thread_rng().r#gen();
+                Ok(Self {
+                    id: format!("{DELIVERY_PREFIX}#{created_at}#{suffix:08x}"),
+                    webhook_id: webhook.id.clone(),
+                    merchant_id: webhook.merchant_id.clone(),
+                    event_id: event_id.clone(),
+                    event_type,
+                    payload,
+                    status: DeliveryStatus::Pending,
+                    attempts: Vec::new(),
+                    created_at: created_at.clone(),
+                    next_attempt_at: Some(created_at.clone()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Timestamps of deliveries sort lexicographically, which the outbox index relies on.
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// The `Webhook-Signature` header value for `body` sent at `sent_at` (unix seconds).
+pub fn signature(secret: &str, sent_at: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{sent_at}.{body}").as_bytes());
+    format!(
+        "t={sent_at},v1={}",
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Where a delivery goes after an attempt.
+pub enum DeliveryOutcome {
+    Delivered,
+    Retry { next_attempt_at: String },
+    Failed,
+}
+
+/// Delivers due outbox entries until the process exits. Several workers may run against the same
+/// table; each delivery is claimed before it is sent.
+pub async fn run_worker(store: SharedStore) {
+    let http = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(http) => http,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to start webhook worker");
+            return;
+        }
+    };
+    tracing::info!("Webhook worker started");
+    loop {
+        if deliver_due(store.as_ref(), &http, Utc::now()).await == 0 {
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Attempts the deliveries due at `now` once each. Returns how many were due.
+async fn deliver_due(store: &dyn Store, http: &reqwest::Client, now: DateTime<Utc>) -> usize {
+    let due = match store
+        .get_due_webhook_deliveries(&timestamp(now), POLL_BATCH)
+        .await
+    {
+        Ok(due) => due,
+        Err(err) => {
+            tracing::warn!(error = %format!("{err:#}"), "Failed to poll webhook outbox");
+            return 0;
+        }
+    };
+    let count = due.len();
+    stream::iter(due)
+        .for_each_concurrent(CONCURRENCY, |delivery| async move {
+            if let Err(err) = process(store, http, delivery).await {
+                tracing::warn!(error = %format!("{err:#}"), "Webhook delivery failed");
+            }
+        })
+        .await;
+    count
+}
+
+async fn process(
+    store: &dyn Store,
+    http: &reqwest::Client,
+    delivery: WebhookDelivery,
+) -> Result<(), Error> {
+    let lease_until = timestamp(Utc::now() + CLAIM_LEASE);
+    if !store
+        .claim_webhook_delivery(&delivery, &lease_until)
+        .await?
+    {
+        return Ok(());
+    }
+    let Some(webhook) = store
+        .get_webhook(&delivery.merchant_id, &delivery.webhook_id)
+        .await?
+    else {
+        let attempt = DeliveryAttempt {
+            attempted_at: timestamp(Utc::now()),
+            status_code: None,
+            error: Some("Webhook was deleted".to_string()),
+            duration_ms: 0,
+        };
+        return store
+            .record_webhook_delivery_attempt(&delivery, &attempt, &DeliveryOutcome::Failed)
+            .await;
+    };
+    let attempt = send(http, &webhook, &delivery.payload).await;
+    let attempts = delivery.attempts.len() + 1;
+    let outcome = if attempt.error.is_none() {
+        DeliveryOutcome::Delivered
+    } else if attempts >= MAX_ATTEMPTS {
+        DeliveryOutcome::Failed
+    } else {
+        DeliveryOutcome::Retry {
+            next_attempt_at: timestamp(Utc::now() + backoff(attempts)),
+        }
+    };
+    tracing::info!(
+        delivery_id = %delivery.id,
+        webhook_id = %delivery.webhook_id,
+        attempt = attempts,
+        status_code = attempt.status_code,
+        error = attempt.error.as_deref(),
+        "Webhook delivery attempted"
+    );
+    store
+        .record_webhook_delivery_attempt(&delivery, &attempt, &outcome)
+        .await
+}
+
+async fn send(http: &reqwest::Client, webhook: &Webhook, payload: &str) -> DeliveryAttempt {
+    let started = Instant::now();
+    let attempted_at = Utc::now();
+    let result = http
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            signature(&webhook.secret, attempted_at.timestamp(), payload),
+        )
+        .body(payload.to_string())
+        .send()
+        .await;
+    let (status_code, error) = match result {
+        Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16()), None),
+        Ok(resp) => (
+            Some(resp.status().as_u16()),
+            Some(format!("Endpoint responded {}", resp.status())),
+        ),
+        Err(err) => (None, Some(err.without_url().to_string())),
+    };
+    DeliveryAttempt {
+        attempted_at: timestamp(attempted_at),
+        status_code,
+        error,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Doubles from [`INITIAL_BACKOFF`] up to [`MAX_BACKOFF`], with up to 20% jitter so retries of
+/// one outage spread out.
+fn backoff(attempts: usize) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1))
+        .unwrap_or(u32::MAX)
+        .min(16);
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(1 << exponent)
+        .min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+}
+
+/// Only absolute http(s) URLs can be delivered to.
+pub fn validate_url(url: &str) -> Result<(), Error> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        Ok(url) => bail!(AppError::validation(format!(
+            "Unsupported webhook URL scheme {}",
+            url.scheme()
+        ))),
+        Err(err) => bail!(AppError::validation(format!("Invalid webhook URL: {err}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DeliveryStatus, INITIAL_BACKOFF, MAX_BACKOFF, SIGNATURE_HEADER, Webhook, WebhookEventType,
+        backoff, deliver_due, signature,
+    };
+    use crate::audit::Actor;
+    use crate::memory::MemoryStore;
+    use crate::models::{PageRequest, Role, TransactionStatus};
+    use crate::store::SharedStore;
+    use crate::testing::seed;
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn signatures_are_the_hmac_sha256_of_timestamp_and_body() {
+        assert_eq!(
+            signature("whsec_test", 1_700_000_000, r#"{"id":"evt_1"}"#),
+            "t=1700000000,v1=c89214b5b5da833daed6f0b8c5bb6bd58cea9022bd80ccc78230f3942d632925"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_maximum() {
+        for (attempts, expected) in [
+            (1, INITIAL_BACKOFF),
+            (2, INITIAL_BACKOFF * 2),
+            (4, INITIAL_BACKOFF * 8),
+            (20, MAX_BACKOFF),
+        ] {
+            let delay = backoff(attempts);
+            assert!(
+                delay >= expected.mul_f64(0.8) && delay <= expected.mul_f64(1.2),
+                "attempt {attempts}: {delay:?}"
+            );
+        }
+    }
+
+    // Received signature headers and bodies, shared with the stand-in endpoint.
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    // Fails the first request with a 500 and accepts the rest.
+    async fn endpoint(
+        received: web::Data<Received>,
+        req: HttpRequest,
+        body: String,
+    ) -> HttpResponse {
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let mut received = received.lock().unwrap();
+        received.push((signature, body));
+        if received.len() == 1 {
+            HttpResponse::InternalServerError().finish()
+        } else {
+            HttpResponse::Ok().finish()
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_worker_retries_failed_deliveries_until_delivered() {
+        let received = Received::default();
+        let server = {
+            let received = received.clone();
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(received.clone()))
+                    .route("/hook", web::post().to(endpoint))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        seed(&store).await;
+        let merchant_id = "MERCHANT#merchant_a_outlet";
+        let webhook = Webhook::new(
+            merchant_id.to_string(),
+            format!("http://{address}/hook"),
+            vec![WebhookEventType::TransactionChargebacked],
+        );
+        let actor = Actor {
+            id: "tester".to_string(),
+            role: Some(Role::Admin),
+            request_id: None,
+        };
+        store.add_webhook(&webhook, &actor).await.unwrap();
+        let (transactions, _) = store
+            .get_transactions(
+                merchant_id.to_string(),
+                None,
+                None,
+                None,
+                None,
+                PageRequest::forward(None, 1),
+            )
+            .await
+            .unwrap();
+        store
+            .update_transaction_status(
+                merchant_id,
+                &transactions[0].id,
+                TransactionStatus::Chargebacked,
+                &actor,
+            )
+            .await
+            .unwrap();
+        let deliveries = || async {
+            let (deliveries, _) = store
+                .get_webhook_deliveries(&webhook.id, PageRequest::forward(None, 10))
+                .await
+                .unwrap();
+            deliveries
+        };
+
+        let http = reqwest::Client::new();
+        let now = Utc::now();
+        assert_eq!(deliver_due(store.as_ref(), &http, now).await, 1);
+        let delivery = &deliveries().await[0];
+        assert!(delivery.status == DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts[0].status_code, Some(500));
+        assert_eq!(
+            deliver_due(store.as_ref(), &http, now).await,
+            0,
+            "the retry waits for its backoff"
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(deliver_due(store.as_ref(), &http, later).await, 1);
+        let delivery = &deliveries().await[0];
+        assert!(delivery.status == DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts.len(), 2);
+        assert_eq!(delivery.attempts[1].status_code, Some(200));
+        assert_eq!(deliver_due(store.as_ref(), &http, later).await, 0);
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        for (header, body) in &received {
+            assert_eq!(*body, delivery.payload);
+            let sent_at = header
+                .strip_prefix("t=")
+                .and_then(|rest| rest.split(',').next())
+                .and_then(|sent_at| sent_at.parse().ok())
+                .unwrap();
+            assert_eq!(*header, signature(&webhook.secret, sent_at, body));
+        }
+        handle.stop(true).await;
+    }
+}